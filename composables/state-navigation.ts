@@ -1,5 +1,5 @@
 import { listen } from "@tauri-apps/api/event";
-import { AppStatus, type AppState } from "~/types";
+import { AppStatus, type AppState, type TwoFactorChallenge } from "~/types";
 
 export function setupHooks() {
   const router = useRouter();
@@ -14,10 +14,19 @@ export function setupHooks() {
     );
   });
 
+  listen("auth/2fa", (event) => {
+    const { method } = event.payload as TwoFactorChallenge;
+    router.push(`/auth/2fa?method=${method}`);
+  });
+
   listen("auth/finished", (event) => {
     router.push("/store");
   });
 
+  listen("auth/signedout", (event) => {
+    router.push("/auth");
+  });
+
   /*
 
   document.addEventListener("contextmenu", (event) => {
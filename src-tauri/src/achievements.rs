@@ -0,0 +1,100 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+use crate::{
+    auth::generate_authorization_header,
+    cache::{read_metadata, write_metadata},
+    db::DatabaseImpls,
+    remote::{blocking_http_client, RemoteAccessError},
+    throttle::{send_throttled, RequestClass},
+    DB,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Achievement {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub icon_id: String,
+    // Seconds since the unix epoch, None while it's still locked
+    pub unlocked_at: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GameAchievements {
+    pub achievements: Vec<Achievement>,
+    pub earned: usize,
+    pub total: usize,
+    // Whether these came from the cache because the server couldn't be reached
+    pub offline: bool,
+}
+
+impl GameAchievements {
+    fn new(achievements: Vec<Achievement>, offline: bool) -> Self {
+        Self {
+            earned: achievements
+                .iter()
+                .filter(|achievement| achievement.unlocked_at.is_some())
+                .count(),
+            total: achievements.len(),
+            achievements,
+            offline,
+        }
+    }
+}
+
+fn cache_key(game_id: &str) -> String {
+    format!("achievements-{}", game_id)
+}
+
+fn fetch_remote_achievements(game_id: &str) -> Result<Vec<Achievement>, RemoteAccessError> {
+    let endpoint = DB
+        .fetch_base_url()
+        .join(&format!("/api/v1/client/achievements?id={}", game_id))?;
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Metadata, || {
+        client
+            .get(endpoint.to_string())
+            .header("Authorization", generate_authorization_header())
+    })?;
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    let achievements = response.json::<Vec<Achievement>>()?;
+    write_metadata(&cache_key(game_id), &achievements);
+    Ok(achievements)
+}
+
+fn fetch_achievements_logic(game_id: &str) -> Result<GameAchievements, RemoteAccessError> {
+    match fetch_remote_achievements(game_id) {
+        Ok(achievements) => Ok(GameAchievements::new(achievements, false)),
+        Err(e) if e.is_unreachable() => {
+            warn!("showing cached achievements for {}: {}", game_id, e);
+            let achievements = read_metadata(&cache_key(game_id)).ok_or(e)?;
+            Ok(GameAchievements::new(achievements, true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetched again once a game exits, since it's likely to have unlocked some
+pub fn refresh_achievements(app_handle: &AppHandle, game_id: &str) {
+    match fetch_remote_achievements(game_id) {
+        Ok(achievements) => app_handle
+            .emit(
+                &format!("update_achievements/{}", game_id),
+                GameAchievements::new(achievements, false),
+            )
+            .unwrap(),
+        Err(e) => warn!("couldn't refresh achievements for {}: {}", game_id, e),
+    }
+}
+
+#[tauri::command]
+pub fn fetch_achievements(game_id: String) -> Result<GameAchievements, String> {
+    fetch_achievements_logic(&game_id).map_err(|e| e.to_string())
+}
@@ -1,10 +1,12 @@
 use std::{
     env,
     sync::Mutex,
-    time::{SystemTime, UNIX_EPOCH},
+    thread::{sleep, spawn},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use chrono::Utc;
+use http::StatusCode;
 use log::{info, warn};
 use openssl::{ec::EcKey, hash::MessageDigest, pkey::PKey, sign::Signer};
 use serde::{Deserialize, Serialize};
@@ -12,16 +14,38 @@ use tauri::{AppHandle, Emitter, Manager};
 use url::Url;
 
 use crate::{
+    cache::clear_metadata,
     db::{DatabaseAuth, DatabaseImpls},
-    remote::RemoteAccessError,
+    devices::register_device,
+    keychain::{delete_secrets, load_secrets, store_secrets},
+    remote::{blocking_http_client, http_client, NetworkTimeouts, RemoteAccessError},
+    signing::sign_request,
     AppState, AppStatus, User, DB,
 };
 
+/// First wait before the startup healthcheck is retried, doubled each time
+const HEALTHCHECK_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_HEALTHCHECK_RETRY_DELAY: Duration = Duration::from_secs(16);
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthcheckRetryEvent {
+    pub attempt: u32,
+    // Seconds until the next attempt
+    pub retry_in: u64,
+    pub error: String,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct InitiateRequestBody {
     name: String,
     platform: String,
+    // Where the browser is sent once the user has signed in, and the
+    // state it has to bring back. Older servers ignore both and send
+    // the browser to drop://handshake instead.
+    callback: String,
+    state: String,
 }
 
 #[derive(Serialize)]
@@ -39,6 +63,58 @@ struct HandshakeResponse {
     id: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TwoFactorMethod {
+    Totp,
+    Email,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFactorChallenge {
+    challenge_id: String,
+    method: TwoFactorMethod,
+}
+
+// Servers with 2FA turned on answer the handshake with a challenge instead
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HandshakeOutcome {
+    Complete(HandshakeResponse),
+    Challenge { challenge: TwoFactorChallenge },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFactorRequestBody {
+    client_id: String,
+    challenge_id: String,
+    code: String,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorEvent {
+    pub method: TwoFactorMethod,
+}
+
+/// A handshake that's waiting on the user's code
+#[derive(Clone)]
+struct PendingChallenge {
+    client_id: String,
+    challenge_id: String,
+}
+
+static PENDING_CHALLENGE: Mutex<Option<PendingChallenge>> = Mutex::new(None);
+
+const AUTH_CALLBACK_URL: &str = "drop://auth/callback";
+
+/// The state sent with the last sign-in started from here. A callback has
+/// to bring it back, so a link from anywhere else can't sign the client
+/// in to an account that isn't the user's.
+static PENDING_SIGN_IN_STATE: Mutex<Option<String>> = Mutex::new(None);
+
 pub fn sign_nonce(private_key: String, nonce: String) -> Result<String, ()> {
     let client_private_key = EcKey::private_key_from_pem(private_key.as_bytes()).unwrap();
     let pkey_private_key = PKey::from_ec_key(client_private_key).unwrap();
@@ -71,15 +147,14 @@ pub fn fetch_user() -> Result<User, RemoteAccessError> {
     let endpoint = base_url.join("/api/v1/client/user")?;
     let header = generate_authorization_header();
 
-    let client = reqwest::blocking::Client::new();
+    let client = blocking_http_client();
     let response = client
         .get(endpoint.to_string())
         .header("Authorization", header)
         .send()?;
 
     if response.status() != 200 {
-        info!("Could not fetch user: {}", response.text().unwrap());
-        return Err(RemoteAccessError::InvalidCodeError(0));
+        return Err(RemoteAccessError::from_response(response));
     }
 
     let user = response.json::<User>()?;
@@ -87,42 +162,117 @@ pub fn fetch_user() -> Result<User, RemoteAccessError> {
     Ok(user)
 }
 
-fn recieve_handshake_logic(app: &AppHandle, path: String) -> Result<(), RemoteAccessError> {
+// Returns how the user has to prove it's them, if the server wants a code
+fn recieve_handshake_logic(
+    app: &AppHandle,
+    path: String,
+) -> Result<Option<TwoFactorMethod>, RemoteAccessError> {
     let path_chunks: Vec<&str> = path.split("/").collect();
     if path_chunks.len() != 3 {
-        app.emit("auth/failed", ()).unwrap();
         return Err(RemoteAccessError::InvalidResponse);
     }
 
+    // Servers that don't send a callback can't bring the state back, but
+    // a sign-in still has to have been started from here, and only once
+    if PENDING_SIGN_IN_STATE.lock().unwrap().take().is_none() {
+        return Err(RemoteAccessError::UnexpectedCallback);
+    }
+
+    let client_id = path_chunks.get(1).unwrap();
+    let token = path_chunks.get(2).unwrap();
+    handshake(app, client_id, token)
+}
+
+// The callback carries the same as a handshake link, as a query along
+// with the state the sign-in was started with
+fn recieve_auth_callback_logic(
+    app: &AppHandle,
+    url: &Url,
+) -> Result<Option<TwoFactorMethod>, RemoteAccessError> {
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let (client_id, token, state) = match (query("client"), query("token"), query("state")) {
+        (Some(client_id), Some(token), Some(state)) => (client_id, token, state),
+        _ => return Err(RemoteAccessError::InvalidResponse),
+    };
+
+    // Only good for the one sign-in
+    let expected_state = PENDING_SIGN_IN_STATE.lock().unwrap().take();
+    if expected_state.as_deref() != Some(state.as_str()) {
+        return Err(RemoteAccessError::UnexpectedCallback);
+    }
+
+    handshake(app, &client_id, &token)
+}
+
+fn handshake(
+    app: &AppHandle,
+    client_id: &str,
+    token: &str,
+) -> Result<Option<TwoFactorMethod>, RemoteAccessError> {
     let base_url = {
         let handle = DB.borrow_data().unwrap();
         Url::parse(handle.base_url.as_str())?
     };
 
-    let client_id = path_chunks.get(1).unwrap();
-    let token = path_chunks.get(2).unwrap();
     let body = HandshakeRequestBody {
         client_id: client_id.to_string(),
         token: token.to_string(),
     };
 
     let endpoint = base_url.join("/api/v1/client/auth/handshake")?;
-    let client = reqwest::blocking::Client::new();
+    let client = blocking_http_client();
     let response = client.post(endpoint).json(&body).send()?;
     info!("{}", response.status().as_u16());
-    let response_struct = response.json::<HandshakeResponse>()?;
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+    let response_struct = match response.json::<HandshakeOutcome>()? {
+        HandshakeOutcome::Complete(response_struct) => response_struct,
+        HandshakeOutcome::Challenge { challenge } => {
+            info!(
+                "server wants a {:?} code to finish signing in",
+                challenge.method
+            );
+            *PENDING_CHALLENGE.lock().unwrap() = Some(PendingChallenge {
+                client_id: client_id.to_string(),
+                challenge_id: challenge.challenge_id,
+            });
+            let app_state = app.state::<Mutex<AppState>>();
+            app_state.lock().unwrap().status = AppStatus::AwaitingTwoFactor;
+            return Ok(Some(challenge.method));
+        }
+    };
 
+    complete_sign_in(app, response_struct)?;
+    Ok(None)
+}
+
+fn complete_sign_in(
+    app: &AppHandle,
+    response_struct: HandshakeResponse,
+) -> Result<(), RemoteAccessError> {
     {
-        let mut handle = DB.borrow_data_mut().unwrap();
-        handle.auth = Some(DatabaseAuth {
+        let mut auth = DatabaseAuth {
             private: response_struct.private,
             cert: response_struct.certificate,
             client_id: response_struct.id,
-        });
+            signing_secret: None,
+            in_keychain: false,
+        };
+        // Before it's saved, so the private key never touches the file
+        store_secrets(&mut auth);
+        let mut handle = DB.borrow_data_mut().unwrap();
+        handle.auth = Some(auth);
         drop(handle);
         DB.save().unwrap();
     }
 
+    register_device();
+
     {
         let app_state = app.state::<Mutex<AppState>>();
         let mut app_state_handle = app_state.lock().unwrap();
@@ -138,13 +288,74 @@ pub fn recieve_handshake(app: AppHandle, path: String) {
     app.emit("auth/processing", ()).unwrap();
 
     let handshake_result = recieve_handshake_logic(&app, path);
-    if let Err(e) = handshake_result {
-        warn!("error with authentication: {}", e);
-        app.emit("auth/failed", e.to_string()).unwrap();
-        return;
+    emit_handshake_result(&app, handshake_result);
+}
+
+/// Finishes a sign-in from the drop://auth/callback link the browser is
+/// sent to, so the credentials never pass through the webview
+pub fn recieve_auth_callback(app: AppHandle, url: Url) {
+    app.emit("auth/processing", ()).unwrap();
+
+    let handshake_result = recieve_auth_callback_logic(&app, &url);
+    emit_handshake_result(&app, handshake_result);
+}
+
+fn emit_handshake_result(
+    app: &AppHandle,
+    handshake_result: Result<Option<TwoFactorMethod>, RemoteAccessError>,
+) {
+    match handshake_result {
+        Ok(Some(method)) => app.emit("auth/2fa", TwoFactorEvent { method }).unwrap(),
+        Ok(None) => app.emit("auth/finished", ()).unwrap(),
+        Err(e) => {
+            warn!("error with authentication: {}", e);
+            app.emit("auth/failed", e.to_string()).unwrap();
+        }
+    }
+}
+
+fn submit_2fa_code_logic(app: &AppHandle, code: &str) -> Result<(), RemoteAccessError> {
+    let pending = PENDING_CHALLENGE
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or(RemoteAccessError::TwoFactorExpired)?;
+
+    let endpoint = DB.fetch_base_url().join("/api/v1/client/auth/2fa")?;
+    let response = blocking_http_client()
+        .post(endpoint)
+        .json(&TwoFactorRequestBody {
+            client_id: pending.client_id,
+            challenge_id: pending.challenge_id,
+            code: code.trim().to_string(),
+        })
+        .send()?;
+    match response.status() {
+        StatusCode::OK => {}
+        // Left pending, so the user can have another go
+        StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => {
+            return Err(RemoteAccessError::InvalidTwoFactorCode)
+        }
+        // The whole sign-in has to start again
+        StatusCode::GONE => {
+            *PENDING_CHALLENGE.lock().unwrap() = None;
+            let app_state = app.state::<Mutex<AppState>>();
+            app_state.lock().unwrap().status = AppStatus::SignedOut;
+            return Err(RemoteAccessError::TwoFactorExpired);
+        }
+        _ => return Err(RemoteAccessError::from_response(response)),
     }
 
+    let response_struct = response.json::<HandshakeResponse>()?;
+    *PENDING_CHALLENGE.lock().unwrap() = None;
+    complete_sign_in(app, response_struct)
+}
+
+#[tauri::command]
+pub fn submit_2fa_code(app: AppHandle, code: String) -> Result<(), String> {
+    submit_2fa_code_logic(&app, &code).map_err(|e| e.to_string())?;
     app.emit("auth/finished", ()).unwrap();
+    Ok(())
 }
 
 async fn auth_initiate_wrapper() -> Result<(), RemoteAccessError> {
@@ -154,16 +365,25 @@ async fn auth_initiate_wrapper() -> Result<(), RemoteAccessError> {
     };
 
     let endpoint = base_url.join("/api/v1/client/auth/initiate")?;
+    let state = uuid::Uuid::new_v4().simple().to_string();
+    *PENDING_SIGN_IN_STATE.lock().unwrap() = Some(state.clone());
     let body = InitiateRequestBody {
         name: "Drop Desktop Client".to_string(),
         platform: env::consts::OS.to_string(),
+        callback: AUTH_CALLBACK_URL.to_string(),
+        state,
     };
 
-    let client = reqwest::Client::new();
-    let response = client.post(endpoint.to_string()).json(&body).send().await?;
+    let client = http_client();
+    let response = client
+        .post(endpoint.to_string())
+        .json(&body)
+        .timeout(NetworkTimeouts::current().request)
+        .send()
+        .await?;
 
     if response.status() != 200 {
-        return Err(RemoteAccessError::InvalidRedirect);
+        return Err(RemoteAccessError::from_async_response(response).await);
     }
 
     let redir_url = response.text().await?;
@@ -185,6 +405,57 @@ pub async fn auth_initiate<'a>() -> Result<(), String> {
     Ok(())
 }
 
+fn revoke_token() -> Result<(), RemoteAccessError> {
+    let endpoint = DB.fetch_base_url().join("/api/v1/client/auth/revoke")?;
+    let request = blocking_http_client()
+        .post(endpoint.clone())
+        .header("Authorization", generate_authorization_header());
+    let response = sign_request(request, "POST", endpoint.as_str(), &[]).send()?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response));
+    }
+    Ok(())
+}
+
+/// Forgets this client's credentials and everything fetched with them.
+/// Downloads are cancelled too, since they can't carry on without them.
+pub fn clear_sign_in(app: &AppHandle) {
+    let state = app.state::<Mutex<AppState>>();
+    let download_manager = state.lock().unwrap().download_manager.clone();
+    for download in download_manager.read_queue() {
+        download_manager.cancel(download.id.clone());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    if let Some(auth) = db_lock.auth.take() {
+        delete_secrets(&auth);
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+    clear_metadata();
+
+    let mut state_lock = state.lock().unwrap();
+    state_lock.status = AppStatus::SignedOut;
+    state_lock.user = None;
+    state_lock.games.clear();
+}
+
+#[tauri::command]
+pub fn sign_out(app: AppHandle) {
+    if DB.borrow_data().unwrap().auth.is_none() {
+        return;
+    }
+    // Signing out locally still goes ahead, the server just won't know
+    match revoke_token() {
+        Ok(()) => info!("revoked this client's token"),
+        Err(e) => warn!("couldn't revoke this client's token: {}", e),
+    }
+
+    clear_sign_in(&app);
+    info!("signed out");
+    app.emit("auth/signedout", ()).unwrap();
+}
+
 #[tauri::command]
 pub fn retry_connect(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), ()> {
     let (app_status, user) = setup()?;
@@ -198,17 +469,65 @@ pub fn retry_connect(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), ()>
 }
 
 pub fn setup() -> Result<(AppStatus, Option<User>), ()> {
+    match setup_logic() {
+        Ok(result) => Ok(result),
+        Err(_) => Ok((AppStatus::ServerUnavailable, None)),
+    }
+}
+
+// Loads the secrets kept in the keychain, or moves them there if they're
+// still in the database from before. Ok(false) if the sign-in can't be used
+// without them, Err if the keychain can't be read yet, which leaves the
+// sign-in as it is.
+fn prepare_credentials() -> Result<bool, String> {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let auth = match db_lock.auth.as_mut() {
+        Some(auth) => auth,
+        None => return Ok(true),
+    };
+    if auth.in_keychain {
+        if load_secrets(auth)? {
+            return Ok(true);
+        }
+        db_lock.auth = None;
+        drop(db_lock);
+        DB.save().unwrap();
+        return Ok(false);
+    }
+
+    store_secrets(auth);
+    // Otherwise there was no keychain, and nothing's changed
+    if auth.in_keychain {
+        drop(db_lock);
+        DB.save().unwrap();
+    }
+    Ok(true)
+}
+
+// Fails only when the server couldn't be reached, with why
+fn setup_logic() -> Result<(AppStatus, Option<User>), RemoteAccessError> {
+    match prepare_credentials() {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("signing in again, the saved credentials are gone");
+            return Ok((AppStatus::SignedOut, None));
+        }
+        // Like a keyring that's still locked just after logging in, so
+        // it's retried the same way as a server that's down
+        Err(e) => return Err(RemoteAccessError::CredentialsUnavailable(e)),
+    }
     let data = DB.borrow_data().unwrap();
 
     if data.auth.is_some() {
+        drop(data);
         let user_result = fetch_user();
         if user_result.is_err() {
             let error = user_result.err().unwrap();
             warn!("auth setup failed with: {}", error);
             match error {
-                RemoteAccessError::FetchError(_) => {
-                    return Ok((AppStatus::ServerUnavailable, None))
-                }
+                RemoteAccessError::FetchError(_) => return Err(error),
+                // Like a proxy in front of a server that's restarting
+                _ if error.is_retryable() => return Err(error),
                 _ => return Ok((AppStatus::SignedInNeedsReauth, None)),
             }
         }
@@ -219,3 +538,61 @@ pub fn setup() -> Result<(AppStatus, Option<User>), ()> {
 
     Ok((AppStatus::SignedOut, None))
 }
+
+/// The server's often only briefly down when the app starts, so it's tried
+/// again with backoff for the startup retry period before the app gives up.
+/// Emits healthcheck/retrying before each wait, then healthcheck/recovered
+/// with the new status or healthcheck/failed.
+pub fn retry_startup_healthcheck(app: AppHandle) {
+    spawn(move || {
+        let period = Duration::from_secs(DB.borrow_data().unwrap().settings.startup_retry_period);
+        let started = Instant::now();
+        let mut delay = HEALTHCHECK_RETRY_DELAY;
+        let mut attempt = 1;
+        let mut error = String::from("Server unavailable");
+        loop {
+            let remaining = period.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                warn!("server still unavailable after {} attempts", attempt);
+                app.emit("healthcheck/failed", error).unwrap();
+                return;
+            }
+
+            let wait = delay.min(remaining);
+            app.emit(
+                "healthcheck/retrying",
+                HealthcheckRetryEvent {
+                    attempt,
+                    retry_in: wait.as_secs(),
+                    error: error.clone(),
+                },
+            )
+            .unwrap();
+            sleep(wait);
+            attempt += 1;
+
+            match setup_logic() {
+                Ok((status, user)) => {
+                    info!("server reachable after {} attempts", attempt);
+                    let state = app.state::<Mutex<AppState>>();
+                    let mut guard = state.lock().unwrap();
+                    guard.status = status;
+                    guard.user = user;
+                    drop(guard);
+                    app.emit("healthcheck/recovered", status).unwrap();
+                    return;
+                }
+                Err(e) => error = e.to_string(),
+            }
+            delay = (delay * 2).min(MAX_HEALTHCHECK_RETRY_DELAY);
+        }
+    });
+}
+
+#[tauri::command]
+pub fn set_startup_retry_period(seconds: u64) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.startup_retry_period = seconds;
+    drop(db_lock);
+    DB.save().unwrap();
+}
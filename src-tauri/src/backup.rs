@@ -0,0 +1,141 @@
+use std::{fs, path::Path};
+
+use chrono::Utc;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::AppHandle;
+
+use crate::{
+    cleanup::shutdown_download_manager,
+    db::{Database, GameStatus},
+    migrations::migrate,
+    DB,
+};
+
+/// Marks a file as one of ours, so importing something else fails early
+const BACKUP_FORMAT: &str = "drop-app-backup";
+
+/// Settings left out of backups, since they hold secrets or decide who to trust
+const SECRET_SETTINGS: [&str; 3] = ["clientCertificates", "pinnedCertificates", "shortcutKey"];
+
+/// Everything the app keeps about the library, its settings and each game's
+/// configuration, in one file. Game files aren't included, and neither is
+/// the sign-in or anything else secret, which belongs to the machine it was
+/// made on.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Backup {
+    format: String,
+    // RFC 3339
+    created_at: String,
+    // As it's stored, so it goes through migrations like the database does
+    database: Value,
+}
+
+fn export_app_data_logic(path: &Path) -> Result<(), String> {
+    let mut database = serde_json::to_value(&*DB.borrow_data().unwrap()).unwrap();
+    let database_object = database.as_object_mut().unwrap();
+    database_object.remove("auth");
+    // Client certificates' keys, and which certificates this machine trusts
+    if let Some(settings) = database_object
+        .get_mut("settings")
+        .and_then(Value::as_object_mut)
+    {
+        for secret in SECRET_SETTINGS {
+            settings.remove(secret);
+        }
+    }
+
+    let backup = Backup {
+        format: BACKUP_FORMAT.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+        database,
+    };
+    fs::write(path, serde_json::to_vec_pretty(&backup).unwrap()).map_err(|e| e.to_string())?;
+    info!("backed up app data to {}", path.display());
+    Ok(())
+}
+
+#[tauri::command]
+pub fn export_app_data(path: String) -> Result<(), String> {
+    export_app_data_logic(Path::new(&path))
+}
+
+// Whatever in the backup only made sense on the machine it came from
+fn adapt_to_this_machine(database: &mut Database, current: &Database) {
+    // Still signed in, as long as it's the same server
+    database.auth = if database.base_url == current.base_url {
+        current.auth.clone()
+    } else {
+        None
+    };
+    // Never in the backup, so they're kept as they are
+    database.settings.client_certificates = current.settings.client_certificates.clone();
+    database.settings.pinned_certificates = current.settings.pinned_certificates.clone();
+    database.settings.shortcut_key = current.settings.shortcut_key.clone();
+
+    let games = &mut database.games;
+    games
+        .install_dirs
+        .retain(|install_dir| Path::new(install_dir).is_dir());
+    if games.install_dirs.is_empty() {
+        games.install_dirs = current.games.install_dirs.clone();
+    }
+    if games.default_install_dir >= games.install_dirs.len() {
+        games.default_install_dir = 0;
+    }
+    // Queued against install directories that may have moved
+    games.download_queue.clear();
+
+    // Games that weren't copied over can be downloaded again
+    let missing: Vec<String> = games
+        .statuses
+        .iter()
+        .filter(|(_, status)| {
+            status
+                .installed()
+                .is_some_and(|(_, install_dir)| !Path::new(install_dir).is_dir())
+        })
+        .map(|(game_id, _)| game_id.clone())
+        .collect();
+    for game_id in missing {
+        games
+            .statuses
+            .insert(game_id.clone(), GameStatus::Remote {});
+        games.install_manifests.remove(&game_id);
+    }
+}
+
+// The backup's database, ready to replace the current one
+fn read_backup(path: &Path) -> Result<Database, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let backup = serde_json::from_slice::<Backup>(&data)
+        .ok()
+        .filter(|backup| backup.format == BACKUP_FORMAT)
+        .ok_or("That file isn't a Drop backup")?;
+    info!(
+        "restoring app data from {}, backed up at {}",
+        path.display(),
+        backup.created_at
+    );
+
+    let mut database = backup.database;
+    migrate(&mut database)?;
+    let mut database: Database = serde_json::from_value(database)
+        .map_err(|e| format!("The backup couldn't be read: {}", e))?;
+    let current = DB.borrow_data().unwrap().clone();
+    adapt_to_this_machine(&mut database, &current);
+    Ok(database)
+}
+
+/// Replaces the app's data with a backup's, then restarts so that nothing
+/// carries on with what was loaded from before
+#[tauri::command]
+pub fn import_app_data(app: AppHandle, path: String) -> Result<(), String> {
+    let database = read_backup(Path::new(&path))?;
+    // So it doesn't write its own state over the backup's
+    shutdown_download_manager(&app);
+    DB.put_data(database, true).map_err(|e| e.to_string())?;
+    app.restart();
+}
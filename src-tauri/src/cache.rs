@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all, rename, File},
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    header::{ETAG, IF_NONE_MATCH},
+    StatusCode,
+};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use url::Url;
+
+use crate::{
+    auth::generate_authorization_header,
+    db::DatabaseImpls,
+    downloads::manifest::DropManifest,
+    profiles::profile_dir,
+    remote::{blocking_http_client, RemoteAccessError},
+    throttle::{send_throttled, RequestClass},
+    DB,
+};
+
+/// Metadata, images and manifests from the server, kept so the library
+/// still shows up when the server can't be reached. It's kept under the
+/// cache_size_limit setting by removing whatever was used longest ago.
+fn cache_dir() -> PathBuf {
+    profile_dir().join("cache")
+}
+
+fn metadata_dir() -> PathBuf {
+    cache_dir().join("metadata")
+}
+
+fn objects_dir() -> PathBuf {
+    cache_dir().join("objects")
+}
+
+fn manifests_dir() -> PathBuf {
+    cache_dir().join("manifests")
+}
+
+// Bytes in the cache, counted the first time a write needs it and added
+// to after that. Files removed by hand aren't noticed until the next count.
+static CACHE_SIZE: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Everything cached under one key, e.g. an object and its type
+struct CacheEntry {
+    files: Vec<PathBuf>,
+    size: u64,
+    last_used: SystemTime,
+}
+
+fn cache_entries() -> Vec<CacheEntry> {
+    let mut entries: HashMap<PathBuf, CacheEntry> = HashMap::new();
+    for dir in [metadata_dir(), objects_dir(), manifests_dir()] {
+        let files = match fs::read_dir(&dir) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for file in files.flatten() {
+            let metadata = match file.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            // Keys don't have dots in them, so what's before the first is the key
+            let name = file.file_name().to_string_lossy().into_owned();
+            let key = name.split('.').next().unwrap_or_default().to_string();
+            let entry = entries.entry(dir.join(key)).or_insert(CacheEntry {
+                files: Vec::new(),
+                size: 0,
+                last_used: UNIX_EPOCH,
+            });
+            entry.files.push(file.path());
+            entry.size += metadata.len();
+            entry.last_used = entry
+                .last_used
+                .max(metadata.modified().unwrap_or(UNIX_EPOCH));
+        }
+    }
+    entries.into_values().collect()
+}
+
+/// Removes the least recently used entries until the cache fits in the
+/// limit. Returns what it's left at.
+fn evict(limit: u64) -> u64 {
+    let mut entries = cache_entries();
+    let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let mut evicted = 0;
+    for entry in entries {
+        if size <= limit {
+            break;
+        }
+        for file in &entry.files {
+            let _ = fs::remove_file(file);
+        }
+        size -= entry.size;
+        evicted += 1;
+    }
+    if evicted > 0 {
+        info!(
+            "evicted {} entries from the cache, down to {} bytes",
+            evicted, size
+        );
+    }
+    size
+}
+
+fn note_written(bytes: u64) {
+    let limit = DB.borrow_data().unwrap().settings.cache_size_limit;
+    let mut cache_size = CACHE_SIZE.lock().unwrap();
+    let size = match *cache_size {
+        Some(size) => size + bytes,
+        // Counting picks up what was just written
+        None => cache_entries().iter().map(|entry| entry.size).sum(),
+    };
+    *cache_size = Some(if size > limit { evict(limit) } else { size });
+}
+
+// Reads count as a use, so what's shown often stays cached
+fn touch(path: &PathBuf) {
+    let _ = File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+// Keys come from the server, so anything that could be read as
+// a path is swapped out
+fn file_name(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Written alongside and renamed over, so readers never see half a file
+fn write_atomic(path: PathBuf, data: &[u8]) {
+    let result = create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(path.with_extension("partial"), data))
+        .and_then(|_| rename(path.with_extension("partial"), &path));
+    match result {
+        Ok(()) => note_written(data.len() as u64),
+        Err(e) => warn!("couldn't write {} to the cache: {}", path.display(), e),
+    }
+}
+
+pub fn read_metadata<T: DeserializeOwned>(key: &str) -> Option<T> {
+    let path = metadata_dir().join(format!("{}.json", file_name(key)));
+    let data = fs::read(&path).ok()?;
+    touch(&path);
+    serde_json::from_slice(&data).ok()
+}
+
+pub fn write_metadata<T: Serialize>(key: &str, value: &T) {
+    let data = serde_json::to_vec(value).unwrap();
+    write_atomic(
+        metadata_dir().join(format!("{}.json", file_name(key))),
+        &data,
+    );
+}
+
+// The server's ETag for what's cached under the key, kept next to it
+fn etag_path(key: &str) -> PathBuf {
+    metadata_dir().join(format!("{}.etag", file_name(key)))
+}
+
+/// Fetches metadata, asking the server to only send it if it's changed
+/// since the cached copy. Returns it along with whether it changed, so
+/// callers can skip updating anything when it hasn't.
+pub fn fetch_metadata<T: DeserializeOwned + Serialize>(
+    endpoint: &Url,
+    key: &str,
+) -> Result<(T, bool), RemoteAccessError> {
+    let etag = fs::read_to_string(etag_path(key)).ok();
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Metadata, || {
+        let request = client
+            .get(endpoint.to_string())
+            .header("Authorization", generate_authorization_header());
+        match &etag {
+            Some(etag) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        }
+    })?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = read_metadata(key) {
+            return Ok((cached, false));
+        }
+        // The cached copy went missing, so it has to be fetched in full
+        let _ = fs::remove_file(etag_path(key));
+        return fetch_metadata(endpoint, key);
+    }
+    if response.status() != StatusCode::OK {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let value = response.json::<T>()?;
+    write_metadata(key, &value);
+    match etag {
+        Some(etag) => write_atomic(etag_path(key), etag.as_bytes()),
+        None => {
+            let _ = fs::remove_file(etag_path(key));
+        }
+    }
+
+    Ok((value, true))
+}
+
+/// Drops everything fetched for the signed in user. Objects stay, since
+/// they're the same for everyone.
+pub fn clear_metadata() {
+    if let Err(e) = fs::remove_dir_all(metadata_dir()) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("couldn't clear cached metadata: {}", e);
+        }
+    }
+}
+
+/// Drops the whole cache, for when the client is pointed at another server
+/// and nothing fetched from the old one applies any more
+pub fn clear_all() {
+    if let Err(e) = fs::remove_dir_all(cache_dir()) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("couldn't clear the cache: {}", e);
+        }
+    }
+    *CACHE_SIZE.lock().unwrap() = Some(0);
+}
+
+/// The object's content type and data, if it's been fetched before
+pub fn read_object(object_id: &str) -> Option<(String, Vec<u8>)> {
+    let path = objects_dir().join(file_name(object_id));
+    let content_type = fs::read_to_string(path.with_extension("type")).ok()?;
+    let data = fs::read(&path).ok()?;
+    touch(&path);
+    Some((content_type, data))
+}
+
+fn write_object(object_id: &str, content_type: &str, data: &[u8]) {
+    let path = objects_dir().join(file_name(object_id));
+    // The data goes first, since the type file is what marks it as there
+    write_atomic(path.clone(), data);
+    write_atomic(path.with_extension("type"), content_type.as_bytes());
+}
+
+pub fn remove_object(object_id: &str) {
+    let path = objects_dir().join(file_name(object_id));
+    let _ = fs::remove_file(path.with_extension("type"));
+    let _ = fs::remove_file(path);
+}
+
+/// Objects never change once they're uploaded, so the cached copy is used
+/// whenever there is one
+pub fn fetch_object(object_id: &str) -> Result<(String, Vec<u8>), RemoteAccessError> {
+    if let Some(object) = read_object(object_id) {
+        return Ok(object);
+    }
+
+    let object_url = DB
+        .fetch_base_url()
+        .join("/api/v1/client/object/")?
+        .join(object_id)?;
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Objects, || {
+        client
+            .get(object_url.to_string())
+            .header("Authorization", generate_authorization_header())
+    })?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    let content_type = response
+        .headers()
+        .get("Content-Type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let data = response.bytes()?.to_vec();
+    write_object(object_id, &content_type, &data);
+
+    Ok((content_type, data))
+}
+
+// Versions can have any characters in them, so they're hashed rather than
+// risk two of them being cleaned up into the same file name
+fn manifest_path(game_id: &str, version: &str) -> PathBuf {
+    let key = blake3::hash(format!("{}/{}", game_id, version).as_bytes());
+    manifests_dir().join(format!("{}.json", key.to_hex()))
+}
+
+/// A version's manifest, if it's been fetched before. Versions don't change
+/// once they're uploaded, so it's used in place of the server's until a
+/// download that used it fails to verify.
+pub fn read_manifest(game_id: &str, version: &str) -> Option<DropManifest> {
+    let path = manifest_path(game_id, version);
+    let data = fs::read(&path).ok()?;
+    touch(&path);
+    serde_json::from_slice(&data).ok()
+}
+
+pub fn write_manifest(game_id: &str, version: &str, manifest: &DropManifest) {
+    let data = serde_json::to_vec(manifest).unwrap();
+    write_atomic(manifest_path(game_id, version), &data);
+}
+
+/// For when the cached manifest doesn't match what the server sends, so the
+/// next download fetches it again
+pub fn remove_manifest(game_id: &str, version: &str) {
+    let _ = fs::remove_file(manifest_path(game_id, version));
+}
+
+/// Bytes the cache takes up right now
+#[tauri::command]
+pub fn fetch_cache_size() -> u64 {
+    let size = cache_entries().iter().map(|entry| entry.size).sum();
+    *CACHE_SIZE.lock().unwrap() = Some(size);
+    size
+}
+
+#[tauri::command]
+pub fn set_cache_size_limit(limit: u64) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.cache_size_limit = limit;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    *CACHE_SIZE.lock().unwrap() = Some(evict(limit));
+}
+
+/// Empties the cache, returning how many bytes that freed. Anything that's
+/// needed again is fetched from the server.
+#[tauri::command]
+pub fn clear_cache() -> u64 {
+    let freed = fetch_cache_size();
+    clear_all();
+    info!("cleared {} bytes from the cache", freed);
+    freed
+}
@@ -1,7 +1,7 @@
 use std::sync::Mutex;
 
-use log::info;
-use tauri::AppHandle;
+use log::{error, info};
+use tauri::{AppHandle, Manager};
 
 use crate::AppState;
 
@@ -14,5 +14,20 @@ pub fn quit(app: tauri::AppHandle) {
 pub fn cleanup_and_exit(app: &AppHandle, ) {
     info!("exiting drop application...");
 
+    shutdown_download_manager(app);
+
     app.exit(0);
 }
+
+/// Waits for in-progress downloads to save their state before the process
+/// goes away. Called both from our own quit path and from Tauri's exit event.
+pub fn shutdown_download_manager(app: &AppHandle) {
+    let state = app.state::<Mutex<AppState>>();
+    let download_manager = state.lock().unwrap().download_manager.clone();
+
+    match download_manager.ensure_terminated() {
+        Ok(Ok(())) => {}
+        Ok(Err(())) => error!("download manager exited without finishing"),
+        Err(_) => error!("download manager thread panicked"),
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use uuid::Uuid;
+
+use crate::DB;
+
+/// A named group of games the user put together. Kept entirely on this
+/// machine, the server never hears about them.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    pub id: String,
+    pub name: String,
+    // In the order they were added
+    pub game_ids: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionsUpdateEvent {
+    pub collections: Vec<Collection>,
+    pub tags: HashMap<String, Vec<String>>,
+}
+
+fn clean_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Name can't be empty".to_string());
+    }
+    Ok(name.to_string())
+}
+
+// Sends everything, since there's never much of it
+fn emit_collections(app_handle: &AppHandle) {
+    let db_lock = DB.borrow_data().unwrap();
+    let event = CollectionsUpdateEvent {
+        collections: db_lock.games.collections.clone(),
+        tags: db_lock.games.tags.clone(),
+    };
+    drop(db_lock);
+    app_handle.emit("update_collections", event).unwrap();
+}
+
+fn modify_collection<F>(app_handle: &AppHandle, collection_id: &str, f: F) -> Result<(), String>
+where
+    F: FnOnce(&mut Collection),
+{
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let collection = db_lock
+        .games
+        .collections
+        .iter_mut()
+        .find(|collection| collection.id == collection_id)
+        .ok_or("Collection doesn't exist".to_string())?;
+    f(collection);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    emit_collections(app_handle);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_collections() -> Vec<Collection> {
+    DB.borrow_data().unwrap().games.collections.clone()
+}
+
+#[tauri::command]
+pub fn create_collection(app_handle: AppHandle, name: String) -> Result<Collection, String> {
+    let collection = Collection {
+        id: Uuid::new_v4().to_string(),
+        name: clean_name(&name)?,
+        game_ids: Vec::new(),
+    };
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.games.collections.push(collection.clone());
+    drop(db_lock);
+    DB.save().unwrap();
+
+    emit_collections(&app_handle);
+    Ok(collection)
+}
+
+#[tauri::command]
+pub fn rename_collection(
+    app_handle: AppHandle,
+    collection_id: String,
+    name: String,
+) -> Result<(), String> {
+    let name = clean_name(&name)?;
+    modify_collection(&app_handle, &collection_id, |collection| {
+        collection.name = name
+    })
+}
+
+#[tauri::command]
+pub fn delete_collection(app_handle: AppHandle, collection_id: String) -> Result<(), String> {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let collections = &mut db_lock.games.collections;
+    let before = collections.len();
+    collections.retain(|collection| collection.id != collection_id);
+    if collections.len() == before {
+        return Err("Collection doesn't exist".to_string());
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+
+    emit_collections(&app_handle);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn add_to_collection(
+    app_handle: AppHandle,
+    collection_id: String,
+    game_id: String,
+) -> Result<(), String> {
+    modify_collection(&app_handle, &collection_id, |collection| {
+        if !collection.game_ids.contains(&game_id) {
+            collection.game_ids.push(game_id);
+        }
+    })
+}
+
+#[tauri::command]
+pub fn remove_from_collection(
+    app_handle: AppHandle,
+    collection_id: String,
+    game_id: String,
+) -> Result<(), String> {
+    modify_collection(&app_handle, &collection_id, |collection| {
+        collection.game_ids.retain(|id| *id != game_id)
+    })
+}
+
+/// Replaces the game's tags. Tags are trimmed and deduplicated, and ones
+/// that end up empty are dropped.
+#[tauri::command]
+pub fn set_game_tags(app_handle: AppHandle, game_id: String, tags: Vec<String>) {
+    let tags: BTreeSet<String> = tags.iter().filter_map(|tag| clean_name(tag).ok()).collect();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    if tags.is_empty() {
+        db_lock.games.tags.remove(&game_id);
+    } else {
+        db_lock
+            .games
+            .tags
+            .insert(game_id, tags.into_iter().collect());
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+
+    emit_collections(&app_handle);
+}
+
+#[tauri::command]
+pub fn fetch_game_tags(game_id: String) -> Vec<String> {
+    DB.borrow_data()
+        .unwrap()
+        .games
+        .tags
+        .get(&game_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Every tag in use, for suggestions and filtering
+#[tauri::command]
+pub fn fetch_all_tags() -> Vec<String> {
+    let db_lock = DB.borrow_data().unwrap();
+    let tags: BTreeSet<&String> = db_lock.games.tags.values().flatten().collect();
+    tags.into_iter().cloned().collect()
+}
@@ -0,0 +1,142 @@
+use std::{
+    sync::Mutex,
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{
+    db::DatabaseImpls,
+    downloads::download_manager::GameDownloadStatus,
+    remote::{fetch_capabilities, set_capabilities, RemoteAccessError, ServerCapabilities},
+    AppState, DB,
+};
+
+/// How often the remote's health endpoint is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Answers slower than this count as degraded
+const DEGRADED_LATENCY: Duration = Duration::from_secs(2);
+/// Failed pings in a row before the remote counts as offline, so that one
+/// dropped request doesn't pause every download
+const OFFLINE_AFTER: u32 = 2;
+
+#[derive(Clone, Copy, Serialize, PartialEq, Eq, Debug, Default)]
+pub enum RemoteStatus {
+    #[default]
+    Online,
+    // Reachable, but slow or failing some requests
+    Degraded,
+    Offline,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteStatusEvent {
+    pub status: RemoteStatus,
+    pub previous: RemoteStatus,
+    pub latency_ms: Option<u64>,
+}
+
+/// Pings the remote in the background, keeping AppState's remote status and
+/// the server's capabilities up to date, and emitting remote_status whenever it changes. Downloads are
+/// paused while it's offline, and resumed once it's back if that's what
+/// paused them.
+pub fn spawn_connection_monitor(app_handle: AppHandle) {
+    spawn(move || {
+        let mut failures = 0;
+        let mut paused_downloads = false;
+        loop {
+            if !DB.database_is_set_up() {
+                sleep(PING_INTERVAL);
+                continue;
+            }
+
+            let (status, latency) = match ping() {
+                Ok((latency, capabilities)) => {
+                    failures = 0;
+                    set_capabilities(&app_handle, capabilities);
+                    let status = if latency > DEGRADED_LATENCY {
+                        RemoteStatus::Degraded
+                    } else {
+                        RemoteStatus::Online
+                    };
+                    (status, Some(latency))
+                }
+                Err(e) if e.is_unreachable() => {
+                    failures += 1;
+                    warn!("remote healthcheck failed ({} in a row): {}", failures, e);
+                    if failures >= OFFLINE_AFTER {
+                        (RemoteStatus::Offline, None)
+                    } else {
+                        (RemoteStatus::Degraded, None)
+                    }
+                }
+                // It answered, just not properly
+                Err(e) => {
+                    failures = 0;
+                    warn!("remote healthcheck failed: {}", e);
+                    (RemoteStatus::Degraded, None)
+                }
+            };
+            set_status(&app_handle, status, latency, &mut paused_downloads);
+            sleep(PING_INTERVAL);
+        }
+    });
+}
+
+// Returns how long the remote took to answer, and what it said it can do
+fn ping() -> Result<(Duration, ServerCapabilities), RemoteAccessError> {
+    let started = Instant::now();
+    let capabilities = fetch_capabilities()?;
+    Ok((started.elapsed(), capabilities))
+}
+
+fn set_status(
+    app_handle: &AppHandle,
+    status: RemoteStatus,
+    latency: Option<Duration>,
+    paused_downloads: &mut bool,
+) {
+    let state = app_handle.state::<Mutex<AppState>>();
+    let mut guard = state.lock().unwrap();
+    let previous = guard.remote_status;
+    if previous == status {
+        return;
+    }
+    guard.remote_status = status;
+    let download_manager = guard.download_manager.clone();
+    drop(guard);
+    info!("remote went from {:?} to {:?}", previous, status);
+
+    if status == RemoteStatus::Offline {
+        let downloading = download_manager.read_queue().iter().any(|download| {
+            matches!(
+                *download.status.lock().unwrap(),
+                GameDownloadStatus::Downloading
+            )
+        });
+        if downloading {
+            info!("pausing downloads while the remote is offline");
+            download_manager.pause_downloads();
+            *paused_downloads = true;
+        }
+    } else if *paused_downloads {
+        info!("resuming downloads now the remote is back");
+        download_manager.resume_downloads();
+        *paused_downloads = false;
+    }
+
+    app_handle
+        .emit(
+            "remote_status",
+            RemoteStatusEvent {
+                status,
+                previous,
+                latency_ms: latency.map(|latency| latency.as_millis() as u64),
+            },
+        )
+        .unwrap();
+}
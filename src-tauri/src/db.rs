@@ -1,24 +1,68 @@
 use std::{
-    collections::HashMap,
-    fs::{self, create_dir_all},
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all, File},
+    io::{self, Write},
     path::{Path, PathBuf},
     sync::{LazyLock, Mutex},
 };
 
 use directories::BaseDirs;
-use log::debug;
-use rustbreak::{DeSerError, DeSerializer, PathDatabase};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use log::{debug, info, warn};
+use rustbreak::{backend::Backend, DeSerError, DeSerializer};
+use serde::{de::DeserializeOwned, ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use url::Url;
 
-use crate::{process::process_manager::Platform, DB};
+use crate::{
+    collections::Collection,
+    db_encryption::{database_key, seal},
+    downloads::{
+        download_manager::DownloadPriority, manifest::DropManifest, post_install::PostInstallAction,
+    },
+    game_settings::GameSettings,
+    migrations::{migrate, SCHEMA_VERSION},
+    process::{
+        compatibility::{CompatibilityProfile, GameCompatibility},
+        playtime::Playtime,
+        process_manager::Platform,
+        save_sync::{SaveLocation, SyncedSaves},
+    },
+    profiles::profile_dir,
+    settings::Settings,
+    sqlite_backend::SqliteBackend,
+    stats::{DailyUsage, DownloadRecord},
+};
 
-#[derive(serde::Serialize, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DatabaseAuth {
     pub private: String,
     pub cert: String,
     pub client_id: String,
+    // Hex, from device registration on servers that sign requests
+    #[serde(default)]
+    pub signing_secret: Option<String>,
+    // The private key and signing secret are in the OS keychain, and
+    // only filled in here once they've been loaded from it
+    #[serde(default)]
+    pub in_keychain: bool,
+}
+
+// Secrets that are in the keychain are left out of the file
+impl Serialize for DatabaseAuth {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DatabaseAuth", 5)?;
+        if self.in_keychain {
+            state.serialize_field("private", "")?;
+            state.serialize_field("signingSecret", &None::<String>)?;
+        } else {
+            state.serialize_field("private", &self.private)?;
+            state.serialize_field("signingSecret", &self.signing_secret)?;
+        }
+        state.serialize_field("cert", &self.cert)?;
+        state.serialize_field("clientId", &self.client_id)?;
+        state.serialize_field("inKeychain", &self.in_keychain)?;
+        state.end()
+    }
 }
 
 // Strings are version names for a particular game
@@ -34,6 +78,34 @@ pub enum GameStatus {
         version_name: String,
         install_dir: String,
     },
+    // Installed, with a newer version on the server
+    UpdateAvailable {
+        version_name: String,
+        install_dir: String,
+        latest_version: String,
+    },
+}
+
+impl GameStatus {
+    /// The version on disk and where it is, if the game is installed at all
+    pub fn installed(&self) -> Option<(&String, &String)> {
+        match self {
+            GameStatus::Remote {} => None,
+            GameStatus::SetupRequired {
+                version_name,
+                install_dir,
+            }
+            | GameStatus::Installed {
+                version_name,
+                install_dir,
+            }
+            | GameStatus::UpdateAvailable {
+                version_name,
+                install_dir,
+                ..
+            } => Some((version_name, install_dir)),
+        }
+    }
 }
 
 // Stuff that shouldn't be synced to disk
@@ -42,6 +114,9 @@ pub enum GameTransientStatus {
     Downloading { version_name: String },
     Uninstalling {},
     Updating { version_name: String },
+    Moving {},
+    // Checking an imported folder before it's counted as installed
+    Importing { version_name: String },
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -52,15 +127,173 @@ pub struct GameVersion {
     pub launch_command: String,
     pub setup_command: String,
     pub platform: Platform,
+    #[serde(default)]
+    pub post_install: Vec<PostInstallAction>,
+    #[serde(default)]
+    pub save_locations: Vec<SaveLocation>,
+}
+
+// A game waiting in (or being downloaded from) the download queue
+// Its position in the queue is its index in DatabaseGames::download_queue
+#[derive(Serialize, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseQueuedDownload {
+    pub game_id: String,
+    pub version: String,
+    pub target_download_dir: usize,
+    #[serde(default)]
+    pub priority: DownloadPriority,
+}
+
+/// A folder picked as an existing install, which isn't recorded as
+/// installed until the download checking it has finished
+#[derive(Serialize, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingImport {
+    pub version_name: String,
+    pub install_dir: String,
+}
+
+/// Kept in the install directory, with the game id after it, listing every
+/// file a download put there along with its chunk sizes and checksums
+const INSTALLED_FILES: &str = ".dropinstall";
+
+/// Exactly what a finished download put on disk, for anything that later
+/// needs to check, remove, move or update the game's files. The file list
+/// can be huge, so it goes next to the install and only its hash is kept here.
+#[derive(Serialize, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallManifest {
+    pub version_name: String,
+    pub install_dir: String,
+    // Bytes the game's files add up to
+    pub size: u64,
+    // BLAKE3 of the file list, None if it couldn't be written
+    #[serde(default)]
+    pub files_hash: Option<String>,
+    // Seconds since the unix epoch
+    pub installed_at: u64,
+}
+
+impl InstallManifest {
+    /// Writes the file list out next to the install
+    pub fn record(
+        game_id: &str,
+        version_name: String,
+        install_dir: String,
+        files: &DropManifest,
+        installed_at: u64,
+    ) -> Self {
+        let path = installed_files_path(Path::new(&install_dir), game_id);
+        let files_hash = match write_installed_files(&path, files) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                warn!("couldn't write {}: {}", path.display(), e);
+                None
+            }
+        };
+        Self {
+            version_name,
+            install_dir,
+            size: files_size(files),
+            files_hash,
+            installed_at,
+        }
+    }
+
+    /// The installed files, as long as their list is still as it was written
+    pub fn files(&self, game_id: &str) -> Option<DropManifest> {
+        let files_hash = self.files_hash.as_ref()?;
+        let path = installed_files_path(Path::new(&self.install_dir), game_id);
+        let data = fs::read(&path).ok()?;
+        if blake3::hash(&data).to_hex().as_str() != files_hash {
+            warn!("ignoring {}, it's been changed", path.display());
+            return None;
+        }
+        serde_json::from_slice(&data).ok()
+    }
+}
+
+pub fn installed_files_path(install_dir: &Path, game_id: &str) -> PathBuf {
+    install_dir.join(format!("{}-{}", INSTALLED_FILES, game_id))
+}
+
+/// Returns the hash to check the list against when it's read back
+pub fn write_installed_files(path: &Path, files: &DropManifest) -> io::Result<String> {
+    let data = serde_json::to_vec(files)?;
+    write_durably(path, &data)?;
+    Ok(blake3::hash(&data).to_hex().to_string())
+}
+
+/// Bytes the files add up to
+pub fn files_size(files: &DropManifest) -> u64 {
+    files
+        .values()
+        .flat_map(|chunk| chunk.lengths.iter())
+        .map(|length| *length as u64)
+        .sum()
 }
 
 #[derive(Serialize, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DatabaseGames {
     pub install_dirs: Vec<String>,
+    // Index into install_dirs
+    #[serde(default)]
+    pub default_install_dir: usize,
     // Guaranteed to exist if the game also exists in the app state map
     pub statuses: HashMap<String, GameStatus>,
     pub versions: HashMap<String, HashMap<String, GameVersion>>,
+    #[serde(default)]
+    pub download_queue: Vec<DatabaseQueuedDownload>,
+    // Keyed by game id, only for games that finished downloading
+    #[serde(default)]
+    pub install_manifests: HashMap<String, InstallManifest>,
+    // Keyed by game id, for Windows builds run on Linux
+    #[serde(default)]
+    pub compatibility: HashMap<String, GameCompatibility>,
+    #[serde(default)]
+    pub compatibility_profiles: Vec<CompatibilityProfile>,
+    #[serde(default)]
+    pub playtime: HashMap<String, Playtime>,
+    // Keyed by game id, only for games with something set
+    #[serde(default)]
+    pub game_settings: HashMap<String, GameSettings>,
+    // Keyed by game id, versions chosen over the latest one
+    #[serde(default)]
+    pub pinned_versions: HashMap<String, String>,
+    // Keyed by DLC id, the game each DLC belongs to. DLC otherwise
+    // has statuses and versions of its own, like any other game.
+    #[serde(default)]
+    pub dlc_parents: HashMap<String, String>,
+    // Keyed by game id, for cloud saves
+    #[serde(default)]
+    pub synced_saves: HashMap<String, SyncedSaves>,
+    #[serde(default)]
+    pub collections: Vec<Collection>,
+    // Keyed by game id, sorted
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub hidden: HashSet<String>,
+    #[serde(default)]
+    pub favourites: HashSet<String>,
+    // Keyed by game id, every download event, oldest first
+    #[serde(default)]
+    pub download_history: HashMap<String, Vec<DownloadRecord>>,
+    // Installed while the client was pointed at another server, and
+    // not yet checked against this one
+    #[serde(default)]
+    pub unverified: HashSet<String>,
+    // Keyed by local date, YYYY-MM-DD, bytes received from the server (see stats.rs)
+    #[serde(default)]
+    pub bandwidth_usage: HashMap<String, DailyUsage>,
+    // Keyed by game id, imports still being checked
+    #[serde(default)]
+    pub pending_imports: HashMap<String, PendingImport>,
+    // Keyed by game id, versions to pin once their rollback finishes
+    #[serde(default)]
+    pub pending_pins: HashMap<String, String>,
 
     #[serde(skip)]
     pub transient_statuses: HashMap<String, GameTransientStatus>,
@@ -69,9 +302,14 @@ pub struct DatabaseGames {
 #[derive(Serialize, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Database {
+    // See migrations.rs
+    #[serde(default)]
+    pub schema_version: u32,
     pub auth: Option<DatabaseAuth>,
     pub base_url: String,
     pub games: DatabaseGames,
+    #[serde(default)]
+    pub settings: Settings,
 }
 pub static DATA_ROOT_DIR: LazyLock<Mutex<PathBuf>> =
     LazyLock::new(|| Mutex::new(BaseDirs::new().unwrap().data_dir().join("drop")));
@@ -90,8 +328,7 @@ impl<T: Serialize + DeserializeOwned> DeSerializer<T> for DropDatabaseSerializer
     }
 }
 
-pub type DatabaseInterface =
-    rustbreak::Database<Database, rustbreak::backend::PathBackend, DropDatabaseSerializer>;
+pub type DatabaseInterface = rustbreak::Database<Database, SqliteBackend, DropDatabaseSerializer>;
 
 pub trait DatabaseImpls {
     fn set_up_database() -> DatabaseInterface;
@@ -100,42 +337,65 @@ pub trait DatabaseImpls {
 }
 impl DatabaseImpls for DatabaseInterface {
     fn set_up_database() -> DatabaseInterface {
-        let data_root_dir = DATA_ROOT_DIR.lock().unwrap();
-        let db_path = data_root_dir.join("drop.db");
-        let games_base_dir = data_root_dir.join("games");
+        let data_root_dir = DATA_ROOT_DIR.lock().unwrap().clone();
+        // Logs are shared, everything else belongs to the profile
+        let profile_dir = profile_dir();
+        let legacy_path = profile_dir.join("drop.db");
+        let games_base_dir = profile_dir.join("games");
         let logs_root_dir = data_root_dir.join("logs");
 
-        debug!("Creating data directory at {:?}", data_root_dir);
-        create_dir_all(data_root_dir.clone()).unwrap();
+        debug!("Creating data directory at {:?}", profile_dir);
+        create_dir_all(&profile_dir).unwrap();
         debug!("Creating games directory");
         create_dir_all(games_base_dir.clone()).unwrap();
         debug!("Creating logs directory");
         create_dir_all(logs_root_dir.clone()).unwrap();
 
-        #[allow(clippy::let_and_return)]
-        let exists = fs::exists(db_path.clone()).unwrap();
-
-        match exists {
-            true => PathDatabase::load_from_path(db_path).expect("Database loading failed"),
-            false => {
-                let default = Database {
-                    auth: None,
-                    base_url: "".to_string(),
-                    games: DatabaseGames {
-                        install_dirs: vec![games_base_dir.to_str().unwrap().to_string()],
-                        statuses: HashMap::new(),
-                        transient_statuses: HashMap::new(),
-                        versions: HashMap::new(),
-                    },
-                };
-                debug!(
-                    "Creating database at path {}",
-                    db_path.as_os_str().to_str().unwrap()
-                );
-                PathDatabase::create_at_path(db_path, default)
-                    .expect("Database could not be created")
+        let sqlite_path = profile_dir.join("drop.sqlite");
+        let mut backend = SqliteBackend::open(&sqlite_path).expect("Database could not be opened");
+        if backend.is_empty() {
+            // From before the database was in SQLite, imported the once
+            let data = if fs::exists(&legacy_path).unwrap() {
+                info!("importing database from {}", legacy_path.display());
+                fs::read(&legacy_path).expect("Database loading failed")
+            } else {
+                debug!("Creating database at path {}", sqlite_path.display());
+                serde_json::to_vec(&default_database(&games_base_dir)).unwrap()
+            };
+            backend
+                .put_data(&data)
+                .expect("Database could not be created");
+            if fs::exists(&legacy_path).unwrap() {
+                fs::rename(&legacy_path, legacy_path.with_extension("db.imported")).unwrap();
             }
         }
+
+        let data = backend.get_data().expect("Database loading failed");
+        let mut database: serde_json::Value =
+            serde_json::from_slice(&data).expect("Database loading failed");
+        let version =
+            migrate(&mut database).unwrap_or_else(|e| panic!("Database migration failed: {}", e));
+        if version != SCHEMA_VERSION {
+            // The data as it was is kept, in case a migration turns out to be wrong.
+            // It's only as readable as the database itself was.
+            let backup = if backend.is_encrypted() {
+                let key = database_key().expect("Database key could not be read");
+                seal(&key, "backup", &String::from_utf8_lossy(&data)).into_bytes()
+            } else {
+                data.clone()
+            };
+            write_durably(
+                &profile_dir.join(format!("drop.schema-{}.bak", version)),
+                &backup,
+            )
+            .unwrap();
+            backend
+                .put_data(&serde_json::to_vec(&database).unwrap())
+                .expect("Database migration failed");
+        }
+
+        let database = serde_json::from_value(database).expect("Database loading failed");
+        DatabaseInterface::from_parts(database, backend, DropDatabaseSerializer)
     }
 
     fn database_is_set_up(&self) -> bool {
@@ -148,57 +408,56 @@ impl DatabaseImpls for DatabaseInterface {
     }
 }
 
-#[tauri::command]
-pub fn add_download_dir(new_dir: String) -> Result<(), String> {
-    // Check the new directory is all good
-    let new_dir_path = Path::new(&new_dir);
-    if new_dir_path.exists() {
-        let metadata = new_dir_path
-            .metadata()
-            .map_err(|e| format!("Unable to access file or directory: {}", e))?;
-        if !metadata.is_dir() {
-            return Err("Invalid path: not a directory".to_string());
-        }
-        let dir_contents = new_dir_path
-            .read_dir()
-            .map_err(|e| format!("Unable to check directory contents: {}", e))?;
-        if dir_contents.count() != 0 {
-            return Err("Directory is not empty".to_string());
-        }
-    } else {
-        create_dir_all(new_dir_path)
-            .map_err(|e| format!("Unable to create directories to path: {}", e))?;
-    }
-
-    // Add it to the dictionary
-    let mut lock = DB.borrow_data_mut().unwrap();
-    if lock.games.install_dirs.contains(&new_dir) {
-        return Err("Download directory already used".to_string());
-    }
-    lock.games.install_dirs.push(new_dir);
-    drop(lock);
-    DB.save().unwrap();
-
+/// Replaces a file so that a crash at any point leaves either the old
+/// contents or the new ones. The data is synced before it's renamed over,
+/// then the directory is synced so the rename itself sticks.
+pub fn write_durably(path: &Path, data: &[u8]) -> io::Result<()> {
+    let partial_path = path.with_file_name(format!(
+        "{}.partial",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    let mut file = File::create(&partial_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&partial_path, path)?;
+    // Windows can't open directories, and commits the rename with the file
+    #[cfg(unix)]
+    File::open(path.parent().unwrap())?.sync_all()?;
     Ok(())
 }
 
-#[tauri::command]
-pub fn delete_download_dir(index: usize) -> Result<(), String> {
-    let mut lock = DB.borrow_data_mut().unwrap();
-    lock.games.install_dirs.remove(index);
-    drop(lock);
-    DB.save().unwrap();
-
-    Ok(())
-}
-
-// Will, in future, return disk/remaining size
-// Just returns the directories that have been set up
-#[tauri::command]
-pub fn fetch_download_dir_stats() -> Result<Vec<String>, String> {
-    let lock = DB.borrow_data().unwrap();
-    let directories = lock.games.install_dirs.clone();
-    drop(lock);
-
-    Ok(directories)
+// What a fresh install starts with
+fn default_database(games_base_dir: &Path) -> Database {
+    Database {
+        schema_version: SCHEMA_VERSION,
+        auth: None,
+        base_url: "".to_string(),
+        games: DatabaseGames {
+            install_dirs: vec![games_base_dir.to_str().unwrap().to_string()],
+            default_install_dir: 0,
+            statuses: HashMap::new(),
+            transient_statuses: HashMap::new(),
+            versions: HashMap::new(),
+            download_queue: Vec::new(),
+            install_manifests: HashMap::new(),
+            compatibility: HashMap::new(),
+            compatibility_profiles: Vec::new(),
+            playtime: HashMap::new(),
+            game_settings: HashMap::new(),
+            pinned_versions: HashMap::new(),
+            dlc_parents: HashMap::new(),
+            synced_saves: HashMap::new(),
+            collections: Vec::new(),
+            tags: HashMap::new(),
+            hidden: HashSet::new(),
+            favourites: HashSet::new(),
+            download_history: HashMap::new(),
+            unverified: HashSet::new(),
+            bandwidth_usage: HashMap::new(),
+            pending_imports: HashMap::new(),
+            pending_pins: HashMap::new(),
+        },
+        settings: Settings::default(),
+    }
 }
@@ -0,0 +1,135 @@
+use std::fs;
+
+use log::info;
+use openssl::{
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+
+use crate::{
+    keychain::{get_secret, set_secret},
+    profiles::profile_dir,
+    DB,
+};
+
+/// Starts every encrypted value. JSON can't start with it, so plain values
+/// can still be told apart.
+const SEALED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Where the secret the key is derived from is kept in the keychain
+const KEY_ACCOUNT: &str = "database-key";
+const KEY_CONTEXT: &str = "drop-app 2024-12 local database encryption";
+
+pub type DatabaseKey = [u8; 32];
+
+/// The key the database is encrypted with. It's derived from a random
+/// secret in the OS keychain, which is made the first time it's needed.
+pub fn database_key() -> Result<DatabaseKey, String> {
+    let secret = match get_secret(KEY_ACCOUNT)? {
+        Some(secret) => secret,
+        None => {
+            let mut secret = [0; 32];
+            rand_bytes(&mut secret).map_err(|e| e.to_string())?;
+            let secret = hex::encode(secret);
+            set_secret(KEY_ACCOUNT, &secret)?;
+            info!("created a database key in the system keychain");
+            secret
+        }
+    };
+    Ok(blake3::derive_key(KEY_CONTEXT, secret.trim().as_bytes()))
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// Encrypts with AES-256-GCM. The location (where the value is stored) is
+/// authenticated along with it, so a value can't be moved somewhere else.
+pub fn seal(key: &DatabaseKey, location: &str, value: &str) -> String {
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce).unwrap();
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        location.as_bytes(),
+        value.as_bytes(),
+        &mut tag,
+    )
+    .unwrap();
+    format!(
+        "{}{}",
+        SEALED_PREFIX,
+        hex::encode([&nonce[..], &ciphertext, &tag].concat())
+    )
+}
+
+pub fn unseal(key: &DatabaseKey, location: &str, sealed: &str) -> Result<String, String> {
+    let sealed = sealed
+        .strip_prefix(SEALED_PREFIX)
+        .and_then(|sealed| hex::decode(sealed).ok())
+        .filter(|sealed| sealed.len() >= NONCE_LEN + TAG_LEN)
+        .ok_or(format!("{} isn't an encrypted value", location))?;
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let value = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        location.as_bytes(),
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        format!(
+            "{} couldn't be decrypted with this keychain's key",
+            location
+        )
+    })?;
+    String::from_utf8(value).map_err(|e| e.to_string())
+}
+
+// Copies of the database from before it was encrypted: the file imported
+// into SQLite and the backups taken before migrations
+fn remove_plaintext_copies() {
+    let entries = match fs::read_dir(profile_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let plaintext = name == "drop.db.imported"
+            || (name.starts_with("drop.schema-") && name.ends_with(".bak"));
+        if plaintext && fs::remove_file(entry.path()).is_ok() {
+            info!("removed unencrypted database copy {}", name);
+        }
+    }
+}
+
+/// Turning it on encrypts everything in the database with a key from the
+/// OS keychain, and removes the unencrypted copies lying around. Turning it
+/// off writes it all back out as it is.
+#[tauri::command]
+pub fn set_database_encryption(enabled: bool) -> Result<(), String> {
+    if enabled {
+        // Fails here, rather than on every save, if there's no keychain
+        database_key()?;
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let previous = db_lock.settings.encrypt_database;
+    db_lock.settings.encrypt_database = enabled;
+    drop(db_lock);
+    if let Err(e) = DB.save() {
+        DB.borrow_data_mut().unwrap().settings.encrypt_database = previous;
+        return Err(e.to_string());
+    }
+
+    if enabled {
+        remove_plaintext_copies();
+    }
+    Ok(())
+}
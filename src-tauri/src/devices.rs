@@ -0,0 +1,203 @@
+use std::env;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+use crate::{
+    auth::{clear_sign_in, generate_authorization_header},
+    db::DatabaseImpls,
+    keychain::store_secrets,
+    remote::{blocking_http_client, RemoteAccessError},
+    signing::sign_request,
+    DB,
+};
+
+/// A machine signed in to the user's account
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub platform: String,
+    // RFC 3339, if the server's seen it since registering
+    pub last_seen: Option<String>,
+    // Whether it's this machine, filled in here rather than by the server
+    #[serde(default)]
+    pub current: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterDeviceBody {
+    name: String,
+    platform: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct RegisterDeviceResponse {
+    // Servers that sign requests hand one out, hex encoded
+    #[serde(default)]
+    signing_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RenameDeviceBody {
+    name: String,
+}
+
+/// What this machine calls itself, for telling devices apart
+pub fn device_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0u8; 256];
+        if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == 0 {
+            let end = buffer
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(buffer.len());
+            if let Ok(name) = std::str::from_utf8(&buffer[..end]) {
+                if !name.is_empty() {
+                    return name.to_string();
+                }
+            }
+        }
+    }
+    #[cfg(windows)]
+    if let Ok(name) = env::var("COMPUTERNAME") {
+        return name;
+    }
+    "Drop Desktop Client".to_string()
+}
+
+/// Called once the handshake's done, so the user can see the machine in
+/// their device list. Servers without device support are fine to ignore.
+pub fn register_device() {
+    let name = device_name();
+    match register_device_logic(&name) {
+        Ok(()) => info!("registered this device as {}", name),
+        Err(e) => warn!("couldn't register this device: {}", e),
+    }
+}
+
+fn register_device_logic(name: &str) -> Result<(), RemoteAccessError> {
+    let endpoint = DB.fetch_base_url().join("/api/v1/client/device")?;
+    let response = blocking_http_client()
+        .post(endpoint.to_string())
+        .header("Authorization", generate_authorization_header())
+        .json(&RegisterDeviceBody {
+            name: name.to_string(),
+            platform: env::consts::OS.to_string(),
+        })
+        .send()?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    // Older servers don't answer with anything
+    let registration = response
+        .json::<RegisterDeviceResponse>()
+        .unwrap_or_default();
+    if let Some(secret) = registration.signing_secret {
+        info!("server will check this device's request signatures");
+        let mut db_lock = DB.borrow_data_mut().unwrap();
+        if let Some(auth) = db_lock.auth.as_mut() {
+            auth.signing_secret = Some(secret);
+            if auth.in_keychain {
+                store_secrets(auth);
+            }
+        }
+        drop(db_lock);
+        DB.save().unwrap();
+    }
+    Ok(())
+}
+
+fn fetch_devices_logic() -> Result<Vec<Device>, RemoteAccessError> {
+    let endpoint = DB.fetch_base_url().join("/api/v1/client/devices")?;
+    let response = blocking_http_client()
+        .get(endpoint.to_string())
+        .header("Authorization", generate_authorization_header())
+        .send()?;
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    let mut devices = response.json::<Vec<Device>>()?;
+    let client_id = current_client_id();
+    for device in devices.iter_mut() {
+        device.current = client_id.as_deref() == Some(device.id.as_str());
+    }
+    Ok(devices)
+}
+
+fn rename_device_logic(device_id: &str, name: &str) -> Result<(), RemoteAccessError> {
+    let endpoint = DB
+        .fetch_base_url()
+        .join(&format!("/api/v1/client/devices/{}", device_id))?;
+    let body = serde_json::to_vec(&RenameDeviceBody {
+        name: name.to_string(),
+    })
+    .unwrap();
+    let request = blocking_http_client()
+        .patch(endpoint.to_string())
+        .header("Authorization", generate_authorization_header())
+        .header("Content-Type", "application/json");
+    let response = sign_request(request, "PATCH", endpoint.as_str(), &body)
+        .body(body)
+        .send()?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response));
+    }
+    Ok(())
+}
+
+fn deregister_device_logic(app: &AppHandle, device_id: &str) -> Result<(), RemoteAccessError> {
+    let endpoint = DB
+        .fetch_base_url()
+        .join(&format!("/api/v1/client/devices/{}", device_id))?;
+    let request = blocking_http_client()
+        .delete(endpoint.to_string())
+        .header("Authorization", generate_authorization_header());
+    let response = sign_request(request, "DELETE", endpoint.as_str(), &[]).send()?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    // This machine's credentials won't work any more
+    if current_client_id().as_deref() == Some(device_id) {
+        info!("deregistered this device, signing out");
+        clear_sign_in(app);
+        app.emit("auth/signedout", ()).unwrap();
+    }
+    Ok(())
+}
+
+fn current_client_id() -> Option<String> {
+    DB.borrow_data()
+        .unwrap()
+        .auth
+        .as_ref()
+        .map(|auth| auth.client_id.clone())
+}
+
+#[tauri::command]
+pub fn fetch_devices() -> Result<Vec<Device>, String> {
+    fetch_devices_logic().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn rename_device(device_id: String, name: String) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Name can't be empty".to_string());
+    }
+    rename_device_logic(&device_id, name).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn deregister_device(app: AppHandle, device_id: String) -> Result<(), String> {
+    deregister_device_logic(&app, &device_id).map_err(|e| e.to_string())
+}
@@ -0,0 +1,161 @@
+use std::{
+    fs::{create_dir_all, remove_file, File},
+    path::Path,
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::{AppState, DB};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadDirStats {
+    pub path: String,
+    // None when the drive can't be read, like when it's been unplugged
+    pub free_space: Option<u64>,
+    pub total_space: Option<u64>,
+    pub is_default: bool,
+    // Taken up by the games installed in it
+    pub used: u64,
+}
+
+/// Created and removed again to check a directory can be written to
+const WRITE_CHECK_FILE: &str = ".drop-write-check";
+
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let check_path = dir.join(WRITE_CHECK_FILE);
+    File::create(&check_path).map_err(|e| format!("Directory is not writable: {}", e))?;
+    remove_file(&check_path).map_err(|e| format!("Unable to clean up after checking: {}", e))
+}
+
+/// Where downloads go when no directory is picked for them
+pub fn default_download_dir() -> usize {
+    let lock = DB.borrow_data().unwrap();
+    // Falls back to the first directory if the database has been edited by hand
+    if lock.games.default_install_dir < lock.games.install_dirs.len() {
+        lock.games.default_install_dir
+    } else {
+        0
+    }
+}
+
+#[tauri::command]
+pub fn add_download_dir(new_dir: String) -> Result<(), String> {
+    // Check the new directory is all good
+    let new_dir_path = Path::new(&new_dir);
+    if new_dir_path.exists() {
+        let metadata = new_dir_path
+            .metadata()
+            .map_err(|e| format!("Unable to access file or directory: {}", e))?;
+        if !metadata.is_dir() {
+            return Err("Invalid path: not a directory".to_string());
+        }
+        let dir_contents = new_dir_path
+            .read_dir()
+            .map_err(|e| format!("Unable to check directory contents: {}", e))?;
+        if dir_contents.count() != 0 {
+            return Err("Directory is not empty".to_string());
+        }
+    } else {
+        create_dir_all(new_dir_path)
+            .map_err(|e| format!("Unable to create directories to path: {}", e))?;
+    }
+    check_writable(new_dir_path)?;
+
+    // Add it to the dictionary
+    let mut lock = DB.borrow_data_mut().unwrap();
+    if lock.games.install_dirs.contains(&new_dir) {
+        return Err("Download directory already used".to_string());
+    }
+    lock.games.install_dirs.push(new_dir);
+    drop(lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_download_dir(
+    state: tauri::State<'_, Mutex<AppState>>,
+    index: usize,
+) -> Result<(), String> {
+    // Queued downloads refer to directories by index, so removing one would
+    // point any queued after it at the wrong place
+    let queued = state
+        .lock()
+        .unwrap()
+        .download_manager
+        .read_queue()
+        .iter()
+        .any(|queued| queued.target_download_dir >= index);
+    if queued {
+        return Err("Finish or cancel downloads into this directory first".to_string());
+    }
+
+    let mut lock = DB.borrow_data_mut().unwrap();
+    let dir = match lock.games.install_dirs.get(index) {
+        Some(dir) => Path::new(dir).to_path_buf(),
+        None => return Err("Invalid download directory".to_string()),
+    };
+    if lock.games.install_dirs.len() == 1 {
+        return Err("At least one download directory is required".to_string());
+    }
+    let has_games = lock.games.statuses.values().any(|status| {
+        status
+            .installed()
+            .is_some_and(|(_, install_dir)| Path::new(install_dir).starts_with(&dir))
+    });
+    if has_games {
+        return Err("Uninstall or move the games in this directory first".to_string());
+    }
+
+    lock.games.install_dirs.remove(index);
+    if lock.games.default_install_dir == index {
+        lock.games.default_install_dir = 0;
+    } else if lock.games.default_install_dir > index {
+        lock.games.default_install_dir -= 1;
+    }
+    drop(lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_default_download_dir(index: usize) -> Result<(), String> {
+    let mut lock = DB.borrow_data_mut().unwrap();
+    if index >= lock.games.install_dirs.len() {
+        return Err("Invalid download directory".to_string());
+    }
+    lock.games.default_install_dir = index;
+    drop(lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_download_dir_stats() -> Result<Vec<DownloadDirStats>, String> {
+    let default = default_download_dir();
+    let db_lock = DB.borrow_data().unwrap();
+    let games = &db_lock.games;
+
+    Ok(games
+        .install_dirs
+        .iter()
+        .enumerate()
+        .map(|(index, path)| DownloadDirStats {
+            free_space: fs4::available_space(path).ok(),
+            total_space: fs4::total_space(path).ok(),
+            is_default: index == default,
+            used: games
+                .install_manifests
+                .values()
+                .filter(|manifest| Path::new(&manifest.install_dir).starts_with(path))
+                .map(|manifest| manifest.size)
+                .sum(),
+            path: path.clone(),
+        })
+        .collect())
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    download_logic::ChunkHasher,
+    manifest::{existing_path, install_path, ChecksumAlgorithm, DropDownloadContext, DropManifest},
+};
+
+struct CachedChunk {
+    path: PathBuf,
+    offset: u64,
+    length: usize,
+}
+
+/// Where each chunk of an installed version is on disk, by checksum, so
+/// that an update can copy chunks it already has rather than download them
+pub struct ChunkCache {
+    chunks: HashMap<(ChecksumAlgorithm, String), CachedChunk>,
+}
+
+impl ChunkCache {
+    pub fn from_manifest(manifest: &DropManifest, base_path: &Path) -> Self {
+        let mut chunks = HashMap::new();
+        for (raw_path, chunk) in manifest {
+            // Files the new version keeps have already been moved to their part files
+            let path = existing_path(&install_path(base_path, raw_path));
+            let mut running_offset = 0;
+            for (index, length) in chunk.lengths.iter().enumerate() {
+                chunks
+                    .entry((chunk.checksum_algorithm, chunk.checksums[index].clone()))
+                    .or_insert_with(|| CachedChunk {
+                        path: path.clone(),
+                        offset: running_offset,
+                        length: *length,
+                    });
+                running_offset += *length as u64;
+            }
+        }
+        Self { chunks }
+    }
+
+    /// Copies a chunk from wherever the installed version keeps it into the
+    /// context's place. Returns false if there's no usable copy, including when
+    /// the update has already overwritten it.
+    pub fn copy_into(&self, context: &DropDownloadContext) -> io::Result<bool> {
+        let cached = match self
+            .chunks
+            .get(&(context.checksum_algorithm, context.checksum.clone()))
+        {
+            Some(cached) if cached.length == context.length => cached,
+            _ => return Ok(false),
+        };
+        // Already in place, nothing to copy
+        if cached.path == context.path && cached.offset == context.offset {
+            return Ok(true);
+        }
+
+        let mut buf = vec![0; cached.length];
+        let mut source = File::open(&cached.path)?;
+        source.seek(SeekFrom::Start(cached.offset))?;
+        match source.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        let mut hasher = ChunkHasher::new(context.checksum_algorithm);
+        hasher.update(&buf);
+        if hasher.finish() != context.checksum {
+            return Ok(false);
+        }
+
+        let mut destination = OpenOptions::new().write(true).open(&context.path)?;
+        destination.seek(SeekFrom::Start(context.offset))?;
+        destination.write_all(&buf)?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+use urlencoding::encode;
+
+use crate::{
+    auth::generate_authorization_header,
+    db::DatabaseImpls,
+    remote::{ContentEndpoint, RemoteAccessError},
+    DB,
+};
+
+/// Signatures are fetched again this long before the server says they
+/// expire, so one doesn't run out part way through a chunk
+const SIGNATURE_MARGIN: Duration = Duration::from_secs(30);
+
+// Where the server said chunk content comes from, and whether
+// requests there need signing
+static CONTENT_ENDPOINT: RwLock<Option<(Url, bool)>> = RwLock::new(None);
+
+struct Signature {
+    query: String,
+    expires: Instant,
+}
+
+// Keyed by game id and version
+static SIGNATURES: LazyLock<Mutex<HashMap<(String, String), Signature>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContentSignature {
+    // Added to the chunk URL's query as it is
+    query: String,
+    expires_in: u64,
+}
+
+// Same origin, and the same path give or take a trailing slash
+fn same_endpoint(a: &Url, b: &Url) -> bool {
+    a.origin() == b.origin() && a.path().trim_end_matches('/') == b.path().trim_end_matches('/')
+}
+
+/// Remembers the content endpoint the server advertised, for the chunk
+/// requests that follow. Returns its URL, if there is a usable one.
+pub fn use_content_endpoint(endpoint: Option<&ContentEndpoint>, base_url: &Url) -> Option<Url> {
+    let endpoint = endpoint.and_then(|endpoint| match Url::parse(&endpoint.url) {
+        // Chunks from the server itself are requested from the API, signed in
+        Ok(url) if same_endpoint(&url, base_url) => None,
+        Ok(mut url) => {
+            // So chunk paths are joined onto the end of it rather than replacing
+            // its last segment
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            Some((url, endpoint.signed))
+        }
+        Err(e) => {
+            warn!("ignoring invalid content url {}: {}", endpoint.url, e);
+            None
+        }
+    });
+    let url = endpoint.as_ref().map(|(url, _)| url.clone());
+    *CONTENT_ENDPOINT.write().unwrap() = endpoint;
+    url
+}
+
+/// Some(signed) if chunks from `base_url` come from the content endpoint,
+/// None if it's the API or a mirror
+pub fn content_endpoint(base_url: &Url) -> Option<bool> {
+    match &*CONTENT_ENDPOINT.read().unwrap() {
+        Some((url, signed)) if url == base_url => Some(*signed),
+        _ => None,
+    }
+}
+
+/// Whether a request to `url` went to the content endpoint
+pub fn is_content_url(url: &Url) -> bool {
+    CONTENT_ENDPOINT
+        .read()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|(content_url, _)| {
+            url.origin() == content_url.origin() && url.path().starts_with(content_url.path())
+        })
+}
+
+/// The query that lets the content endpoint serve a version's chunks. The
+/// API signs it, so the client's own credentials never leave for the CDN.
+pub async fn signed_query(
+    client: &Client,
+    game_id: &str,
+    version: &str,
+) -> Result<String, RemoteAccessError> {
+    let key = (game_id.to_string(), version.to_string());
+    {
+        let signatures = SIGNATURES.lock().unwrap();
+        if let Some(signature) = signatures.get(&key) {
+            if Instant::now() + SIGNATURE_MARGIN < signature.expires {
+                return Ok(signature.query.clone());
+            }
+        }
+    }
+
+    let endpoint = DB.fetch_base_url().join(&format!(
+        "/api/v1/client/chunk/sign?id={}&version={}",
+        encode(game_id),
+        encode(version)
+    ))?;
+    let response = client
+        .get(endpoint)
+        .header("Authorization", generate_authorization_header())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_async_response(response).await);
+    }
+    let signature = response.json::<ContentSignature>().await?;
+
+    SIGNATURES.lock().unwrap().insert(
+        key,
+        Signature {
+            query: signature.query.clone(),
+            expires: Instant::now() + Duration::from_secs(signature.expires_in),
+        },
+    );
+    Ok(signature.query)
+}
+
+/// For when the content endpoint turns a signature down before it should
+/// have expired, so the next attempt asks for a new one
+pub fn forget_signatures() {
+    SIGNATURES.lock().unwrap().clear();
+}
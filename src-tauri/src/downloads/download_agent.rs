@@ -1,57 +1,92 @@
 use crate::auth::generate_authorization_header;
-use crate::db::DatabaseImpls;
-use crate::downloads::manifest::{DropDownloadContext, DropManifest};
+use crate::cache::{read_manifest, write_manifest};
+use crate::db::{DatabaseImpls, GameStatus};
+use crate::downloads::manifest::{
+    existing_path, install_path, part_path, validate_manifest, DropChunk, DropDownloadContext,
+    DropManifest, FileLink, ManifestError, SUPPORTED_CHECKSUM_ALGORITHMS,
+};
 use crate::downloads::progress_object::ProgressHandle;
-use crate::remote::RemoteAccessError;
+use crate::game_settings::settings_for;
+use crate::remote::{blocking_http_client, http_client, ErrorClass, RemoteAccessError};
+use crate::stats::{flush_usage, record_downloaded};
 use crate::DB;
 use core::time;
-use log::{debug, error, info};
-use rayon::ThreadPoolBuilder;
+use fs4::fs_std::FileExt;
+use log::{debug, error, info, warn};
 use serde::ser::{Error, SerializeMap};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::fs::{create_dir_all, File};
-use std::io;
-use std::path::Path;
+use std::fs::{
+    create_dir_all, hard_link, metadata, remove_dir, remove_file, rename, File, OpenOptions,
+};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use url::Url;
 use urlencoding::encode;
 
-#[cfg(target_os = "linux")]
-use rustix::fs::{fallocate, FallocateFlags};
-
-use super::download_logic::download_game_chunk;
+use super::chunk_cache::ChunkCache;
+use super::download_logic::{
+    chunks_per_request, download_game_chunk, download_game_chunks, BatchedChunk, ChunkActivity,
+    ChunkHasher, DOWNLOAD_RUNTIME,
+};
 use super::download_manager::DownloadManagerSignal;
 use super::download_thread_control_flag::{DownloadThreadControl, DownloadThreadControlFlag};
+use super::file_attributes::{apply_file_attributes, hide, make_writable};
+use super::links::create_symlink;
+use super::mirrors::fetch_mirrors;
 use super::progress_object::ProgressObject;
+use super::rate_limiter::RateLimiter;
+use super::sparse::{disk_usage, make_sparse};
 use super::stored_manifest::StoredManifest;
 
 pub struct GameDownloadAgent {
     pub id: String,
     pub version: String,
+    pub target_download_dir: usize,
     pub control_flag: DownloadThreadControl,
     contexts: Vec<DropDownloadContext>,
     completed_contexts: Mutex<Vec<usize>>,
+    last_checkpoint: Mutex<Instant>,
     pub manifest: Mutex<Option<DropManifest>>,
     pub progress: Arc<ProgressObject>,
+    pub rate_limiter: Arc<RateLimiter>,
     sender: Sender<DownloadManagerSignal>,
     pub stored_manifest: StoredManifest,
+    // Where the game ends up, which the stored manifest's
+    // base path only matches once it's out of staging
+    pub install_dir: PathBuf,
+    // The game this is DLC for, if it is
+    pub parent_id: Option<String>,
 }
 
+/// Games are downloaded in here, within the install directory,
+/// and only moved to their own directory once they're complete
+pub const STAGING_DIR: &str = ".drop-staging";
+
 #[derive(Debug)]
 pub enum GameDownloadError {
     Communication(RemoteAccessError),
     Checksum,
     Setup(SetupError),
     Lock,
+    // Our own disk
     IoError(io::Error),
+    // The server took too long, or the connection was cut short
+    Interrupted(io::Error),
     DownloadError,
+    Stalled,
 }
 
 #[derive(Debug)]
 pub enum SetupError {
     Context,
+    Manifest(ManifestError),
 }
 
 impl Display for GameDownloadError {
@@ -61,16 +96,78 @@ impl Display for GameDownloadError {
             GameDownloadError::Setup(error) => write!(f, "An error occurred while setting up the download: {}", error),
             GameDownloadError::Lock => write!(f, "Failed to acquire lock. Something has gone very wrong internally. Please restart the application"),
             GameDownloadError::Checksum => write!(f, "Checksum failed to validate for download"),
-            GameDownloadError::IoError(error) => write!(f, "{}", error),
+            GameDownloadError::IoError(error) | GameDownloadError::Interrupted(error) => write!(f, "{}", error),
             GameDownloadError::DownloadError => write!(f, "Download failed. See Download Manager status for specific error"),
+            GameDownloadError::Stalled => write!(f, "Download stopped receiving data"),
         }
     }
 }
 
+impl GameDownloadError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            GameDownloadError::Communication(error) => error.class(),
+            GameDownloadError::Checksum => ErrorClass::Corrupted,
+            GameDownloadError::Stalled => ErrorClass::Timeout,
+            GameDownloadError::Interrupted(error) => {
+                ErrorClass::from_connection_io(error).unwrap_or(ErrorClass::Connection)
+            }
+            GameDownloadError::IoError(_)
+            | GameDownloadError::Setup(_)
+            | GameDownloadError::Lock
+            | GameDownloadError::DownloadError => ErrorClass::Local,
+        }
+    }
+
+    pub fn manifest_error(&self) -> Option<&ManifestError> {
+        match self {
+            GameDownloadError::Setup(SetupError::Manifest(error)) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Lets the UI react to particular responses, like 401 meaning we need to sign in again
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            GameDownloadError::Communication(error) => error.status_code(),
+            _ => None,
+        }
+    }
+
+    /// Whether trying again could plausibly succeed, i.e. the
+    /// failure was caused by the network rather than by us
+    pub fn is_retryable(&self) -> bool {
+        self.class().is_transient()
+    }
+}
+
 impl Display for SetupError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             SetupError::Context => write!(f, "Failed to generate contexts for download"),
+            SetupError::Manifest(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// How often the watchdog looks at the chunks in flight
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Only chunks up to this size are worth batching, larger
+/// ones spend far longer transferring than being requested
+const BATCH_CHUNK_MAX_SIZE: usize = 1024 * 1024;
+
+struct WatchedChunk {
+    activity: Arc<ChunkActivity>,
+    last_received: usize,
+    last_change: Instant,
+}
+impl WatchedChunk {
+    fn new(activity: Arc<ChunkActivity>) -> Self {
+        Self {
+            activity,
+            last_received: 0,
+            last_change: Instant::now(),
         }
     }
 }
@@ -87,24 +184,60 @@ impl GameDownloadAgent {
 
         let db_lock = DB.borrow_data().unwrap();
         let base_dir = db_lock.games.install_dirs[target_download_dir].clone();
+        // DLC goes into its game's directory, alongside the game's own files
+        let parent_id = db_lock.games.dlc_parents.get(&id).cloned();
+        // Installed games are updated (or repaired) wherever they already are,
+        // which may not be the directory picked if they've been moved or imported
+        let existing_install = db_lock
+            .games
+            .statuses
+            .get(parent_id.as_ref().unwrap_or(&id))
+            .and_then(GameStatus::installed)
+            .map(|(_, install_dir)| install_dir)
+            .or_else(|| {
+                db_lock
+                    .games
+                    .pending_imports
+                    .get(&id)
+                    .map(|import| &import.install_dir)
+            })
+            .map(PathBuf::from)
+            .filter(|dir| dir.exists());
+        let bandwidth_limit = settings_for(&db_lock, &id).bandwidth_limit;
         drop(db_lock);
 
         let base_dir_path = Path::new(&base_dir);
-        let data_base_dir_path = base_dir_path.join(id.clone());
+        let install_dir = existing_install.unwrap_or_else(|| base_dir_path.join(id.clone()));
+        // Anything already in the game's directory (an install being updated,
+        // or a download from before staging) carries on where it is
+        let data_base_dir_path = if install_dir.exists() {
+            install_dir.clone()
+        } else {
+            base_dir_path.join(STAGING_DIR).join(id.clone())
+        };
 
-        let stored_manifest =
-            StoredManifest::generate(id.clone(), version.clone(), data_base_dir_path.clone());
+        let stored_manifest = StoredManifest::generate(
+            id.clone(),
+            version.clone(),
+            data_base_dir_path.clone(),
+            parent_id.is_some(),
+        );
 
         Self {
             id,
             version,
+            target_download_dir,
             control_flag,
             manifest: Mutex::new(None),
             contexts: Vec::new(),
             completed_contexts: Mutex::new(Vec::new()),
+            last_checkpoint: Mutex::new(Instant::now()),
             progress: Arc::new(ProgressObject::new(0, 0, sender.clone())),
+            rate_limiter: Arc::new(RateLimiter::new(bandwidth_limit)),
             sender,
             stored_manifest,
+            install_dir,
+            parent_id,
         }
     }
 
@@ -113,6 +246,8 @@ impl GameDownloadAgent {
         self.ensure_manifest_exists()?;
         info!("Ensured manifest exists");
 
+        self.check_manifest()?;
+
         self.ensure_contexts()?;
         info!("Ensured contexts exists");
 
@@ -145,43 +280,215 @@ impl GameDownloadAgent {
     }
 
     fn download_manifest(&self) -> Result<(), GameDownloadError> {
-        let base_url = DB.fetch_base_url();
-        let manifest_url = base_url
-            .join(
-                format!(
-                    "/api/v1/client/metadata/manifest?id={}&version={}",
-                    self.id,
-                    encode(&self.version)
-                )
-                .as_str(),
-            )
-            .unwrap();
+        let manifest_download = fetch_manifest(&self.id, &self.version)?;
 
-        let header = generate_authorization_header();
-        let client = reqwest::blocking::Client::new();
-        let response = client
-            .get(manifest_url.to_string())
-            .header("Authorization", header)
-            .send()
-            .unwrap();
+        if let Ok(mut manifest) = self.manifest.lock() {
+            *manifest = Some(manifest_download);
+            return Ok(());
+        }
+
+        Err(GameDownloadError::Lock)
+    }
+
+    // Nothing on disk has been touched yet, so a bad manifest or a full disk
+    // fails here rather than partway through the download
+    fn check_manifest(&self) -> Result<(), GameDownloadError> {
+        let manifest_lock = self.manifest.lock().unwrap();
+        let manifest = manifest_lock.as_ref().unwrap();
+        let invalid = |error| GameDownloadError::Setup(SetupError::Manifest(error));
+
+        let total = validate_manifest(manifest).map_err(invalid)?;
+
+        // Anything already there, from an earlier run or the
+        // installed version, doesn't need the space again
+        let base_path = &self.stored_manifest.base_path;
+        let existing: u64 = manifest
+            .iter()
+            .filter(|(_, chunk)| chunk.link.is_none())
+            .filter_map(|(raw_path, _)| {
+                disk_usage(&existing_path(&install_path(base_path, raw_path))).ok()
+            })
+            .sum();
+        let needed = total.saturating_sub(existing);
+
+        // The game's own directory may not exist yet
+        let existing_dir = base_path
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or(base_path);
+        let available = fs4::available_space(existing_dir).map_err(GameDownloadError::IoError)?;
+        if available < needed {
+            return Err(invalid(ManifestError::InsufficientSpace {
+                needed,
+                available,
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// If this download is updating an existing install in place, returns
+    /// the version that's currently installed there
+    pub fn installed_version(&self) -> Option<String> {
+        let db_lock = DB.borrow_data().unwrap();
+        let (version_name, install_dir) = db_lock.games.statuses.get(&self.id)?.installed()?;
+
+        if *version_name == self.version || Path::new(install_dir) != self.stored_manifest.base_path
+        {
+            return None;
+        }
+
+        Some(version_name.clone())
+    }
+
+    /// Whether this exact version is already recorded as installed where it's
+    /// being downloaded to, or is being imported there
+    pub fn installed_in_place(&self) -> bool {
+        let db_lock = DB.borrow_data().unwrap();
+        let installed = db_lock
+            .games
+            .statuses
+            .get(&self.id)
+            .and_then(GameStatus::installed)
+            .or_else(|| {
+                let import = db_lock.games.pending_imports.get(&self.id)?;
+                Some((&import.version_name, &import.install_dir))
+            });
+        match installed {
+            Some((version_name, install_dir)) => {
+                *version_name == self.version
+                    && Path::new(install_dir) == self.stored_manifest.base_path
+            }
+            None => false,
+        }
+    }
 
-        if response.status() != 200 {
-            return Err(GameDownloadError::Communication(
-                RemoteAccessError::ManifestDownloadFailed(
-                    response.status(),
-                    response.text().unwrap(),
+    /// Removes the part files left by this download, and its stored progress,
+    /// from a directory that has others' files in it too
+    pub fn delete_part_files(&self) -> io::Result<()> {
+        if let Some(manifest) = &*self.manifest.lock().unwrap() {
+            let base_path = &self.stored_manifest.base_path;
+            for raw_path in manifest.keys() {
+                match remove_file(part_path(&install_path(base_path, raw_path))) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        self.stored_manifest.delete()
+    }
+
+    /// Whether this download is checking a folder picked with import_install
+    pub fn is_import(&self) -> bool {
+        DB.borrow_data()
+            .unwrap()
+            .games
+            .pending_imports
+            .get(&self.id)
+            .is_some_and(|import| {
+                import.version_name == self.version
+                    && Path::new(&import.install_dir) == self.stored_manifest.base_path
+            })
+    }
+
+    // Works out which of our contexts are already on disk from the installed
+    // version, and removes any files the new version no longer has
+    fn apply_delta(&self, installed_version: &str) -> Result<(), GameDownloadError> {
+        let installed_manifest = fetch_manifest(&self.id, installed_version)?;
+        let new_manifest = self.manifest.lock().unwrap().clone().unwrap();
+
+        let mut installed_chunks = HashSet::new();
+        for (raw_path, chunk) in installed_manifest.iter() {
+            let mut running_offset = 0;
+            for (index, length) in chunk.lengths.iter().enumerate() {
+                installed_chunks.insert((
+                    raw_path.as_str(),
+                    running_offset,
+                    *length,
+                    chunk.checksums[index].as_str(),
+                ));
+                running_offset += *length as u64;
+            }
+        }
+
+        let mut completed_lock = self.completed_contexts.lock().unwrap();
+        for (index, context) in self.contexts.iter().enumerate() {
+            if installed_chunks.contains(&(
+                context.file_name.as_str(),
+                context.offset,
+                context.length,
+                context.checksum.as_str(),
+            )) {
+                completed_lock.push(index);
+            }
+        }
+        let reused = completed_lock.len();
+
+        // Chunks that have moved, to another file or another part of one, can
+        // still be copied over. This has to happen before old files are removed.
+        let cache = ChunkCache::from_manifest(&installed_manifest, &self.stored_manifest.base_path);
+        let already_completed: HashSet<usize> = completed_lock.iter().cloned().collect();
+        for (index, context) in self.contexts.iter().enumerate() {
+            if already_completed.contains(&index) {
+                continue;
+            }
+            match cache.copy_into(context) {
+                Ok(true) => completed_lock.push(index),
+                Ok(false) => {}
+                Err(e) => debug!(
+                    "couldn't copy chunk {} of {} locally: {}",
+                    context.index, context.file_name, e
                 ),
-            ));
+            }
         }
 
-        let manifest_download = response.json::<DropManifest>().unwrap();
+        info!(
+            "updating {} from {} to {}, reusing {} and copying {} of {} chunks",
+            self.id,
+            installed_version,
+            self.version,
+            reused,
+            completed_lock.len() - reused,
+            self.contexts.len()
+        );
+        drop(completed_lock);
 
-        if let Ok(mut manifest) = self.manifest.lock() {
-            *manifest = Some(manifest_download);
-            return Ok(());
+        for raw_path in installed_manifest.keys() {
+            if new_manifest.contains_key(raw_path) {
+                continue;
+            }
+            let path = install_path(&self.stored_manifest.base_path, raw_path);
+            // Windows won't remove read-only files
+            if let Err(e) = make_writable(&path).and_then(|_| remove_file(&path)) {
+                warn!("failed to remove {} during update: {}", path.display(), e);
+            }
         }
 
-        Err(GameDownloadError::Lock)
+        self.checkpoint_completed_contexts(true);
+
+        Ok(())
+    }
+
+    /// Bytes which still need to be written to disk to finish this download.
+    /// Files which already exist, from a paused download or preallocation,
+    /// only count for the part they're missing.
+    pub fn required_space(&self) -> u64 {
+        let manifest_lock = self.manifest.lock().unwrap();
+        let manifest = match manifest_lock.as_ref() {
+            Some(manifest) => manifest,
+            None => return 0,
+        };
+
+        manifest
+            .iter()
+            .filter(|(_, chunk)| chunk.link.is_none())
+            .map(|(raw_path, chunk)| {
+                let total: u64 = chunk.lengths.iter().map(|length| *length as u64).sum();
+                let path = existing_path(&install_path(&self.stored_manifest.base_path, raw_path));
+                let existing = metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+                total.saturating_sub(existing)
+            })
+            .sum()
     }
 
     fn set_progress_object_params(&self) {
@@ -218,6 +525,13 @@ impl GameDownloadAgent {
         let mut contexts = Vec::new();
         let base_path = Path::new(&self.stored_manifest.base_path);
         create_dir_all(base_path).unwrap();
+        if *base_path != self.install_dir {
+            if let Some(staging_dir) = base_path.parent() {
+                if let Err(e) = hide(staging_dir) {
+                    debug!("couldn't hide {}: {}", staging_dir.display(), e);
+                }
+            }
+        }
 
         *self.completed_contexts.lock().unwrap() = self.stored_manifest.get_completed_contexts();
 
@@ -226,13 +540,66 @@ impl GameDownloadAgent {
             *self.completed_contexts.lock().unwrap()
         );
 
+        let sparse_files = DB.borrow_data().unwrap().settings.sparse_files;
+
+        // Contexts are identified by their index in the stored manifest, so
+        // they need to be generated in the same order on every run
+        let mut manifest: Vec<(String, DropChunk)> = manifest.into_iter().collect();
+        manifest.sort_by(|(a, _), (b, _)| a.cmp(b));
+
         for (raw_path, chunk) in manifest {
-            let path = base_path.join(Path::new(&raw_path));
+            // Made by create_links once everything else is in place
+            if chunk.link.is_some() {
+                continue;
+            }
+            let final_path = install_path(base_path, &raw_path);
+            // Chunks go into the part file, which only takes the real
+            // name once all of it has been checked in finalize_files.
+            // Empty files have nothing to check, so they go straight in.
+            let empty_file = chunk.is_empty_file();
+            let path = if empty_file {
+                final_path.clone()
+            } else {
+                part_path(&final_path)
+            };
 
             let container = path.parent().unwrap();
             create_dir_all(container).unwrap();
 
-            let file = File::create(path.clone()).unwrap();
+            // A file from the installed version (or one finished by a run that
+            // didn't get to the end) is where the download picks up from
+            // Unless it was a link, which writing through would change its target
+            if final_path.is_symlink() {
+                remove_file(&final_path).map_err(GameDownloadError::IoError)?;
+            } else if final_path.exists() {
+                make_writable(&final_path).map_err(GameDownloadError::IoError)?;
+                if !path.exists() {
+                    rename(&final_path, &path).map_err(GameDownloadError::IoError)?;
+                }
+            }
+
+            // Don't truncate, we may be resuming a partial download
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path.clone())
+                .unwrap();
+            // Has to happen before the file is extended, or Windows allocates it all anyway
+            if sparse_files {
+                if let Err(e) = make_sparse(&file) {
+                    debug!("couldn't make {} sparse: {}", raw_path, e);
+                }
+            }
+            // Empty files are complete as soon as they exist, so there's
+            // nothing to request or finalize, just mark them now
+            if empty_file {
+                file.set_len(0).map_err(GameDownloadError::IoError)?;
+                if let Err(e) = apply_file_attributes(&path, chunk.permissions, chunk.attributes) {
+                    warn!("couldn't set attributes of {}: {}", raw_path, e);
+                }
+                continue;
+            }
             let mut running_offset = 0;
 
             for (index, length) in chunk.lengths.iter().enumerate() {
@@ -244,84 +611,570 @@ impl GameDownloadAgent {
                     game_id: game_id.to_string(),
                     path: path.clone(),
                     checksum: chunk.checksums[index].clone(),
+                    checksum_algorithm: chunk.checksum_algorithm,
                     length: *length,
                     permissions: chunk.permissions,
+                    attributes: chunk.attributes,
                 });
                 running_offset += *length as u64;
             }
 
-            #[cfg(target_os = "linux")]
-            if running_offset > 0 {
-                let _ = fallocate(file, FallocateFlags::empty(), 0, running_offset);
+            // Files from an installed version may be a different size
+            file.set_len(running_offset)
+                .map_err(GameDownloadError::IoError)?;
+
+            // Reserve the space now, so chunks landing all over the file don't
+            // fragment it and we find out about a full disk before downloading
+            if !sparse_files && running_offset > 0 {
+                match file.allocate(running_offset) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+                        return Err(GameDownloadError::IoError(e));
+                    }
+                    // Not every filesystem can preallocate, which is fine
+                    Err(e) => debug!("couldn't preallocate {}: {}", raw_path, e),
+                }
             }
         }
         self.contexts = contexts;
 
+        self.record_disk_usage();
+
+        // Only on a fresh start, otherwise the stored
+        // manifest already has everything we reused
+        if self.completed_contexts.lock().unwrap().is_empty() {
+            if let Some(installed_version) = self.installed_version() {
+                self.apply_delta(&installed_version)?;
+            } else if self.installed_in_place() {
+                self.adopt_existing_chunks();
+            }
+        }
+
         Ok(())
     }
 
-    pub fn run(&self) -> Result<(), ()> {
-        info!("downloading game: {}", self.id);
-        const DOWNLOAD_MAX_THREADS: usize = 1;
+    // Keeps whatever's already on disk that matches the manifest,
+    // so only the missing or damaged chunks are downloaded
+    fn adopt_existing_chunks(&self) {
+        let mut completed = Vec::new();
+        for range in self.file_ranges() {
+            let failed: HashSet<usize> = match self.verify_file(range.clone()) {
+                Ok(failed) => failed.into_iter().collect(),
+                Err(e) => {
+                    debug!(
+                        "couldn't check {}: {}",
+                        self.contexts[range.start].file_name, e
+                    );
+                    continue;
+                }
+            };
+            completed.extend(range.filter(|index| !failed.contains(index)));
+        }
 
-        let pool = ThreadPoolBuilder::new()
-            .num_threads(DOWNLOAD_MAX_THREADS)
-            .build()
-            .unwrap();
+        info!(
+            "found {} of {} chunks of {} already on disk",
+            completed.len(),
+            self.contexts.len(),
+            self.id
+        );
+        *self.completed_contexts.lock().unwrap() = completed;
+        self.checkpoint_completed_contexts(true);
+    }
 
-        let completed_indexes = Arc::new(Mutex::new(Vec::new()));
-        let completed_indexes_loop_arc = completed_indexes.clone();
+    // Contexts for the same file are next to each other
+    fn file_ranges(&self) -> Vec<std::ops::Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < self.contexts.len() {
+            let path = &self.contexts[start].path;
+            let end = start
+                + self.contexts[start..]
+                    .iter()
+                    .take_while(|context| context.path == *path)
+                    .count();
+            ranges.push(start..end);
+            start = end;
+        }
+        ranges
+    }
+
+    // Only accurate once every chunk has stopped writing
+    fn record_partial_contexts(&self) {
+        let completed: HashSet<usize> = self
+            .completed_contexts
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        let partial_contexts = (0..self.contexts.len())
+            .filter(|index| !completed.contains(index))
+            .map(|index| (index, self.progress.get(index).load(Ordering::Relaxed)))
+            .filter(|(_, written)| *written != 0)
+            .collect();
+        self.stored_manifest.set_partial_contexts(partial_contexts);
+    }
 
-        pool.scope(move |scope| {
-            let completed_lock = self.completed_contexts.lock().unwrap();
+    // Writes the completed contexts to the stored manifest, so that an
+    // interrupted download (including a crash) can pick up where it left off.
+    // Unless forced, this only writes once every CHECKPOINT_INTERVAL.
+    fn checkpoint_completed_contexts(&self, force: bool) {
+        const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
 
-            for (index, context) in self.contexts.iter().enumerate() {
-                let progress = self.progress.get(index); // Clone arcs
-                let progress_handle = ProgressHandle::new(progress, self.progress.clone());
-                // If we've done this one already, skip it
-                if completed_lock.contains(&index) {
-                    progress_handle.add(context.length);
-                    continue;
+        let mut last_checkpoint = self.last_checkpoint.lock().unwrap();
+        if !force && last_checkpoint.elapsed() < CHECKPOINT_INTERVAL {
+            return;
+        }
+
+        self.stored_manifest
+            .set_completed_contexts(&self.completed_contexts);
+        self.stored_manifest.write();
+        self.record_disk_usage();
+        *last_checkpoint = Instant::now();
+    }
+
+    fn final_path(&self, context: &DropDownloadContext) -> PathBuf {
+        install_path(&self.stored_manifest.base_path, &context.file_name)
+    }
+
+    // Checks every chunk of each part file against the manifest, and renames
+    // the files that pass to their real names. Returns the chunks that failed.
+    fn finalize_files(&self) -> Vec<usize> {
+        let mut corrupt = Vec::new();
+        for range in self.file_ranges() {
+            let path = &self.contexts[range.start].path;
+            // Already renamed, by an earlier attempt at finalizing
+            if !path.exists() {
+                continue;
+            }
+
+            let failed = match self.verify_file(range.clone()) {
+                Ok(failed) => failed,
+                Err(e) => {
+                    warn!("couldn't verify {}: {}", path.display(), e);
+                    range.clone().collect()
+                }
+            };
+            if failed.is_empty() {
+                let context = &self.contexts[range.start];
+                let final_path = self.final_path(context);
+                if let Err(e) = rename(path, &final_path) {
+                    warn!("couldn't move {} into place: {}", final_path.display(), e);
+                    corrupt.extend(range);
+                } else if let Err(e) =
+                    apply_file_attributes(&final_path, context.permissions, context.attributes)
+                {
+                    // The file's fine, it just isn't marked quite right
+                    warn!("couldn't set attributes of {}: {}", final_path.display(), e);
                 }
+            } else {
+                corrupt.extend(failed);
+            }
+        }
+        corrupt
+    }
 
-                let context = context.clone();
-                let control_flag = self.control_flag.clone(); // Clone arcs
-                let completed_indexes_ref = completed_indexes_loop_arc.clone();
-
-                scope.spawn(move |_| {
-                    match download_game_chunk(context.clone(), control_flag, progress_handle) {
-                        Ok(res) => {
-                            if res {
-                                let mut lock = completed_indexes_ref.lock().unwrap();
-                                lock.push(index);
-                            }
-                        }
-                        Err(e) => {
-                            error!("GameDownloadError: {}", e);
-                            self.sender.send(DownloadManagerSignal::Error(e)).unwrap();
+    // The chunks in `contexts`, all from one file, that don't match their checksums
+    fn verify_file(&self, contexts: std::ops::Range<usize>) -> io::Result<Vec<usize>> {
+        let mut file = File::open(&self.contexts[contexts.start].path)?;
+        let mut failed = Vec::new();
+        for index in contexts {
+            let context = &self.contexts[index];
+            let mut buf = vec![0; context.length];
+            file.seek(SeekFrom::Start(context.offset))?;
+            file.read_exact(&mut buf)?;
+
+            let mut hasher = ChunkHasher::new(context.checksum_algorithm);
+            hasher.update(&buf);
+            if hasher.finish() != context.checksum {
+                warn!(
+                    "chunk {} of {} doesn't match its checksum",
+                    context.index, context.file_name
+                );
+                failed.push(index);
+            }
+        }
+        Ok(failed)
+    }
+
+    // Hard links need their targets finalized, so links are made last
+    fn create_links(&self) -> io::Result<()> {
+        let manifest = self.manifest.lock().unwrap().clone().unwrap();
+        let base_path = &self.stored_manifest.base_path;
+        for (raw_path, chunk) in manifest.iter() {
+            let link = match &chunk.link {
+                Some(link) => link,
+                None => continue,
+            };
+            let path = install_path(base_path, raw_path);
+            create_dir_all(path.parent().unwrap())?;
+
+            // Whatever's there, from the installed version or an earlier attempt
+            if path.symlink_metadata().is_ok() {
+                make_writable(&path)?;
+                remove_file(&path)?;
+            }
+            match link {
+                FileLink::Symlink { target } => create_symlink(Path::new(target), &path)?,
+                FileLink::Hardlink { target } => hard_link(install_path(base_path, target), &path)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Everything has been verified by now, so the game can show up
+    fn move_into_place(&self) -> io::Result<()> {
+        let staged = &self.stored_manifest.base_path;
+        if *staged == self.install_dir {
+            return Ok(());
+        }
+        rename(staged, &self.install_dir)?;
+        // Only goes once nothing else is being staged
+        if let Some(staging_dir) = staged.parent() {
+            let _ = remove_dir(staging_dir);
+        }
+        Ok(())
+    }
+
+    // Marks chunks as not downloaded at all, so they're fetched from scratch
+    fn forget_contexts(&self, indexes: &[usize]) {
+        let forget: HashSet<&usize> = indexes.iter().collect();
+        self.completed_contexts
+            .lock()
+            .unwrap()
+            .retain(|index| !forget.contains(index));
+        for index in indexes {
+            self.progress.get(*index).store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Preallocated files take up their full size from the start,
+    // but sparse ones only grow as chunks are written to them
+    fn record_disk_usage(&self) {
+        let mut paths = HashSet::new();
+        let usage = self
+            .contexts
+            .iter()
+            .filter(|context| paths.insert(&context.path))
+            .filter_map(|context| {
+                disk_usage(&context.path)
+                    .or_else(|_| disk_usage(&self.final_path(context)))
+                    .ok()
+            })
+            .sum();
+        self.progress.set_disk_usage(usage);
+    }
+
+    // Keeps up to `concurrency` chunks in flight, and records
+    // each one as it finishes
+    async fn download_contexts(
+        &self,
+        pending: Vec<usize>,
+        mirrors: Arc<Vec<Url>>,
+        concurrency: usize,
+    ) {
+        let client = http_client();
+        let mut pending = pending.into_iter().peekable();
+        let mut tasks = JoinSet::new();
+
+        let (stall_timeout, chunks_per_request) = {
+            let db_handle = DB.borrow_data().unwrap();
+            let settings = &db_handle.settings;
+            // Any longer and the chunk's read timeout would always go first
+            let longest = settings.chunk_read_timeout.saturating_sub(1).max(1);
+            (
+                Duration::from_secs(settings.stall_timeout.clamp(1, longest)),
+                chunks_per_request(settings.chunks_per_request),
+            )
+        };
+        let mut watched = HashMap::new();
+        // Each task's first chunk, for when one panics and takes its result with it
+        let mut first_indexes = HashMap::new();
+        let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
+
+        loop {
+            while tasks.len() < concurrency
+                && self.control_flag.get() == DownloadThreadControlFlag::Go
+            {
+                let index = match pending.next() {
+                    Some(index) => index,
+                    None => break,
+                };
+                let mut batch = vec![index];
+                while batch.len() < chunks_per_request {
+                    match pending.peek() {
+                        Some(&next) if self.can_batch(*batch.last().unwrap(), next) => {
+                            batch.push(next);
+                            pending.next();
                         }
+                        _ => break,
                     }
-                });
+                }
+
+                // A batch is watched as one, under its first chunk
+                let activity = Arc::new(ChunkActivity::default());
+                watched.insert(index, WatchedChunk::new(activity.clone()));
+                let control_flag = self.control_flag.clone();
+                let rate_limiter = self.rate_limiter.clone();
+                let (mirrors, client) = (mirrors.clone(), client.clone());
+
+                if batch.len() == 1 {
+                    let download = download_game_chunk(
+                        self.contexts[index].clone(),
+                        mirrors,
+                        client,
+                        control_flag,
+                        self.progress_handle(index),
+                        rate_limiter,
+                        activity,
+                    );
+                    let task = tasks.spawn(async move { (index, vec![(index, download.await)]) });
+                    first_indexes.insert(task.id(), index);
+                } else {
+                    let batch = batch
+                        .into_iter()
+                        .map(|index| BatchedChunk {
+                            index,
+                            context: self.contexts[index].clone(),
+                            progress: self.progress_handle(index),
+                        })
+                        .collect();
+                    let download = download_game_chunks(
+                        batch,
+                        mirrors,
+                        client,
+                        control_flag,
+                        rate_limiter,
+                        activity,
+                    );
+                    let task = tasks.spawn(async move { (index, download.await) });
+                    first_indexes.insert(task.id(), index);
+                }
             }
-        });
 
-        let completed_lock_len = {
-            let mut completed_lock = self.completed_contexts.lock().unwrap();
-            let newly_completed_lock = completed_indexes.lock().unwrap();
+            let joined = tokio::select! {
+                joined = tasks.join_next_with_id() => joined,
+                _ = watchdog.tick() => {
+                    self.check_for_stalls(&mut watched, stall_timeout);
+                    continue;
+                }
+            };
+            let (first_index, results) = match joined {
+                Some(Ok((id, finished))) => {
+                    first_indexes.remove(&id);
+                    finished
+                }
+                // Its chunks never finished, and there's no telling how far
+                // they got, so stop here rather than complete without them
+                Some(Err(e)) => {
+                    error!("chunk download task for {} failed: {}", self.id, e);
+                    let first_index = first_indexes.remove(&e.id());
+                    if let Some(chunk) = first_index.and_then(|index| watched.remove(&index)) {
+                        record_downloaded(&self.id, chunk.activity.received() as u64);
+                    }
+                    self.control_flag.set(DownloadThreadControlFlag::Stop);
+                    self.sender
+                        .send(DownloadManagerSignal::Error(
+                            self.id.clone(),
+                            GameDownloadError::DownloadError,
+                        ))
+                        .unwrap();
+                    continue;
+                }
+                None => break,
+            };
+            if let Some(chunk) = watched.remove(&first_index) {
+                record_downloaded(&self.id, chunk.activity.received() as u64);
+            }
 
-            completed_lock.extend(newly_completed_lock.iter());
+            for (index, result) in results {
+                match result {
+                    Ok(true) => {
+                        self.completed_contexts.lock().unwrap().push(index);
+                        self.checkpoint_completed_contexts(false);
+                    }
+                    Ok(false) => {}
+                    // Transient errors have already been retried as far as the
+                    // settings allow, so this one is final. Let the chunks still
+                    // running wind down rather than starting any more.
+                    Err(e) => {
+                        error!("GameDownloadError ({:?}): {}", e.class(), e);
+                        self.control_flag.set(DownloadThreadControlFlag::Stop);
+                        self.sender
+                            .send(DownloadManagerSignal::Error(self.id.clone(), e))
+                            .unwrap();
+                    }
+                }
+            }
+        }
 
-            completed_lock.len()
-        };
+        flush_usage();
+    }
+
+    fn progress_handle(&self, index: usize) -> ProgressHandle {
+        ProgressHandle::new(self.progress.get(index), self.progress.clone())
+    }
+
+    // Whether `next` can be asked for in the same request as `previous`.
+    // Only small chunks that follow on in the same file and haven't
+    // been started go together, anything partial resumes on its own.
+    fn can_batch(&self, previous: usize, next: usize) -> bool {
+        let (previous_context, next_context) = (&self.contexts[previous], &self.contexts[next]);
+        let untouched = |index: usize| self.progress.get(index).load(Ordering::Relaxed) == 0;
+        previous_context.path == next_context.path
+            && next_context.index == previous_context.index + 1
+            && previous_context.length <= BATCH_CHUNK_MAX_SIZE
+            && next_context.length <= BATCH_CHUNK_MAX_SIZE
+            && untouched(previous)
+            && untouched(next)
+    }
+
+    // Flags chunks that haven't received anything for `stall_timeout`, which
+    // sends them back through the retry path
+    fn check_for_stalls(
+        &self,
+        watched: &mut HashMap<usize, WatchedChunk>,
+        stall_timeout: Duration,
+    ) {
+        for (index, chunk) in watched.iter_mut() {
+            let received = chunk.activity.received();
+            if received != chunk.last_received {
+                chunk.last_received = received;
+                chunk.last_change = Instant::now();
+                continue;
+            }
+            if chunk.last_change.elapsed() < stall_timeout {
+                continue;
+            }
+
+            let context = &self.contexts[*index];
+            warn!(
+                "chunk {} of {} received nothing for {}s, restarting it",
+                context.index,
+                context.file_name,
+                stall_timeout.as_secs()
+            );
+            chunk.activity.flag_stalled();
+            // Give the next attempt the full timeout
+            chunk.last_change = Instant::now();
+            self.sender
+                .send(DownloadManagerSignal::Stalled(
+                    self.id.clone(),
+                    context.file_name.clone(),
+                ))
+                .unwrap();
+        }
+    }
+
+    pub fn run(&self) -> Result<(), ()> {
+        info!("downloading game: {}", self.id);
+        // Each chunk writes to its own offset through its own file
+        // handle, so chunks can safely be downloaded in parallel
+        let concurrency = DB.borrow_data().unwrap().settings.download_threads.max(1);
+
+        let already_completed: HashSet<usize> = self
+            .completed_contexts
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
 
-        // If we're not out of contexts, we're not done, so we don't fire completed
-        if completed_lock_len != self.contexts.len() {
-            info!("da for {} exited without completing", self.id.clone());
-            self.stored_manifest
-                .set_completed_contexts(&self.completed_contexts);
-            info!("Setting completed contexts");
-            self.stored_manifest.write();
-            info!("Wrote completed contexts");
+        // Unfinished chunks pick up from what they last wrote,
+        // which also keeps progress right after a pause
+        let partial_contexts = self.stored_manifest.get_partial_contexts();
+        for (index, context) in self.contexts.iter().enumerate() {
+            let progress = self.progress.get(index);
+            if already_completed.contains(&index) {
+                progress.store(context.length, Ordering::Relaxed);
+            } else {
+                let written = partial_contexts.get(&index).copied().unwrap_or(0);
+                progress.store(written.min(context.length), Ordering::Relaxed);
+            }
+        }
+        self.progress.reset_speed();
+
+        // Blocking request, so it has to happen outside of the runtime
+        let mirrors = Arc::new(fetch_mirrors());
+        let mut pending: Vec<usize> = (0..self.contexts.len())
+            .filter(|index| !already_completed.contains(index))
+            .collect();
+
+        let mut redownloaded = false;
+        loop {
+            DOWNLOAD_RUNTIME.block_on(self.download_contexts(
+                pending,
+                mirrors.clone(),
+                concurrency,
+            ));
+
+            self.progress.push_update();
+            self.record_partial_contexts();
+
+            let completed_lock_len = self.completed_contexts.lock().unwrap().len();
+
+            // If we're not out of contexts, we're not done, so we don't fire completed
+            if completed_lock_len != self.contexts.len() {
+                info!("da for {} exited without completing", self.id.clone());
+                self.checkpoint_completed_contexts(true);
+                info!("Wrote completed contexts");
+                return Ok(());
+            }
+
+            let corrupt = self.finalize_files();
+            if corrupt.is_empty() {
+                break;
+            }
+
+            // Something changed the files under us, or a reused chunk
+            // wasn't what the manifest said. Worth one more go.
+            self.forget_contexts(&corrupt);
+            if redownloaded {
+                error!(
+                    "{} chunks of {} still failed verification after downloading them again",
+                    corrupt.len(),
+                    self.id
+                );
+                self.checkpoint_completed_contexts(true);
+                self.sender
+                    .send(DownloadManagerSignal::Error(
+                        self.id.clone(),
+                        GameDownloadError::Checksum,
+                    ))
+                    .unwrap();
+                return Ok(());
+            }
+            warn!(
+                "{} chunks of {} failed verification, downloading them again",
+                corrupt.len(),
+                self.id
+            );
+            redownloaded = true;
+            pending = corrupt;
+        }
+
+        if let Err(e) = self.create_links() {
+            error!("couldn't create links for {}: {}", self.id, e);
+            self.sender
+                .send(DownloadManagerSignal::Error(
+                    self.id.clone(),
+                    GameDownloadError::IoError(e),
+                ))
+                .unwrap();
+            return Ok(());
+        }
+
+        // Keep the stored manifest in line with what's on disk
+        self.checkpoint_completed_contexts(true);
+
+        if let Err(e) = self.move_into_place() {
+            error!("couldn't move {} out of staging: {}", self.id, e);
+            self.sender
+                .send(DownloadManagerSignal::Error(
+                    self.id.clone(),
+                    GameDownloadError::IoError(e),
+                ))
+                .unwrap();
             return Ok(());
         }
 
@@ -333,3 +1186,43 @@ impl GameDownloadAgent {
         Ok(())
     }
 }
+
+pub fn fetch_manifest(game_id: &str, version: &str) -> Result<DropManifest, GameDownloadError> {
+    if let Some(manifest) = read_manifest(game_id, version) {
+        return Ok(manifest);
+    }
+
+    let base_url = DB.fetch_base_url();
+    let manifest_url = base_url
+        .join(
+            format!(
+                "/api/v1/client/metadata/manifest?id={}&version={}",
+                game_id,
+                encode(version)
+            )
+            .as_str(),
+        )
+        .unwrap();
+
+    let header = generate_authorization_header();
+    let client = blocking_http_client();
+    let response = client
+        .get(manifest_url.to_string())
+        .header("Authorization", header)
+        // Servers that understand this pick one per chunk, older ones ignore it
+        .header("X-Drop-Checksum-Algorithms", SUPPORTED_CHECKSUM_ALGORITHMS)
+        .send()
+        .map_err(|e| GameDownloadError::Communication(e.into()))?;
+
+    if response.status() != 200 {
+        return Err(GameDownloadError::Communication(
+            RemoteAccessError::from_response(response),
+        ));
+    }
+
+    let manifest = response
+        .json::<DropManifest>()
+        .map_err(|e| GameDownloadError::Communication(e.into()))?;
+    write_manifest(game_id, version, &manifest);
+    Ok(manifest)
+}
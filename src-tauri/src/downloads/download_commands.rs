@@ -1,14 +1,57 @@
-use std::sync::Mutex;
+use std::{fs::metadata, path::Path, sync::Mutex};
 
-use crate::AppState;
+use tauri::AppHandle;
+
+use crate::{
+    db::{GameStatus, PendingImport},
+    download_dirs::default_download_dir,
+    game_settings::update_game_settings,
+    AppState, DB,
+};
+
+use super::download_agent::fetch_manifest;
+
+use super::download_manager::DownloadPriority;
+use super::download_schedule::DownloadWindow;
+use super::install_move;
+use super::manifest::{install_path, validate_manifest};
+use super::rate_limiter::DOWNLOAD_RATE_LIMITER;
+use super::speed_history::SpeedSample;
+use super::write_backend::DiskWriteMode;
 
 #[tauri::command]
 pub fn download_game(
     game_id: String,
     game_version: String,
-    install_dir: usize,
+    install_dir: Option<usize>,
     state: tauri::State<'_, Mutex<AppState>>,
 ) -> Result<(), String> {
+    let install_dir = install_dir.unwrap_or_else(default_download_dir);
+    if install_dir >= DB.borrow_data().unwrap().games.install_dirs.len() {
+        return Err("Invalid download directory".to_string());
+    }
+
+    // Picking another version by hand moves the game off the one it was pinned to
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let games = &mut db_lock.games;
+    let mut unpinned = false;
+    // Including one a rollback was about to pin
+    for pins in [&mut games.pinned_versions, &mut games.pending_pins] {
+        if pins
+            .get(&game_id)
+            .is_some_and(|pinned| *pinned != game_version)
+        {
+            pins.remove(&game_id);
+            unpinned = true;
+        }
+    }
+    if unpinned {
+        drop(db_lock);
+        DB.save().unwrap();
+    } else {
+        drop(db_lock);
+    }
+
     state
         .lock()
         .unwrap()
@@ -17,6 +60,109 @@ pub fn download_game(
         .map_err(|_| "An error occurred while communicating with the download manager.".to_string())
 }
 
+#[tauri::command]
+pub fn download_dlc(
+    dlc_id: String,
+    dlc_version: String,
+    state: tauri::State<'_, Mutex<AppState>>,
+) -> Result<(), String> {
+    let db_lock = DB.borrow_data().unwrap();
+    let game_id = match db_lock.games.dlc_parents.get(&dlc_id) {
+        Some(game_id) => game_id.clone(),
+        None => return Err("Unknown DLC".to_string()),
+    };
+    let game_installed = db_lock
+        .games
+        .statuses
+        .get(&game_id)
+        .and_then(GameStatus::installed)
+        .is_some();
+    let game_busy = db_lock.games.transient_statuses.contains_key(&game_id);
+    drop(db_lock);
+    if !game_installed {
+        return Err("The game has to be installed before its DLC".to_string());
+    }
+    if game_busy {
+        return Err("Wait for the game to finish before installing its DLC".to_string());
+    }
+
+    // Goes into the game's directory, whichever directory is passed
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .queue_game(dlc_id, dlc_version, default_download_dir())
+        .map_err(|_| "An error occurred while communicating with the download manager.".to_string())
+}
+
+#[tauri::command]
+pub fn import_install(
+    game_id: String,
+    game_version: String,
+    path: String,
+    state: tauri::State<'_, Mutex<AppState>>,
+) -> Result<(), String> {
+    let install_dir = Path::new(&path);
+    if !install_dir.is_dir() {
+        return Err("Invalid path: not a directory".to_string());
+    }
+
+    let db_lock = DB.borrow_data().unwrap();
+    let installed = db_lock
+        .games
+        .statuses
+        .get(&game_id)
+        .and_then(GameStatus::installed)
+        .is_some();
+    let busy = db_lock.games.transient_statuses.contains_key(&game_id);
+    drop(db_lock);
+    if installed {
+        return Err("Game is already installed".to_string());
+    }
+    if busy {
+        return Err("Game is busy, try again once it's finished".to_string());
+    }
+
+    let manifest = fetch_manifest(&game_id, &game_version).map_err(|e| e.to_string())?;
+    validate_manifest(&manifest).map_err(|e| e.to_string())?;
+
+    // A folder with none of the game's files in it is more likely
+    // the wrong folder than a very broken install
+    let matching = manifest
+        .iter()
+        .filter(|(_, chunk)| chunk.link.is_none() && !chunk.is_empty_file())
+        .filter(|(raw_path, chunk)| {
+            let length: usize = chunk.lengths.iter().sum();
+            metadata(install_path(install_dir, raw_path))
+                .is_ok_and(|metadata| metadata.len() == length as u64)
+        })
+        .count();
+    if matching == 0 {
+        return Err("Folder doesn't contain this version of the game".to_string());
+    }
+
+    // Recorded as being imported there, so the download checks the files
+    // already in the folder and only fetches what's missing, rather than
+    // starting afresh. It's only installed once that's done.
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.games.pending_imports.insert(
+        game_id.clone(),
+        PendingImport {
+            version_name: game_version.clone(),
+            install_dir: path,
+        },
+    );
+    drop(db_lock);
+    DB.save().unwrap();
+
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .queue_game(game_id, game_version, default_download_dir())
+        .map_err(|_| "An error occurred while communicating with the download manager.".to_string())
+}
+
 #[tauri::command]
 pub fn pause_game_downloads(state: tauri::State<'_, Mutex<AppState>>) {
     state.lock().unwrap().download_manager.pause_downloads()
@@ -40,6 +186,242 @@ pub fn move_game_in_queue(
         .rearrange(old_index, new_index)
 }
 
+#[tauri::command]
+pub fn reorder_download_queue(
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    new_index: usize,
+) {
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .move_game(game_id, new_index)
+}
+
+#[tauri::command]
+pub fn set_download_priority(
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    priority: DownloadPriority,
+) {
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .set_priority(game_id, priority)
+}
+
+#[tauri::command]
+pub fn set_max_concurrent_downloads(
+    state: tauri::State<'_, Mutex<AppState>>,
+    limit: usize,
+) -> Result<(), String> {
+    if limit == 0 {
+        return Err("At least one download must be allowed to run".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.max_concurrent_downloads = limit;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .set_max_concurrent_downloads(limit);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_download_threads(threads: usize) -> Result<(), String> {
+    if threads == 0 {
+        return Err("At least one download thread is required".to_string());
+    }
+
+    // Picked up by agents the next time they start
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.download_threads = threads;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_download_buffer_sizes(
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+) -> Result<(), String> {
+    if read_buffer_size == 0 || write_buffer_size == 0 {
+        return Err("Buffer sizes must be greater than zero".to_string());
+    }
+
+    // Picked up by each chunk as it starts
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.read_buffer_size = read_buffer_size;
+    db_lock.settings.write_buffer_size = write_buffer_size;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_stall_timeout(seconds: u64) -> Result<(), String> {
+    if seconds == 0 {
+        return Err("Stall timeout must be at least one second".to_string());
+    }
+
+    // Picked up by agents the next time they start
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.stall_timeout = seconds;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_chunks_per_request(count: usize) -> Result<(), String> {
+    if count == 0 {
+        return Err("At least one chunk must be requested at a time".to_string());
+    }
+
+    // Picked up by agents the next time they start
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.chunks_per_request = count;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_disk_write_mode(mode: DiskWriteMode) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.disk_write_mode = mode;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn set_sparse_files(enabled: bool) {
+    // Only affects files as downloads set them up
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.sparse_files = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn set_auto_start_downloads(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.auto_start_downloads = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn set_delete_partial_on_cancel(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.delete_partial_on_cancel = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn set_bandwidth_limit(limit: Option<usize>) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.bandwidth_limit = limit;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    DOWNLOAD_RATE_LIMITER.set_rate(limit);
+}
+
+#[tauri::command]
+pub fn set_game_bandwidth_limit(
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    limit: Option<usize>,
+) {
+    update_game_settings(&game_id, |settings| settings.bandwidth_limit = limit);
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .set_game_bandwidth_limit(game_id, limit)
+}
+
+#[tauri::command]
+pub fn fetch_bandwidth_limit() -> Option<usize> {
+    DOWNLOAD_RATE_LIMITER.get_rate()
+}
+
+#[tauri::command]
+pub fn set_download_windows(
+    state: tauri::State<'_, Mutex<AppState>>,
+    windows: Vec<DownloadWindow>,
+) -> Result<(), String> {
+    if windows.iter().any(|window| !window.is_valid()) {
+        return Err("Invalid download window".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.download_windows = windows;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    // Apply straight away, rather than on the next scheduled check
+    state.lock().unwrap().download_manager.check_schedule();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_download_windows() -> Vec<DownloadWindow> {
+    DB.borrow_data().unwrap().settings.download_windows.clone()
+}
+
+#[tauri::command]
+pub fn get_download_speed_history(state: tauri::State<'_, Mutex<AppState>>) -> Vec<SpeedSample> {
+    state.lock().unwrap().download_manager.get_speed_history()
+}
+
+#[tauri::command]
+pub fn move_game_install(
+    app_handle: AppHandle,
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    target_dir: usize,
+) -> Result<(), String> {
+    let state_lock = state.lock().unwrap();
+    if state_lock
+        .process_manager
+        .lock()
+        .unwrap()
+        .is_running(&game_id)
+    {
+        return Err("Game can't be moved while it's running".to_string());
+    }
+    // DLC downloads into the game's directory, so has to finish first too
+    let dlc_parents = DB.borrow_data().unwrap().games.dlc_parents.clone();
+    if state_lock
+        .download_manager
+        .read_queue()
+        .iter()
+        .any(|queued| queued.id == game_id || dlc_parents.get(&queued.id) == Some(&game_id))
+    {
+        return Err("Game can't be moved while it's downloading".to_string());
+    }
+    drop(state_lock);
+
+    install_move::move_game_install(app_handle, game_id, target_dir)
+}
+
 #[tauri::command]
 pub fn cancel_game(state: tauri::State<'_, Mutex<AppState>>, game_id: String) {
     state.lock().unwrap().download_manager.cancel(game_id)
@@ -1,213 +1,1107 @@
 use crate::auth::generate_authorization_header;
-use crate::db::DatabaseImpls;
-use crate::downloads::manifest::DropDownloadContext;
-use crate::remote::RemoteAccessError;
+use crate::downloads::manifest::{ChecksumAlgorithm, DropDownloadContext};
+use crate::remote::{http_client_for, NetworkTimeouts, RemoteAccessError};
+use crate::throttle::{note_rate_limited, remaining, RequestClass};
 use crate::DB;
-use log::warn;
-use md5::{Context, Digest};
-use reqwest::blocking::Response;
-use tauri::utils::acl::Permission;
-
-use std::fs::{set_permissions, Permissions};
-use std::io::Read;
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-use std::thread::sleep;
-use std::time::Duration;
-use std::{
-    fs::{File, OpenOptions},
-    io::{self, BufWriter, Seek, SeekFrom, Write},
-    path::PathBuf,
+use bytes::Bytes;
+use http::{
+    header::{ACCEPT_ENCODING, RANGE},
+    StatusCode,
 };
+use log::warn;
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, Response};
+
+use std::collections::HashSet;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use url::Url;
 use urlencoding::encode;
 
+use super::content::{content_endpoint, forget_signatures, is_content_url, signed_query};
 use super::download_agent::GameDownloadError;
 use super::download_thread_control_flag::{DownloadThreadControl, DownloadThreadControlFlag};
+use super::mirrors::{pick_mirror, record_failure, record_success};
 use super::progress_object::ProgressHandle;
+use super::rate_limiter::{RateLimiter, DOWNLOAD_RATE_LIMITER};
+use super::write_backend::{DiskWriteMode, WriteBackend};
+
+/// Chunk transfers from every download run as tasks on this runtime, so the
+/// number of threads doesn't grow with the number of chunks in flight
+pub static DOWNLOAD_RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .thread_name("drop-download")
+        .enable_all()
+        .build()
+        .unwrap()
+});
 
-pub struct DropWriter<W: Write> {
-    hasher: Context,
-    destination: W,
+/// Buffers are never smaller than this, whatever the settings say
+const MIN_BUFFER_SIZE: usize = 4 * 1024;
+
+// There's no point in buffering more than the chunk itself
+fn buffer_size(configured: usize, chunk_length: usize) -> usize {
+    configured.min(chunk_length).max(MIN_BUFFER_SIZE)
 }
-impl DropWriter<File> {
-    fn new(path: PathBuf) -> Self {
-        Self {
-            destination: OpenOptions::new().write(true).open(path).unwrap(),
-            hasher: Context::new(),
+
+pub enum ChunkHasher {
+    Md5(md5::Context),
+    Blake3(Box<blake3::Hasher>),
+}
+impl ChunkHasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => ChunkHasher::Md5(md5::Context::new()),
+            ChecksumAlgorithm::Blake3 => ChunkHasher::Blake3(Box::new(blake3::Hasher::new())),
         }
     }
 
-    fn finish(mut self) -> io::Result<Digest> {
-        self.flush().unwrap();
-        Ok(self.hasher.compute())
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            ChunkHasher::Md5(context) => context.consume(buf),
+            ChunkHasher::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+    }
+
+    // Hex, to match the manifest
+    pub fn finish(self) -> String {
+        match self {
+            ChunkHasher::Md5(context) => hex::encode(context.compute().0),
+            ChunkHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
     }
 }
-// Write automatically pushes to file and hasher
-impl Write for DropWriter<File> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        /*
-        self.hasher.write_all(buf).map_err(|e| {
-            io::Error::new(
-                ErrorKind::Other,
-                format!("Unable to write to hasher: {}", e),
-            )
-        })?;
-         */
-        self.destination.write(buf)
+/// How many buffers can be waiting on each hashing thread before writes wait for it
+const HASH_QUEUE_DEPTH: usize = 32;
+
+enum HashCommand {
+    Update(Arc<Mutex<ChunkHasher>>, Bytes),
+    // Answered once everything sent before it has been hashed
+    Sync(oneshot::Sender<()>),
+}
+
+/// Hashing happens on these threads, so that it overlaps with writing to
+/// disk rather than tying up one of the runtime's workers. Each chunk sticks
+/// to one of them, which keeps its buffers in order.
+static HASHERS: LazyLock<Vec<Sender<HashCommand>>> = LazyLock::new(|| {
+    let count = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    (0..count)
+        .map(|index| {
+            let (sender, receiver) = channel(HASH_QUEUE_DEPTH);
+            std::thread::Builder::new()
+                .name(format!("drop-hash-{}", index))
+                .spawn(move || run_hasher(receiver))
+                .unwrap();
+            sender
+        })
+        .collect()
+});
+static NEXT_HASHER: AtomicUsize = AtomicUsize::new(0);
+
+fn run_hasher(mut commands: Receiver<HashCommand>) {
+    while let Some(command) = commands.blocking_recv() {
+        match command {
+            HashCommand::Update(hasher, buf) => hasher.lock().unwrap().update(&buf),
+            HashCommand::Sync(reply) => {
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+/// How many buffers can be waiting to go to disk before the
+/// network side waits for them. Covers slow disks briefly
+/// stalling without holding a whole chunk in memory.
+const WRITE_QUEUE_DEPTH: usize = 8;
+
+/// Data from an earlier attempt is read back in pieces of this size
+const EXISTING_READ_SIZE: usize = 64 * 1024;
+
+enum WriteCommand {
+    Write(Bytes),
+    Flush(oneshot::Sender<io::Result<()>>),
+    ReadExisting(usize, oneshot::Sender<io::Result<Vec<u8>>>),
+}
+
+// Owns the chunk's file handle, so the disk is only ever touched here. Stops
+// at the first failed write, which the writer picks up from the task's result.
+async fn run_writer(
+    mut destination: WriteBackend,
+    mut commands: Receiver<WriteCommand>,
+) -> io::Result<()> {
+    while let Some(command) = commands.recv().await {
+        match command {
+            WriteCommand::Write(buf) => destination.write_all(&buf).await?,
+            WriteCommand::Flush(reply) => {
+                let _ = reply.send(destination.flush().await);
+            }
+            WriteCommand::ReadExisting(length, reply) => {
+                let mut buf = vec![0; length];
+                let read = destination.read_existing(&mut buf).await.map(|read| {
+                    buf.truncate(read);
+                    buf
+                });
+                let _ = reply.send(read);
+            }
+        }
+    }
+    destination.flush().await
+}
+
+pub struct DropWriter {
+    hasher: Arc<Mutex<ChunkHasher>>,
+    hash_sender: Sender<HashCommand>,
+    write_sender: Sender<WriteCommand>,
+    write_task: Option<JoinHandle<io::Result<()>>>,
+}
+impl DropWriter {
+    async fn open(
+        path: &Path,
+        offset: u64,
+        length: usize,
+        buffer_size: usize,
+        mode: DiskWriteMode,
+        checksum_algorithm: ChecksumAlgorithm,
+    ) -> io::Result<Self> {
+        let destination = WriteBackend::open(path, offset, length, buffer_size, mode).await?;
+
+        // Writes go through their own task, so a slow disk only holds up
+        // the network once the queue is full, and the other way around
+        let (write_sender, write_receiver) = channel(WRITE_QUEUE_DEPTH);
+        let write_task = tokio::spawn(run_writer(destination, write_receiver));
+
+        let hasher_index = NEXT_HASHER.fetch_add(1, Ordering::Relaxed) % HASHERS.len();
+
+        Ok(Self {
+            hasher: Arc::new(Mutex::new(ChunkHasher::new(checksum_algorithm))),
+            hash_sender: HASHERS[hasher_index].clone(),
+            write_sender,
+            write_task: Some(write_task),
+        })
+    }
+
+    async fn hash(&self, buf: Bytes) -> io::Result<()> {
+        self.hash_sender
+            .send(HashCommand::Update(self.hasher.clone(), buf))
+            .await
+            .map_err(|e| io::Error::other(format!("Unable to write to hasher: {}", e)))
+    }
+
+    // The writer task only goes away early if a write failed, so this gets
+    // that error back
+    async fn writer_failure(&mut self) -> io::Error {
+        match self.write_task.take() {
+            Some(task) => match task.await {
+                Ok(Err(e)) => e,
+                Ok(Ok(())) => io::Error::other("Writer stopped early"),
+                Err(_) => io::Error::other("Writer task panicked"),
+            },
+            None => io::Error::other("Writer stopped early"),
+        }
+    }
+
+    async fn send(&mut self, command: WriteCommand) -> io::Result<()> {
+        if self.write_sender.send(command).await.is_err() {
+            return Err(self.writer_failure().await);
+        }
+        Ok(())
+    }
+
+    // Sends a command that expects an answer, and waits for it
+    async fn request<T>(
+        &mut self,
+        command: impl FnOnce(oneshot::Sender<io::Result<T>>) -> WriteCommand,
+    ) -> io::Result<T> {
+        let (reply_sender, reply) = oneshot::channel();
+        self.send(command(reply_sender)).await?;
+        match reply.await {
+            Ok(result) => result,
+            Err(_) => Err(self.writer_failure().await),
+        }
+    }
+
+    /// Hashes the `length` bytes after the current position, left there by
+    /// an earlier attempt at this chunk, and moves past them
+    async fn skip_existing(&mut self, length: usize) -> io::Result<()> {
+        let mut read = 0;
+        while read < length {
+            let piece = EXISTING_READ_SIZE.min(length - read);
+            let buf = self
+                .request(|reply| WriteCommand::ReadExisting(piece, reply))
+                .await?;
+            if buf.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("expected {} bytes already on disk, found {}", length, read),
+                ));
+            }
+            read += buf.len();
+            self.hash(Bytes::from(buf)).await?;
+        }
+        Ok(())
+    }
+
+    // The hasher gets each buffer first, so it can work while it's written.
+    // Neither copies it, they share the one allocation.
+    async fn write_all(&mut self, buf: Vec<u8>) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let buf = Bytes::from(buf);
+        self.hash(buf.clone()).await?;
+        self.send(WriteCommand::Write(buf)).await
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        // self.hasher.flush()?;
-        self.destination.flush()
+    // Waits for everything queued so far to reach the file
+    async fn flush(&mut self) -> io::Result<()> {
+        self.request(WriteCommand::Flush).await
     }
+
+    async fn finish(mut self) -> io::Result<String> {
+        self.flush().await?;
+
+        let DropWriter {
+            hasher,
+            hash_sender,
+            write_sender,
+            write_task,
+        } = self;
+        // Lets the writer run out of buffers and exit
+        drop(write_sender);
+        if let Some(write_task) = write_task {
+            write_task
+                .await
+                .map_err(|_| io::Error::other("Writer task panicked"))??;
+        }
+
+        let (reply_sender, reply) = oneshot::channel();
+        let synced = hash_sender.send(HashCommand::Sync(reply_sender)).await;
+        if synced.is_err() || reply.await.is_err() {
+            return Err(io::Error::other("Hashing thread stopped"));
+        }
+        // The hashing thread has let go of it by now
+        let hasher =
+            Arc::try_unwrap(hasher).map_err(|_| io::Error::other("Hasher is still in use"))?;
+        Ok(hasher.into_inner().unwrap().finish())
+    }
+}
+
+/// How often a chunk checks whether the watchdog has given up on it
+const STALL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shared between a chunk and its agent's watchdog, which
+/// flags the chunk if it stops receiving anything
+#[derive(Default)]
+pub struct ChunkActivity {
+    received: AtomicUsize,
+    stalled: AtomicBool,
+}
+impl ChunkActivity {
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn flag_stalled(&self) {
+        self.stalled.store(true, Ordering::Relaxed);
+    }
+
+    // Resolves once the watchdog has flagged the chunk
+    async fn stalled(&self) {
+        while !self.stalled.load(Ordering::Relaxed) {
+            tokio::time::sleep(STALL_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Hands out a response's body in pieces no longer than asked for, so a
+/// response carrying several chunks can be split at chunk boundaries
+pub struct ChunkSource {
+    response: Response,
+    leftover: Option<Bytes>,
 }
-// Seek moves around destination output
-impl Seek for DropWriter<File> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.destination.seek(pos)
+impl ChunkSource {
+    pub fn new(response: Response) -> Self {
+        Self {
+            response,
+            leftover: None,
+        }
+    }
+
+    async fn next(&mut self, max: usize) -> reqwest::Result<Option<Bytes>> {
+        let mut bytes = match self.leftover.take() {
+            Some(bytes) => bytes,
+            None => match self.response.chunk().await? {
+                Some(bytes) => bytes,
+                None => return Ok(None),
+            },
+        };
+        if bytes.len() > max {
+            self.leftover = Some(bytes.split_off(max));
+        }
+        Ok(Some(bytes))
+    }
+
+    fn has_leftover(&self) -> bool {
+        self.leftover
+            .as_ref()
+            .is_some_and(|bytes| !bytes.is_empty())
     }
 }
 
-pub struct DropDownloadPipeline<R: Read, W: Write> {
-    pub source: R,
-    pub destination: DropWriter<W>,
+pub struct DropDownloadPipeline<'a> {
+    pub source: &'a mut ChunkSource,
+    pub destination: DropWriter,
     pub control_flag: DownloadThreadControl,
     pub progress: ProgressHandle,
+    pub rate_limiter: Arc<RateLimiter>,
+    // Shared by every download, normally DOWNLOAD_RATE_LIMITER
+    pub global_rate_limiter: &'a RateLimiter,
     pub size: usize,
+    pub read_buffer_size: usize,
+    pub read_timeout: Duration,
+    pub activity: Arc<ChunkActivity>,
 }
-impl DropDownloadPipeline<Response, File> {
-    fn new(
-        source: Response,
-        destination: DropWriter<File>,
-        control_flag: DownloadThreadControl,
-        progress: ProgressHandle,
-        size: usize,
-    ) -> Self {
-        Self {
-            source,
-            destination,
-            control_flag,
-            progress,
-            size,
-        }
+impl DropDownloadPipeline<'_> {
+    // The next piece of the response (no more than `max` bytes),
+    // once the rate limits let it through
+    async fn receive(&mut self, max: usize) -> Result<Option<Bytes>, GameDownloadError> {
+        let bytes = match tokio::time::timeout(self.read_timeout, self.source.next(max))
+            .await
+            .map_err(|_| timed_out("stopped receiving chunk", self.read_timeout))?
+            .map_err(|e| GameDownloadError::Communication(e.into()))?
+        {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        self.activity
+            .received
+            .fetch_add(bytes.len(), Ordering::Relaxed);
+
+        // Per-download limit first, so a throttled download
+        // doesn't hold up the global bucket while it waits
+        self.rate_limiter.acquire(bytes.len()).await;
+        self.global_rate_limiter.acquire(bytes.len()).await;
+
+        Ok(Some(bytes))
+    }
+
+    // Queues everything in `read_buf` to be written out and counts it as
+    // downloaded. Anything that resumes from it flushes first.
+    async fn write_buffered(&mut self, read_buf: &mut Vec<u8>) -> Result<(), GameDownloadError> {
+        let buf = std::mem::replace(read_buf, Vec::with_capacity(self.read_buffer_size));
+        let length = buf.len();
+        self.destination
+            .write_all(buf)
+            .await
+            .map_err(GameDownloadError::IoError)?;
+        self.progress.add(length);
+        Ok(())
+    }
+
+    // Gets whatever we've already received out of the buffers and onto disk,
+    // so that the next attempt at this chunk can pick up where this one stopped
+    async fn save_received(&mut self, read_buf: &mut Vec<u8>) -> Result<(), GameDownloadError> {
+        self.write_buffered(read_buf).await?;
+        self.destination
+            .flush()
+            .await
+            .map_err(GameDownloadError::IoError)
     }
 
-    fn copy(&mut self) -> Result<bool, io::Error> {
-        let copy_buf_size = 512;
-        let mut copy_buf = vec![0; copy_buf_size];
-        let mut buf_writer = BufWriter::with_capacity(1024 * 1024, &mut self.destination);
+    async fn copy(&mut self) -> Result<bool, GameDownloadError> {
+        let stopped = self.control_flag.token();
+        let activity = self.activity.clone();
+        let mut read_buf = Vec::with_capacity(self.read_buffer_size);
 
         let mut current_size = 0;
         loop {
-            if self.control_flag.get() == DownloadThreadControlFlag::Stop {
-                return Ok(false);
-            }
-
-            let bytes_read = self.source.read(&mut copy_buf)?;
-            current_size += bytes_read;
+            let received = tokio::select! {
+                biased;
+                _ = stopped.cancelled() => {
+                    self.save_received(&mut read_buf).await?;
+                    return Ok(false);
+                }
+                _ = activity.stalled() => {
+                    self.save_received(&mut read_buf).await?;
+                    return Err(GameDownloadError::Stalled);
+                }
+                received = self.receive(self.size - current_size) => received,
+            };
+            let bytes = match received {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    self.save_received(&mut read_buf).await?;
+                    return Err(e);
+                }
+            };
 
-            buf_writer.write_all(&copy_buf[0..bytes_read])?;
-            self.progress.add(bytes_read);
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                // The connection closed before we got the whole chunk
+                None => {
+                    return Err(GameDownloadError::Interrupted(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("chunk ended after {} of {} bytes", current_size, self.size),
+                    )))
+                }
+            };
+            current_size += bytes.len();
+            read_buf.extend_from_slice(&bytes);
 
             if current_size == self.size {
+                self.write_buffered(&mut read_buf).await?;
                 break;
             }
+            if read_buf.len() >= self.read_buffer_size {
+                self.write_buffered(&mut read_buf).await?;
+            }
         }
+        self.destination
+            .flush()
+            .await
+            .map_err(GameDownloadError::IoError)?;
 
         Ok(true)
     }
 
-    fn finish(self) -> Result<Digest, io::Error> {
-        let checksum = self.destination.finish()?;
-        Ok(checksum)
+    // Checks what was written against the manifest
+    async fn finish(self, ctx: &DropDownloadContext) -> Result<(), GameDownloadError> {
+        let checksum = self
+            .destination
+            .finish()
+            .await
+            .map_err(GameDownloadError::IoError)?;
+
+        if checksum != ctx.checksum {
+            warn!(
+                "{:?} checksum mismatch for chunk {} of {}: expected {}, got {}",
+                ctx.checksum_algorithm, ctx.index, ctx.file_name, ctx.checksum, checksum
+            );
+            return Err(GameDownloadError::Checksum);
+        }
+
+        // Permissions and attributes wait until the whole file is in place
+        Ok(())
     }
 }
 
-pub fn download_game_chunk(
+// Used in place of an overall timeout, which large chunks
+// on slow connections would run into
+fn timed_out(what: &str, after: Duration) -> GameDownloadError {
+    GameDownloadError::Interrupted(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} after {}s", what, after.as_secs()),
+    ))
+}
+
+/// Exponential backoff, with up to 50% jitter so that
+/// chunks which failed together don't retry together
+fn retry_delay(attempt: u32) -> Duration {
+    const BASE_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_DELAY);
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..0.5))
+}
+
+/// Holds the chunk back while the server's rate limiting chunk requests.
+/// Returns false if the download was paused in the meantime.
+async fn wait_out_rate_limit(control_flag: &DownloadThreadControl) -> bool {
+    match remaining(RequestClass::Chunks) {
+        Some(wait) => sleep_unless_stopped(wait, control_flag).await,
+        None => true,
+    }
+}
+
+/// Sleeps for `duration`, unless the download is paused in the meantime.
+/// Returns false if it was paused.
+async fn sleep_unless_stopped(duration: Duration, control_flag: &DownloadThreadControl) -> bool {
+    let stopped = control_flag.token();
+    tokio::select! {
+        _ = stopped.cancelled() => false,
+        _ = tokio::time::sleep(duration) => true,
+    }
+}
+
+pub async fn download_game_chunk(
     ctx: DropDownloadContext,
+    mirrors: Arc<Vec<Url>>,
+    client: Client,
     control_flag: DownloadThreadControl,
     progress: ProgressHandle,
+    rate_limiter: Arc<RateLimiter>,
+    activity: Arc<ChunkActivity>,
 ) -> Result<bool, GameDownloadError> {
-    // If we're paused
-    if control_flag.get() == DownloadThreadControlFlag::Stop {
-        progress.set(0);
-        return Ok(false);
+    let max_attempts = DB
+        .borrow_data()
+        .unwrap()
+        .settings
+        .max_download_attempts
+        .max(1);
+
+    // A mirror that keeps rate limiting past this is treated as failing
+    const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+
+    let mut attempt = 0;
+    // Mirrors that have failed this chunk since we last went through all of them
+    let mut tried = HashSet::new();
+    let mut rate_limited = 0;
+    loop {
+        attempt += 1;
+        let mirror = pick_mirror(&mirrors, &tried);
+        let started = Instant::now();
+        let result = download_game_chunk_attempt(
+            ctx.clone(),
+            &mirror,
+            &client,
+            control_flag.clone(),
+            progress.clone(),
+            rate_limiter.clone(),
+            activity.clone(),
+        )
+        .await;
+
+        // The server's only asked us to slow down, which the next attempt
+        // waits out, so neither the mirror nor the chunk is to blame
+        if matches!(&result, Err(error) if error.status_code() == Some(429)) {
+            rate_limited += 1;
+            if rate_limited <= MAX_RATE_LIMITED_RETRIES {
+                attempt -= 1;
+                continue;
+            }
+        }
+        rate_limited = 0;
+        match &result {
+            Ok(true) => record_success(&mirror, ctx.length, started.elapsed()),
+            Ok(false) => {}
+            Err(_) => record_failure(&mirror),
+        }
+        // Whatever we have of this chunk is wrong, so it can't be resumed
+        if let Err(GameDownloadError::Checksum) = result {
+            progress.set(0);
+        }
+
+        tried.insert(mirror.clone());
+        let other_mirror_left = tried.len() < mirrors.len();
+
+        // A mirror might be missing a file the others have, so
+        // request errors are worth taking to the next mirror
+        let error = match result {
+            Err(error)
+                if attempt < max_attempts
+                    && (error.is_retryable()
+                        || (other_mirror_left
+                            && matches!(error, GameDownloadError::Communication(_)))) =>
+            {
+                error
+            }
+            result => return result,
+        };
+
+        if other_mirror_left {
+            warn!(
+                "chunk {} of {} failed on {} (attempt {}/{}), trying another mirror: {:?} {}",
+                ctx.index,
+                ctx.file_name,
+                mirror,
+                attempt,
+                max_attempts,
+                error.class(),
+                error
+            );
+            continue;
+        }
+        tried.clear();
+
+        let delay = retry_delay(attempt);
+        warn!(
+            "chunk {} of {} failed (attempt {}/{}), retrying in {}ms: {:?} {}",
+            ctx.index,
+            ctx.file_name,
+            attempt,
+            max_attempts,
+            delay.as_millis(),
+            error.class(),
+            error
+        );
+
+        if !sleep_unless_stopped(delay, &control_flag).await {
+            return Ok(false);
+        }
     }
+}
 
-    let base_url = DB.fetch_base_url();
+// `count` consecutive chunks starting at `ctx`, for servers that can send more than one
+fn chunk_query(ctx: &DropDownloadContext, count: usize) -> String {
+    let mut chunk_query = format!(
+        "id={}&version={}&name={}&chunk={}",
+        // Encode the parts we don't trust
+        ctx.game_id,
+        encode(&ctx.version),
+        encode(&ctx.file_name),
+        ctx.index
+    );
+    if count > 1 {
+        chunk_query.push_str(&format!("&count={}", count));
+    }
+    chunk_query
+}
 
-    let client = reqwest::blocking::Client::new();
-    let chunk_url = base_url
-        .join(&format!(
-            "/api/v1/client/chunk?id={}&version={}&name={}&chunk={}",
-            // Encode the parts we don't trust
-            ctx.game_id,
-            encode(&ctx.version),
-            encode(&ctx.file_name),
-            ctx.index
-        ))
-        .unwrap();
+// The content endpoint never sees the client's credentials, only
+// a signature from the API if it asks for one. `client` is the remote's,
+// which the chunk request itself only goes through if it's to the remote.
+async fn chunk_request(
+    client: &Client,
+    base_url: &Url,
+    ctx: &DropDownloadContext,
+    count: usize,
+) -> Result<RequestBuilder, GameDownloadError> {
+    let query = chunk_query(ctx, count);
+    let signed = match content_endpoint(base_url) {
+        Some(signed) => signed,
+        None => {
+            let url = base_url
+                .join(&format!("/api/v1/client/chunk?{}", query))
+                .unwrap();
+            return Ok(http_client_for(&url)
+                .get(url)
+                .header("Authorization", generate_authorization_header()));
+        }
+    };
 
-    let header = generate_authorization_header();
+    let mut url = base_url.join(&format!("chunk?{}", query)).unwrap();
+    if signed {
+        let signature = signed_query(client, &ctx.game_id, &ctx.version)
+            .await
+            .map_err(GameDownloadError::Communication)?;
+        url.set_query(Some(&format!("{}&{}", query, signature)));
+    }
+    Ok(http_client_for(&url).get(url))
+}
 
-    let response = client
-        .get(chunk_url)
-        .header("Authorization", header)
-        .send()
-        .map_err(|e| GameDownloadError::Communication(e.into()))?;
+/// How chunks are read and written, as the settings had it when their request was sent
+#[derive(Clone, Copy)]
+pub struct WriterSettings {
+    pub read_buffer_size: usize,
+    pub write_buffer_size: usize,
+    pub write_mode: DiskWriteMode,
+}
+impl WriterSettings {
+    pub fn current() -> Self {
+        let db_handle = DB.borrow_data().unwrap();
+        Self {
+            read_buffer_size: db_handle.settings.read_buffer_size,
+            write_buffer_size: db_handle.settings.write_buffer_size,
+            write_mode: db_handle.settings.disk_write_mode,
+        }
+    }
+}
 
-    if response.status() != 200 {
-        warn!("{}", response.text().unwrap());
-        return Err(GameDownloadError::Communication(
-            RemoteAccessError::InvalidCodeError(400),
-        ));
+// Returns the writer along with the read buffer size to go with it
+async fn open_chunk_writer(
+    ctx: &DropDownloadContext,
+    settings: WriterSettings,
+) -> io::Result<(DropWriter, usize)> {
+    let destination = DropWriter::open(
+        &ctx.path,
+        ctx.offset,
+        ctx.length,
+        buffer_size(settings.write_buffer_size, ctx.length),
+        settings.write_mode,
+        ctx.checksum_algorithm,
+    )
+    .await?;
+    Ok((
+        destination,
+        buffer_size(settings.read_buffer_size, ctx.length),
+    ))
+}
+
+async fn send_chunk_request(
+    request: reqwest::RequestBuilder,
+    read_timeout: Duration,
+) -> Result<Response, GameDownloadError> {
+    tokio::time::timeout(read_timeout, request.send())
+        .await
+        .map_err(|_| timed_out("no response to chunk request", read_timeout))?
+        .map_err(|e| GameDownloadError::Communication(e.into()))
+}
+
+async fn unexpected_status(response: Response, what: &str) -> GameDownloadError {
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => {
+            note_rate_limited(RequestClass::Chunks, response.headers())
+        }
+        // Most likely a signature that expired early
+        StatusCode::FORBIDDEN if is_content_url(response.url()) => forget_signatures(),
+        _ => {}
     }
+    let error = RemoteAccessError::from_async_response(response).await;
+    warn!("{} failed: {}", what, error);
+    GameDownloadError::Communication(error)
+}
 
-    let mut destination = DropWriter::new(ctx.path.clone());
+async fn download_game_chunk_attempt(
+    ctx: DropDownloadContext,
+    base_url: &Url,
+    client: &Client,
+    control_flag: DownloadThreadControl,
+    progress: ProgressHandle,
+    rate_limiter: Arc<RateLimiter>,
+    activity: Arc<ChunkActivity>,
+) -> Result<bool, GameDownloadError> {
+    // If we're paused
+    if control_flag.get() == DownloadThreadControlFlag::Stop {
+        return Ok(false);
+    }
+    if !wait_out_rate_limit(&control_flag).await {
+        return Ok(false);
+    }
+    // A fresh attempt, so any earlier stall no longer applies
+    activity.stalled.store(false, Ordering::Relaxed);
+
+    // Anything from an earlier attempt (or before a pause) is already on
+    // disk, so only ask for the rest of the chunk
+    let resume_from = progress.get().min(ctx.length);
 
-    if ctx.offset != 0 {
-        destination
-            .seek(SeekFrom::Start(ctx.offset))
-            .expect("Failed to seek to file offset");
+    let mut request = chunk_request(client, base_url, &ctx, 1).await?;
+    if resume_from != 0 {
+        // Ranges of a compressed response are ranges of the compressed bytes,
+        // which don't line up with what's on disk, so only ask for it as it is
+        request = request
+            .header(RANGE, format!("bytes={}-", resume_from))
+            .header(ACCEPT_ENCODING, "identity");
     }
+    let read_timeout = NetworkTimeouts::current().chunk_read;
+    let response = send_chunk_request(request, read_timeout).await?;
 
-    let content_length = response.content_length();
-    if content_length.is_none() {
+    let resume_from = match response.status() {
+        StatusCode::PARTIAL_CONTENT if resume_from != 0 => {
+            let expected_range = format!("bytes {}-", resume_from);
+            let content_range = response
+                .headers()
+                .get("Content-Range")
+                .and_then(|range| range.to_str().ok());
+            if !content_range.is_some_and(|range| range.starts_with(&expected_range)) {
+                warn!(
+                    "server sent range {:?} for chunk {} of {}, expected {}",
+                    content_range, ctx.index, ctx.file_name, expected_range
+                );
+                progress.set(0);
+                return Err(GameDownloadError::Communication(
+                    RemoteAccessError::InvalidResponse,
+                ));
+            }
+            resume_from
+        }
+        // The server doesn't do ranges, so we're getting the whole chunk again
+        StatusCode::OK => 0,
+        _ => {
+            let what = format!("chunk {} of {}", ctx.index, ctx.file_name);
+            return Err(unexpected_status(response, &what).await);
+        }
+    };
+    progress.set(resume_from);
+
+    let (mut destination, read_buffer_size) = open_chunk_writer(&ctx, WriterSettings::current())
+        .await
+        .map_err(GameDownloadError::IoError)?;
+    if resume_from != 0 {
+        destination.skip_existing(resume_from).await.map_err(|e| {
+            // Start over next time rather than trusting the file again
+            progress.set(0);
+            GameDownloadError::IoError(e)
+        })?;
+    }
+
+    // Compressed responses (reqwest asks for zstd or gzip, and decompresses
+    // them before we see them) don't say how big the chunk really is, so go by
+    // the manifest. The checksum is of the decompressed data either way.
+    let remaining = ctx.length - resume_from;
+    if response
+        .content_length()
+        .is_some_and(|length| length != remaining as u64)
+    {
         return Err(GameDownloadError::Communication(
             RemoteAccessError::InvalidResponse,
         ));
     }
 
-    let mut pipeline = DropDownloadPipeline::new(
-        response,
+    let mut source = ChunkSource::new(response);
+    let mut pipeline = DropDownloadPipeline {
+        source: &mut source,
         destination,
         control_flag,
         progress,
-        content_length.unwrap().try_into().unwrap(),
-    );
+        rate_limiter,
+        global_rate_limiter: &DOWNLOAD_RATE_LIMITER,
+        size: remaining,
+        read_buffer_size,
+        read_timeout,
+        activity,
+    };
 
-    let completed = pipeline.copy().map_err(GameDownloadError::IoError)?;
+    let completed = pipeline.copy().await?;
     if !completed {
         return Ok(false);
     };
+    // The server sent something other than the chunk, so none of it is
+    // trusted and the next attempt asks for all of it again
+    if pipeline.source.has_leftover() {
+        warn!(
+            "chunk {} of {} came with more data than it should have",
+            ctx.index, ctx.file_name
+        );
+        pipeline.progress.set(0);
+        return Err(GameDownloadError::Communication(
+            RemoteAccessError::InvalidResponse,
+        ));
+    }
+    pipeline.finish(&ctx).await?;
+
+    Ok(true)
+}
+
+/// How many chunks the server sent, when asked for more than one.
+/// Servers that don't know about batches leave it out and send one.
+const CHUNK_COUNT_HEADER: &str = "X-Drop-Chunk-Count";
+
+/// The feature servers list in their healthcheck when they can send
+/// several chunks in one response
+pub const CHUNK_BATCHING_FEATURE: &str = "chunk-batching";
+
+// Whether the server in use advertised batching, as of the last download to start
+static SERVER_BATCHES_CHUNKS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_server_batches_chunks(batches: bool) {
+    SERVER_BATCHES_CHUNKS.store(batches, Ordering::Relaxed);
+}
+
+/// Most chunks to ask for in one request. Servers that don't batch would
+/// send the rest one at a time from the same task, so they're asked for one.
+pub fn chunks_per_request(configured: usize) -> usize {
+    if SERVER_BATCHES_CHUNKS.load(Ordering::Relaxed) {
+        configured.max(1)
+    } else {
+        1
+    }
+}
+
+/// One chunk of a batch requested together by `download_game_chunks`
+pub struct BatchedChunk {
+    pub index: usize,
+    pub context: DropDownloadContext,
+    pub progress: ProgressHandle,
+}
+
+/// Asks for a run of consecutive chunks from the same file in one request,
+/// then falls back to `download_game_chunk` (and its retries) for any the
+/// server didn't send or that failed part way. Results are keyed by the
+/// `index` of each chunk, in batch order.
+pub async fn download_game_chunks(
+    batch: Vec<BatchedChunk>,
+    mirrors: Arc<Vec<Url>>,
+    client: Client,
+    control_flag: DownloadThreadControl,
+    rate_limiter: Arc<RateLimiter>,
+    activity: Arc<ChunkActivity>,
+) -> Vec<(usize, Result<bool, GameDownloadError>)> {
+    let mirror = pick_mirror(&mirrors, &HashSet::new());
+    let started = Instant::now();
+    let mut completed = 0;
+    let result = download_game_chunks_attempt(
+        &batch,
+        &mirror,
+        &client,
+        &control_flag,
+        &rate_limiter,
+        &activity,
+        &mut completed,
+    )
+    .await;
+
+    if completed != 0 {
+        let bytes = batch[..completed]
+            .iter()
+            .map(|chunk| chunk.context.length)
+            .sum();
+        record_success(&mirror, bytes, started.elapsed());
+    }
+    if let Err(e) = result {
+        record_failure(&mirror);
+        if let (GameDownloadError::Checksum, Some(failed)) = (&e, batch.get(completed)) {
+            failed.progress.set(0);
+        }
+        warn!(
+            "batch of {} chunks from chunk {} of {} failed after {}, finishing them one by one: {:?} {}",
+            batch.len(),
+            batch[0].context.index,
+            batch[0].context.file_name,
+            completed,
+            e.class(),
+            e
+        );
+    }
+
+    let mut results = Vec::with_capacity(batch.len());
+    for (position, chunk) in batch.into_iter().enumerate() {
+        if position < completed {
+            results.push((chunk.index, Ok(true)));
+            continue;
+        }
+        let result = download_game_chunk(
+            chunk.context,
+            mirrors.clone(),
+            client.clone(),
+            control_flag.clone(),
+            chunk.progress,
+            rate_limiter.clone(),
+            activity.clone(),
+        )
+        .await;
+        results.push((chunk.index, result));
+    }
+    results
+}
+
+// Counts finished chunks in `completed` as it goes, so that
+// the caller knows where to pick up if this fails part way
+async fn download_game_chunks_attempt(
+    batch: &[BatchedChunk],
+    base_url: &Url,
+    client: &Client,
+    control_flag: &DownloadThreadControl,
+    rate_limiter: &Arc<RateLimiter>,
+    activity: &Arc<ChunkActivity>,
+    completed: &mut usize,
+) -> Result<(), GameDownloadError> {
+    if control_flag.get() == DownloadThreadControlFlag::Stop {
+        return Ok(());
+    }
+    if !wait_out_rate_limit(control_flag).await {
+        return Ok(());
+    }
+    activity.stalled.store(false, Ordering::Relaxed);
 
-    // If we complete the file, set the permissions (if on Linux)
-    #[cfg(unix)]
+    let first = &batch[0].context;
+    let request = chunk_request(client, base_url, first, batch.len()).await?;
+    let read_timeout = NetworkTimeouts::current().chunk_read;
+    let response = send_chunk_request(request, read_timeout).await?;
+
+    if response.status() != StatusCode::OK {
+        let what = format!(
+            "{} chunks from chunk {} of {}",
+            batch.len(),
+            first.index,
+            first.file_name
+        );
+        return Err(unexpected_status(response, &what).await);
+    }
+
+    let count = response
+        .headers()
+        .get(CHUNK_COUNT_HEADER)
+        .and_then(|count| count.to_str().ok()?.parse::<usize>().ok())
+        .unwrap_or(1)
+        .clamp(1, batch.len());
+    let batch = &batch[..count];
+
+    let expected_length: usize = batch.iter().map(|chunk| chunk.context.length).sum();
+    if response
+        .content_length()
+        .is_some_and(|length| length != expected_length as u64)
     {
-        let permissions = Permissions::from_mode(ctx.permissions);
-        set_permissions(ctx.path, permissions).unwrap();
+        return Err(GameDownloadError::Communication(
+            RemoteAccessError::InvalidResponse,
+        ));
     }
 
-    /*
-    let checksum = pipeline
-        .finish()
-        .map_err(|e| GameDownloadError::IoError(e))?;
+    let receive = BatchReceiver {
+        control_flag: control_flag.clone(),
+        rate_limiter: rate_limiter.clone(),
+        global_rate_limiter: &DOWNLOAD_RATE_LIMITER,
+        activity: activity.clone(),
+        writer_settings: WriterSettings::current(),
+        read_timeout,
+    };
+    receive_batch(&mut ChunkSource::new(response), batch, &receive, completed).await
+}
+
+/// What every chunk in a batched response is received with
+pub struct BatchReceiver<'a> {
+    pub control_flag: DownloadThreadControl,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub global_rate_limiter: &'a RateLimiter,
+    pub activity: Arc<ChunkActivity>,
+    pub writer_settings: WriterSettings,
+    pub read_timeout: Duration,
+}
 
-    let res = hex::encode(checksum.0);
-    if res != ctx.checksum {
-        return Err(GameDownloadError::Checksum);
+/// Splits a response carrying every chunk in `batch` into their files,
+/// counting finished chunks in `completed`. A response with anything after
+/// the last chunk isn't what was asked for, so none of it counts.
+pub async fn receive_batch(
+    source: &mut ChunkSource,
+    batch: &[BatchedChunk],
+    receive: &BatchReceiver<'_>,
+    completed: &mut usize,
+) -> Result<(), GameDownloadError> {
+    for (position, chunk) in batch.iter().enumerate() {
+        // These only get batched before anything of them is written
+        chunk.progress.set(0);
+        let (destination, read_buffer_size) =
+            open_chunk_writer(&chunk.context, receive.writer_settings)
+                .await
+                .map_err(GameDownloadError::IoError)?;
+        let mut pipeline = DropDownloadPipeline {
+            source: &mut *source,
+            destination,
+            control_flag: receive.control_flag.clone(),
+            progress: chunk.progress.clone(),
+            rate_limiter: receive.rate_limiter.clone(),
+            global_rate_limiter: receive.global_rate_limiter,
+            size: chunk.context.length,
+            read_buffer_size,
+            read_timeout: receive.read_timeout,
+            activity: receive.activity.clone(),
+        };
+
+        if !pipeline.copy().await? {
+            return Ok(());
+        }
+        // Checked before the last chunk is accepted, like a single chunk is
+        if position == batch.len() - 1 && pipeline.source.has_leftover() {
+            warn!(
+                "{} chunks from chunk {} of {} came with more data than they should have",
+                batch.len(),
+                batch[0].context.index,
+                batch[0].context.file_name
+            );
+            // The next attempts ask for every one of them again
+            for chunk in batch {
+                chunk.progress.set(0);
+            }
+            *completed = 0;
+            return Err(GameDownloadError::Communication(
+                RemoteAccessError::InvalidResponse,
+            ));
+        }
+        pipeline.finish(&chunk.context).await?;
+        *completed += 1;
     }
-     */
 
-    Ok(true)
+    Ok(())
 }
@@ -10,13 +10,15 @@ use std::{
 };
 
 use log::info;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::{
     download_agent::{GameDownloadAgent, GameDownloadError},
-    download_manager_builder::CurrentProgressObject,
+    download_manager_builder::ActiveProgressObjects,
     progress_object::ProgressObject,
     queue::Queue,
+    rate_limiter::RateLimiter,
+    speed_history::{SpeedHistory, SpeedSample},
 };
 
 pub enum DownloadManagerSignal {
@@ -33,14 +35,24 @@ pub enum DownloadManagerSignal {
     /// download, sync everything to disk, and
     /// then exit
     Finish,
-    /// Stops (but doesn't remove) current download
-    Cancel,
     /// Removes a given game
     Remove(String),
     /// Any error which occurs in the agent
-    Error(GameDownloadError),
+    Error(String, GameDownloadError),
     /// Pushes UI update
     Update,
+    /// Moves a given game to a new position in the queue
+    Move(String, usize),
+    /// Sets the bandwidth limit of a given game's download
+    SetBandwidthLimit(String, Option<usize>),
+    /// Starts or stops agents as download windows open and close
+    CheckSchedule,
+    /// Changes how many agents may download at once
+    SetConcurrency(usize),
+    /// Changes the priority of a given game's download
+    SetPriority(String, DownloadPriority),
+    /// A chunk of the given file stopped receiving data and is being retried
+    Stalled(String, String),
 }
 
 pub enum DownloadManagerStatus {
@@ -51,6 +63,16 @@ pub enum DownloadManagerStatus {
     Finished,
 }
 
+/// Queued downloads are ordered by priority first, then by
+/// when they were queued. Variants are declared highest first.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DownloadPriority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
 #[derive(Serialize, Clone)]
 pub enum GameDownloadStatus {
     Queued,
@@ -69,22 +91,31 @@ pub enum GameDownloadStatus {
 /// which provides raw access to the underlying queue.
 /// THIS EDITING IS BLOCKING!!!
 pub struct DownloadManager {
-    terminator: JoinHandle<Result<(), ()>>,
+    terminator: Mutex<Option<JoinHandle<Result<(), ()>>>>,
     download_queue: Queue,
-    progress: CurrentProgressObject,
+    progress: ActiveProgressObjects,
+    speed_history: Arc<SpeedHistory>,
     command_sender: Sender<DownloadManagerSignal>,
 }
 pub struct GameDownloadAgentQueueStandin {
     pub id: String,
+    pub version: String,
+    pub target_download_dir: usize,
     pub status: Mutex<GameDownloadStatus>,
+    pub priority: Mutex<DownloadPriority>,
     pub progress: Arc<ProgressObject>,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 impl From<Arc<GameDownloadAgent>> for GameDownloadAgentQueueStandin {
     fn from(value: Arc<GameDownloadAgent>) -> Self {
         Self {
             id: value.id.clone(),
+            version: value.version.clone(),
+            target_download_dir: value.target_download_dir,
             status: Mutex::from(GameDownloadStatus::Queued),
+            priority: Mutex::from(DownloadPriority::default()),
             progress: value.progress.clone(),
+            rate_limiter: value.rate_limiter.clone(),
         }
     }
 }
@@ -101,13 +132,15 @@ impl DownloadManager {
     pub fn new(
         terminator: JoinHandle<Result<(), ()>>,
         download_queue: Queue,
-        progress: CurrentProgressObject,
+        progress: ActiveProgressObjects,
+        speed_history: Arc<SpeedHistory>,
         command_sender: Sender<DownloadManagerSignal>,
     ) -> Self {
         Self {
-            terminator,
+            terminator: Mutex::new(Some(terminator)),
             download_queue,
             progress,
+            speed_history,
             command_sender,
         }
     }
@@ -119,12 +152,12 @@ impl DownloadManager {
         target_download_dir: usize,
     ) -> Result<(), SendError<DownloadManagerSignal>> {
         info!("Adding game id {}", id);
+        // Whether this also starts the queue is up to the manager
         self.command_sender.send(DownloadManagerSignal::Queue(
             id,
             version,
             target_download_dir,
-        ))?;
-        self.command_sender.send(DownloadManagerSignal::Go)
+        ))
     }
     pub fn edit(&self) -> MutexGuard<'_, VecDeque<Arc<GameDownloadAgentQueueStandin>>> {
         self.download_queue.edit()
@@ -132,17 +165,29 @@ impl DownloadManager {
     pub fn read_queue(&self) -> VecDeque<Arc<GameDownloadAgentQueueStandin>> {
         self.download_queue.read()
     }
-    pub fn get_current_game_download_progress(&self) -> Option<f64> {
-        let progress_object = (*self.progress.lock().unwrap()).clone()?;
+    pub fn get_speed_history(&self) -> Vec<SpeedSample> {
+        self.speed_history.get()
+    }
+    pub fn get_game_download_progress(&self, game_id: &String) -> Option<f64> {
+        let progress_object = self.progress.lock().unwrap().get(game_id)?.clone();
         Some(progress_object.get_progress())
     }
     pub fn rearrange_string(&self, id: String, new_index: usize) {
-        let mut queue = self.edit();
-        let current_index = get_index_from_id(&mut queue, id).unwrap();
-        let to_move = queue.remove(current_index).unwrap();
-        queue.insert(new_index, to_move);
+        self.move_game(id, new_index);
+    }
+    pub fn move_game(&self, game_id: String, new_index: usize) {
         self.command_sender
-            .send(DownloadManagerSignal::Update)
+            .send(DownloadManagerSignal::Move(game_id, new_index))
+            .unwrap();
+    }
+    pub fn set_game_bandwidth_limit(&self, game_id: String, limit: Option<usize>) {
+        self.command_sender
+            .send(DownloadManagerSignal::SetBandwidthLimit(game_id, limit))
+            .unwrap();
+    }
+    pub fn check_schedule(&self) {
+        self.command_sender
+            .send(DownloadManagerSignal::CheckSchedule)
             .unwrap();
     }
     pub fn cancel(&self, game_id: String) {
@@ -155,27 +200,11 @@ impl DownloadManager {
             return;
         };
 
-        let needs_pause = current_index == 0 || new_index == 0;
-        if needs_pause {
-            self.command_sender
-                .send(DownloadManagerSignal::Cancel)
-                .unwrap();
-        }
-
-        info!("moving {} to {}", current_index, new_index);
-
-        let mut queue = self.edit();
-        let to_move = queue.remove(current_index).unwrap();
-        queue.insert(new_index, to_move);
-        info!("new queue: {:?}", queue);
-        drop(queue);
-
-        if needs_pause {
-            self.command_sender.send(DownloadManagerSignal::Go).unwrap();
-        }
-        self.command_sender
-            .send(DownloadManagerSignal::Update)
-            .unwrap();
+        let game_id = match self.download_queue.read().get(current_index) {
+            Some(interface) => interface.id.clone(),
+            None => return,
+        };
+        self.move_game(game_id, new_index);
     }
     pub fn pause_downloads(&self) {
         self.command_sender
@@ -185,21 +214,27 @@ impl DownloadManager {
     pub fn resume_downloads(&self) {
         self.command_sender.send(DownloadManagerSignal::Go).unwrap();
     }
-    pub fn ensure_terminated(self) -> Result<Result<(), ()>, Box<dyn Any + Send>> {
+    pub fn set_priority(&self, game_id: String, priority: DownloadPriority) {
         self.command_sender
-            .send(DownloadManagerSignal::Finish)
+            .send(DownloadManagerSignal::SetPriority(game_id, priority))
             .unwrap();
-        self.terminator.join()
     }
-}
-
-/// Takes in the locked value from .edit() and attempts to
-/// get the index of whatever game_id is passed in
-fn get_index_from_id(
-    queue: &mut MutexGuard<'_, VecDeque<Arc<GameDownloadAgentQueueStandin>>>,
-    id: String,
-) -> Option<usize> {
-    queue
-        .iter()
-        .position(|download_agent| download_agent.id == id)
+    pub fn set_max_concurrent_downloads(&self, limit: usize) {
+        self.command_sender
+            .send(DownloadManagerSignal::SetConcurrency(limit))
+            .unwrap();
+    }
+    /// Stops all running downloads, waits for them to write out their
+    /// resume state and then shuts down the manager thread.
+    ///
+    /// Safe to call more than once; later calls return immediately.
+    pub fn ensure_terminated(&self) -> Result<Result<(), ()>, Box<dyn Any + Send>> {
+        let terminator = match self.terminator.lock().unwrap().take() {
+            Some(terminator) => terminator,
+            None => return Ok(Ok(())),
+        };
+        // If the manager thread is already gone there's nothing to tell it
+        let _ = self.command_sender.send(DownloadManagerSignal::Finish);
+        terminator.join()
+    }
 }
@@ -1,31 +1,48 @@
 use std::{
     collections::HashMap,
+    fs::remove_dir_all,
     sync::{
         mpsc::{channel, Receiver, Sender},
         Arc, Mutex, RwLockWriteGuard,
     },
-    thread::{spawn, JoinHandle},
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use log::{error, info};
+use log::{error, info, warn};
 use tauri::{AppHandle, Emitter};
 
 use crate::{
-    db::{Database, GameStatus, GameTransientStatus},
-    library::{on_game_complete, GameUpdateEvent, QueueUpdateEvent, QueueUpdateEventQueueData},
+    cache::remove_manifest,
+    db::{
+        Database, DatabaseImpls, DatabaseQueuedDownload, GameStatus, GameTransientStatus,
+        InstallManifest,
+    },
+    library::{
+        on_game_complete, DownloadCleanupEvent, DownloadErrorEvent, DownloadRejectedEvent,
+        DownloadStalledEvent, GameUpdateEvent, QueueUpdateEvent, QueueUpdateEventQueueData,
+    },
+    process::process_manager::ProcessManager,
+    remote::ErrorClass,
+    shortcuts::on_game_installed,
     state::GameStatusManager,
+    stats::{record_download_event, DownloadEventKind},
     DB,
 };
 
 use super::{
     download_agent::{GameDownloadAgent, GameDownloadError},
     download_manager::{
-        DownloadManager, DownloadManagerSignal, DownloadManagerStatus,
+        DownloadManager, DownloadManagerSignal, DownloadManagerStatus, DownloadPriority,
         GameDownloadAgentQueueStandin, GameDownloadStatus,
     },
+    download_schedule::{current_minute, is_within_windows},
     download_thread_control_flag::{DownloadThreadControl, DownloadThreadControlFlag},
+    manifest::DropManifest,
+    post_install::run_post_install_actions,
     progress_object::ProgressObject,
     queue::Queue,
+    speed_history::SpeedHistory,
 };
 
 /*
@@ -33,8 +50,9 @@ use super::{
 Welcome to the download manager, the most overengineered, glorious piece of bullshit.
 
 The download manager takes a queue of game_ids and their associated
-GameDownloadAgents, and then, one-by-one, executes them. It provides an interface
-to interact with the currently downloading agent, and manage the queue.
+GameDownloadAgents, and then executes them from the front of the queue, running
+at most `max_concurrent_downloads` of them at any one time. It provides an
+interface to interact with the currently downloading agents, and manage the queue.
 
 When the DownloadManager is initialised, it is designed to provide a reference
 which can be used to provide some instructions (the DownloadManagerInterface),
@@ -66,30 +84,45 @@ Behold, my madness - quexeky
 */
 
 // Refactored to consolidate this type. It's a monster.
-pub type CurrentProgressObject = Arc<Mutex<Option<Arc<ProgressObject>>>>;
+pub type ActiveProgressObjects = Arc<Mutex<HashMap<String, Arc<ProgressObject>>>>;
+
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Everything the manager needs to keep track of for a running agent
+struct ActiveDownload {
+    interface: Arc<GameDownloadAgentQueueStandin>,
+    thread: JoinHandle<()>,
+    control_flag: DownloadThreadControl,
+}
 
 pub struct DownloadManagerBuilder {
     download_agent_registry: HashMap<String, Arc<Mutex<GameDownloadAgent>>>,
     download_queue: Queue,
     command_receiver: Receiver<DownloadManagerSignal>,
     sender: Sender<DownloadManagerSignal>,
-    progress: CurrentProgressObject,
+    progress: ActiveProgressObjects,
     status: Arc<Mutex<DownloadManagerStatus>>,
     app_handle: AppHandle,
+    process_manager: Arc<Mutex<ProcessManager>>,
 
-    current_download_agent: Option<Arc<GameDownloadAgentQueueStandin>>, // Should be the only game download agent in the map with the "Go" flag
-    current_download_thread: Mutex<Option<JoinHandle<()>>>,
-    active_control_flag: Option<DownloadThreadControl>,
+    // Should be the only game download agents in the map with the "Go" flag
+    active_downloads: HashMap<String, ActiveDownload>,
+    max_concurrent_downloads: usize,
 }
 
 impl DownloadManagerBuilder {
-    pub fn build(app_handle: AppHandle) -> DownloadManager {
+    pub fn build(
+        app_handle: AppHandle,
+        process_manager: Arc<Mutex<ProcessManager>>,
+    ) -> DownloadManager {
         let queue = Queue::new();
         let (command_sender, command_receiver) = channel();
-        let active_progress = Arc::new(Mutex::new(None));
+        let active_progress = Arc::new(Mutex::new(HashMap::new()));
         let status = Arc::new(Mutex::new(DownloadManagerStatus::Empty));
 
-        let manager = Self {
+        let max_concurrent_downloads = DB.borrow_data().unwrap().settings.max_concurrent_downloads;
+
+        let mut manager = Self {
             download_agent_registry: HashMap::new(),
             download_queue: queue.clone(),
             command_receiver,
@@ -97,18 +130,50 @@ impl DownloadManagerBuilder {
             sender: command_sender.clone(),
             progress: active_progress.clone(),
             app_handle,
+            process_manager,
 
-            current_download_agent: None,
-            current_download_thread: Mutex::new(None),
-            active_control_flag: None,
+            active_downloads: HashMap::new(),
+            max_concurrent_downloads: max_concurrent_downloads.max(1),
         };
 
+        manager.restore_queue();
+        if !queue.empty() {
+            let auto_start = DB.borrow_data().unwrap().settings.auto_start_downloads;
+            if auto_start && DB.database_is_set_up() {
+                command_sender.send(DownloadManagerSignal::Go).unwrap();
+            } else {
+                manager.set_status(DownloadManagerStatus::Paused);
+            }
+        }
+
         let terminator = spawn(|| manager.manage_queue());
 
-        DownloadManager::new(terminator, queue, active_progress, command_sender)
+        // Download windows are checked on a timer, as nothing
+        // else would wake the manager up when one opens
+        let schedule_sender = command_sender.clone();
+        spawn(move || loop {
+            sleep(SCHEDULE_CHECK_INTERVAL);
+            if schedule_sender
+                .send(DownloadManagerSignal::CheckSchedule)
+                .is_err()
+            {
+                // Manager has exited
+                break;
+            }
+        });
+
+        let speed_history = SpeedHistory::start(active_progress.clone());
+
+        DownloadManager::new(
+            terminator,
+            queue,
+            active_progress,
+            speed_history,
+            command_sender,
+        )
     }
 
-    fn set_game_status<F: FnOnce(&mut RwLockWriteGuard<'_, Database>, &String) -> ()>(
+    fn set_game_status<F: FnOnce(&mut RwLockWriteGuard<'_, Database>, &String)>(
         &self,
         id: String,
         setter: F,
@@ -118,6 +183,10 @@ impl DownloadManagerBuilder {
         drop(db_handle);
         DB.save().unwrap();
 
+        self.push_game_update(id);
+    }
+
+    fn push_game_update(&self, id: String) {
         let status = GameStatusManager::fetch_state(&id);
 
         self.app_handle
@@ -131,14 +200,92 @@ impl DownloadManagerBuilder {
             .unwrap();
     }
 
+    // Writes the current queue order to the database, so it
+    // can be restored by restore_queue on the next launch
+    fn persist_queue(&self) {
+        let stored_queue = self
+            .download_queue
+            .read()
+            .iter()
+            .map(|interface| DatabaseQueuedDownload {
+                game_id: interface.id.clone(),
+                version: interface.version.clone(),
+                target_download_dir: interface.target_download_dir,
+                priority: *interface.priority.lock().unwrap(),
+            })
+            .collect();
+
+        let mut db_handle = DB.borrow_data_mut().unwrap();
+        db_handle.games.download_queue = stored_queue;
+        drop(db_handle);
+        DB.save().unwrap();
+    }
+
+    fn restore_queue(&mut self) {
+        let db_handle = DB.borrow_data().unwrap();
+        let stored_queue = db_handle.games.download_queue.clone();
+        let install_dir_count = db_handle.games.install_dirs.len();
+        drop(db_handle);
+
+        let mut restored = Vec::new();
+        for queued in stored_queue {
+            if queued.target_download_dir >= install_dir_count {
+                warn!(
+                    "dropping queued download for {} as its install directory no longer exists",
+                    queued.game_id
+                );
+                continue;
+            }
+            info!("restoring queued download for {}", queued.game_id);
+            // These were already checked when they were first queued
+            restored.push(self.add_agent(
+                GameDownloadAgent::new(
+                    queued.game_id.clone(),
+                    queued.version,
+                    queued.target_download_dir,
+                    self.sender.clone(),
+                ),
+                queued.priority,
+            ));
+        }
+
+        // All at once, rather than a save for each
+        let mut db_handle = DB.borrow_data_mut().unwrap();
+        for (id, transient_status) in &restored {
+            db_handle
+                .games
+                .transient_statuses
+                .insert(id.clone(), transient_status.clone());
+        }
+        drop(db_handle);
+        // Also drops any entries skipped above
+        self.persist_queue();
+
+        for (id, _) in restored {
+            self.push_game_update(id);
+        }
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+
     fn push_manager_update(&self) {
         let queue = self.download_queue.read();
         let queue_objs: Vec<QueueUpdateEventQueueData> = queue
             .iter()
-            .map(|interface| QueueUpdateEventQueueData {
-                id: interface.id.clone(),
-                status: interface.status.lock().unwrap().clone(),
-                progress: interface.progress.get_progress(),
+            .map(|interface| {
+                let status = interface.status.lock().unwrap().clone();
+                let speed = match status {
+                    GameDownloadStatus::Downloading => interface.progress.get_speed(),
+                    _ => 0,
+                };
+                QueueUpdateEventQueueData {
+                    id: interface.id.clone(),
+                    status,
+                    priority: *interface.priority.lock().unwrap(),
+                    progress: interface.progress.get_progress(),
+                    speed,
+                    eta: interface.progress.get_eta(speed).map(|eta| eta.as_secs()),
+                    disk_usage: interface.progress.get_disk_usage(),
+                }
             })
             .collect();
 
@@ -146,38 +293,99 @@ impl DownloadManagerBuilder {
         self.app_handle.emit("update_queue", event_data).unwrap();
     }
 
-    fn stop_and_wait_current_download(&self) {
-        self.set_status(DownloadManagerStatus::Paused);
-        if let Some(current_flag) = &self.active_control_flag {
-            current_flag.set(DownloadThreadControlFlag::Stop);
+    fn stop_and_wait_all_downloads(&mut self) {
+        let active_ids: Vec<String> = self.active_downloads.keys().cloned().collect();
+        for game_id in active_ids {
+            self.stop_and_wait_download(&game_id);
         }
+    }
 
-        let mut download_thread_lock = self.current_download_thread.lock().unwrap();
-        if let Some(current_download_thread) = download_thread_lock.take() {
-            current_download_thread.join().unwrap();
-        }
-        drop(download_thread_lock);
+    // Stops the agent's thread, waits for it to exit, and then releases
+    // everything the manager was holding for it. The agent itself stays
+    // in the registry and queue.
+    fn stop_and_wait_download(&mut self, game_id: &String) {
+        let active_download = match self.active_downloads.remove(game_id) {
+            Some(active_download) => active_download,
+            None => return,
+        };
+
+        active_download
+            .control_flag
+            .set(DownloadThreadControlFlag::Stop);
+        active_download.thread.join().unwrap();
+
+        *active_download.interface.status.lock().unwrap() = GameDownloadStatus::Queued;
+        self.progress.lock().unwrap().remove(game_id);
     }
 
-    fn sync_download_agent(&self) {}
+    fn in_download_window(&self) -> bool {
+        let db_handle = DB.borrow_data().unwrap();
+        is_within_windows(&db_handle.settings.download_windows, current_minute())
+    }
 
-    fn remove_and_cleanup_game(&mut self, game_id: &String) -> Arc<Mutex<GameDownloadAgent>> {
-        self.download_queue.pop_front();
-        let download_agent = self.download_agent_registry.remove(game_id).unwrap();
-        self.cleanup_current_download();
-        download_agent
+    // Picks the first `max_concurrent_downloads` agents in the queue, stops
+    // any running agent that has fallen out of that window, and starts any
+    // agent within it that isn't running yet. Outside of the user's download
+    // windows, nothing is picked, so every agent is stopped.
+    fn sync_download_agents(&mut self) {
+        let limit = if self.in_download_window() {
+            self.max_concurrent_downloads
+        } else {
+            0
+        };
+        let wanted: Vec<Arc<GameDownloadAgentQueueStandin>> = self
+            .download_queue
+            .read()
+            .into_iter()
+            .filter(|interface| self.download_agent_registry.contains_key(&interface.id))
+            .take(limit)
+            .collect();
+
+        let to_stop: Vec<String> = self
+            .active_downloads
+            .keys()
+            .filter(|id| !wanted.iter().any(|interface| &interface.id == *id))
+            .cloned()
+            .collect();
+        for game_id in to_stop {
+            info!(
+                "stopping download for {} as it left the active window",
+                game_id
+            );
+            self.stop_and_wait_download(&game_id);
+        }
+
+        for interface in wanted {
+            if self.active_downloads.contains_key(&interface.id) {
+                continue;
+            }
+            self.start_download(interface);
+        }
+
+        if self.active_downloads.is_empty() {
+            if self.download_queue.empty() {
+                self.set_status(DownloadManagerStatus::Empty);
+            }
+        } else {
+            self.set_status(DownloadManagerStatus::Downloading);
+        }
+    }
+
+    fn remove_and_cleanup_game(
+        &mut self,
+        game_id: &String,
+    ) -> Option<Arc<Mutex<GameDownloadAgent>>> {
+        self.download_queue.remove_by_id(game_id.clone());
+        self.persist_queue();
+        self.cleanup_download(game_id);
+        self.download_agent_registry.remove(game_id)
     }
 
     // CAREFUL WITH THIS FUNCTION
     // Make sure the download thread is terminated
-    fn cleanup_current_download(&mut self) {
-        self.active_control_flag = None;
-        *self.progress.lock().unwrap() = None;
-        self.current_download_agent = None;
-
-        let mut download_thread_lock = self.current_download_thread.lock().unwrap();
-        *download_thread_lock = None;
-        drop(download_thread_lock);
+    fn cleanup_download(&mut self, game_id: &String) {
+        self.active_downloads.remove(game_id);
+        self.progress.lock().unwrap().remove(game_id);
     }
 
     fn manage_queue(mut self) -> Result<(), ()> {
@@ -200,130 +408,405 @@ impl DownloadManagerBuilder {
                 DownloadManagerSignal::Queue(game_id, version, target_download_dir) => {
                     self.manage_queue_signal(game_id, version, target_download_dir);
                 }
-                DownloadManagerSignal::Error(e) => {
-                    self.manage_error_signal(e);
-                }
-                DownloadManagerSignal::Cancel => {
-                    self.manage_cancel_signal();
+                DownloadManagerSignal::Error(game_id, e) => {
+                    self.manage_error_signal(game_id, e);
                 }
                 DownloadManagerSignal::Update => {
                     self.push_manager_update();
                 }
                 DownloadManagerSignal::Finish => {
-                    self.stop_and_wait_current_download();
+                    info!("finishing download manager");
+                    self.stop_and_wait_all_downloads();
+                    self.persist_queue();
                     return Ok(());
                 }
                 DownloadManagerSignal::Remove(game_id) => {
                     self.manage_remove_game(game_id);
                 }
+                DownloadManagerSignal::Move(game_id, new_index) => {
+                    self.manage_move_signal(game_id, new_index);
+                }
+                DownloadManagerSignal::SetBandwidthLimit(game_id, limit) => {
+                    self.manage_set_bandwidth_limit_signal(game_id, limit);
+                }
+                DownloadManagerSignal::CheckSchedule => {
+                    self.manage_check_schedule_signal();
+                }
+                DownloadManagerSignal::SetConcurrency(limit) => {
+                    self.manage_set_concurrency_signal(limit);
+                }
+                DownloadManagerSignal::SetPriority(game_id, priority) => {
+                    self.manage_set_priority_signal(game_id, priority);
+                }
+                DownloadManagerSignal::Stalled(game_id, file_name) => {
+                    self.manage_stalled_signal(game_id, file_name);
+                }
             };
         }
     }
 
+    fn is_paused(&self) -> bool {
+        matches!(*self.status.lock().unwrap(), DownloadManagerStatus::Paused)
+    }
+
     fn manage_remove_game(&mut self, game_id: String) {
-        if let Some(current_download) = &self.current_download_agent {
-            if current_download.id == game_id {
-                self.manage_cancel_signal();
-            }
+        self.stop_and_wait_download(&game_id);
+        if let Some(download_agent) = self.remove_and_cleanup_game(&game_id) {
+            let download_agent_lock = download_agent.lock().unwrap();
+            record_download_event(
+                &game_id,
+                &download_agent_lock.version,
+                DownloadEventKind::Cancelled,
+                Some(download_agent_lock.progress.sum() as u64),
+                None,
+            );
+            self.delete_partial_files(&download_agent_lock);
         }
 
-        let index = self.download_queue.get_by_id(game_id.clone()).unwrap();
-        let mut queue_handle = self.download_queue.edit();
-        queue_handle.remove(index);
         self.set_game_status(game_id, |db_handle, id| {
             db_handle.games.transient_statuses.remove(id);
+            // A cancelled import was never installed
+            db_handle.games.pending_imports.remove(id);
+            // Nor is a cancelled rollback pinned
+            db_handle.games.pending_pins.remove(id);
         });
-        drop(queue_handle);
 
-        if self.current_download_agent.is_none() {
-            self.manage_go_signal();
+        if !self.is_paused() {
+            self.sync_download_agents();
         }
 
         self.push_manager_update();
     }
 
+    // Only call this once the agent's download thread has exited
+    fn delete_partial_files(&self, download_agent: &GameDownloadAgent) {
+        if !DB.borrow_data().unwrap().settings.delete_partial_on_cancel {
+            return;
+        }
+        // An update or repair writes over an installed version, so there's
+        // nothing partial we could remove without also removing the game.
+        // Only a staged download has a directory that's entirely its own.
+        if download_agent.installed_version().is_some()
+            || download_agent.installed_in_place()
+            || download_agent.stored_manifest.base_path == download_agent.install_dir
+        {
+            return;
+        }
+
+        let base_path = &download_agent.stored_manifest.base_path;
+        info!("deleting partial download at {}", base_path.display());
+        // DLC is downloaded straight into its game's directory,
+        // so only its own part files can go
+        let result = if download_agent.parent_id.is_some() {
+            download_agent.delete_part_files()
+        } else {
+            remove_dir_all(base_path)
+        };
+        let success = match result {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+            Err(e) => {
+                error!(
+                    "failed to delete partial download at {}: {}",
+                    base_path.display(),
+                    e
+                );
+                false
+            }
+        };
+
+        self.app_handle
+            .emit(
+                "download_cleanup",
+                DownloadCleanupEvent {
+                    game_id: download_agent.id.clone(),
+                    success,
+                },
+            )
+            .unwrap();
+    }
+
     fn manage_stop_signal(&mut self) {
         info!("Got signal 'Stop'");
         self.set_status(DownloadManagerStatus::Paused);
-        if let Some(active_control_flag) = self.active_control_flag.clone() {
-            active_control_flag.set(DownloadThreadControlFlag::Stop);
-        }
+        self.stop_and_wait_all_downloads();
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
     }
 
     fn manage_completed_signal(&mut self, game_id: String) {
         info!("Got signal 'Completed'");
-        if let Some(interface) = &self.current_download_agent {
-            // When if let chains are stabilised, combine these two statements
-            if interface.id == game_id {
-                info!("Popping consumed data");
-                let download_agent = self.remove_and_cleanup_game(&game_id);
-                let download_agent_lock = download_agent.lock().unwrap();
-
-                let version = download_agent_lock.version.clone();
-                let install_dir = download_agent_lock.stored_manifest.base_path.clone().to_string_lossy().to_string();
+        // The agent may have been removed while it was finishing up
+        if self.download_agent_registry.contains_key(&game_id) {
+            info!("Popping consumed data");
+            let download_agent = self.remove_and_cleanup_game(&game_id).unwrap();
+            let download_agent_lock = download_agent.lock().unwrap();
+
+            let version = download_agent_lock.version.clone();
+            let install_dir = download_agent_lock
+                .install_dir
+                .to_string_lossy()
+                .to_string();
+            let files = download_agent_lock.manifest.lock().unwrap().clone();
+            let download_size = download_agent_lock.progress.get_max() as u64;
+            // Checked before on_game_complete marks it installed
+            let first_install = download_agent_lock.parent_id.is_none()
+                && DB
+                    .borrow_data()
+                    .unwrap()
+                    .games
+                    .statuses
+                    .get(&game_id)
+                    .and_then(GameStatus::installed)
+                    .is_none();
 
-                drop(download_agent_lock);
+            drop(download_agent_lock);
 
-                if let Err(error) =
-                    on_game_complete(game_id, version, install_dir, &self.app_handle)
-                {
+            DB.borrow_data_mut()
+                .unwrap()
+                .games
+                .transient_statuses
+                .remove(&game_id);
+
+            match on_game_complete(
+                game_id.clone(),
+                version.clone(),
+                install_dir.clone(),
+                &self.app_handle,
+            ) {
+                Ok(()) => {
+                    // Checked and installed now, saved with the download event below
+                    let mut db_lock = DB.borrow_data_mut().unwrap();
+                    db_lock.games.pending_imports.remove(&game_id);
+                    if db_lock.games.pending_pins.get(&game_id) == Some(&version) {
+                        db_lock.games.pending_pins.remove(&game_id);
+                        db_lock
+                            .games
+                            .pinned_versions
+                            .insert(game_id.clone(), version.clone());
+                    }
+                    drop(db_lock);
+                    if let Some(files) = files {
+                        record_install_manifest(&game_id, &version, &install_dir, files);
+                    }
+                    record_download_event(
+                        &game_id,
+                        &version,
+                        DownloadEventKind::Completed,
+                        Some(download_size),
+                        None,
+                    );
+                    if first_install {
+                        on_game_installed(&self.app_handle, &game_id);
+                    }
+                    self.start_post_install(game_id, version, install_dir)
+                }
+                Err(error) => {
                     self.sender
                         .send(DownloadManagerSignal::Error(
+                            game_id,
                             GameDownloadError::Communication(error),
                         ))
                         .unwrap();
                 }
             }
         }
+
+        if !self.is_paused() {
+            self.sync_download_agents();
+        }
         self.sender.send(DownloadManagerSignal::Update).unwrap();
-        self.sender.send(DownloadManagerSignal::Go).unwrap();
+    }
+
+    // Installers can take a while, so these run
+    // off the manager thread
+    fn start_post_install(&self, game_id: String, version: String, install_dir: String) {
+        let actions = DB
+            .borrow_data()
+            .unwrap()
+            .games
+            .versions
+            .get(&game_id)
+            .and_then(|versions| versions.get(&version))
+            .map(|game_version| game_version.post_install.clone())
+            .unwrap_or_default();
+        if actions.is_empty() {
+            return;
+        }
+
+        let app_handle = self.app_handle.clone();
+        spawn(move || run_post_install_actions(&app_handle, game_id, install_dir, actions));
     }
 
     fn manage_queue_signal(&mut self, id: String, version: String, target_download_dir: usize) {
         info!("Got signal Queue");
-        let download_agent = Arc::new(Mutex::new(GameDownloadAgent::new(
+        let download_agent = GameDownloadAgent::new(
             id.clone(),
             version,
             target_download_dir,
             self.sender.clone(),
-        )));
+        );
+
+        let moving = matches!(
+            DB.borrow_data().unwrap().games.transient_statuses.get(&id),
+            Some(GameTransientStatus::Moving {})
+        );
+        if moving {
+            warn!("rejecting download for {}: it's being moved", id);
+            self.reject_download(id, "Game is being moved to another directory".to_string());
+            return;
+        }
+        // Its files are in use
+        if self.process_manager.lock().unwrap().is_running(&id) {
+            warn!("rejecting download for {}: it's running", id);
+            self.reject_download(id, "Game can't be updated while it's running".to_string());
+            return;
+        }
+
+        if let Err(reason) = self.check_free_space(&download_agent) {
+            warn!("rejecting download for {}: {}", id, reason);
+            record_download_event(
+                &id,
+                &download_agent.version,
+                DownloadEventKind::Failed,
+                None,
+                Some(reason.clone()),
+            );
+            self.reject_download(id, reason);
+            return;
+        }
+
+        record_download_event(
+            &id,
+            &download_agent.version,
+            DownloadEventKind::Queued,
+            Some(download_agent.required_space()),
+            None,
+        );
+        self.enqueue_agent(download_agent, DownloadPriority::default());
+
+        let downloading = matches!(
+            *self.status.lock().unwrap(),
+            DownloadManagerStatus::Downloading
+        );
+        if downloading {
+            self.sync_download_agents();
+        } else if self.is_paused() {
+            // Queueing something shouldn't undo the user's pause
+        } else if DB.borrow_data().unwrap().settings.auto_start_downloads {
+            self.manage_go_signal();
+        } else {
+            // Stays put until the user resumes the queue
+            self.set_status(DownloadManagerStatus::Paused);
+        }
+    }
+
+    fn reject_download(&self, id: String, reason: String) {
+        // A rollback that never started shouldn't pin anything
+        let mut db_lock = DB.borrow_data_mut().unwrap();
+        if db_lock.games.pending_pins.remove(&id).is_some() {
+            drop(db_lock);
+            DB.save().unwrap();
+        } else {
+            drop(db_lock);
+        }
+
+        self.app_handle
+            .emit(
+                "download_rejected",
+                DownloadRejectedEvent {
+                    game_id: id,
+                    reason,
+                },
+            )
+            .unwrap();
+    }
+
+    // Makes sure the game will fit in its install directory, so we don't
+    // find out halfway through the download. Fetches the agent's manifest,
+    // which it then reuses once the download starts.
+    fn check_free_space(&self, download_agent: &GameDownloadAgent) -> Result<(), String> {
+        download_agent
+            .ensure_manifest_exists()
+            .map_err(|e| e.to_string())?;
+
+        let install_dir = {
+            let db_handle = DB.borrow_data().unwrap();
+            db_handle.games.install_dirs[download_agent.target_download_dir].clone()
+        };
+
+        let required = download_agent.required_space();
+        let available = fs4::available_space(&install_dir)
+            .map_err(|e| format!("Unable to check free space in {}: {}", install_dir, e))?;
+
+        if required > available {
+            return Err(format!(
+                "Not enough free space in {}: {} bytes required, but only {} bytes available",
+                install_dir, required, available
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn enqueue_agent(&mut self, download_agent: GameDownloadAgent, priority: DownloadPriority) {
+        let (id, transient_status) = self.add_agent(download_agent, priority);
+        self.persist_queue();
+
+        self.set_game_status(id, |db, id| {
+            db.games
+                .transient_statuses
+                .insert(id.to_string(), transient_status);
+        });
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+
+    // Puts the agent in the queue without saving anything, returning its
+    // game id and the status to show for it
+    fn add_agent(
+        &mut self,
+        download_agent: GameDownloadAgent,
+        priority: DownloadPriority,
+    ) -> (String, GameTransientStatus) {
+        let id = download_agent.id.clone();
+        let target_download_dir = download_agent.target_download_dir;
+        let download_agent = Arc::new(Mutex::new(download_agent));
         let download_agent_lock = download_agent.lock().unwrap();
 
         let agent_status = GameDownloadStatus::Queued;
         let interface_data = GameDownloadAgentQueueStandin {
             id: id.clone(),
+            version: download_agent_lock.version.clone(),
+            target_download_dir,
             status: Mutex::new(agent_status),
+            priority: Mutex::new(priority),
             progress: download_agent_lock.progress.clone(),
+            rate_limiter: download_agent_lock.rate_limiter.clone(),
         };
-        let version_name = download_agent_lock.version.clone();
+        let transient_status = transient_status_for(&download_agent_lock);
 
         drop(download_agent_lock);
 
         self.download_agent_registry
             .insert(interface_data.id.clone(), download_agent);
-        self.download_queue.append(interface_data);
-
-        self.set_game_status(id, |db, id| {
-            db.games.transient_statuses.insert(
-                id.to_string(),
-                GameTransientStatus::Downloading { version_name },
-            );
-        });
-        self.sender.send(DownloadManagerSignal::Update).unwrap();
+        self.download_queue
+            .insert_by_priority(Arc::new(interface_data));
+        (id, transient_status)
     }
 
     fn manage_go_signal(&mut self) {
-        if !(!self.download_agent_registry.is_empty() && !self.download_queue.empty()) {
-            return;
-        }
-
-        if self.current_download_agent.is_some() {
-            info!("skipping go signal due to existing download job");
+        info!("Got signal 'Go'");
+        if self.download_agent_registry.is_empty() || self.download_queue.empty() {
             return;
         }
 
         info!("current download queue: {:?}", self.download_queue.read());
-        let agent_data = self.download_queue.read().front().unwrap().clone();
+        self.set_status(DownloadManagerStatus::Downloading);
+        self.sync_download_agents();
+
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+
+    fn start_download(&mut self, agent_data: Arc<GameDownloadAgentQueueStandin>) {
         info!("starting download for {}", agent_data.id.clone());
         let download_agent = self
             .download_agent_registry
@@ -331,25 +814,24 @@ impl DownloadManagerBuilder {
             .unwrap()
             .clone();
         let download_agent_lock = download_agent.lock().unwrap();
-        self.current_download_agent = Some(agent_data);
-        // Cloning option should be okay because it only clones the Arc inside, not the AgentInterfaceData
-        let agent_data = self.current_download_agent.clone().unwrap();
 
-        let version_name = download_agent_lock.version.clone();
+        let transient_status = transient_status_for(&download_agent_lock);
 
         let progress_object = download_agent_lock.progress.clone();
-        *self.progress.lock().unwrap() = Some(progress_object);
+        self.progress
+            .lock()
+            .unwrap()
+            .insert(agent_data.id.clone(), progress_object);
 
-        let active_control_flag = download_agent_lock.control_flag.clone();
-        self.active_control_flag = Some(active_control_flag.clone());
+        let control_flag = download_agent_lock.control_flag.clone();
 
         let sender = self.sender.clone();
+        let game_id = agent_data.id.clone();
 
         drop(download_agent_lock);
 
         info!("Spawning download");
-        let mut download_thread_lock = self.current_download_thread.lock().unwrap();
-        *download_thread_lock = Some(spawn(move || {
+        let thread = spawn(move || {
             let mut download_agent_lock = download_agent.lock().unwrap();
             match download_agent_lock.download() {
                 // Returns once we've exited the download
@@ -359,59 +841,216 @@ impl DownloadManagerBuilder {
                 // If an error occurred while *starting* the download
                 Err(err) => {
                     error!("error while managing download: {}", err);
-                    sender.send(DownloadManagerSignal::Error(err)).unwrap();
+                    sender
+                        .send(DownloadManagerSignal::Error(game_id, err))
+                        .unwrap();
                 }
             };
             drop(download_agent_lock);
-        }));
+        });
 
-        // Set status for games
-        for queue_game in self.download_queue.read() {
-            let mut status_handle = queue_game.status.lock().unwrap();
-            if queue_game.id == agent_data.id {
-                *status_handle = GameDownloadStatus::Downloading;
-            } else {
-                *status_handle = GameDownloadStatus::Queued;
-            }
-            drop(status_handle);
-        }
+        *agent_data.status.lock().unwrap() = GameDownloadStatus::Downloading;
+        record_download_event(
+            &agent_data.id,
+            &agent_data.version,
+            DownloadEventKind::Started,
+            None,
+            None,
+        );
+
+        // Set flags for the agent
+        control_flag.set(DownloadThreadControlFlag::Go);
+        self.active_downloads.insert(
+            agent_data.id.clone(),
+            ActiveDownload {
+                interface: agent_data.clone(),
+                thread,
+                control_flag,
+            },
+        );
 
-        // Set flags for download manager
-        active_control_flag.set(DownloadThreadControlFlag::Go);
-        self.set_status(DownloadManagerStatus::Downloading);
         self.set_game_status(agent_data.id.clone(), |db, id| {
-            db.games.transient_statuses.insert(
-                id.to_string(),
-                GameTransientStatus::Downloading { version_name },
-            );
+            db.games
+                .transient_statuses
+                .insert(id.to_string(), transient_status);
         });
-
-        self.sender.send(DownloadManagerSignal::Update).unwrap();
     }
-    fn manage_error_signal(&mut self, error: GameDownloadError) {
-        let current_status = self.current_download_agent.clone().unwrap();
+    fn manage_error_signal(&mut self, game_id: String, error: GameDownloadError) {
+        error!("download for {} failed: {}", game_id, error);
+        let message = error.to_string();
+        // The chunks or the manifest itself didn't add up, which a stale
+        // or damaged cached manifest would explain
+        let manifest_suspect =
+            error.class() == ErrorClass::Corrupted || error.manifest_error().is_some();
+        self.app_handle
+            .emit(
+                "download_error",
+                DownloadErrorEvent {
+                    game_id: game_id.clone(),
+                    message: message.clone(),
+                    status: error.status_code(),
+                    manifest_error: error.manifest_error().cloned(),
+                },
+            )
+            .unwrap();
+        // Multiple chunks can fail for the same agent, so the agent
+        // may already have been cleaned up by an earlier error
+        let interface = self
+            .download_queue
+            .read()
+            .into_iter()
+            .find(|interface| interface.id == game_id);
+        self.set_status(DownloadManagerStatus::Error(error));
 
-        self.remove_and_cleanup_game(&current_status.id); // Remove all the locks and shit
+        if let Some(interface) = interface {
+            if manifest_suspect {
+                remove_manifest(&game_id, &interface.version);
+            }
+            record_download_event(
+                &game_id,
+                &interface.version,
+                DownloadEventKind::Failed,
+                Some(interface.progress.sum() as u64),
+                Some(message),
+            );
+            self.stop_and_wait_download(&game_id);
+            self.remove_and_cleanup_game(&game_id); // Remove all the locks and shit
 
-        let mut lock = current_status.status.lock().unwrap();
-        *lock = GameDownloadStatus::Error;
-        self.set_status(DownloadManagerStatus::Error(error));
+            let mut lock = interface.status.lock().unwrap();
+            *lock = GameDownloadStatus::Error;
+            drop(lock);
 
-        let game_id = current_status.id.clone();
-        self.set_game_status(game_id, |db_handle, id| {
-            db_handle.games.transient_statuses.remove(id);
-        });
+            self.set_game_status(game_id, |db_handle, id| {
+                db_handle.games.transient_statuses.remove(id);
+            });
+        }
 
         self.sender.send(DownloadManagerSignal::Update).unwrap();
     }
-    fn manage_cancel_signal(&mut self) {
-        self.stop_and_wait_current_download();
+    fn manage_stalled_signal(&self, game_id: String, file_name: String) {
+        self.app_handle
+            .emit(
+                "download_stalled",
+                DownloadStalledEvent { game_id, file_name },
+            )
+            .unwrap();
+    }
+    fn manage_move_signal(&mut self, game_id: String, new_index: usize) {
+        info!("moving {} to {}", game_id, new_index);
+        if self
+            .download_queue
+            .move_to_index_by_id(game_id.clone(), new_index)
+            .is_err()
+        {
+            warn!("tried to move {} which isn't in the queue", game_id);
+            return;
+        }
+        self.persist_queue();
 
-        info!("cancel waited for download to finish");
+        // Moving a game in or out of the front of the queue
+        // changes which agents should be running
+        if !self.is_paused() {
+            self.sync_download_agents();
+        }
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+    fn manage_set_priority_signal(&mut self, game_id: String, priority: DownloadPriority) {
+        info!("setting priority of {} to {:?}", game_id, priority);
+        if self
+            .download_queue
+            .set_priority_by_id(game_id.clone(), priority)
+            .is_err()
+        {
+            warn!(
+                "tried to set priority of {} which isn't in the queue",
+                game_id
+            );
+            return;
+        }
+        self.persist_queue();
+
+        if !self.is_paused() {
+            self.sync_download_agents();
+        }
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+    fn manage_set_bandwidth_limit_signal(&mut self, game_id: String, limit: Option<usize>) {
+        let interface = match self
+            .download_queue
+            .read()
+            .into_iter()
+            .find(|interface| interface.id == game_id)
+        {
+            Some(interface) => interface,
+            // It's in the game's settings for when it's queued
+            None => return,
+        };
+
+        info!("setting bandwidth limit for {} to {:?}", game_id, limit);
+        interface.rate_limiter.set_rate(limit);
+    }
+    fn manage_check_schedule_signal(&mut self) {
+        if self.is_paused() || self.download_queue.empty() {
+            return;
+        }
 
-        self.cleanup_current_download();
+        let was_downloading = !self.active_downloads.is_empty();
+        self.sync_download_agents();
+        let is_downloading = !self.active_downloads.is_empty();
+
+        if was_downloading != is_downloading {
+            info!(
+                "download window {}",
+                if is_downloading { "opened" } else { "closed" }
+            );
+            self.sender.send(DownloadManagerSignal::Update).unwrap();
+        }
+    }
+    fn manage_set_concurrency_signal(&mut self, limit: usize) {
+        info!("setting concurrent download limit to {}", limit);
+        self.max_concurrent_downloads = limit.max(1);
+
+        if !self.is_paused() && !self.download_queue.empty() {
+            self.sync_download_agents();
+        }
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
     }
     fn set_status(&self, status: DownloadManagerStatus) {
         *self.status.lock().unwrap() = status;
     }
 }
+
+// Updates are downloaded in place over an existing install
+fn transient_status_for(download_agent: &GameDownloadAgent) -> GameTransientStatus {
+    let version_name = download_agent.version.clone();
+    if download_agent.is_import() {
+        return GameTransientStatus::Importing { version_name };
+    }
+    match download_agent.installed_version() {
+        Some(_) => GameTransientStatus::Updating { version_name },
+        None => GameTransientStatus::Downloading { version_name },
+    }
+}
+
+// Replaces whatever was recorded for an earlier version
+fn record_install_manifest(game_id: &str, version: &str, install_dir: &str, files: DropManifest) {
+    let installed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let manifest = InstallManifest::record(
+        game_id,
+        version.to_string(),
+        install_dir.to_string(),
+        &files,
+        installed_at,
+    );
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock
+        .games
+        .install_manifests
+        .insert(game_id.to_string(), manifest);
+    drop(db_lock);
+    DB.save().unwrap();
+}
@@ -0,0 +1,37 @@
+use chrono::{Local, Timelike};
+use serde::{Deserialize, Serialize};
+
+pub const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A time of day (in local time) during which downloads are allowed to run.
+/// Both ends are minutes since midnight. If `end` is before `start`, the
+/// window wraps around midnight, e.g. 23:00-02:00.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl DownloadWindow {
+    pub fn is_valid(&self) -> bool {
+        self.start < MINUTES_PER_DAY && self.end < MINUTES_PER_DAY && self.start != self.end
+    }
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// No windows means downloads are allowed at any time
+pub fn is_within_windows(windows: &[DownloadWindow], minute: u32) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(minute))
+}
+
+pub fn current_minute() -> u32 {
+    let now = Local::now();
+    now.hour() * 60 + now.minute()
+}
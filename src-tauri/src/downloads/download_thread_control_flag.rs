@@ -1,49 +1,51 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub enum DownloadThreadControlFlag {
     Stop,
     Go,
 }
-/// Go => true
-/// Stop => false
-impl From<DownloadThreadControlFlag> for bool {
-    fn from(value: DownloadThreadControlFlag) -> Self {
-        match value {
-            DownloadThreadControlFlag::Go => true,
-            DownloadThreadControlFlag::Stop => false,
-        }
-    }
-}
-/// true => Go
-/// false => Stop
-impl From<bool> for DownloadThreadControlFlag {
-    fn from(value: bool) -> Self {
-        match value {
-            true => DownloadThreadControlFlag::Go,
-            false => DownloadThreadControlFlag::Stop,
-        }
-    }
-}
 
+/// Stop cancels the current token, which every in-flight chunk of the
+/// download is waiting on. Go after a Stop hands out a fresh one, as a
+/// cancelled token can't be reused.
 #[derive(Clone)]
 pub struct DownloadThreadControl {
-    inner: Arc<AtomicBool>,
+    inner: Arc<Mutex<CancellationToken>>,
 }
 
 impl DownloadThreadControl {
     pub fn new(flag: DownloadThreadControlFlag) -> Self {
+        let token = CancellationToken::new();
+        if flag == DownloadThreadControlFlag::Stop {
+            token.cancel();
+        }
         Self {
-            inner: Arc::new(AtomicBool::new(flag.into())),
+            inner: Arc::new(Mutex::new(token)),
         }
     }
     pub fn get(&self) -> DownloadThreadControlFlag {
-        self.inner.load(Ordering::Relaxed).into()
+        if self.inner.lock().unwrap().is_cancelled() {
+            DownloadThreadControlFlag::Stop
+        } else {
+            DownloadThreadControlFlag::Go
+        }
     }
     pub fn set(&self, flag: DownloadThreadControlFlag) {
-        self.inner.store(flag.into(), Ordering::Relaxed);
+        let mut token = self.inner.lock().unwrap();
+        match flag {
+            DownloadThreadControlFlag::Stop => token.cancel(),
+            DownloadThreadControlFlag::Go => {
+                if token.is_cancelled() {
+                    *token = CancellationToken::new();
+                }
+            }
+        }
+    }
+    /// Cancelled as soon as the download is told to stop
+    pub fn token(&self) -> CancellationToken {
+        self.inner.lock().unwrap().clone()
     }
 }
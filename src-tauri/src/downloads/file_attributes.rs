@@ -0,0 +1,129 @@
+use std::{io, path::Path};
+
+use super::manifest::FileAttributes;
+
+/// Gives a finished file the permissions and attributes its manifest asks
+/// for. Unix gets the permission bits, Windows the read-only and hidden
+/// attributes. Launchers stay visible and writable on Windows, so shortcuts
+/// to them and launchers that update themselves keep working.
+pub fn apply_file_attributes(
+    path: &Path,
+    permissions: u32,
+    attributes: FileAttributes,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let mut mode = permissions;
+        if attributes.launcher {
+            // Executable by whoever can read it
+            mode |= (mode & 0o444) >> 2;
+        }
+        if attributes.read_only {
+            mode &= !0o222;
+        }
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Storage::FileSystem::{
+            FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY,
+        };
+
+        let _ = permissions;
+        let mut flags = windows::get(path)? & !(FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN);
+        if attributes.read_only && !attributes.launcher {
+            flags |= FILE_ATTRIBUTE_READONLY;
+        }
+        if attributes.hidden && !attributes.launcher {
+            flags |= FILE_ATTRIBUTE_HIDDEN;
+        }
+        windows::set(path, flags)?;
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = (path, permissions, attributes);
+
+    Ok(())
+}
+
+/// Undoes read-only, so that an update can write to
+/// (or remove) a file an earlier install locked down
+pub fn make_writable(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Changing a symlink's permissions would change its target's
+        if path.is_symlink() {
+            return Ok(());
+        }
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o200);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+
+        let flags = windows::get(path)?;
+        if flags & FILE_ATTRIBUTE_READONLY != 0 {
+            windows::set(path, flags & !FILE_ATTRIBUTE_READONLY)?;
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Hides a directory on Windows. Everywhere else,
+/// names starting with a dot are already hidden.
+pub fn hide(path: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+        let flags = windows::get(path)?;
+        windows::set(path, flags | FILE_ATTRIBUTE_HIDDEN)?;
+    }
+    #[cfg(not(windows))]
+    let _ = path;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{io, os::windows::ffi::OsStrExt, path::Path};
+
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileAttributesW, SetFileAttributesW, INVALID_FILE_ATTRIBUTES,
+    };
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    }
+
+    pub fn get(path: &Path) -> io::Result<u32> {
+        let wide_path = wide(path);
+        // SAFETY: the path is null terminated and outlives the call
+        let flags = unsafe { GetFileAttributesW(wide_path.as_ptr()) };
+        if flags == INVALID_FILE_ATTRIBUTES {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flags)
+    }
+
+    pub fn set(path: &Path, flags: u32) -> io::Result<()> {
+        let wide_path = wide(path);
+        // SAFETY: as above
+        if unsafe { SetFileAttributesW(wide_path.as_ptr(), flags) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
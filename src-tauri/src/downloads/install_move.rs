@@ -0,0 +1,353 @@
+use std::{
+    fs::{self, create_dir_all, remove_dir, remove_dir_all, rename, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Weak,
+    },
+    thread::spawn,
+};
+
+use log::{error, info, warn};
+use tauri::{AppHandle, Emitter};
+
+use crate::{
+    db::{GameStatus, GameTransientStatus},
+    library::{GameUpdateEvent, MoveCompleteEvent, MoveProgressEvent},
+    state::GameStatusManager,
+    DB,
+};
+
+use super::{
+    download_agent::STAGING_DIR,
+    download_logic::ChunkHasher,
+    download_manager::DownloadManagerSignal,
+    links::create_symlink,
+    manifest::{install_path, DropManifest},
+    progress_object::{ProgressHandle, ProgressObject},
+};
+
+/// Files are copied across in pieces of this size
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Starts moving an installed game into another of the download directories.
+/// The copy happens in the background; `move_progress` and `move_complete`
+/// events report how it's going.
+pub fn move_game_install(
+    app_handle: AppHandle,
+    game_id: String,
+    target_dir: usize,
+) -> Result<(), String> {
+    // Held until the game is marked as moving, so nothing else can start
+    // on it in between
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    if db_lock.games.transient_statuses.contains_key(&game_id) {
+        return Err("Game is busy, try again once it's finished".to_string());
+    }
+    let install_dir = match db_lock
+        .games
+        .statuses
+        .get(&game_id)
+        .and_then(GameStatus::installed)
+    {
+        Some((_, install_dir)) => PathBuf::from(install_dir),
+        None => return Err("Game isn't installed".to_string()),
+    };
+    let target_root = match db_lock.games.install_dirs.get(target_dir) {
+        Some(dir) => PathBuf::from(dir),
+        None => return Err("Invalid download directory".to_string()),
+    };
+    // Installed DLC lives in the game's directory, so goes along with it
+    let mut content_ids = vec![game_id.clone()];
+    content_ids.extend(
+        db_lock
+            .games
+            .dlc_parents
+            .iter()
+            .filter(|(_, parent_id)| **parent_id == game_id)
+            .map(|(dlc_id, _)| dlc_id.clone()),
+    );
+    // Only used to check the copy, so older installs just go without
+    let files: Vec<DropManifest> = content_ids
+        .iter()
+        .filter_map(|id| db_lock.games.install_manifests.get(id)?.files(id))
+        .collect();
+
+    let destination = target_root.join(&game_id);
+    if destination == install_dir {
+        return Err("Game is already installed there".to_string());
+    }
+    if destination.exists() {
+        return Err(format!("{} already exists", destination.display()));
+    }
+
+    db_lock
+        .games
+        .transient_statuses
+        .insert(game_id.clone(), GameTransientStatus::Moving {});
+    drop(db_lock);
+    push_game_update(&app_handle, &game_id);
+
+    spawn(move || {
+        info!(
+            "moving {} from {} to {}",
+            game_id,
+            install_dir.display(),
+            destination.display()
+        );
+        let result = move_files(&app_handle, &game_id, &install_dir, &target_root, files);
+        let error = match result {
+            Ok(copied) => {
+                update_install_dir(&content_ids, &destination);
+                // The old copy only goes once nothing points at it any more
+                if copied {
+                    if let Err(e) = remove_install(&install_dir) {
+                        warn!("couldn't remove old copy of {}: {}", game_id, e);
+                    }
+                }
+                None
+            }
+            Err(e) => {
+                error!("failed to move {}: {}", game_id, e);
+                Some(e.to_string())
+            }
+        };
+
+        DB.borrow_data_mut()
+            .unwrap()
+            .games
+            .transient_statuses
+            .remove(&game_id);
+        push_game_update(&app_handle, &game_id);
+        app_handle
+            .emit(
+                "move_complete",
+                MoveCompleteEvent {
+                    game_id,
+                    success: error.is_none(),
+                    error,
+                },
+            )
+            .unwrap();
+    });
+
+    Ok(())
+}
+
+// Returns whether the files were copied, rather than just renamed, in which
+// case the original is still there
+fn move_files(
+    app_handle: &AppHandle,
+    game_id: &str,
+    source: &Path,
+    target_root: &Path,
+    files: Vec<DropManifest>,
+) -> io::Result<bool> {
+    let destination = target_root.join(game_id);
+    create_dir_all(target_root)?;
+    // Same filesystem, so nothing needs copying
+    if rename(source, &destination).is_ok() {
+        return Ok(false);
+    }
+
+    let staging_dir = target_root.join(STAGING_DIR);
+    let staged = staging_dir.join(game_id);
+    // Left behind by a move that didn't finish
+    if staged.exists() {
+        remove_install(&staged)?;
+    }
+    create_dir_all(&staged)?;
+
+    let result = copy_install(app_handle, game_id, source, &staged, &files)
+        .and_then(|_| rename(&staged, &destination));
+    if result.is_err() {
+        let _ = remove_install(&staged);
+    }
+    // Only goes once nothing else is being staged
+    let _ = remove_dir(&staging_dir);
+    result.map(|_| true)
+}
+
+fn copy_install(
+    app_handle: &AppHandle,
+    game_id: &str,
+    source: &Path,
+    staged: &Path,
+    files: &[DropManifest],
+) -> io::Result<()> {
+    let (sender, receiver) = channel();
+    let progress = Arc::new(ProgressObject::new(dir_size(source)? as usize, 1, sender));
+    let reporter = {
+        let app_handle = app_handle.clone();
+        let game_id = game_id.to_string();
+        let progress = Arc::downgrade(&progress);
+        spawn(move || report_progress(app_handle, game_id, progress, receiver))
+    };
+
+    let handle = ProgressHandle::new(progress.get(0), progress.clone());
+    copy_dir(source, staged, &handle)?;
+    progress.push_update();
+    drop(handle);
+    drop(progress);
+    let _ = reporter.join();
+
+    for files in files {
+        verify_copy(staged, files)?;
+    }
+    Ok(())
+}
+
+// Runs until the progress object is dropped, which closes the channel
+fn report_progress(
+    app_handle: AppHandle,
+    game_id: String,
+    progress: Weak<ProgressObject>,
+    receiver: Receiver<DownloadManagerSignal>,
+) {
+    while receiver.recv().is_ok() {
+        let progress = match progress.upgrade() {
+            Some(progress) => progress,
+            None => break,
+        };
+        let speed = progress.get_speed();
+        app_handle
+            .emit(
+                "move_progress",
+                MoveProgressEvent {
+                    game_id: game_id.clone(),
+                    progress: progress.get_progress(),
+                    speed,
+                    eta: progress.get_eta(speed).map(|eta| eta.as_secs()),
+                },
+            )
+            .unwrap();
+    }
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn copy_dir(source: &Path, destination: &Path, progress: &ProgressHandle) -> io::Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        let target = destination.join(entry.file_name());
+        if file_type.is_symlink() {
+            create_symlink(&fs::read_link(&path)?, &target)?;
+        } else if file_type.is_dir() {
+            create_dir_all(&target)?;
+            copy_dir(&path, &target, progress)?;
+        } else {
+            copy_file(&path, &target, progress)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_file(source: &Path, destination: &Path, progress: &ProgressHandle) -> io::Result<()> {
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(destination)?;
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buf[..read])?;
+        progress.add(read);
+    }
+    writer.sync_all()?;
+    drop(writer);
+
+    // Carries over exec bits, and read-only on Windows
+    fs::set_permissions(destination, reader.metadata()?.permissions())
+}
+
+// Checks the copied files against the manifest they were installed from
+fn verify_copy(base_path: &Path, files: &DropManifest) -> io::Result<()> {
+    for (raw_path, chunk) in files {
+        if chunk.link.is_some() || chunk.is_empty_file() {
+            continue;
+        }
+        let mut file = File::open(install_path(base_path, raw_path))?;
+        for (length, checksum) in chunk.lengths.iter().zip(&chunk.checksums) {
+            let mut buf = vec![0; *length];
+            file.read_exact(&mut buf)?;
+
+            let mut hasher = ChunkHasher::new(chunk.checksum_algorithm);
+            hasher.update(&buf);
+            if hasher.finish() != *checksum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} didn't copy correctly", raw_path),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn update_install_dir(content_ids: &[String], destination: &Path) {
+    let destination = destination.to_string_lossy().to_string();
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    for id in content_ids {
+        match db_lock.games.statuses.get_mut(id) {
+            Some(GameStatus::Installed { install_dir, .. })
+            | Some(GameStatus::SetupRequired { install_dir, .. })
+            | Some(GameStatus::UpdateAvailable { install_dir, .. }) => {
+                *install_dir = destination.clone();
+            }
+            _ => {}
+        }
+        if let Some(manifest) = db_lock.games.install_manifests.get_mut(id) {
+            manifest.install_dir = destination.clone();
+        }
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+// Installed files can be read-only, which Windows won't delete
+fn remove_install(path: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    clear_readonly(path)?;
+    remove_dir_all(path)
+}
+
+#[cfg(windows)]
+fn clear_readonly(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            clear_readonly(&entry.path())?;
+        } else if file_type.is_file() {
+            super::file_attributes::make_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn push_game_update(app_handle: &AppHandle, game_id: &str) {
+    let game_id = game_id.to_string();
+    let status = GameStatusManager::fetch_state(&game_id);
+    app_handle
+        .emit(
+            &format!("update_game/{}", game_id),
+            GameUpdateEvent { game_id, status },
+        )
+        .unwrap();
+}
@@ -0,0 +1,42 @@
+use std::{io, path::Path};
+
+/// Makes `link` a symlink to `target`, which is relative to the directory
+/// `link` is in. Windows only lets administrators (or developer mode) make
+/// symlinks, so there a file target is copied instead if that fails.
+pub fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        // Windows doesn't treat / as a separator inside a link
+        let target: std::path::PathBuf = target.components().collect();
+        let target = target.as_path();
+        let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+        let result = if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        };
+        match result {
+            Err(e) if resolved.is_file() => {
+                log::debug!(
+                    "couldn't symlink {}, copying it instead: {}",
+                    link.display(),
+                    e
+                );
+                std::fs::copy(&resolved, link).map(|_| ())
+            }
+            result => result,
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (target, link);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks aren't supported on this platform",
+        ))
+    }
+}
@@ -1,8 +1,55 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
 
 pub type DropManifest = HashMap<String, DropChunk>;
+
+/// What a chunk's checksum was made with. Servers that don't
+/// say are older ones, which only know MD5.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, Ord, PartialOrd, Eq, PartialEq, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Md5,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    fn hex_length(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Md5 => 32,
+            ChecksumAlgorithm::Blake3 => 64,
+        }
+    }
+}
+
+/// Sent with manifest requests, in order of preference
+pub const SUPPORTED_CHECKSUM_ALGORITHMS: &str = "blake3, md5";
+
+/// How a file should be marked once it's installed, beyond its permissions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Ord, PartialOrd, Eq, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FileAttributes {
+    pub read_only: bool,
+    // Only on Windows, where dotfiles aren't hidden by their names
+    pub hidden: bool,
+    // Something the game is started through, which has to be executable
+    pub launcher: bool,
+}
+
+/// An entry that's made on disk rather than downloaded
+#[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FileLink {
+    // Relative to the directory the link is in, like on disk
+    Symlink { target: String },
+    // Relative to the install directory, and has to be a file in the manifest
+    Hardlink { target: String },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DropChunk {
@@ -11,6 +58,21 @@ pub struct DropChunk {
     pub checksums: Vec<String>,
     pub lengths: Vec<usize>,
     pub version_name: String,
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    #[serde(default)]
+    pub attributes: FileAttributes,
+    // Links have no chunks of their own
+    #[serde(default)]
+    pub link: Option<FileLink>,
+}
+
+impl DropChunk {
+    /// Empty placeholder files come with no chunks, or only empty ones,
+    /// and are made locally rather than downloaded
+    pub fn is_empty_file(&self) -> bool {
+        self.link.is_none() && self.lengths.iter().all(|length| *length == 0)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +84,308 @@ pub struct DropDownloadContext {
     pub game_id: String,
     pub path: PathBuf,
     pub checksum: String,
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
     pub length: usize,
     pub permissions: u32,
+    #[serde(default)]
+    pub attributes: FileAttributes,
+}
+
+/// Something wrong with a manifest, found before any of it was downloaded
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ManifestError {
+    // Absolute, or reaching outside the game's directory
+    UnsafePath { path: String },
+    // Different numbers of chunk ids, checksums and lengths
+    InconsistentChunks { path: String },
+    EmptyChunk { path: String, index: usize },
+    InvalidChecksum { path: String, index: usize },
+    InsufficientSpace { needed: u64, available: u64 },
+    // A link pointing outside the game's directory, or at something it can't
+    UnsafeLink { path: String, target: String },
+    // Files Windows won't create, like CON or names ending in a dot
+    ReservedNames { paths: Vec<String> },
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestError::UnsafePath { path } => {
+                write!(f, "{} is outside of the game's directory", path)
+            }
+            ManifestError::InconsistentChunks { path } => {
+                write!(f, "Chunk information for {} doesn't add up", path)
+            }
+            ManifestError::EmptyChunk { path, index } => {
+                write!(f, "Chunk {} of {} is empty", index, path)
+            }
+            ManifestError::InvalidChecksum { path, index } => {
+                write!(f, "Chunk {} of {} has an invalid checksum", index, path)
+            }
+            ManifestError::InsufficientSpace { needed, available } => write!(
+                f,
+                "Not enough disk space: {} bytes are needed, but only {} are free",
+                needed, available
+            ),
+            ManifestError::UnsafeLink { path, target } => {
+                write!(f, "{} links to {}, which isn't allowed", path, target)
+            }
+            ManifestError::ReservedNames { paths } => write!(
+                f,
+                "These files can't be created on Windows: {}",
+                paths.join(", ")
+            ),
+        }
+    }
+}
+
+/// Where a manifest path ends up in the install directory. Windows paths
+/// longer than MAX_PATH only work with the `\\?\` prefix, which also turns
+/// off the usual tidying of separators, so the path is rebuilt part by part.
+pub fn install_path(base_path: &Path, raw_path: &str) -> PathBuf {
+    let mut path: PathBuf = base_path.components().collect();
+    for component in Path::new(raw_path).components() {
+        if let Component::Normal(part) = component {
+            path.push(part);
+        }
+    }
+    long_path(path)
+}
+
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    let raw = match path.to_str() {
+        Some(raw) if path.is_absolute() && !raw.starts_with(r"\\?\") => raw,
+        _ => return path,
+    };
+    PathBuf::from(match raw.strip_prefix(r"\\") {
+        Some(share) => format!(r"\\?\UNC\{}", share),
+        None => format!(r"\\?\{}", raw),
+    })
+}
+
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether Windows can create every part of this path. Device names are
+/// reserved whatever their extension, and trailing dots and spaces are
+/// silently dropped, which would put the file somewhere else.
+pub fn is_valid_windows_path(raw_path: &str) -> bool {
+    raw_path.split('/').all(|part| {
+        if part == "." {
+            return true;
+        }
+        let stem = part.split('.').next().unwrap_or_default().trim_end();
+        let reserved = WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|name| stem.eq_ignore_ascii_case(name));
+        let invalid_char = part
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\\'));
+        !reserved && !invalid_char && !part.ends_with('.') && !part.ends_with(' ')
+    })
+}
+
+/// Where a file is downloaded to, until every chunk of it has been verified
+/// and it's renamed into place
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".part");
+    path.with_file_name(file_name)
+}
+
+// Whichever of a file and its part file is there
+pub fn existing_path(path: &Path) -> PathBuf {
+    let part = part_path(path);
+    if part.exists() {
+        part
+    } else {
+        path.to_path_buf()
+    }
+}
+
+// Only plain names, so joining it to the install directory stays inside,
+// and at least one of them, so it isn't the install directory itself
+fn is_safe_path(raw_path: &str) -> bool {
+    let components = Path::new(raw_path).components();
+    components
+        .clone()
+        .any(|component| matches!(component, Component::Normal(_)))
+        && components
+            .into_iter()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// How many links deep a path can go before it's taken to be a loop
+const MAX_LINK_DEPTH: usize = 40;
+
+// Every symlink in the manifest, by its path's plain names
+fn manifest_symlinks(manifest: &DropManifest) -> HashMap<String, &str> {
+    manifest
+        .iter()
+        .filter_map(|(raw_path, chunk)| match &chunk.link {
+            Some(FileLink::Symlink { target }) => Some((plain_names(raw_path), target.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn plain_names(raw_path: &str) -> String {
+    Path::new(raw_path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Works through `path` from the directory `from` without touching the disk,
+// following the manifest's own symlinks the way the filesystem will once
+// they're made. None if it climbs out of the install directory.
+fn resolve_inside<'a>(
+    symlinks: &HashMap<String, &'a str>,
+    from: Vec<&'a str>,
+    path: &'a str,
+    follow_last: bool,
+    depth: usize,
+) -> Option<Vec<&'a str>> {
+    if depth > MAX_LINK_DEPTH {
+        return None;
+    }
+    let mut resolved = from;
+    let components: Vec<_> = Path::new(path).components().collect();
+    let count = components.len();
+    for (index, component) in components.into_iter().enumerate() {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name.to_str()?);
+                let is_last = index + 1 == count;
+                if is_last && !follow_last {
+                    continue;
+                }
+                if let Some(target) = symlinks.get(&resolved.join("/")) {
+                    resolved.pop();
+                    resolved = resolve_inside(symlinks, resolved, target, true, depth + 1)?;
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop()?;
+            }
+            // Absolute
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+fn validate_link(
+    manifest: &DropManifest,
+    symlinks: &HashMap<String, &str>,
+    raw_path: &str,
+    link: &FileLink,
+) -> bool {
+    match link {
+        FileLink::Symlink { target } => {
+            let link_dir =
+                resolve_inside(symlinks, Vec::new(), raw_path, false, 0).map(|mut resolved| {
+                    resolved.pop();
+                    resolved
+                });
+            !target.is_empty()
+                && link_dir
+                    .and_then(|dir| resolve_inside(symlinks, dir, target, true, 0))
+                    .is_some()
+        }
+        // A hard link has to end up at a real file, not another link
+        FileLink::Hardlink { target } => {
+            is_safe_path(target)
+                && target != raw_path
+                && resolve_inside(symlinks, Vec::new(), target, false, 0).is_some()
+                && manifest
+                    .get(target)
+                    .is_some_and(|chunk| chunk.link.is_none())
+        }
+    }
+}
+
+/// Checks everything about a manifest that can be checked without the disk,
+/// and returns the total size of the game
+pub fn validate_manifest(manifest: &DropManifest) -> Result<u64, ManifestError> {
+    let mut total = 0u64;
+    let symlinks = manifest_symlinks(manifest);
+    // Collected rather than failing on the first, so they can all be fixed at once
+    let mut reserved_names = Vec::new();
+    for (raw_path, chunk) in manifest {
+        if !is_safe_path(raw_path) {
+            return Err(ManifestError::UnsafePath {
+                path: raw_path.clone(),
+            });
+        }
+        if cfg!(windows) && !is_valid_windows_path(raw_path) {
+            reserved_names.push(raw_path.clone());
+        }
+
+        if let Some(link) = &chunk.link {
+            if !chunk.lengths.is_empty() {
+                return Err(ManifestError::InconsistentChunks {
+                    path: raw_path.clone(),
+                });
+            }
+            if !validate_link(manifest, &symlinks, raw_path, link) {
+                let (FileLink::Symlink { target } | FileLink::Hardlink { target }) = link;
+                return Err(ManifestError::UnsafeLink {
+                    path: raw_path.clone(),
+                    target: target.clone(),
+                });
+            }
+            continue;
+        }
+
+        let chunk_count = chunk.lengths.len();
+        if chunk.ids.len() != chunk_count || chunk.checksums.len() != chunk_count {
+            return Err(ManifestError::InconsistentChunks {
+                path: raw_path.clone(),
+            });
+        }
+
+        let checksum_length = chunk.checksum_algorithm.hex_length();
+        let empty_file = chunk.is_empty_file();
+        for (index, (length, checksum)) in chunk.lengths.iter().zip(&chunk.checksums).enumerate() {
+            // Only allowed when the whole file is empty
+            if *length == 0 && !empty_file {
+                return Err(ManifestError::EmptyChunk {
+                    path: raw_path.clone(),
+                    index,
+                });
+            }
+            if checksum.len() != checksum_length || !checksum.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(ManifestError::InvalidChecksum {
+                    path: raw_path.clone(),
+                    index,
+                });
+            }
+            total = total.saturating_add(*length as u64);
+        }
+    }
+
+    if !reserved_names.is_empty() {
+        reserved_names.sort();
+        return Err(ManifestError::ReservedNames {
+            paths: reserved_names,
+        });
+    }
+    Ok(total)
 }
@@ -0,0 +1,155 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use url::Url;
+
+use crate::{
+    auth::generate_authorization_header,
+    db::DatabaseImpls,
+    remote::{blocking_http_client, fetch_capabilities, RemoteAccessError},
+    DB,
+};
+
+use super::content::use_content_endpoint;
+use super::download_logic::{set_server_batches_chunks, CHUNK_BATCHING_FEATURE};
+
+#[derive(Default)]
+struct MirrorHealth {
+    consecutive_failures: u32,
+    // Smoothed bytes per second, None until a chunk has come from this mirror
+    average_speed: Option<f64>,
+}
+
+/// Shared between every download, so what one game learns about a
+/// mirror carries over to the next
+static MIRROR_HEALTH: LazyLock<Mutex<HashMap<Url, MirrorHealth>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Weight given to the newest measurement in a mirror's average speed
+const SPEED_SMOOTHING: f64 = 0.3;
+
+/// Returns every endpoint chunks can be downloaded from: the server's
+/// content endpoint if it has one, then the server we're connected to,
+/// followed by any mirrors it advertises. Also notes whether it batches chunks.
+pub fn fetch_mirrors() -> Vec<Url> {
+    let base_url = DB.fetch_base_url();
+    let capabilities = fetch_capabilities()
+        .inspect_err(|e| warn!("couldn't check for a content endpoint: {}", e))
+        .ok();
+    let content_url = use_content_endpoint(
+        capabilities.as_ref().and_then(|caps| caps.content.as_ref()),
+        &base_url,
+    );
+    set_server_batches_chunks(
+        capabilities
+            .as_ref()
+            .is_some_and(|caps| caps.advertises(CHUNK_BATCHING_FEATURE)),
+    );
+
+    let mut mirrors: Vec<Url> = content_url.into_iter().collect();
+    if !mirrors.contains(&base_url) {
+        mirrors.push(base_url.clone());
+    }
+
+    match fetch_advertised_mirrors(&base_url) {
+        Ok(advertised) => {
+            for mirror in advertised {
+                if !mirrors.contains(&mirror) {
+                    mirrors.push(mirror);
+                }
+            }
+        }
+        // Servers without mirrors are the common case
+        Err(e) => info!("not using any download mirrors: {}", e),
+    }
+
+    mirrors
+}
+
+fn fetch_advertised_mirrors(base_url: &Url) -> Result<Vec<Url>, RemoteAccessError> {
+    let endpoint = base_url.join("/api/v1/client/metadata/mirrors")?;
+    let response = blocking_http_client()
+        .get(endpoint)
+        .header("Authorization", generate_authorization_header())
+        .send()?;
+
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    let advertised: Vec<String> = response.json()?;
+    let mirrors = advertised
+        .iter()
+        .filter_map(|mirror| match Url::parse(mirror) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("ignoring invalid mirror {}: {}", mirror, e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(mirrors)
+}
+
+/// Picks which mirror the next attempt at a chunk should use, skipping any
+/// in `tried` unless there's nothing else left. Failing mirrors go last and
+/// the fastest healthy one wins. Mirrors we've never downloaded from are
+/// picked first so that they get measured.
+pub fn pick_mirror(mirrors: &[Url], tried: &HashSet<Url>) -> Url {
+    let health = MIRROR_HEALTH.lock().unwrap();
+    let rank = |mirror: &Url| match health.get(mirror) {
+        Some(health) => (
+            health.consecutive_failures,
+            -health.average_speed.unwrap_or(f64::INFINITY),
+        ),
+        None => (0, f64::NEG_INFINITY),
+    };
+
+    let untried: Vec<&Url> = mirrors
+        .iter()
+        .filter(|mirror| !tried.contains(mirror))
+        .collect();
+    let candidates = if untried.is_empty() {
+        mirrors.iter().collect()
+    } else {
+        untried
+    };
+
+    // min_by keeps the first of equals, so ties go to the primary server
+    candidates
+        .into_iter()
+        .min_by(|a, b| {
+            let (a_failures, a_speed) = rank(a);
+            let (b_failures, b_speed) = rank(b);
+            a_failures
+                .cmp(&b_failures)
+                .then(a_speed.total_cmp(&b_speed))
+        })
+        .unwrap()
+        .clone()
+}
+
+pub fn record_success(mirror: &Url, bytes: usize, elapsed: Duration) {
+    let speed = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+
+    let mut health = MIRROR_HEALTH.lock().unwrap();
+    let health = health.entry(mirror.clone()).or_default();
+    health.consecutive_failures = 0;
+    health.average_speed = Some(match health.average_speed {
+        Some(average) => average + SPEED_SMOOTHING * (speed - average),
+        None => speed,
+    });
+}
+
+pub fn record_failure(mirror: &Url) {
+    let mut health = MIRROR_HEALTH.lock().unwrap();
+    health
+        .entry(mirror.clone())
+        .or_default()
+        .consecutive_failures += 1;
+}
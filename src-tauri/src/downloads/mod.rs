@@ -1,10 +1,23 @@
+mod chunk_cache;
+mod content;
 pub mod download_agent;
 pub mod download_commands;
-mod download_logic;
+pub mod download_logic;
 pub mod download_manager;
 pub mod download_manager_builder;
-mod download_thread_control_flag;
-mod manifest;
-mod progress_object;
+pub mod download_schedule;
+pub mod download_thread_control_flag;
+mod file_attributes;
+mod install_move;
+mod links;
+pub mod manifest;
+mod mirrors;
+pub mod orphans;
+pub mod post_install;
+pub mod progress_object;
 pub mod queue;
-mod stored_manifest;
\ No newline at end of file
+pub mod rate_limiter;
+mod sparse;
+pub mod speed_history;
+mod stored_manifest;
+pub mod write_backend;
\ No newline at end of file
@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    thread::spawn,
+};
+
+use log::{info, warn};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use crate::{db::Database, download_dirs::default_download_dir, AppState, DB};
+
+use super::{download_agent::STAGING_DIR, stored_manifest::StoredManifest};
+
+/// A download that was stopped without finishing and isn't queued any more,
+/// e.g. because the app was killed before the queue was saved. Queueing the
+/// same version again carries on from where it got to.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanedDownload {
+    pub game_id: String,
+    pub version: String,
+    // Bytes it's taking up
+    pub size: u64,
+    // Index into install_dirs, for downloads that hadn't been installed yet
+    pub install_dir: Option<usize>,
+    #[serde(skip)]
+    location: OrphanLocation,
+}
+
+#[derive(Clone)]
+enum OrphanLocation {
+    // Its own directory in the staging area
+    Staging(PathBuf),
+    // An update or DLC, in the directory of an installed game
+    InPlace { install_dir: PathBuf },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanedDownloadsEvent {
+    // Bytes freed by what couldn't be resumed
+    pub reclaimed: u64,
+    pub resumable: Vec<OrphanedDownload>,
+}
+
+/// Found at startup, until they're resumed or discarded
+static ORPHANED_DOWNLOADS: Mutex<Vec<OrphanedDownload>> = Mutex::new(Vec::new());
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+fn part_files(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => part_files(&path, found),
+            Ok(_)
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "part") =>
+            {
+                found.push(path)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Relative paths as a manifest has them, ignoring separators and anything
+// that isn't a plain name
+fn normalise(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+// The installed files in a directory, and which game each belongs to
+fn installed_files(db: &Database, install_dir: &Path) -> HashMap<PathBuf, String> {
+    db.games
+        .install_manifests
+        .iter()
+        .filter(|(_, manifest)| Path::new(&manifest.install_dir) == install_dir)
+        .flat_map(|(game_id, manifest)| {
+            manifest
+                .files(game_id)
+                .unwrap_or_default()
+                .into_keys()
+                .map(move |path| (normalise(Path::new(&path)), game_id.clone()))
+        })
+        .collect()
+}
+
+/// Part files in an installed game's directory that aren't one of the
+/// installed files, which can be named anything, including .part
+fn stray_part_files(db: &Database, install_dir: &Path) -> Vec<PathBuf> {
+    let installed = installed_files(db, install_dir);
+
+    let mut found = Vec::new();
+    part_files(install_dir, &mut found);
+    found.retain(|path| match path.strip_prefix(install_dir) {
+        Ok(relative) => !installed.contains_key(&normalise(relative)),
+        Err(_) => false,
+    });
+    found
+}
+
+fn installed_version<'a>(db: &'a Database, game_id: &str) -> Option<&'a String> {
+    let (version_name, _) = db.games.statuses.get(game_id)?.installed()?;
+    Some(version_name)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+// Returns the bytes freed, along with the games whose installed files
+// had been taken over by the download and were put back
+fn remove(db: &Database, orphan: &OrphanedDownload) -> (u64, HashSet<String>) {
+    let mut restored = HashSet::new();
+    match &orphan.location {
+        OrphanLocation::Staging(path) => {
+            let size = dir_size(path);
+            match fs::remove_dir_all(path) {
+                Ok(()) => (size, restored),
+                Err(e) => {
+                    warn!("couldn't remove {}: {}", path.display(), e);
+                    (0, restored)
+                }
+            }
+        }
+        OrphanLocation::InPlace { install_dir } => {
+            let installed = installed_files(db, install_dir);
+            let mut freed = 0;
+            for path in stray_part_files(db, install_dir) {
+                // Updating in place starts by renaming the installed file to
+                // its part file, so that's undone rather than deleting the game
+                let final_path = path.with_extension("");
+                let owner = final_path
+                    .strip_prefix(install_dir)
+                    .ok()
+                    .and_then(|relative| installed.get(&normalise(relative)));
+                if let Some(game_id) = owner.filter(|_| !final_path.exists()) {
+                    match fs::rename(&path, &final_path) {
+                        Ok(()) => {
+                            restored.insert(game_id.clone());
+                        }
+                        Err(e) => warn!("couldn't restore {}: {}", final_path.display(), e),
+                    }
+                    continue;
+                }
+                let size = file_size(&path);
+                if fs::remove_file(&path).is_ok() {
+                    freed += size;
+                }
+            }
+            // Their progress is meaningless without the part files, and
+            // there's no telling which part files were whose
+            for (game_id, version, data_file) in StoredManifest::stored_in(install_dir) {
+                if installed_version(db, &game_id) != Some(&version) {
+                    let _ = fs::remove_file(data_file);
+                }
+            }
+            (freed, restored)
+        }
+    }
+}
+
+/// Finds partial downloads that nothing's going to finish. Ones that can
+/// be carried on are returned; the rest are removed, returning the bytes
+/// that freed.
+fn find_orphans(db: &Database) -> (Vec<OrphanedDownload>, u64) {
+    let games = &db.games;
+    // DLC downloads into its game's directory, so that's in use too
+    let busy: HashSet<&String> = games
+        .download_queue
+        .iter()
+        .flat_map(|queued| {
+            [
+                Some(&queued.game_id),
+                games.dlc_parents.get(&queued.game_id),
+            ]
+        })
+        .flatten()
+        .collect();
+
+    let mut resumable = Vec::new();
+    let mut reclaimed = 0;
+
+    for (index, install_dir) in games.install_dirs.iter().enumerate() {
+        let staging_dirs = match fs::read_dir(Path::new(install_dir).join(STAGING_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in staging_dirs.flatten() {
+            let game_id = entry.file_name().to_string_lossy().into_owned();
+            if busy.contains(&game_id) {
+                continue;
+            }
+            let stored = StoredManifest::stored_in(&entry.path())
+                .into_iter()
+                .find(|(stored_id, _, _)| *stored_id == game_id);
+            let orphan = OrphanedDownload {
+                game_id: game_id.clone(),
+                version: stored
+                    .as_ref()
+                    .map(|(_, version, _)| version.clone())
+                    .unwrap_or_default(),
+                size: dir_size(&entry.path()),
+                install_dir: Some(index),
+                location: OrphanLocation::Staging(entry.path()),
+            };
+            // Nothing to carry on from, or a game the library doesn't have
+            if stored.is_some() && games.statuses.contains_key(&game_id) {
+                resumable.push(orphan);
+            } else {
+                reclaimed += remove(db, &orphan).0;
+            }
+        }
+    }
+
+    // Keyed by directory, since DLC shares its game's, along with whether
+    // anything in it is being downloaded
+    let mut install_dirs: HashMap<&String, bool> = HashMap::new();
+    for (game_id, status) in &games.statuses {
+        if let Some((_, install_dir)) = status.installed() {
+            *install_dirs.entry(install_dir).or_default() |= busy.contains(game_id);
+        }
+    }
+    for (install_dir, in_use) in install_dirs {
+        if in_use {
+            continue;
+        }
+        let install_dir = PathBuf::from(install_dir);
+        for (stored_id, version, _) in StoredManifest::stored_in(&install_dir) {
+            // Finished downloads leave theirs behind as well
+            if busy.contains(&stored_id) || installed_version(db, &stored_id) == Some(&version) {
+                continue;
+            }
+            let size = stray_part_files(db, &install_dir)
+                .iter()
+                .map(|path| file_size(path))
+                .sum();
+            resumable.push(OrphanedDownload {
+                game_id: stored_id,
+                version,
+                size,
+                install_dir: None,
+                location: OrphanLocation::InPlace {
+                    install_dir: install_dir.clone(),
+                },
+            });
+        }
+    }
+
+    (resumable, reclaimed)
+}
+
+/// Looks for partial downloads left behind by earlier runs, in the
+/// background. What can be resumed is offered through an event.
+pub fn spawn_orphan_cleanup(app_handle: AppHandle) {
+    spawn(move || {
+        let db = DB.borrow_data().unwrap().clone();
+        let (resumable, reclaimed) = find_orphans(&db);
+        if resumable.is_empty() && reclaimed == 0 {
+            return;
+        }
+        info!(
+            "found {} resumable partial downloads, removed {} bytes of others",
+            resumable.len(),
+            reclaimed
+        );
+        *ORPHANED_DOWNLOADS.lock().unwrap() = resumable.clone();
+        app_handle
+            .emit(
+                "orphaned_downloads",
+                OrphanedDownloadsEvent {
+                    reclaimed,
+                    resumable,
+                },
+            )
+            .unwrap();
+    });
+}
+
+#[tauri::command]
+pub fn fetch_orphaned_downloads() -> Vec<OrphanedDownload> {
+    let queue = DB.borrow_data().unwrap().games.download_queue.clone();
+    let mut orphans = ORPHANED_DOWNLOADS.lock().unwrap();
+    // Resuming one is queueing it again
+    orphans.retain(|orphan| !queue.iter().any(|queued| queued.game_id == orphan.game_id));
+    orphans.clone()
+}
+
+/// Removes what a partial download had written instead of resuming it.
+/// Installed files the download had started on are put back and the games
+/// they belong to are queued for repair, since they may have been partly
+/// overwritten. Returns the bytes freed.
+#[tauri::command]
+pub fn discard_orphaned_download(
+    game_id: String,
+    state: tauri::State<'_, Mutex<AppState>>,
+) -> Result<u64, String> {
+    let orphan = fetch_orphaned_downloads()
+        .into_iter()
+        .find(|orphan| orphan.game_id == game_id)
+        .ok_or("There's no partial download of that game to discard")?;
+
+    let db = DB.borrow_data().unwrap().clone();
+    let (freed, restored) = remove(&db, &orphan);
+    for restored_id in restored {
+        let version = match installed_version(&db, &restored_id) {
+            Some(version) => version.clone(),
+            None => continue,
+        };
+        info!(
+            "repairing {} after discarding a partial update",
+            restored_id
+        );
+        // Installed games are checked where they are, so only what
+        // was overwritten gets fetched again
+        state
+            .lock()
+            .unwrap()
+            .download_manager
+            .queue_game(restored_id, version, default_download_dir())
+            .map_err(|_| {
+                "An error occurred while communicating with the download manager.".to_string()
+            })?;
+    }
+    // Anything else partway through in the same directory went with it
+    ORPHANED_DOWNLOADS
+        .lock()
+        .unwrap()
+        .retain(|other| match (&other.location, &orphan.location) {
+            (
+                OrphanLocation::InPlace { install_dir },
+                OrphanLocation::InPlace {
+                    install_dir: removed,
+                },
+            ) => install_dir != removed,
+            _ => other.game_id != orphan.game_id,
+        });
+    info!("discarded partial download of {}, {} bytes", game_id, freed);
+    Ok(freed)
+}
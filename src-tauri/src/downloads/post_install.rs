@@ -0,0 +1,146 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+use crate::library::{PostInstallCompleteEvent, PostInstallEvent};
+
+/// A step declared in a game version's metadata that has to run once
+/// the game's files are on disk. Paths are relative to the install directory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PostInstallAction {
+    /// Runs an executable, e.g. a redistributable installer
+    Run {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Imports a .reg file, only on Windows
+    RegistryImport { path: String },
+    /// Sets the unix permissions of a file, e.g. a launcher script
+    Chmod { path: String, mode: u32 },
+}
+
+/// Runs each action in order, stopping at the first one that fails.
+/// Progress is reported through `post_install/{game_id}` events.
+pub fn run_post_install_actions(
+    app_handle: &AppHandle,
+    game_id: String,
+    install_dir: String,
+    actions: Vec<PostInstallAction>,
+) {
+    let install_dir = Path::new(&install_dir);
+    let mut success = true;
+
+    for (index, action) in actions.iter().enumerate() {
+        info!("running post-install action {:?} for {}", action, game_id);
+        let error = match run_action(install_dir, action) {
+            Ok(()) => None,
+            Err(e) => {
+                error!(
+                    "post-install action {:?} for {} failed: {}",
+                    action, game_id, e
+                );
+                Some(e.to_string())
+            }
+        };
+        let failed = error.is_some();
+
+        app_handle
+            .emit(
+                &format!("post_install/{}", game_id),
+                PostInstallEvent {
+                    game_id: game_id.clone(),
+                    action: index,
+                    total_actions: actions.len(),
+                    error,
+                },
+            )
+            .unwrap();
+
+        if failed {
+            success = false;
+            break;
+        }
+    }
+
+    app_handle
+        .emit(
+            "post_install_complete",
+            PostInstallCompleteEvent { game_id, success },
+        )
+        .unwrap();
+}
+
+fn run_action(install_dir: &Path, action: &PostInstallAction) -> io::Result<()> {
+    match action {
+        PostInstallAction::Run { command, args } => {
+            let status = Command::new(resolve(install_dir, command)?)
+                .args(args)
+                .current_dir(install_dir)
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{} exited with {}",
+                    command, status
+                )));
+            }
+            Ok(())
+        }
+        PostInstallAction::RegistryImport { path } => {
+            if !cfg!(windows) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "registry imports are only supported on Windows",
+                ));
+            }
+            let status = Command::new("reg")
+                .arg("import")
+                .arg(resolve(install_dir, path)?)
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "importing {} exited with {}",
+                    path, status
+                )));
+            }
+            Ok(())
+        }
+        PostInstallAction::Chmod { path, mode } => {
+            let path = resolve(install_dir, path)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*mode))?;
+            }
+            #[cfg(not(unix))]
+            info!(
+                "skipping chmod {:o} of {} as this isn't unix",
+                mode,
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+// Actions come from the server, so keep them inside the install directory
+fn resolve(install_dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(path);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside of the install directory", path),
+        ));
+    }
+    Ok(install_dir.join(relative))
+}
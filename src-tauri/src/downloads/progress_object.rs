@@ -1,14 +1,13 @@
 use std::{
+    collections::VecDeque,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         mpsc::Sender,
         Arc, Mutex,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use log::info;
-
 use super::download_manager::DownloadManagerSignal;
 
 #[derive(Clone)]
@@ -18,10 +17,16 @@ pub struct ProgressObject {
     start: Arc<Mutex<Instant>>,
     sender: Sender<DownloadManagerSignal>,
 
-    points_towards_update: Arc<AtomicUsize>,
-    points_to_push_update: Arc<Mutex<usize>>,
+    last_update: Arc<Mutex<Instant>>,
+
+    // (time, bytes downloaded) pairs used to work out the current speed
+    speed_samples: Arc<Mutex<VecDeque<(Instant, usize)>>>,
+
+    // Bytes the download's files take up, as of the agent's last checkpoint
+    disk_usage: Arc<AtomicU64>,
 }
 
+#[derive(Clone)]
 pub struct ProgressHandle {
     progress: Arc<AtomicUsize>,
     progress_object: Arc<ProgressObject>,
@@ -34,48 +39,67 @@ impl ProgressHandle {
             progress_object,
         }
     }
+    pub fn get(&self) -> usize {
+        self.progress.load(Ordering::Relaxed)
+    }
     pub fn set(&self, amount: usize) {
         self.progress.store(amount, Ordering::Relaxed);
     }
     pub fn add(&self, amount: usize) {
         self.progress
             .fetch_add(amount, std::sync::atomic::Ordering::Relaxed);
-        self.progress_object.check_push_update(amount);
+        self.progress_object.check_push_update();
     }
 }
 
-static PROGRESS_UPDATES: usize = 100;
+/// Progress updates for a single download are sent at most this often,
+/// however many chunks are reporting progress
+const MAX_UPDATES_PER_SECOND: u32 = 4;
+/// How far back the transfer speed is averaged over
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
 
 impl ProgressObject {
     pub fn new(max: usize, length: usize, sender: Sender<DownloadManagerSignal>) -> Self {
         let arr = Mutex::new((0..length).map(|_| Arc::new(AtomicUsize::new(0))).collect());
-        // TODO: consolidate this calculation with the set_max function below
-        let points_to_push_update = max / PROGRESS_UPDATES;
         Self {
             max: Arc::new(Mutex::new(max)),
             progress_instances: Arc::new(arr),
             start: Arc::new(Mutex::new(Instant::now())),
             sender,
 
-            points_towards_update: Arc::new(AtomicUsize::new(0)),
-            points_to_push_update: Arc::new(Mutex::new(points_to_push_update)),
+            last_update: Arc::new(Mutex::new(Instant::now())),
+
+            speed_samples: Arc::new(Mutex::new(VecDeque::new())),
+
+            disk_usage: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub fn check_push_update(&self, amount_added: usize) {
-        let current_amount = self
-            .points_towards_update
-            .fetch_add(amount_added, Ordering::Relaxed);
-
-        let to_update_handle = self.points_to_push_update.lock().unwrap();
-        let to_update = *to_update_handle;
-        drop(to_update_handle);
+    pub fn set_disk_usage(&self, bytes: u64) {
+        self.disk_usage.store(bytes, Ordering::Relaxed);
+    }
+    pub fn get_disk_usage(&self) -> u64 {
+        self.disk_usage.load(Ordering::Relaxed)
+    }
 
-        if current_amount < to_update {
+    pub fn check_push_update(&self) {
+        // Another chunk is already deciding whether to send one
+        let mut last_update = match self.last_update.try_lock() {
+            Ok(last_update) => last_update,
+            Err(_) => return,
+        };
+        if last_update.elapsed() < Duration::from_secs(1) / MAX_UPDATES_PER_SECOND {
             return;
         }
-        self.points_towards_update
-            .fetch_sub(to_update, Ordering::Relaxed);
+        *last_update = Instant::now();
+        drop(last_update);
+
+        self.sender.send(DownloadManagerSignal::Update).unwrap();
+    }
+    /// Sends an update regardless of how recently the last one went out,
+    /// so that the frontend always sees where a download stopped
+    pub fn push_update(&self) {
+        *self.last_update.lock().unwrap() = Instant::now();
         self.sender.send(DownloadManagerSignal::Update).unwrap();
     }
 
@@ -95,8 +119,6 @@ impl ProgressObject {
     }
     pub fn set_max(&self, new_max: usize) {
         *self.max.lock().unwrap() = new_max;
-        *self.points_to_push_update.lock().unwrap() = new_max / PROGRESS_UPDATES;
-        info!("points to push update: {}", new_max / PROGRESS_UPDATES);
     }
     pub fn set_size(&self, length: usize) {
         *self.progress_instances.lock().unwrap() =
@@ -105,6 +127,37 @@ impl ProgressObject {
     pub fn get_progress(&self) -> f64 {
         self.sum() as f64 / self.get_max() as f64
     }
+    /// Bytes per second, averaged over roughly the last `SPEED_WINDOW`
+    pub fn get_speed(&self) -> usize {
+        let now = Instant::now();
+        let sum = self.sum();
+
+        let mut samples = self.speed_samples.lock().unwrap();
+        samples.push_back((now, sum));
+        // Keep one sample from just before the window so that it's always covered
+        while samples.len() > 2 && now.duration_since(samples[1].0) >= SPEED_WINDOW {
+            samples.pop_front();
+        }
+
+        let (oldest_time, oldest_sum) = *samples.front().unwrap();
+        let elapsed = now.duration_since(oldest_time).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        (sum.saturating_sub(oldest_sum) as f64 / elapsed) as usize
+    }
+    /// Time left at the given speed, or None if we aren't making progress
+    pub fn get_eta(&self, speed: usize) -> Option<Duration> {
+        if speed == 0 {
+            return None;
+        }
+        let remaining = self.get_max().saturating_sub(self.sum());
+        Some(Duration::from_secs_f64(remaining as f64 / speed as f64))
+    }
+    /// Forgets the speed history, e.g. after progress jumps when a download resumes
+    pub fn reset_speed(&self) {
+        self.speed_samples.lock().unwrap().clear();
+    }
     pub fn get(&self, index: usize) -> Arc<AtomicUsize> {
         self.progress_instances.lock().unwrap()[index].clone()
     }
@@ -3,7 +3,7 @@ use std::{
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use super::download_manager::GameDownloadAgentQueueStandin;
+use super::download_manager::{DownloadPriority, GameDownloadAgentQueueStandin};
 
 #[derive(Clone)]
 pub struct Queue {
@@ -41,6 +41,32 @@ impl Queue {
     pub fn append(&self, interface: GameDownloadAgentQueueStandin) {
         self.edit().push_back(Arc::new(interface));
     }
+    /// Inserts `interface` behind every queued download of the same or
+    /// higher priority, but ahead of any with a lower priority
+    pub fn insert_by_priority(&self, interface: Arc<GameDownloadAgentQueueStandin>) {
+        let priority = *interface.priority.lock().unwrap();
+        let mut queue = self.edit();
+        let index = queue
+            .iter()
+            .position(|data| *data.priority.lock().unwrap() > priority)
+            .unwrap_or(queue.len());
+        queue.insert(index, interface);
+    }
+    /// Changes the priority of the given game and moves it to
+    /// where it would have been queued with that priority
+    pub fn set_priority_by_id(
+        &self,
+        game_id: String,
+        priority: DownloadPriority,
+    ) -> Result<(), ()> {
+        let existing = match self.remove_by_id(game_id) {
+            Some(existing) => existing,
+            None => return Err(()),
+        };
+        *existing.priority.lock().unwrap() = priority;
+        self.insert_by_priority(existing);
+        Ok(())
+    }
     pub fn pop_front_if_equal(
         &self,
         game_id: String,
@@ -55,19 +81,27 @@ impl Queue {
         }
         None
     }
+    pub fn remove_by_id(&self, game_id: String) -> Option<Arc<GameDownloadAgentQueueStandin>> {
+        let index = self.get_by_id(game_id)?;
+        self.edit().remove(index)
+    }
     pub fn get_by_id(&self, game_id: String) -> Option<usize> {
         self.read().iter().position(|data| data.id == game_id)
     }
+    /// Moves the given game to `new_index`, or to the back of the
+    /// deque if `new_index` is past the end of it
     pub fn move_to_index_by_id(&self, game_id: String, new_index: usize) -> Result<(), ()> {
         let index = match self.get_by_id(game_id) {
             Some(index) => index,
             None => return Err(()),
         };
-        let existing = match self.edit().remove(index) {
+        let mut queue = self.edit();
+        let existing = match queue.remove(index) {
             Some(existing) => existing,
             None => return Err(()),
         };
-        self.edit().insert(new_index, existing);
+        let new_index = new_index.min(queue.len());
+        queue.insert(new_index, existing);
         Ok(())
     }
 }
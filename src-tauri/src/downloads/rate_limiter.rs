@@ -0,0 +1,78 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::DB;
+
+/// Shared by every DropDownloadPipeline, so the limit applies to the
+/// combined throughput of all agents
+pub static DOWNLOAD_RATE_LIMITER: LazyLock<RateLimiter> =
+    LazyLock::new(|| RateLimiter::new(DB.borrow_data().unwrap().settings.bandwidth_limit));
+
+/// Token bucket rate limiter
+///
+/// Tokens are bytes, and refill at `rate` bytes per second up to a burst of
+/// one second's worth. Callers are allowed to take the bucket into debt, and
+/// then sleep until that debt would have been paid off. This means that
+/// large reads are never starved by small ones.
+pub struct RateLimiter {
+    inner: Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    // Bytes per second, None means unlimited
+    rate: Option<usize>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<usize>) -> Self {
+        Self {
+            inner: Mutex::new(TokenBucket {
+                rate: rate.filter(|rate| *rate > 0),
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+    pub fn set_rate(&self, rate: Option<usize>) {
+        let mut bucket = self.inner.lock().unwrap();
+        bucket.rate = rate.filter(|rate| *rate > 0);
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now();
+    }
+    pub fn get_rate(&self) -> Option<usize> {
+        self.inner.lock().unwrap().rate
+    }
+    /// Waits until `amount` bytes are allowed through
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut bucket = self.inner.lock().unwrap();
+            let rate = match bucket.rate {
+                Some(rate) => rate,
+                None => return,
+            };
+
+            bucket.refill(rate);
+            bucket.tokens -= amount as f64;
+            let debt = -bucket.tokens;
+            if debt <= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(debt / rate as f64)
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,72 @@
+use std::{fs::File, io, path::Path};
+
+/// Marks a file as sparse, so that the parts of it no chunk has written
+/// yet don't take up any space. Files extended with set_len already are
+/// on Linux and macOS, but NTFS has to be told.
+pub fn make_sparse(file: &File) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::{Ioctl::FSCTL_SET_SPARSE, IO::DeviceIoControl};
+
+        let mut returned = 0;
+        // SAFETY: the handle is valid for as long as `file` is, and this
+        // control code takes no input or output buffers
+        let succeeded = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle() as _,
+                FSCTL_SET_SPARSE,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if succeeded == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(windows))]
+    let _ = file;
+
+    Ok(())
+}
+
+/// How much of the disk a file really takes up, which
+/// for a sparse file can be much less than its length
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Always counted in 512 byte blocks, whatever the filesystem uses
+        Ok(std::fs::metadata(path)?.blocks() * 512)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::Storage::FileSystem::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+        let wide_path: Vec<u16> = path
+            .as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let mut high = 0;
+        // SAFETY: the path is null terminated and outlives the call
+        let low = unsafe { GetCompressedFileSizeW(wide_path.as_ptr(), &mut high) };
+        if low == INVALID_FILE_SIZE {
+            // Also a valid low half, so only an error if the OS says so
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(0) {
+                return Err(error);
+            }
+        }
+        Ok(((high as u64) << 32) | low as u64)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        Ok(std::fs::metadata(path)?.len())
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+    thread::{sleep, spawn},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use super::download_manager_builder::ActiveProgressObjects;
+
+/// How many samples are kept, one per `SAMPLE_INTERVAL`
+const HISTORY_LENGTH: usize = 120;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Clone)]
+pub struct SpeedSample {
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    // Bytes per second across all downloads
+    pub speed: usize,
+}
+
+/// Total download throughput over the last couple of minutes, for the UI
+pub struct SpeedHistory {
+    samples: Mutex<VecDeque<SpeedSample>>,
+}
+
+impl SpeedHistory {
+    /// Creates the history and starts sampling `progress` into it. Sampling
+    /// stops once the returned history is dropped.
+    pub fn start(progress: ActiveProgressObjects) -> Arc<Self> {
+        let history = Arc::new(Self {
+            samples: Mutex::new(VecDeque::with_capacity(HISTORY_LENGTH)),
+        });
+
+        let weak_history = Arc::downgrade(&history);
+        spawn(move || Self::sample_loop(weak_history, progress));
+
+        history
+    }
+
+    pub fn get(&self) -> Vec<SpeedSample> {
+        self.samples.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, speed: usize) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == HISTORY_LENGTH {
+            samples.pop_front();
+        }
+        samples.push_back(SpeedSample { timestamp, speed });
+    }
+
+    fn sample_loop(history: Weak<Self>, progress: ActiveProgressObjects) {
+        let mut last_totals: HashMap<String, usize> = HashMap::new();
+        loop {
+            sleep(SAMPLE_INTERVAL);
+            let history = match history.upgrade() {
+                Some(history) => history,
+                None => break,
+            };
+
+            let totals: HashMap<String, usize> = progress
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, progress)| (id.clone(), progress.sum()))
+                .collect();
+
+            // Downloads we haven't seen before count from their current
+            // total, so resuming one doesn't show up as a spike
+            let downloaded: usize = totals
+                .iter()
+                .map(|(id, total)| {
+                    let last_total = last_totals.get(id).unwrap_or(total);
+                    total.saturating_sub(*last_total)
+                })
+                .sum();
+            last_totals = totals;
+
+            history.push((downloaded as f64 / SAMPLE_INTERVAL.as_secs_f64()) as usize);
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
+    collections::HashMap,
     default,
     fs::File,
-    io::{Read, Write},
+    io::Read,
     path::{Path, PathBuf},
     sync::Mutex,
 };
@@ -10,49 +11,110 @@ use log::{error, info};
 use serde::{Deserialize, Serialize};
 use serde_binary::binary_stream::Endian;
 
+use crate::db::write_durably;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StoredManifest {
     game_id: String,
     game_version: String,
     pub completed_contexts: Mutex<Vec<usize>>,
     pub base_path: PathBuf,
+    // Bytes already written of chunks that were stopped partway through
+    #[serde(default)]
+    pub partial_contexts: Mutex<HashMap<usize, usize>>,
+    // Name of the file in base_path this is kept in
+    #[serde(skip)]
+    data_file: String,
 }
 
 static DROP_DATA_PATH: &str = ".dropdata";
 
 impl StoredManifest {
-    pub fn new(game_id: String, game_version: String, base_path: PathBuf) -> Self {
+    pub fn new(
+        game_id: String,
+        game_version: String,
+        base_path: PathBuf,
+        data_file: String,
+    ) -> Self {
         Self {
             base_path,
             game_id,
             game_version,
             completed_contexts: Mutex::new(Vec::new()),
+            partial_contexts: Mutex::new(HashMap::new()),
+            data_file,
         }
     }
-    pub fn generate(game_id: String, game_version: String, base_path: PathBuf) -> Self {
-        let mut file = match File::open(base_path.join(DROP_DATA_PATH)) {
-            Ok(file) => file,
-            Err(_) => return StoredManifest::new(game_id, game_version, base_path),
+    /// `shared_dir` is for content, like DLC, that downloads into a directory
+    /// another game's files are in, so it keeps its own file there
+    pub fn generate(
+        game_id: String,
+        game_version: String,
+        base_path: PathBuf,
+        shared_dir: bool,
+    ) -> Self {
+        let data_file = if shared_dir {
+            format!("{}-{}", DROP_DATA_PATH, game_id)
+        } else {
+            DROP_DATA_PATH.to_string()
+        };
+        let manifest = match StoredManifest::read(&base_path.join(&data_file)) {
+            Some(manifest) => manifest,
+            None => return StoredManifest::new(game_id, game_version, base_path, data_file),
         };
 
+        // Completed contexts from another version don't line up with this one
+        if manifest.game_id != game_id || manifest.game_version != game_version {
+            info!(
+                "discarding stored manifest for {} version {}",
+                manifest.game_id, manifest.game_version
+            );
+            return StoredManifest::new(game_id, game_version, base_path, data_file);
+        }
+
+        // The directory may have been moved, or copied from another machine,
+        // since this was written
+        StoredManifest {
+            base_path,
+            data_file,
+            ..manifest
+        }
+    }
+    fn read(path: &Path) -> Option<StoredManifest> {
+        let mut file = File::open(path).ok()?;
+
         let mut s = Vec::new();
-        match file.read_to_end(&mut s) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("{}", e);
-                return StoredManifest::new(game_id, game_version, base_path);
-            }
-        };
+        if let Err(e) = file.read_to_end(&mut s) {
+            error!("{}", e);
+            return None;
+        }
 
-        let manifest = match serde_binary::from_vec::<StoredManifest>(s, Endian::Little) {
-            Ok(manifest) => manifest,
+        match serde_binary::from_vec::<StoredManifest>(s, Endian::Little) {
+            Ok(manifest) => Some(manifest),
             Err(e) => {
                 error!("{}", e);
-                StoredManifest::new(game_id, game_version, base_path)
+                None
             }
+        }
+    }
+    /// Every download that's left its progress in the directory, as the
+    /// game id and version, along with the file it's kept in
+    pub fn stored_in(base_path: &Path) -> Vec<(String, String, PathBuf)> {
+        let entries = match std::fs::read_dir(base_path) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
         };
-
-        return manifest;
+        entries
+            .flatten()
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name == DROP_DATA_PATH || name.starts_with(&format!("{}-", DROP_DATA_PATH))
+            })
+            .filter_map(|entry| {
+                let manifest = StoredManifest::read(&entry.path())?;
+                Some((manifest.game_id, manifest.game_version, entry.path()))
+            })
+            .collect()
     }
     pub fn write(&self) {
         let manifest_raw = match serde_binary::to_vec(&self, Endian::Little) {
@@ -60,18 +122,17 @@ impl StoredManifest {
             Err(_) => return,
         };
 
-        let mut file = match File::create(self.base_path.join(DROP_DATA_PATH)) {
-            Ok(file) => file,
-            Err(e) => {
-                error!("{}", e);
-                return;
-            }
-        };
-
-        match file.write_all(&manifest_raw) {
-            Ok(_) => {}
-            Err(e) => error!("{}", e),
-        };
+        // A torn file would lose everything downloaded so far on resume
+        if let Err(e) = write_durably(&self.base_path.join(&self.data_file), &manifest_raw) {
+            error!("{}", e);
+        }
+    }
+    /// Removes the file this is kept in, once the download's been given up on
+    pub fn delete(&self) -> std::io::Result<()> {
+        match std::fs::remove_file(self.base_path.join(&self.data_file)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
     }
     pub fn set_completed_contexts(&self, completed_contexts: &Mutex<Vec<usize>>) {
         *self.completed_contexts.lock().unwrap() = completed_contexts.lock().unwrap().clone();
@@ -79,4 +140,10 @@ impl StoredManifest {
     pub fn get_completed_contexts(&self) -> Vec<usize> {
         self.completed_contexts.lock().unwrap().clone()
     }
+    pub fn set_partial_contexts(&self, partial_contexts: HashMap<usize, usize>) {
+        *self.partial_contexts.lock().unwrap() = partial_contexts;
+    }
+    pub fn get_partial_contexts(&self) -> HashMap<usize, usize> {
+        self.partial_contexts.lock().unwrap().clone()
+    }
 }
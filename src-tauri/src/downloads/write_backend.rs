@@ -0,0 +1,391 @@
+use std::{
+    io::{self, SeekFrom},
+    path::Path,
+};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use std::{
+    collections::{HashMap, VecDeque},
+    os::fd::AsRawFd,
+    os::unix::fs::FileExt,
+    sync::{mpsc, Arc, LazyLock},
+};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use io_uring::{opcode, types, IoUring};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use log::warn;
+use memmap2::{MmapMut, MmapOptions};
+use serde::{Deserialize, Serialize};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use tokio::sync::oneshot;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+/// How downloaded chunks are written to disk
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiskWriteMode {
+    /// Seeks to each chunk and writes through a buffer
+    #[default]
+    Buffered,
+    /// Maps each chunk's part of the file into memory and copies into it.
+    /// Smaller files are still written buffered.
+    MemoryMapped,
+    /// Submits writes through io_uring without waiting on each one.
+    /// Needs Linux and the `io-uring` feature, otherwise writes are buffered.
+    IoUring,
+}
+
+/// Files smaller than this aren't worth mapping
+const MEMORY_MAP_MIN_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a DropWriter puts the bytes of a single chunk
+pub enum WriteBackend {
+    Buffered(BufWriter<File>),
+    Mapped(MappedRegion),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(UringRegion),
+}
+
+impl WriteBackend {
+    pub async fn open(
+        path: &Path,
+        offset: u64,
+        length: usize,
+        buffer_size: usize,
+        mode: DiskWriteMode,
+    ) -> io::Result<Self> {
+        if mode == DiskWriteMode::MemoryMapped
+            && length != 0
+            && tokio::fs::metadata(path).await?.len() >= MEMORY_MAP_MIN_FILE_SIZE
+        {
+            return Ok(WriteBackend::Mapped(MappedRegion::open(
+                path, offset, length,
+            )?));
+        }
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if mode == DiskWriteMode::IoUring && URING_WRITER.is_some() {
+            return Ok(WriteBackend::Uring(UringRegion::open(path, offset)?));
+        }
+
+        // Read too, so that a resumed chunk can hash what's already there
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+        if offset != 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(WriteBackend::Buffered(BufWriter::with_capacity(
+            buffer_size,
+            file,
+        )))
+    }
+
+    /// Reads data left at the current position by an earlier attempt at
+    /// this chunk, and moves past it. Only valid before anything is written.
+    pub async fn read_existing(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            // Nothing has been buffered yet, so reading the file directly is safe
+            WriteBackend::Buffered(destination) => destination.get_mut().read(buf).await,
+            WriteBackend::Mapped(region) => {
+                let read = buf.len().min(region.map.len() - region.position);
+                buf[..read].copy_from_slice(&region.map[region.position..region.position + read]);
+                region.position += read;
+                Ok(read)
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            WriteBackend::Uring(region) => region.read_existing(buf),
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            WriteBackend::Buffered(destination) => destination.write_all(buf).await,
+            WriteBackend::Mapped(region) => region.write_all(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            WriteBackend::Uring(region) => region.write_all(buf).await,
+        }
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            WriteBackend::Buffered(destination) => destination.flush().await,
+            // The pages are already in the page cache, this just
+            // gets the OS started on writing them back
+            WriteBackend::Mapped(region) => region.map.flush_async(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            WriteBackend::Uring(region) => region.flush().await,
+        }
+    }
+}
+
+/// A single chunk's part of a preallocated file, mapped into memory
+pub struct MappedRegion {
+    map: MmapMut,
+    position: usize,
+}
+
+impl MappedRegion {
+    fn open(path: &Path, offset: u64, length: usize) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        // Touching a mapping past the end of the file is fatal, not an error
+        if file.metadata()?.len() < offset + length as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is smaller than the chunk being written to it",
+            ));
+        }
+
+        // SAFETY: nothing else should be changing the file while it's being
+        // downloaded, and every chunk maps a different region of it
+        let map = unsafe {
+            MmapOptions::new()
+                .offset(offset)
+                .len(length)
+                .map_mut(&file)?
+        };
+        Ok(Self { map, position: 0 })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let end = self.position + buf.len();
+        if end > self.map.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received more data than the chunk can hold",
+            ));
+        }
+        self.map[self.position..end].copy_from_slice(buf);
+        self.position = end;
+        Ok(())
+    }
+}
+
+/// Writes the shared ring has in flight at once, across every chunk
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_RING_DEPTH: u32 = 128;
+
+/// Writes each chunk is allowed to have queued up before waiting on the kernel
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_QUEUE_DEPTH: usize = 32;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+struct UringWrite {
+    file: Arc<std::fs::File>,
+    buf: Vec<u8>,
+    offset: u64,
+    written: usize,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+/// Every io_uring write goes through one ring, owned by its own thread, so
+/// waiting on the kernel never ties up one of the runtime's workers. Older
+/// kernels, and some sandboxes, don't allow io_uring at all, leaving this None.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+static URING_WRITER: LazyLock<Option<mpsc::Sender<UringWrite>>> = LazyLock::new(|| {
+    let ring = match IoUring::new(URING_RING_DEPTH) {
+        Ok(ring) => ring,
+        Err(e) => {
+            warn!(
+                "io_uring is unavailable, writing downloads buffered instead: {}",
+                e
+            );
+            return None;
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("drop-uring".to_string())
+        .spawn(move || run_uring_writer(ring, receiver))
+        .ok()?;
+    Some(sender)
+});
+
+// Only blocks on the channel when nothing is in flight, otherwise on the
+// ring, picking up whatever writes arrived in the meantime after each wait
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn run_uring_writer(mut ring: IoUring, requests: mpsc::Receiver<UringWrite>) {
+    let mut in_flight: HashMap<u64, UringWrite> = HashMap::new();
+    let mut next_id = 0;
+    loop {
+        if in_flight.is_empty() {
+            match requests.recv() {
+                Ok(write) => submit_new(&mut ring, &mut in_flight, &mut next_id, write),
+                // Every region is gone
+                Err(_) => return,
+            }
+        }
+        while in_flight.len() < URING_RING_DEPTH as usize {
+            match requests.try_recv() {
+                Ok(write) => submit_new(&mut ring, &mut in_flight, &mut next_id, write),
+                Err(_) => break,
+            }
+        }
+        if in_flight.is_empty() {
+            continue;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("io_uring stopped working: {}", e);
+                // No way to tell when the kernel is done with these, so never free them
+                for (_, write) in in_flight.drain() {
+                    let _ = write
+                        .done
+                        .send(Err(io::Error::new(e.kind(), e.to_string())));
+                    std::mem::forget(write.buf);
+                    std::mem::forget(write.file);
+                }
+                continue;
+            }
+        }
+
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect();
+        for (id, res) in completed {
+            let mut write = match in_flight.remove(&id) {
+                Some(write) => write,
+                None => continue,
+            };
+            if res <= 0 {
+                let error = if res == 0 {
+                    io::Error::from(io::ErrorKind::WriteZero)
+                } else {
+                    io::Error::from_raw_os_error(-res)
+                };
+                let _ = write.done.send(Err(error));
+                continue;
+            }
+
+            write.written += res as usize;
+            if write.written < write.buf.len() {
+                // Short, so whatever's left goes again
+                submit(&mut ring, &mut in_flight, id, write);
+            } else {
+                let _ = write.done.send(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn submit_new(
+    ring: &mut IoUring,
+    in_flight: &mut HashMap<u64, UringWrite>,
+    next_id: &mut u64,
+    write: UringWrite,
+) {
+    let id = *next_id;
+    *next_id += 1;
+    submit(ring, in_flight, id, write);
+}
+
+// Queues whatever is left of a write
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn submit(
+    ring: &mut IoUring,
+    in_flight: &mut HashMap<u64, UringWrite>,
+    id: u64,
+    write: UringWrite,
+) {
+    let remaining = &write.buf[write.written..];
+    let entry = opcode::Write::new(
+        types::Fd(write.file.as_raw_fd()),
+        remaining.as_ptr(),
+        remaining.len() as u32,
+    )
+    .offset(write.offset + write.written as u64)
+    .build()
+    .user_data(id);
+
+    // SAFETY: the buffer and file stay in in_flight, untouched, until the
+    // write's completion has been reaped
+    let pushed = unsafe { ring.submission().push(&entry) };
+    if pushed.is_err() {
+        let _ = write
+            .done
+            .send(Err(io::Error::other("io_uring submission queue is full")));
+        return;
+    }
+    in_flight.insert(id, write);
+}
+
+/// A single chunk's part of a file, written through the shared io_uring writer
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub struct UringRegion {
+    file: Arc<std::fs::File>,
+    // Where the next write goes, from the start of the file
+    position: u64,
+    // Oldest first
+    pending: VecDeque<oneshot::Receiver<io::Result<()>>>,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl UringRegion {
+    fn open(path: &Path, offset: u64) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Self {
+            file: Arc::new(file),
+            position: offset,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn read_existing(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.pending.len() >= URING_QUEUE_DEPTH {
+            self.wait_for_one().await?;
+        }
+
+        let (done, receiver) = oneshot::channel();
+        let write = UringWrite {
+            file: self.file.clone(),
+            buf: buf.to_vec(),
+            offset: self.position,
+            written: 0,
+            done,
+        };
+        let sent = match URING_WRITER.as_ref() {
+            Some(writer) => writer.send(write).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(io::Error::other("io_uring writer stopped"));
+        }
+        self.position += buf.len() as u64;
+        self.pending.push_back(receiver);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            self.wait_for_one().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_one(&mut self) -> io::Result<()> {
+        match self.pending.pop_front() {
+            Some(receiver) => receiver
+                .await
+                .map_err(|_| io::Error::other("io_uring writer stopped"))?,
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, fs};
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+use crate::{db::GameStatus, library::fetch_cached_game, DB};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+/// One game in an export, flattened so it fits in a CSV row as well
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedGame {
+    pub id: String,
+    pub name: String,
+    // Remote, SetupRequired, Installed or UpdateAvailable
+    pub status: String,
+    pub installed_version: Option<String>,
+    pub latest_version: Option<String>,
+    pub install_dir: Option<String>,
+    pub install_size: Option<u64>,
+    pub playtime_seconds: u64,
+    // RFC 3339
+    pub last_played: Option<String>,
+    pub favourite: bool,
+    pub hidden: bool,
+    pub tags: Vec<String>,
+}
+
+const CSV_HEADER: [&str; 12] = [
+    "id",
+    "name",
+    "status",
+    "installedVersion",
+    "latestVersion",
+    "installDir",
+    "installSize",
+    "playtimeSeconds",
+    "lastPlayed",
+    "favourite",
+    "hidden",
+    "tags",
+];
+
+fn exported_games(app: &AppHandle) -> Vec<ExportedGame> {
+    // Looked up first, since the app state is locked before the database
+    // everywhere else. Only games that have been shown here are known by name.
+    let game_ids: Vec<String> = DB
+        .borrow_data()
+        .unwrap()
+        .games
+        .statuses
+        .keys()
+        .cloned()
+        .collect();
+    let names: HashMap<String, String> = game_ids
+        .into_iter()
+        .filter_map(|game_id| {
+            let game = fetch_cached_game(app, &game_id)?;
+            Some((game_id, game.m_name))
+        })
+        .collect();
+
+    let db_lock = DB.borrow_data().unwrap();
+    let games = &db_lock.games;
+    let mut exported: Vec<ExportedGame> = games
+        .statuses
+        .iter()
+        .map(|(game_id, status)| {
+            let installed = status.installed();
+            let (status_name, latest_version) = match status {
+                GameStatus::Remote {} => ("Remote", None),
+                GameStatus::SetupRequired { .. } => ("SetupRequired", None),
+                GameStatus::Installed { .. } => ("Installed", None),
+                GameStatus::UpdateAvailable { latest_version, .. } => {
+                    ("UpdateAvailable", Some(latest_version.clone()))
+                }
+            };
+            let playtime = games.playtime.get(game_id).cloned().unwrap_or_default();
+            ExportedGame {
+                id: game_id.clone(),
+                name: names.get(game_id).cloned().unwrap_or_default(),
+                status: status_name.to_string(),
+                installed_version: installed.map(|(version_name, _)| version_name.clone()),
+                latest_version,
+                install_dir: installed.map(|(_, install_dir)| install_dir.clone()),
+                install_size: games
+                    .install_manifests
+                    .get(game_id)
+                    .map(|manifest| manifest.size),
+                playtime_seconds: playtime.total_seconds,
+                last_played: (playtime.last_played != 0)
+                    .then(|| DateTime::from_timestamp(playtime.last_played as i64, 0))
+                    .flatten()
+                    .map(|last_played| last_played.to_rfc3339()),
+                favourite: games.favourites.contains(game_id),
+                hidden: games.hidden.contains(game_id),
+                tags: games.tags.get(game_id).cloned().unwrap_or_default(),
+            }
+        })
+        .collect();
+    drop(db_lock);
+
+    exported.sort_by_key(|game| game.name.to_lowercase());
+    exported
+}
+
+// Quoted only when it has to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_csv(games: &[ExportedGame]) -> String {
+    let mut csv = CSV_HEADER.join(",");
+    csv.push('\n');
+    for game in games {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let row = [
+            game.id.clone(),
+            game.name.clone(),
+            game.status.clone(),
+            optional(game.installed_version.clone()),
+            optional(game.latest_version.clone()),
+            optional(game.install_dir.clone()),
+            optional(game.install_size.map(|size| size.to_string())),
+            game.playtime_seconds.to_string(),
+            optional(game.last_played.clone()),
+            game.favourite.to_string(),
+            game.hidden.to_string(),
+            // A single column, so tags are split on semicolons
+            game.tags.join(";"),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Writes out every game the library knows about, with its install state
+/// and playtime. Returns how many games went in.
+#[tauri::command]
+pub fn export_library(app: AppHandle, path: String, format: ExportFormat) -> Result<usize, String> {
+    let games = exported_games(&app);
+    let data = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&games).unwrap(),
+        ExportFormat::Csv => to_csv(&games),
+    };
+    fs::write(&path, data).map_err(|e| e.to_string())?;
+    Ok(games.len())
+}
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::Database,
+    process::launch_options::{check_env_name, split_args, LaunchOptions},
+    AppState, DB,
+};
+
+/// Everything configured for one game in particular. Whatever's left at
+/// its default follows the app's settings or the game's compatibility
+/// profile instead.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GameSettings {
+    pub launch: LaunchOptions,
+    // Run in front of the game (and any compatibility layer), e.g.
+    // `gamemoderun` or `mangohud`. Goes inside the profile's wrapper.
+    pub wrapper: String,
+    // None follows the auto_update setting
+    pub auto_update: Option<bool>,
+    // Bytes per second for the game's downloads, within the global limit
+    pub bandwidth_limit: Option<usize>,
+}
+
+impl GameSettings {
+    /// Catches what would otherwise only fail once the game is launched
+    pub fn check(&self) -> Result<(), String> {
+        split_args(&self.launch.args)?;
+        for name in self.launch.env.keys() {
+            check_env_name(name)?;
+        }
+        split_args(&self.wrapper)?;
+        Ok(())
+    }
+}
+
+/// For code that already has the database borrowed
+pub fn settings_for(db: &Database, game_id: &str) -> GameSettings {
+    db.games
+        .game_settings
+        .get(game_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub fn game_settings(game_id: &str) -> GameSettings {
+    settings_for(&DB.borrow_data().unwrap(), game_id)
+}
+
+/// Changes a game's settings and saves them. Games with nothing but
+/// defaults left don't keep an entry.
+pub fn update_game_settings<T>(game_id: &str, update: impl FnOnce(&mut GameSettings) -> T) -> T {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let game_settings = &mut db_lock.games.game_settings;
+    let settings = game_settings.entry(game_id.to_string()).or_default();
+    let result = update(settings);
+    if *settings == GameSettings::default() {
+        game_settings.remove(game_id);
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+    result
+}
+
+#[tauri::command]
+pub fn fetch_game_settings(game_id: String) -> GameSettings {
+    game_settings(&game_id)
+}
+
+#[tauri::command]
+pub fn set_game_settings(
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    settings: GameSettings,
+) -> Result<(), String> {
+    settings.check()?;
+
+    let bandwidth_limit = settings.bandwidth_limit;
+    update_game_settings(&game_id, |current| *current = settings);
+    // In case it's downloading right now
+    state
+        .lock()
+        .unwrap()
+        .download_manager
+        .set_game_bandwidth_limit(game_id, bandwidth_limit);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_game_wrapper(game_id: String, wrapper: String) -> Result<(), String> {
+    split_args(&wrapper)?;
+    update_game_settings(&game_id, |settings| settings.wrapper = wrapper);
+    Ok(())
+}
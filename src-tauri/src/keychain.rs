@@ -0,0 +1,270 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::db::DatabaseAuth;
+
+/// What entries are filed under in the OS's secret store
+const SERVICE: &str = "drop-app";
+
+/// The parts of the sign-in that would let someone else act as this client
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Secrets {
+    private: String,
+    signing_secret: Option<String>,
+}
+
+/// Puts the auth's secrets in the OS's secret store, keyed by client id, and
+/// marks it so they're left out of the database file. They stay in the file
+/// if there's no secret store to put them in.
+pub fn store_secrets(auth: &mut DatabaseAuth) {
+    let secrets = serde_json::to_string(&Secrets {
+        private: auth.private.clone(),
+        signing_secret: auth.signing_secret.clone(),
+    })
+    .unwrap();
+    match platform::set(&auth.client_id, &secrets) {
+        Ok(()) => {
+            if !auth.in_keychain {
+                info!("moved credentials into the system keychain");
+            }
+            auth.in_keychain = true;
+        }
+        Err(e) => {
+            warn!("keeping credentials in the database, no keychain: {}", e);
+            auth.in_keychain = false;
+        }
+    }
+}
+
+/// Fills in the secrets of auth that was loaded from the database without
+/// them. Ok(false) if they're gone from the keychain, Err if the keychain
+/// couldn't be read at all, which may well work later on.
+pub fn load_secrets(auth: &mut DatabaseAuth) -> Result<bool, String> {
+    if !auth.in_keychain || !auth.private.is_empty() {
+        return Ok(true);
+    }
+    let secrets = match platform::get(&auth.client_id) {
+        Ok(Some(secrets)) => secrets,
+        Ok(None) => {
+            warn!("credentials are missing from the keychain");
+            return Ok(false);
+        }
+        Err(e) => {
+            return Err(format!(
+                "couldn't read credentials from the keychain: {}",
+                e
+            ))
+        }
+    };
+    match serde_json::from_str::<Secrets>(&secrets) {
+        Ok(secrets) => {
+            auth.private = secrets.private;
+            auth.signing_secret = secrets.signing_secret;
+            Ok(true)
+        }
+        Err(e) => {
+            warn!("credentials in the keychain are unreadable: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+pub fn delete_secrets(auth: &DatabaseAuth) {
+    if !auth.in_keychain {
+        return;
+    }
+    if let Err(e) = platform::delete(&auth.client_id) {
+        warn!("couldn't remove credentials from the keychain: {}", e);
+    }
+}
+
+/// For the app's own secrets, rather than the sign-in's
+pub fn get_secret(account: &str) -> Result<Option<String>, String> {
+    platform::get(account).map_err(|e| format!("couldn't read from the keychain: {}", e))
+}
+
+pub fn set_secret(account: &str, secret: &str) -> Result<(), String> {
+    platform::set(account, secret).map_err(|e| format!("couldn't write to the keychain: {}", e))
+}
+
+// Secret Service, through libsecret's command line tool so there's no
+// D-Bus session to manage here
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{
+        io::{self, Write},
+        process::{Command, Stdio},
+    };
+
+    use super::SERVICE;
+
+    pub fn set(account: &str, secret: &str) -> io::Result<()> {
+        let mut child = Command::new("secret-tool")
+            .args(["store", "--label", "Drop Desktop Client"])
+            .args(["service", SERVICE, "account", account])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Over stdin, so the secret never shows up in the process list
+        child.stdin.take().unwrap().write_all(secret.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn get(account: &str) -> io::Result<Option<String>> {
+        let output = Command::new("secret-tool")
+            .args(["lookup", "service", SERVICE, "account", account])
+            .stdin(Stdio::null())
+            .output()?;
+        // Not found is a failure with nothing printed
+        if !output.status.success() {
+            if output.stderr.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+
+    pub fn delete(account: &str) -> io::Result<()> {
+        let status = Command::new("secret-tool")
+            .args(["clear", "service", SERVICE, "account", account])
+            .stdin(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other("secret-tool clear failed"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io;
+
+    use security_framework::passwords::{
+        delete_generic_password, get_generic_password, set_generic_password,
+    };
+
+    use super::SERVICE;
+
+    const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+
+    pub fn set(account: &str, secret: &str) -> io::Result<()> {
+        set_generic_password(SERVICE, account, secret.as_bytes()).map_err(io::Error::other)
+    }
+
+    pub fn get(account: &str) -> io::Result<Option<String>> {
+        match get_generic_password(SERVICE, account) {
+            Ok(secret) => Ok(Some(String::from_utf8_lossy(&secret).into_owned())),
+            Err(e) if e.code() == ERR_SEC_ITEM_NOT_FOUND => Ok(None),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    pub fn delete(account: &str) -> io::Result<()> {
+        delete_generic_password(SERVICE, account).map_err(io::Error::other)
+    }
+}
+
+// Windows Credential Manager, as generic credentials
+#[cfg(windows)]
+mod platform {
+    use std::{io, ptr::null_mut};
+
+    use windows_sys::Win32::{
+        Foundation::ERROR_NOT_FOUND,
+        Security::Credentials::{
+            CredDeleteW, CredFree, CredReadW, CredWriteW, CREDENTIALW, CRED_PERSIST_LOCAL_MACHINE,
+            CRED_TYPE_GENERIC,
+        },
+    };
+
+    use super::SERVICE;
+
+    fn target(account: &str) -> Vec<u16> {
+        format!("{}/{}", SERVICE, account)
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect()
+    }
+
+    pub fn set(account: &str, secret: &str) -> io::Result<()> {
+        let mut target = target(account);
+        // SAFETY: an all zero CREDENTIALW is empty, and everything it's
+        // pointed at below outlives the call
+        let mut credential: CREDENTIALW = unsafe { std::mem::zeroed() };
+        credential.Type = CRED_TYPE_GENERIC;
+        credential.TargetName = target.as_mut_ptr();
+        credential.CredentialBlobSize = secret.len() as u32;
+        credential.CredentialBlob = secret.as_ptr() as *mut u8;
+        credential.Persist = CRED_PERSIST_LOCAL_MACHINE;
+        if unsafe { CredWriteW(&credential, 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn get(account: &str) -> io::Result<Option<String>> {
+        let target = target(account);
+        let mut credential: *mut CREDENTIALW = null_mut();
+        // SAFETY: the target is null terminated, and the credential is
+        // freed once its blob has been copied out
+        if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(ERROR_NOT_FOUND as i32) {
+                return Ok(None);
+            }
+            return Err(error);
+        }
+        let secret = unsafe {
+            let blob = std::slice::from_raw_parts(
+                (*credential).CredentialBlob,
+                (*credential).CredentialBlobSize as usize,
+            );
+            let secret = String::from_utf8_lossy(blob).into_owned();
+            CredFree(credential as *const _);
+            secret
+        };
+        Ok(Some(secret))
+    }
+
+    pub fn delete(account: &str) -> io::Result<()> {
+        let target = target(account);
+        // SAFETY: as above
+        if unsafe { CredDeleteW(target.as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "no keychain on this platform")
+    }
+
+    pub fn set(_account: &str, _secret: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn get(_account: &str) -> io::Result<Option<String>> {
+        Err(unsupported())
+    }
+
+    pub fn delete(_account: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
@@ -1,38 +1,95 @@
+mod achievements;
 mod auth;
+mod backup;
+mod cache;
+mod collections;
+mod connection;
 mod db;
+mod db_encryption;
+mod devices;
+mod download_dirs;
 mod downloads;
+mod export;
+mod game_settings;
+mod keychain;
 mod library;
+mod migrations;
 
 mod process;
+mod profiles;
+mod proxy;
+mod push;
 mod remote;
+mod settings;
+mod shortcuts;
+mod signing;
+mod sqlite_backend;
 mod state;
+mod stats;
+mod tls;
 #[cfg(test)]
 mod tests;
+mod throttle;
 mod cleanup;
+mod updates;
 
 use crate::db::DatabaseImpls;
-use auth::{auth_initiate, generate_authorization_header, recieve_handshake, retry_connect};
-use cleanup::{cleanup_and_exit, quit};
-use db::{
-    add_download_dir, delete_download_dir, fetch_download_dir_stats, DatabaseInterface,
-    DATA_ROOT_DIR,
+use achievements::fetch_achievements;
+use auth::{
+    auth_initiate, recieve_auth_callback, recieve_handshake, retry_connect,
+    retry_startup_healthcheck, set_startup_retry_period, sign_out, submit_2fa_code,
+};
+use backup::{export_app_data, import_app_data};
+use cache::{clear_cache, fetch_cache_size, fetch_object, set_cache_size_limit};
+use cleanup::{cleanup_and_exit, quit, shutdown_download_manager};
+use collections::{
+    add_to_collection, create_collection, delete_collection, fetch_all_tags, fetch_collections,
+    fetch_game_tags, remove_from_collection, rename_collection, set_game_tags,
+};
+use connection::{spawn_connection_monitor, RemoteStatus};
+use db::{DatabaseInterface, DATA_ROOT_DIR};
+use db_encryption::set_database_encryption;
+use devices::{deregister_device, fetch_devices, rename_device};
+use download_dirs::{
+    add_download_dir, delete_download_dir, fetch_download_dir_stats, set_default_download_dir,
 };
 use downloads::download_commands::*;
 use downloads::download_manager::DownloadManager;
 use downloads::download_manager_builder::DownloadManagerBuilder;
-use http::{header::*, response::Builder as ResponseBuilder};
-use library::{fetch_game, fetch_game_status, fetch_game_verion_options, fetch_library, Game};
-use log::{debug, info, LevelFilter};
+use downloads::orphans::{
+    discard_orphaned_download, fetch_orphaned_downloads, spawn_orphan_cleanup,
+};
+use export::export_library;
+use game_settings::{fetch_game_settings, set_game_settings, set_game_wrapper};
+use http::{header::*, response::Builder as ResponseBuilder, StatusCode};
+use library::{
+    fetch_game, fetch_game_dlc, fetch_game_status, fetch_game_verion_options, fetch_library,
+    query_library, refresh_metadata, set_game_favourite, set_game_hidden, Game,
+};
+use log::{debug, info, warn, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::Config;
-use process::process_commands::launch_game;
+use process::process_commands::{
+    apply_compatibility_profile, delete_compatibility_profile, export_compatibility_profile,
+    fetch_compatibility_profiles, fetch_game_compatibility, fetch_game_playtime, fetch_launch_args,
+    fetch_launch_env, import_compatibility_profile, launch_game, resolve_save_conflict,
+    save_compatibility_profile, set_cloud_saves, set_compatibility_layer, set_game_compatibility,
+    set_hook_timeout, set_launch_args, set_launch_env, set_launch_hooks, sync_game_saves,
+};
 use process::process_manager::ProcessManager;
-use remote::{gen_drop_url, use_remote};
+use profiles::{create_profile, delete_profile, fetch_profiles, rename_profile, switch_profile};
+use proxy::set_use_system_proxy;
+use push::spawn_push_listener;
+use remote::{gen_drop_url, set_network_timeouts, use_remote, ServerCapabilities};
 use serde::{Deserialize, Serialize};
+use shortcuts::{
+    create_game_shortcut, handle_launch_link, remove_game_shortcut, set_create_shortcuts,
+};
+use stats::{get_download_history, get_game_stats, get_library_stats, get_usage_stats};
 use std::sync::Arc;
 use std::{
     collections::HashMap,
@@ -42,6 +99,16 @@ use tauri::menu::{Menu, MenuItem, MenuItemBuilder, PredefinedMenuItem};
 use tauri::tray::TrayIconBuilder;
 use tauri::{AppHandle, Manager, RunEvent, WindowEvent};
 use tauri_plugin_deep_link::DeepLinkExt;
+use tls::{
+    add_ca_certificate, add_ca_certificate_file, fetch_ca_certificates, fetch_client_certificates,
+    fetch_trusted_certificates, fetch_untrusted_certificate, remove_ca_certificate,
+    remove_client_certificate, set_client_certificate, trust_server_certificate,
+    untrust_server_certificate,
+};
+use updates::{
+    check_for_updates, fetch_pinned_version, rollback_game, set_auto_update,
+    set_game_auto_update, spawn_update_checker, unpin_game_version, verify_installed_games,
+};
 
 #[derive(Clone, Copy, Serialize)]
 pub enum AppStatus {
@@ -51,6 +118,8 @@ pub enum AppStatus {
     SignedIn,
     SignedInNeedsReauth,
     ServerUnavailable,
+    // Part way through signing in, waiting on a code from the user
+    AwaitingTwoFactor,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -69,6 +138,9 @@ pub struct AppState {
     status: AppStatus,
     user: Option<User>,
     games: HashMap<String, Game>,
+    remote_status: RemoteStatus,
+    // From the server's healthcheck, once it's answered one
+    capabilities: Option<ServerCapabilities>,
 
     #[serde(skip_serializing)]
     download_manager: Arc<DownloadManager>,
@@ -110,8 +182,11 @@ fn setup(handle: AppHandle) -> AppState {
     log4rs::init_config(config).unwrap();
 
     let games = HashMap::new();
-    let download_manager = Arc::new(DownloadManagerBuilder::build(handle));
-    let process_manager = Arc::new(Mutex::new(ProcessManager::new()));
+    let process_manager = Arc::new(Mutex::new(ProcessManager::new(handle.clone())));
+    let download_manager = Arc::new(DownloadManagerBuilder::build(
+        handle,
+        process_manager.clone(),
+    ));
 
     debug!("Checking if database is set up");
     let is_set_up = DB.database_is_set_up();
@@ -120,18 +195,26 @@ fn setup(handle: AppHandle) -> AppState {
             status: AppStatus::NotConfigured,
             user: None,
             games,
+            remote_status: RemoteStatus::default(),
+            capabilities: None,
             download_manager,
             process_manager,
         };
     }
 
     debug!("Database is set up");
+    tls::seal_client_certificates();
 
     let (app_status, user) = auth::setup().unwrap();
     AppState {
         status: app_status,
         user,
         games,
+        remote_status: match app_status {
+            AppStatus::ServerUnavailable => RemoteStatus::Offline,
+            _ => RemoteStatus::Online,
+        },
+        capabilities: None,
         download_manager,
         process_manager,
     }
@@ -161,33 +244,146 @@ pub fn run() {
             // Auth
             auth_initiate,
             retry_connect,
+            set_startup_retry_period,
+            submit_2fa_code,
+            sign_out,
+            fetch_devices,
+            rename_device,
+            deregister_device,
             // Remote
             use_remote,
             gen_drop_url,
+            set_network_timeouts,
+            set_use_system_proxy,
+            add_ca_certificate,
+            add_ca_certificate_file,
+            remove_ca_certificate,
+            fetch_ca_certificates,
+            fetch_untrusted_certificate,
+            trust_server_certificate,
+            untrust_server_certificate,
+            fetch_trusted_certificates,
+            set_client_certificate,
+            remove_client_certificate,
+            fetch_client_certificates,
             // Library
             fetch_library,
+            query_library,
+            refresh_metadata,
             fetch_game,
             add_download_dir,
             delete_download_dir,
+            set_default_download_dir,
             fetch_download_dir_stats,
             fetch_game_status,
+            fetch_game_dlc,
             fetch_game_verion_options,
+            check_for_updates,
+            set_auto_update,
+            set_game_auto_update,
+            rollback_game,
+            unpin_game_version,
+            fetch_pinned_version,
+            verify_installed_games,
+            fetch_collections,
+            create_collection,
+            rename_collection,
+            delete_collection,
+            add_to_collection,
+            remove_from_collection,
+            set_game_tags,
+            fetch_game_tags,
+            fetch_all_tags,
+            set_game_hidden,
+            set_game_favourite,
             // Downloads
             download_game,
+            import_install,
+            download_dlc,
             move_game_in_queue,
+            reorder_download_queue,
             pause_game_downloads,
             resume_game_downloads,
             cancel_game,
+            set_max_concurrent_downloads,
+            set_download_priority,
+            get_download_speed_history,
+            set_download_threads,
+            set_delete_partial_on_cancel,
+            set_auto_start_downloads,
+            set_download_buffer_sizes,
+            set_disk_write_mode,
+            set_stall_timeout,
+            set_chunks_per_request,
+            set_sparse_files,
+            set_bandwidth_limit,
+            fetch_bandwidth_limit,
+            set_game_bandwidth_limit,
+            set_download_windows,
+            fetch_download_windows,
+            move_game_install,
             // Processes
             launch_game,
+            set_compatibility_layer,
+            set_game_compatibility,
+            fetch_game_compatibility,
+            fetch_compatibility_profiles,
+            save_compatibility_profile,
+            delete_compatibility_profile,
+            apply_compatibility_profile,
+            export_compatibility_profile,
+            import_compatibility_profile,
+            set_launch_args,
+            fetch_launch_args,
+            set_launch_env,
+            fetch_launch_env,
+            set_launch_hooks,
+            set_game_wrapper,
+            fetch_game_settings,
+            set_game_settings,
+            set_hook_timeout,
+            fetch_game_playtime,
+            fetch_achievements,
+            get_game_stats,
+            get_library_stats,
+            export_app_data,
+            import_app_data,
+            export_library,
+            set_database_encryption,
+            fetch_profiles,
+            create_profile,
+            rename_profile,
+            delete_profile,
+            switch_profile,
+            fetch_cache_size,
+            set_cache_size_limit,
+            clear_cache,
+            fetch_orphaned_downloads,
+            discard_orphaned_download,
+            get_download_history,
+            get_usage_stats,
+            set_cloud_saves,
+            sync_game_saves,
+            resolve_save_conflict,
+            create_game_shortcut,
+            remove_game_shortcut,
+            set_create_shortcuts,
         ])
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_dialog::init())
         .setup(|app| {
             let handle = app.handle().clone();
             let state = setup(handle);
+            let server_unavailable = matches!(state.status, AppStatus::ServerUnavailable);
             info!("initialized drop client");
             app.manage(Mutex::new(state));
+            if server_unavailable {
+                retry_startup_healthcheck(app.handle().clone());
+            }
+            spawn_update_checker(app.handle().clone());
+            spawn_push_listener(app.handle().clone());
+            spawn_connection_monitor(app.handle().clone());
+            spawn_orphan_cleanup(app.handle().clone());
 
             #[cfg(any(target_os = "linux", all(debug_assertions, windows)))]
             {
@@ -216,8 +412,14 @@ pub fn run() {
                 info!("handling drop:// url");
                 let binding = event.urls();
                 let url = binding.first().unwrap();
-                if url.host_str().unwrap() == "handshake" {
-                    recieve_handshake(handle.clone(), url.path().to_string())
+                match url.host_str().unwrap() {
+                    "handshake" => recieve_handshake(handle.clone(), url.path().to_string()),
+                    "auth" if url.path() == "/callback" => {
+                        recieve_auth_callback(handle.clone(), url.clone())
+                    }
+                    // From desktop shortcuts
+                    "launch" => handle_launch_link(handle.clone(), url),
+                    _ => {}
                 }
             });
 
@@ -256,33 +458,29 @@ pub fn run() {
             Ok(())
         })
         .register_asynchronous_uri_scheme_protocol("object", move |_ctx, request, responder| {
-            let base_url = DB.fetch_base_url();
-
             // Drop leading /
-            let object_id = &request.uri().path()[1..];
-
-            let object_url = base_url
-                .join("/api/v1/client/object/")
-                .unwrap()
-                .join(object_id)
-                .unwrap();
-
-            let header = generate_authorization_header();
-            let client: reqwest::blocking::Client = reqwest::blocking::Client::new();
-            let response = client
-                .get(object_url.to_string())
-                .header("Authorization", header)
-                .send()
-                .unwrap();
-
-            let resp_builder = ResponseBuilder::new().header(
-                CONTENT_TYPE,
-                response.headers().get("Content-Type").unwrap(),
-            );
-            let data = Vec::from(response.bytes().unwrap());
-            let resp = resp_builder.body(data).unwrap();
+            let object_id = request.uri().path()[1..].to_string();
+
+            // Off the protocol thread, since uncached objects have to be downloaded.
+            // The runtime's blocking pool caps how many threads a page full of
+            // images can start.
+            tauri::async_runtime::spawn_blocking(move || {
+                let resp = match fetch_object(&object_id) {
+                    Ok((content_type, data)) => ResponseBuilder::new()
+                        .header(CONTENT_TYPE, content_type)
+                        .body(data)
+                        .unwrap(),
+                    Err(e) => {
+                        warn!("couldn't load object {}: {}", object_id, e);
+                        ResponseBuilder::new()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Vec::new())
+                            .unwrap()
+                    }
+                };
 
-            responder.respond(resp);
+                responder.respond(resp);
+            });
         })
         .on_window_event(|window, event| match event {
             WindowEvent::CloseRequested { api, .. } => {
@@ -300,6 +498,7 @@ pub fn run() {
                 api.prevent_exit();
             }
         }
+        RunEvent::Exit => shutdown_download_manager(app_handle),
         _ => {}
     });
 }
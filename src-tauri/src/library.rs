@@ -1,17 +1,21 @@
 use std::sync::Mutex;
 
+use log::warn;
 use serde::{Deserialize, Serialize};
 use tauri::Emitter;
 use tauri::{AppHandle, Manager};
 use urlencoding::encode;
 
+use crate::cache::{fetch_metadata, read_metadata, remove_object, write_metadata};
 use crate::db::DatabaseImpls;
 use crate::db::GameVersion;
 use crate::db::{GameStatus, GameTransientStatus};
-use crate::downloads::download_manager::GameDownloadStatus;
+use crate::downloads::download_manager::{DownloadPriority, GameDownloadStatus};
+use crate::downloads::manifest::ManifestError;
 use crate::process::process_manager::Platform;
-use crate::remote::RemoteAccessError;
+use crate::remote::{blocking_http_client, RemoteAccessError};
 use crate::state::{GameStatusManager, GameStatusWithTransient};
+use crate::throttle::{send_throttled, RequestClass};
 use crate::{auth::generate_authorization_header, AppState, DB};
 
 #[derive(serde::Serialize)]
@@ -20,20 +24,98 @@ pub struct FetchGameStruct {
     status: GameStatusWithTransient,
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+pub enum LibrarySort {
+    Name,
+    Size,
+    LastPlayed,
+    Playtime,
+}
+
+/// What `query_library` should return. Everything is optional, so
+/// an empty query is the first page of the whole library by name.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LibraryQuery {
+    pub search: Option<String>,
+    pub installed: Option<bool>,
+    pub tag: Option<String>,
+    pub collection: Option<String>,
+    pub favourites_only: bool,
+    pub include_hidden: bool,
+    pub sort: LibrarySort,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Default for LibraryQuery {
+    fn default() -> Self {
+        Self {
+            search: None,
+            installed: None,
+            tag: None,
+            collection: None,
+            favourites_only: false,
+            include_hidden: false,
+            sort: LibrarySort::Name,
+            descending: false,
+            offset: 0,
+            limit: 50,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct LibraryPage {
+    games: Vec<FetchGameStruct>,
+    // How many games matched, across every page
+    total: usize,
+}
+
+#[derive(serde::Serialize)]
+pub struct FetchDlcStruct {
+    dlc: Game,
+    status: GameStatusWithTransient,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Game {
-    id: String,
-    m_name: String,
+    pub id: String,
+    pub m_name: String,
     m_short_description: String,
     m_description: String,
     // mDevelopers
     // mPublishers
-    m_icon_id: String,
+    pub m_icon_id: String,
     m_banner_id: String,
     m_cover_id: String,
     m_image_library: Vec<String>,
+    // Set on this machine rather than by the server
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    favourite: bool,
+}
+
+impl Game {
+    fn image_ids(&self) -> impl Iterator<Item = &String> {
+        [&self.m_icon_id, &self.m_banner_id, &self.m_cover_id]
+            .into_iter()
+            .chain(self.m_image_library.iter())
+    }
+
+    // Games are cached in the app state, so the flags are filled in each
+    // time one is handed to the frontend
+    fn with_flags(mut self) -> Self {
+        let db_lock = DB.borrow_data().unwrap();
+        self.hidden = db_lock.games.hidden.contains(&self.id);
+        self.favourite = db_lock.games.favourites.contains(&self.id);
+        self
+    }
 }
+
 #[derive(serde::Serialize, Clone)]
 pub struct GameUpdateEvent {
     pub game_id: String,
@@ -44,7 +126,14 @@ pub struct GameUpdateEvent {
 pub struct QueueUpdateEventQueueData {
     pub id: String,
     pub status: GameDownloadStatus,
+    pub priority: DownloadPriority,
     pub progress: f64,
+    // Bytes per second
+    pub speed: usize,
+    // Seconds until the download finishes at the current speed
+    pub eta: Option<u64>,
+    // Bytes the download's files take up on disk so far
+    pub disk_usage: u64,
 }
 
 #[derive(serde::Serialize, Clone)]
@@ -52,12 +141,89 @@ pub struct QueueUpdateEvent {
     pub queue: Vec<QueueUpdateEventQueueData>,
 }
 
+#[derive(serde::Serialize, Clone)]
+pub struct DownloadRejectedEvent {
+    pub game_id: String,
+    pub reason: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct PostInstallEvent {
+    pub game_id: String,
+    // Index of the action that just finished
+    pub action: usize,
+    pub total_actions: usize,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct PostInstallCompleteEvent {
+    pub game_id: String,
+    pub success: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct DownloadCleanupEvent {
+    pub game_id: String,
+    pub success: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct DownloadErrorEvent {
+    pub game_id: String,
+    pub message: String,
+    pub status: Option<u16>,
+    // Set when the download never started because of its manifest
+    pub manifest_error: Option<ManifestError>,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct DownloadStalledEvent {
+    pub game_id: String,
+    pub file_name: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct UpdateAvailableEvent {
+    pub game_id: String,
+    pub installed_version: String,
+    pub latest_version: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct MetadataUpdateEvent {
+    pub game: Game,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct GameFlagsEvent {
+    pub game_id: String,
+    pub hidden: bool,
+    pub favourite: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct MoveProgressEvent {
+    pub game_id: String,
+    pub progress: f64,
+    // Bytes per second
+    pub speed: usize,
+    pub eta: Option<u64>,
+}
+
+#[derive(serde::Serialize, Clone)]
+pub struct MoveCompleteEvent {
+    pub game_id: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
 // Game version with some fields missing and size information
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GameVersionOption {
-    version_index: usize,
-    version_name: String,
+    pub version_index: usize,
+    pub version_name: String,
     platform: Platform,
     setup_command: String,
     launch_command: String,
@@ -66,23 +232,37 @@ pub struct GameVersionOption {
     // total_size: usize,
 }
 
-fn fetch_library_logic(app: AppHandle) -> Result<Vec<Game>, RemoteAccessError> {
-    let base_url = DB.fetch_base_url();
-    let library_url = base_url.join("/api/v1/client/user/library")?;
+/// Where the library is kept in the metadata cache
+const LIBRARY_CACHE_KEY: &str = "library";
 
-    let header = generate_authorization_header();
+fn game_cache_key(id: &str) -> String {
+    format!("game-{}", id)
+}
 
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(library_url.to_string())
-        .header("Authorization", header)
-        .send()?;
+/// The game's details without going to the server, if they've been loaded before
+pub fn fetch_cached_game(app: &AppHandle, id: &str) -> Option<Game> {
+    let state = app.state::<Mutex<AppState>>();
+    let game = state.lock().unwrap().games.get(id).cloned();
+    game.or_else(|| read_metadata(&game_cache_key(id)))
+}
 
-    if response.status() != 200 {
-        return Err(response.status().as_u16().into());
-    }
+// Along with whether it's changed since it was last fetched
+fn fetch_remote_library() -> Result<(Vec<Game>, bool), RemoteAccessError> {
+    let base_url = DB.fetch_base_url();
+    let library_url = base_url.join("/api/v1/client/user/library")?;
+
+    fetch_metadata(&library_url, LIBRARY_CACHE_KEY)
+}
 
-    let games: Vec<Game> = response.json::<Vec<Game>>()?;
+fn fetch_library_logic(app: AppHandle) -> Result<Vec<Game>, RemoteAccessError> {
+    let games = match fetch_remote_library() {
+        Ok((games, _)) => games,
+        Err(e) if e.is_unreachable() => {
+            warn!("showing cached library, server is unreachable: {}", e);
+            read_metadata(LIBRARY_CACHE_KEY).ok_or(e)?
+        }
+        Err(e) => return Err(e),
+    };
 
     let state = app.state::<Mutex<AppState>>();
     let mut handle = state.lock().unwrap();
@@ -100,8 +280,9 @@ fn fetch_library_logic(app: AppHandle) -> Result<Vec<Game>, RemoteAccessError> {
     }
 
     drop(handle);
+    drop(db_handle);
 
-    Ok(games)
+    Ok(games.into_iter().map(Game::with_flags).collect())
 }
 
 #[tauri::command]
@@ -121,34 +302,21 @@ fn fetch_game_logic(
         let status = GameStatusManager::fetch_state(&id);
 
         let data = FetchGameStruct {
-            game: game.clone(),
+            game: game.clone().with_flags(),
             status,
         };
 
         return Ok(data);
     }
 
-    let base_url = DB.fetch_base_url();
-
-    let endpoint = base_url.join(&format!("/api/v1/game/{}", id))?;
-    let header = generate_authorization_header();
-
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(endpoint.to_string())
-        .header("Authorization", header)
-        .send()?;
-
-    if response.status() == 404 {
-        return Err(RemoteAccessError::GameNotFound);
-    }
-    if response.status() != 200 {
-        return Err(RemoteAccessError::InvalidCodeError(
-            response.status().into(),
-        ));
-    }
-
-    let game = response.json::<Game>()?;
+    let game = match fetch_remote_game(&id) {
+        Ok((game, _)) => game,
+        Err(e) if e.is_unreachable() => {
+            warn!("showing cached {}, server is unreachable: {}", id, e);
+            read_metadata(&game_cache_key(&id)).ok_or(e)?
+        }
+        Err(e) => return Err(e),
+    };
     state_handle.games.insert(id.clone(), game.clone());
 
     let mut db_handle = DB.borrow_data_mut().unwrap();
@@ -163,13 +331,26 @@ fn fetch_game_logic(
     let status = GameStatusManager::fetch_state(&id);
 
     let data = FetchGameStruct {
-        game: game.clone(),
+        game: game.with_flags(),
         status,
     };
 
     Ok(data)
 }
 
+// Along with whether it's changed since it was last fetched
+fn fetch_remote_game(id: &str) -> Result<(Game, bool), RemoteAccessError> {
+    let base_url = DB.fetch_base_url();
+
+    let endpoint = base_url.join(&format!("/api/v1/game/{}", id))?;
+    match fetch_metadata(&endpoint, &game_cache_key(id)) {
+        Err(RemoteAccessError::Status { status, .. }) if status == 404 => {
+            Err(RemoteAccessError::GameNotFound)
+        }
+        result => result,
+    }
+}
+
 #[tauri::command]
 pub fn fetch_game(id: String, app: tauri::AppHandle) -> Result<FetchGameStruct, String> {
     let result = fetch_game_logic(id, app);
@@ -181,6 +362,53 @@ pub fn fetch_game(id: String, app: tauri::AppHandle) -> Result<FetchGameStruct,
     Ok(result.unwrap())
 }
 
+fn fetch_game_dlc_logic(game_id: String) -> Result<Vec<FetchDlcStruct>, RemoteAccessError> {
+    let base_url = DB.fetch_base_url();
+
+    let endpoint = base_url.join(&format!("/api/v1/client/metadata/dlc?id={}", game_id))?;
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Metadata, || {
+        client
+            .get(endpoint.to_string())
+            .header("Authorization", generate_authorization_header())
+    })?;
+
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+
+    // Only the DLC this user owns
+    let dlc = response.json::<Vec<Game>>()?;
+
+    let mut db_handle = DB.borrow_data_mut().unwrap();
+    for item in dlc.iter() {
+        db_handle
+            .games
+            .dlc_parents
+            .insert(item.id.clone(), game_id.clone());
+        db_handle
+            .games
+            .statuses
+            .entry(item.id.clone())
+            .or_insert(GameStatus::Remote {});
+    }
+    drop(db_handle);
+    DB.save().unwrap();
+
+    Ok(dlc
+        .into_iter()
+        .map(|dlc| FetchDlcStruct {
+            status: GameStatusManager::fetch_state(&dlc.id),
+            dlc,
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub fn fetch_game_dlc(game_id: String) -> Result<Vec<FetchDlcStruct>, String> {
+    fetch_game_dlc_logic(game_id).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 pub fn fetch_game_status(id: String) -> Result<GameStatusWithTransient, String> {
     let status = GameStatusManager::fetch_state(&id);
@@ -188,26 +416,23 @@ pub fn fetch_game_status(id: String) -> Result<GameStatusWithTransient, String>
     Ok(status)
 }
 
-fn fetch_game_verion_options_logic<'a>(
+pub fn fetch_game_verion_options_logic(
     game_id: String,
-    state: tauri::State<'_, Mutex<AppState>>,
+    state: &Mutex<AppState>,
 ) -> Result<Vec<GameVersionOption>, RemoteAccessError> {
     let base_url = DB.fetch_base_url();
 
     let endpoint =
         base_url.join(format!("/api/v1/client/metadata/versions?id={}", game_id).as_str())?;
-    let header = generate_authorization_header();
-
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(endpoint.to_string())
-        .header("Authorization", header)
-        .send()?;
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Metadata, || {
+        client
+            .get(endpoint.to_string())
+            .header("Authorization", generate_authorization_header())
+    })?;
 
     if response.status() != 200 {
-        return Err(RemoteAccessError::InvalidCodeError(
-            response.status().into(),
-        ));
+        return Err(RemoteAccessError::from_response(response));
     }
 
     let data = response.json::<Vec<GameVersionOption>>()?;
@@ -216,7 +441,11 @@ fn fetch_game_verion_options_logic<'a>(
     let process_manager_lock = state_lock.process_manager.lock().unwrap();
     let data = data
         .into_iter()
-        .filter(|v| process_manager_lock.valid_platform(&v.platform).unwrap())
+        .filter(|v| {
+            process_manager_lock
+                .valid_platform(&game_id, &v.platform)
+                .unwrap()
+        })
         .collect::<Vec<GameVersionOption>>();
     drop(process_manager_lock);
     drop(state_lock);
@@ -229,7 +458,7 @@ pub fn fetch_game_verion_options<'a>(
     game_id: String,
     state: tauri::State<'_, Mutex<AppState>>,
 ) -> Result<Vec<GameVersionOption>, String> {
-    fetch_game_verion_options_logic(game_id, state).map_err(|e| e.to_string())
+    fetch_game_verion_options_logic(game_id, &state).map_err(|e| e.to_string())
 }
 
 pub fn on_game_complete(
@@ -249,13 +478,12 @@ pub fn on_game_complete(
         )
         .as_str(),
     )?;
-    let header = generate_authorization_header();
-
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .get(endpoint.to_string())
-        .header("Authorization", header)
-        .send()?;
+    let client = blocking_http_client();
+    let response = send_throttled(RequestClass::Metadata, || {
+        client
+            .get(endpoint.to_string())
+            .header("Authorization", generate_authorization_header())
+    })?;
 
     let data = response.json::<GameVersion>()?;
 
@@ -286,6 +514,8 @@ pub fn on_game_complete(
         .games
         .statuses
         .insert(game_id.clone(), status.clone());
+    // Whatever was there before, it's now the new server's version
+    db_handle.games.unverified.remove(&game_id);
     drop(db_handle);
     DB.save().unwrap();
     app_handle
@@ -300,3 +530,207 @@ pub fn on_game_complete(
 
     Ok(())
 }
+
+fn set_game_flag(
+    app_handle: &AppHandle,
+    game_id: String,
+    hidden: Option<bool>,
+    favourite: Option<bool>,
+) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let games = &mut db_lock.games;
+    let flags = [
+        (hidden, &mut games.hidden),
+        (favourite, &mut games.favourites),
+    ];
+    for (value, set) in flags {
+        match value {
+            Some(true) => {
+                set.insert(game_id.clone());
+            }
+            Some(false) => {
+                set.remove(&game_id);
+            }
+            None => {}
+        }
+    }
+    let event = GameFlagsEvent {
+        hidden: db_lock.games.hidden.contains(&game_id),
+        favourite: db_lock.games.favourites.contains(&game_id),
+        game_id,
+    };
+    drop(db_lock);
+    DB.save().unwrap();
+
+    app_handle.emit("update_game_flags", event).unwrap();
+}
+
+#[tauri::command]
+pub fn set_game_hidden(app_handle: AppHandle, game_id: String, hidden: bool) {
+    set_game_flag(&app_handle, game_id, Some(hidden), None);
+}
+
+#[tauri::command]
+pub fn set_game_favourite(app_handle: AppHandle, game_id: String, favourite: bool) {
+    set_game_flag(&app_handle, game_id, None, Some(favourite));
+}
+
+fn query_library_logic(
+    app: AppHandle,
+    query: LibraryQuery,
+) -> Result<LibraryPage, RemoteAccessError> {
+    let state = app.state::<Mutex<AppState>>();
+    // Only goes to the server if the library hasn't been loaded yet
+    if state.lock().unwrap().games.is_empty() {
+        fetch_library_logic(app.clone())?;
+    }
+    let games: Vec<Game> = state.lock().unwrap().games.values().cloned().collect();
+
+    let search = query
+        .search
+        .as_ref()
+        .map(|search| search.trim().to_lowercase());
+    let db_lock = DB.borrow_data().unwrap();
+    let collection = match &query.collection {
+        Some(id) => db_lock
+            .games
+            .collections
+            .iter()
+            .find(|collection| collection.id == *id)
+            .map(|collection| collection.game_ids.clone()),
+        None => None,
+    };
+    let sort_value = |id: &String| {
+        let playtime = db_lock.games.playtime.get(id);
+        match query.sort {
+            LibrarySort::Name => 0,
+            LibrarySort::Size => db_lock
+                .games
+                .install_manifests
+                .get(id)
+                .map(|manifest| manifest.size)
+                .unwrap_or(0),
+            LibrarySort::LastPlayed => playtime.map_or(0, |playtime| playtime.last_played),
+            LibrarySort::Playtime => playtime.map_or(0, |playtime| playtime.total_seconds),
+        }
+    };
+
+    let mut games: Vec<Game> = games
+        .into_iter()
+        // DLC shows up under its game instead
+        .filter(|game| !db_lock.games.dlc_parents.contains_key(&game.id))
+        .filter(|game| query.include_hidden || !db_lock.games.hidden.contains(&game.id))
+        .filter(|game| !query.favourites_only || db_lock.games.favourites.contains(&game.id))
+        .filter(|game| match query.installed {
+            Some(installed) => {
+                let status = db_lock.games.statuses.get(&game.id);
+                status.and_then(GameStatus::installed).is_some() == installed
+            }
+            None => true,
+        })
+        .filter(|game| match &query.tag {
+            Some(tag) => db_lock
+                .games
+                .tags
+                .get(&game.id)
+                .is_some_and(|tags| tags.contains(tag)),
+            None => true,
+        })
+        .filter(|game| match &collection {
+            Some(game_ids) => game_ids.contains(&game.id),
+            None => query.collection.is_none(),
+        })
+        .filter(|game| match &search {
+            Some(search) => {
+                game.m_name.to_lowercase().contains(search)
+                    || game.m_short_description.to_lowercase().contains(search)
+            }
+            None => true,
+        })
+        .collect();
+
+    // Name always breaks ties, so pages don't shuffle between calls.
+    // Each game's key is only worked out once, not on every comparison.
+    games.sort_by_cached_key(|game| (sort_value(&game.id), game.m_name.to_lowercase()));
+    if query.descending {
+        games.reverse();
+    }
+    drop(db_lock);
+
+    let total = games.len();
+    let games = games
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .map(|game| FetchGameStruct {
+            status: GameStatusManager::fetch_state(&game.id),
+            game: game.with_flags(),
+        })
+        .collect();
+
+    Ok(LibraryPage { games, total })
+}
+
+#[tauri::command]
+pub fn query_library(app: AppHandle, query: LibraryQuery) -> Result<LibraryPage, String> {
+    query_library_logic(app, query).map_err(|e| e.to_string())
+}
+
+// Swaps in the new metadata, dropping images from before and after so
+// they're downloaded again next time they're shown
+fn replace_metadata(app: &AppHandle, game: Game) {
+    let state = app.state::<Mutex<AppState>>();
+    let old_game = state
+        .lock()
+        .unwrap()
+        .games
+        .insert(game.id.clone(), game.clone());
+    for object_id in old_game
+        .iter()
+        .flat_map(Game::image_ids)
+        .chain(game.image_ids())
+    {
+        remove_object(object_id);
+    }
+
+    app.emit(
+        &format!("update_metadata/{}", game.id),
+        MetadataUpdateEvent {
+            game: game.with_flags(),
+        },
+    )
+    .unwrap();
+}
+
+pub fn refresh_metadata_logic(
+    app: AppHandle,
+    game_id: Option<String>,
+) -> Result<(), RemoteAccessError> {
+    let games = match game_id {
+        Some(game_id) => {
+            let (game, changed) = fetch_remote_game(&game_id)?;
+            changed.then(|| vec![game])
+        }
+        None => {
+            let (games, changed) = fetch_remote_library()?;
+            changed.then_some(games)
+        }
+    };
+    // Unchanged, so there's nothing to redraw
+    let games = match games {
+        Some(games) => games,
+        None => return Ok(()),
+    };
+    for game in games {
+        write_metadata(&game_cache_key(&game.id), &game);
+        replace_metadata(&app, game);
+    }
+
+    Ok(())
+}
+
+/// Fetches a game's details again, or the whole library's without a game id
+#[tauri::command]
+pub fn refresh_metadata(app: AppHandle, game_id: Option<String>) -> Result<(), String> {
+    refresh_metadata_logic(app, game_id).map_err(|e| e.to_string())
+}
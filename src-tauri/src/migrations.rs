@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use log::{info, warn};
+use serde_json::{Map, Value};
+
+use crate::{
+    db::{files_size, installed_files_path, write_installed_files},
+    downloads::manifest::DropManifest,
+};
+
+/// Turns the database from one schema version into the next, working on
+/// the raw JSON so fields can be renamed or restructured before serde sees
+/// them
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// Index n takes the database from version n to n + 1. Once one has
+/// shipped it mustn't change; anything else goes in a new one at the end.
+const MIGRATIONS: &[Migration] = &[
+    // Databases from before the schema version was stored. Everything up to
+    // here was added with serde defaults, so there's nothing to change.
+    |_| Ok(()),
+    gather_game_settings,
+    move_installed_files,
+];
+
+/// What databases created by this build are at
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+// Launch options, auto update overrides and queued downloads' bandwidth
+// limits were each kept on their own, and now all go in games.gameSettings
+fn gather_game_settings(database: &mut Value) -> Result<(), String> {
+    let games = match database.get_mut("games").and_then(Value::as_object_mut) {
+        Some(games) => games,
+        None => return Ok(()),
+    };
+
+    let mut game_settings = Map::new();
+    let mut set = |game_id: &str, field: &str, value: Value| {
+        game_settings
+            .entry(game_id)
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .unwrap()
+            .insert(field.to_string(), value);
+    };
+    if let Some(Value::Object(launch_options)) = games.remove("launchOptions") {
+        for (game_id, options) in launch_options {
+            set(&game_id, "launch", options);
+        }
+    }
+    if let Some(Value::Object(auto_update)) = games.remove("autoUpdate") {
+        for (game_id, enabled) in auto_update {
+            set(&game_id, "autoUpdate", enabled);
+        }
+    }
+    if let Some(Value::Array(queue)) = games.get_mut("downloadQueue") {
+        for queued in queue.iter_mut().filter_map(Value::as_object_mut) {
+            let limit = queued
+                .remove("bandwidthLimit")
+                .filter(|limit| !limit.is_null());
+            if let (Some(game_id), Some(limit)) =
+                (queued.get("gameId").and_then(Value::as_str), limit)
+            {
+                set(game_id, "bandwidthLimit", limit);
+            }
+        }
+    }
+
+    games.insert("gameSettings".to_string(), Value::Object(game_settings));
+    Ok(())
+}
+
+// Each install's file list was kept in the database, and now goes
+// in the install directory with only its size and hash kept here
+fn move_installed_files(database: &mut Value) -> Result<(), String> {
+    let manifests = match database
+        .pointer_mut("/games/installManifests")
+        .and_then(Value::as_object_mut)
+    {
+        Some(manifests) => manifests,
+        None => return Ok(()),
+    };
+
+    for (game_id, manifest) in manifests.iter_mut() {
+        let manifest = match manifest.as_object_mut() {
+            Some(manifest) => manifest,
+            None => continue,
+        };
+        let files: DropManifest = manifest
+            .remove("files")
+            .and_then(|files| serde_json::from_value(files).ok())
+            .unwrap_or_default();
+        let install_dir = manifest
+            .get("installDir")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        // Without it, anything that checks the files just goes without
+        let path = installed_files_path(Path::new(install_dir), game_id);
+        let files_hash = if Path::new(install_dir).is_dir() {
+            match write_installed_files(&path, &files) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    warn!("couldn't write {}: {}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        manifest.insert("size".to_string(), files_size(&files).into());
+        manifest.insert("filesHash".to_string(), files_hash.into());
+    }
+    Ok(())
+}
+
+/// Runs whichever migrations the database hasn't had yet, in order.
+/// Returns the version it started at.
+pub fn migrate(database: &mut Value) -> Result<u32, String> {
+    let object = database
+        .as_object_mut()
+        .ok_or("the database isn't a JSON object")?;
+    let version = match object.get("schemaVersion") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or("the database's schema version isn't a number")? as u32,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "the database is from a newer version of Drop (schema {}, this build reads up to {})",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(database).map_err(|e| format!("migrating from schema {}: {}", from, e))?;
+        info!("migrated database from schema {} to {}", from, from + 1);
+    }
+    database
+        .as_object_mut()
+        .unwrap()
+        .insert("schemaVersion".to_string(), SCHEMA_VERSION.into());
+
+    Ok(version)
+}
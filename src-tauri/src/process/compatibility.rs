@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    fs::create_dir_all,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::{db::Database, profiles::profile_dir};
+
+/// Runs Windows builds on Linux
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CompatibilityLayer {
+    /// A wine binary, e.g. /usr/bin/wine
+    Wine { path: String },
+    /// A Proton directory, the one with the `proton` script in it
+    Proton { path: String },
+}
+
+impl CompatibilityLayer {
+    fn executable(&self) -> PathBuf {
+        match self {
+            CompatibilityLayer::Wine { path } => PathBuf::from(path),
+            CompatibilityLayer::Proton { path } => Path::new(path).join("proton"),
+        }
+    }
+
+    pub fn is_installed(&self) -> bool {
+        self.executable().is_file()
+    }
+}
+
+/// Overrides for a single game. Anything left unset
+/// falls back to the global settings and defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GameCompatibility {
+    pub layer: Option<CompatibilityLayer>,
+    // Defaults to a prefix of the game's own in the data directory
+    pub prefix: Option<String>,
+    // Extra variables for the game, e.g. WINEDLLOVERRIDES
+    pub env: HashMap<String, String>,
+    // Id of the profile the game uses, which the settings above override
+    pub profile: Option<String>,
+}
+
+/// Compatibility settings saved under a name, so several games can share
+/// them. Unlike a game's own settings, the env and args apply to native
+/// games too.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompatibilityProfile {
+    pub id: String,
+    pub name: String,
+    pub layer: Option<CompatibilityLayer>,
+    pub env: HashMap<String, String>,
+    // Go before the game's own launch arguments, with the same templates
+    pub args: String,
+    // What the game is run through, e.g. "gamemoderun mangohud"
+    pub wrapper: String,
+}
+
+pub fn profile_for<'a>(db: &'a Database, game_id: &str) -> Option<&'a CompatibilityProfile> {
+    let profile_id = db.games.compatibility.get(game_id)?.profile.as_ref()?;
+    db.games
+        .compatibility_profiles
+        .iter()
+        .find(|profile| profile.id == *profile_id)
+}
+
+pub fn layer_for(db: &Database, game_id: &str) -> Option<CompatibilityLayer> {
+    db.games
+        .compatibility
+        .get(game_id)
+        .and_then(|compatibility| compatibility.layer.clone())
+        .or_else(|| profile_for(db, game_id).and_then(|profile| profile.layer.clone()))
+        .or_else(|| db.settings.compatibility_layer.clone())
+}
+
+/// Runs `command` through the wrapper's programs instead, the first one
+/// being what's started
+pub fn wrap_command(command: Command, wrapper: &[String]) -> Command {
+    let (program, wrapper_args) = match wrapper.split_first() {
+        Some(split) => split,
+        None => return command,
+    };
+
+    let mut wrapped = Command::new(program);
+    wrapped
+        .args(wrapper_args)
+        .arg(command.get_program())
+        .args(command.get_args());
+    for (name, value) in command.get_envs() {
+        match value {
+            Some(value) => wrapped.env(name, value),
+            None => wrapped.env_remove(name),
+        };
+    }
+    wrapped
+}
+
+pub fn prefix_for(db: &Database, game_id: &str) -> PathBuf {
+    match db
+        .games
+        .compatibility
+        .get(game_id)
+        .and_then(|compatibility| compatibility.prefix.as_ref())
+    {
+        Some(prefix) => PathBuf::from(prefix),
+        None => profile_dir().join("prefixes").join(game_id),
+    }
+}
+
+/// Builds the command that runs `executable` through the layer, creating
+/// the prefix if this is the first time the game has been run
+pub fn compatibility_command(
+    layer: &CompatibilityLayer,
+    prefix: &Path,
+    executable: &str,
+    args: &[String],
+) -> io::Result<Command> {
+    if !layer.is_installed() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} doesn't exist", layer.executable().display()),
+        ));
+    }
+    create_dir_all(prefix)?;
+
+    let mut command = Command::new(layer.executable());
+    match layer {
+        CompatibilityLayer::Wine { .. } => {
+            command.env("WINEPREFIX", prefix);
+        }
+        CompatibilityLayer::Proton { .. } => {
+            // Proton keeps its own wine prefix inside this, in pfx/
+            command.arg("run").env("STEAM_COMPAT_DATA_PATH", prefix);
+            // Proton expects to find a Steam install, but runs without one
+            let steam = BaseDirs::new()
+                .map(|dirs| dirs.home_dir().join(".steam/steam"))
+                .filter(|steam| steam.exists())
+                .unwrap_or_else(|| prefix.to_path_buf());
+            command.env("STEAM_COMPAT_CLIENT_INSTALL_PATH", steam);
+        }
+    }
+    command.arg(executable).args(args);
+    Ok(command)
+}
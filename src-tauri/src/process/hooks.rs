@@ -0,0 +1,145 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{db::GameStatus, game_settings::settings_for, DB};
+
+use super::playtime::ProcessTree;
+
+/// How often a running hook is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug)]
+pub enum HookStage {
+    PreLaunch,
+    PostExit,
+}
+
+impl HookStage {
+    fn name(&self) -> &'static str {
+        match self {
+            HookStage::PreLaunch => "pre-launch",
+            HookStage::PostExit => "post-exit",
+        }
+    }
+}
+
+/// Runs the game's script for this stage, if it has one. Scripts run
+/// through the system shell in the install directory, and are killed along
+/// with everything they started if they take longer than the hook timeout
+/// setting. Blocks until then, so it's never called on the main thread.
+pub fn run_hook(game_id: &str, stage: HookStage) -> Result<(), String> {
+    let db_lock = DB.borrow_data().unwrap();
+    let options = settings_for(&db_lock, game_id).launch;
+    let script = match stage {
+        HookStage::PreLaunch => options.pre_launch,
+        HookStage::PostExit => options.post_exit,
+    }
+    .filter(|script| !script.trim().is_empty());
+    let script = match script {
+        Some(script) => script,
+        None => return Ok(()),
+    };
+    let (version_name, install_dir) = db_lock
+        .games
+        .statuses
+        .get(game_id)
+        .and_then(GameStatus::installed)
+        .map(|(version_name, install_dir)| (version_name.clone(), install_dir.clone()))
+        .ok_or("Game not installed.")?;
+    let timeout = Duration::from_secs(db_lock.settings.hook_timeout);
+    drop(db_lock);
+
+    info!("running {} hook for {}", stage.name(), game_id);
+    let mut command = shell_command(&script);
+    // Its own process group, so whatever it starts can be killed along with it
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command
+        .current_dir(Path::new(&install_dir))
+        .env("DROP_GAME_ID", game_id)
+        .env("DROP_GAME_VERSION", &version_name)
+        .env("DROP_INSTALL_DIR", &install_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Couldn't run {} hook: {}", stage.name(), e))?;
+
+    let tree = ProcessTree::new(&child)
+        .inspect_err(|e| warn!("can't follow what the {} hook starts: {}", stage.name(), e))
+        .ok();
+
+    let prefix = format!("{} {}", game_id, stage.name());
+    let stdout = log_output(prefix.clone(), child.stdout.take().unwrap(), false);
+    let stderr = log_output(prefix, child.stderr.take().unwrap(), true);
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {}
+            Err(e) => return Err(format!("Lost track of {} hook: {}", stage.name(), e)),
+        }
+        if started.elapsed() >= timeout {
+            if let Some(tree) = &tree {
+                tree.kill();
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!(
+                "{} hook didn't finish within {} seconds",
+                stage.name(),
+                timeout.as_secs()
+            ));
+        }
+        sleep(POLL_INTERVAL);
+    };
+    // Anything the script started can hold these open, so they're not waited on
+    drop((stdout, stderr));
+
+    if !status.success() {
+        return Err(format!("{} hook failed with {}", stage.name(), status));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell_command(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    command
+}
+
+#[cfg(windows)]
+fn shell_command(script: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(script);
+    command
+}
+
+// Hooks' output goes into the app log, a line at a time
+fn log_output<R: Read + Send + 'static>(prefix: String, output: R, error: bool) -> JoinHandle<()> {
+    spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if error {
+                warn!("[{}] {}", prefix, line);
+            } else {
+                info!("[{}] {}", prefix, line);
+            }
+        }
+    })
+}
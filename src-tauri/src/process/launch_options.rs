@@ -0,0 +1,102 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// How the user wants a game started, on top of what the server says
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LaunchOptions {
+    // Added after the game's own arguments, split like a shell would.
+    // {install_dir}, {game_id} and {version} are filled in at launch.
+    pub args: String,
+    // Set for the game on top of anything the compatibility layer sets,
+    // e.g. DXVK_HUD. Values take the same templates as the arguments.
+    pub env: HashMap<String, String>,
+    // Shell commands run in the install directory before the game
+    // starts and after it exits, see hooks.rs
+    pub pre_launch: Option<String>,
+    pub post_exit: Option<String>,
+}
+
+/// What the templates in a game's launch arguments are filled in with
+pub struct LaunchContext<'a> {
+    pub game_id: &'a str,
+    pub version_name: &'a str,
+    pub install_dir: &'a Path,
+}
+
+impl LaunchOptions {
+    pub fn launch_args(&self, context: &LaunchContext) -> Result<Vec<String>, String> {
+        Ok(split_args(&self.args)?
+            .iter()
+            .map(|arg| fill_template(arg, context))
+            .collect())
+    }
+
+    pub fn launch_env(&self, context: &LaunchContext) -> HashMap<String, String> {
+        self.env
+            .iter()
+            .map(|(name, value)| (name.clone(), fill_template(value, context)))
+            .collect()
+    }
+}
+
+/// Names the OS would refuse, or read as something else
+pub fn check_env_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Err(format!("{:?} can't be used as a variable name", name));
+    }
+    Ok(())
+}
+
+fn fill_template(arg: &str, context: &LaunchContext) -> String {
+    arg.replace("{install_dir}", &context.install_dir.to_string_lossy())
+        .replace("{game_id}", context.game_id)
+        .replace("{version}", context.version_name)
+}
+
+/// Splits arguments on whitespace, keeping anything in single or double
+/// quotes together. A backslash outside single quotes escapes what follows.
+pub fn split_args(args: &str) -> Result<Vec<String>, String> {
+    let mut split = Vec::new();
+    let mut current = String::new();
+    // Quotes can make an argument that's empty, so this can't go by `current`
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = args.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\'', None) | ('"', None) => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            ('\\', None) | ('\\', Some('"')) => match chars.next() {
+                Some(escaped) => {
+                    current.push(escaped);
+                    in_arg = true;
+                }
+                None => return Err("Arguments can't end with a backslash".to_string()),
+            },
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    split.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (c, _) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("Arguments have a quote that isn't closed".to_string());
+    }
+    if in_arg {
+        split.push(current);
+    }
+    Ok(split)
+}
@@ -1,2 +1,7 @@
+pub mod compatibility;
+pub mod hooks;
+pub mod launch_options;
+pub mod playtime;
 pub mod process_manager;
-pub mod process_commands;
\ No newline at end of file
+pub mod process_commands;
+pub mod save_sync;
\ No newline at end of file
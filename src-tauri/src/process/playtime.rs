@@ -0,0 +1,246 @@
+use std::{
+    collections::HashSet,
+    io,
+    process::Child,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+use crate::DB;
+
+/// How long a game has been played for, in seconds
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Playtime {
+    pub total_seconds: u64,
+    // Seconds since the unix epoch, 0 if it's never been played
+    pub last_played: u64,
+    // How many times it's been launched
+    #[serde(default)]
+    pub sessions: u64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaytimeUpdateEvent {
+    pub game_id: String,
+    pub playtime: Playtime,
+    pub running: bool,
+}
+
+/// How often a running game's playtime is saved and reported,
+/// so a crash (the game's or ours) loses at most this much
+const PLAYTIME_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the monitor checks whether the game is still running
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts playtime until the game, and anything it started, has exited.
+/// Launchers often start the real game and exit straight away, so the
+/// game's own process finishing isn't enough.
+pub fn monitor_game(
+    app_handle: AppHandle,
+    game_id: String,
+    mut child: Child,
+    processes: Arc<Mutex<HashSet<String>>>,
+) {
+    let tree = match ProcessTree::new(&child) {
+        Ok(tree) => Some(tree),
+        Err(e) => {
+            warn!("can't follow processes started by {}: {}", game_id, e);
+            None
+        }
+    };
+
+    record_playtime(&app_handle, &game_id, 0, true, true);
+    let mut unsaved_since = Instant::now();
+    let mut exited = false;
+    loop {
+        if !exited {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("{} exited with {}", game_id, status);
+                    exited = true;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("lost track of {}: {}", game_id, e);
+                    exited = true;
+                }
+            }
+        }
+        if exited && !tree.as_ref().is_some_and(|tree| tree.is_alive()) {
+            break;
+        }
+
+        sleep(POLL_INTERVAL);
+        if unsaved_since.elapsed() >= PLAYTIME_SAVE_INTERVAL {
+            let seconds = unsaved_since.elapsed().as_secs();
+            record_playtime(&app_handle, &game_id, seconds, true, false);
+            // Keeps the part second for next time
+            unsaved_since += Duration::from_secs(seconds);
+        }
+    }
+
+    processes.lock().unwrap().remove(&game_id);
+    let seconds = unsaved_since.elapsed().as_secs();
+    record_playtime(&app_handle, &game_id, seconds, false, false);
+}
+
+fn record_playtime(
+    app_handle: &AppHandle,
+    game_id: &str,
+    seconds: u64,
+    running: bool,
+    new_session: bool,
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let playtime = db_lock
+        .games
+        .playtime
+        .entry(game_id.to_string())
+        .or_default();
+    playtime.total_seconds += seconds;
+    playtime.last_played = now;
+    if new_session {
+        playtime.sessions += 1;
+    }
+    let playtime = playtime.clone();
+    drop(db_lock);
+    DB.save().unwrap();
+
+    app_handle
+        .emit(
+            "playtime_update",
+            PlaytimeUpdateEvent {
+                game_id: game_id.to_string(),
+                playtime,
+                running,
+            },
+        )
+        .unwrap();
+}
+
+/// A launched game (or hook) along with everything it starts. On unix that's
+/// its process group, which it's put in its own of when it's spawned.
+#[cfg(unix)]
+pub(super) struct ProcessTree {
+    group: i32,
+}
+
+#[cfg(unix)]
+impl ProcessTree {
+    pub(super) fn new(child: &Child) -> io::Result<Self> {
+        Ok(Self {
+            group: child.id() as i32,
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        // SAFETY: signal 0 only checks whether the group exists
+        if unsafe { libc::kill(-self.group, 0) } == 0 {
+            return true;
+        }
+        // There, but not ours to signal
+        io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    pub(super) fn kill(&self) {
+        // SAFETY: the group is the child's own, so nothing else is signalled
+        unsafe { libc::kill(-self.group, libc::SIGKILL) };
+    }
+}
+
+/// On Windows, a job object the game is added to once it's started.
+/// Anything it starts from then on joins the job too.
+#[cfg(windows)]
+pub(super) struct ProcessTree {
+    job: windows_sys::Win32::Foundation::HANDLE,
+}
+
+// SAFETY: job handles can be used from any thread
+#[cfg(windows)]
+unsafe impl Send for ProcessTree {}
+
+#[cfg(windows)]
+impl ProcessTree {
+    pub(super) fn new(child: &Child) -> io::Result<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+
+        // SAFETY: no security attributes or name are given
+        let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let tree = Self { job };
+        // SAFETY: both handles are valid, and the child's outlives the call
+        if unsafe { AssignProcessToJobObject(tree.job, child.as_raw_handle() as _) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(tree)
+    }
+
+    fn is_alive(&self) -> bool {
+        use windows_sys::Win32::System::JobObjects::{
+            JobObjectBasicAccountingInformation, QueryInformationJobObject,
+            JOBOBJECT_BASIC_ACCOUNTING_INFORMATION,
+        };
+
+        // SAFETY: all zeroes is a valid value for this plain data struct
+        let mut info: JOBOBJECT_BASIC_ACCOUNTING_INFORMATION = unsafe { std::mem::zeroed() };
+        // SAFETY: the buffer is the size we say it is
+        let succeeded = unsafe {
+            QueryInformationJobObject(
+                self.job,
+                JobObjectBasicAccountingInformation,
+                &mut info as *mut _ as _,
+                std::mem::size_of::<JOBOBJECT_BASIC_ACCOUNTING_INFORMATION>() as u32,
+                std::ptr::null_mut(),
+            )
+        };
+        succeeded != 0 && info.ActiveProcesses > 0
+    }
+
+    pub(super) fn kill(&self) {
+        use windows_sys::Win32::System::JobObjects::TerminateJobObject;
+
+        // SAFETY: the handle is ours and still open
+        unsafe { TerminateJobObject(self.job, 1) };
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        // SAFETY: the handle is ours and isn't used again
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.job) };
+    }
+}
+
+/// Elsewhere, only the game's own process is followed
+#[cfg(not(any(unix, windows)))]
+pub(super) struct ProcessTree;
+
+#[cfg(not(any(unix, windows)))]
+impl ProcessTree {
+    pub(super) fn new(_child: &Child) -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    fn is_alive(&self) -> bool {
+        false
+    }
+
+    pub(super) fn kill(&self) {}
+}
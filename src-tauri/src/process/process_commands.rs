@@ -1,9 +1,40 @@
-use std::sync::Mutex;
+use std::{collections::HashMap, sync::Mutex, thread::spawn};
 
-use crate::AppState;
+use tauri::{AppHandle, Manager};
+use uuid::Uuid;
+
+use crate::{
+    game_settings::{game_settings, update_game_settings},
+    AppState, DB,
+};
+
+use super::compatibility::{CompatibilityLayer, CompatibilityProfile, GameCompatibility};
+use super::hooks::{run_hook, HookStage};
+use super::launch_options::{check_env_name, split_args};
+use super::playtime::Playtime;
+use super::save_sync::{resolve_conflict, sync_saves, SaveSide};
 
 #[tauri::command]
-pub fn launch_game(game_id: String, state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
+pub async fn launch_game(app_handle: AppHandle, game_id: String) -> Result<(), String> {
+    // Syncing saves and running hooks can take a while, so they're kept
+    // off the main thread
+    tauri::async_runtime::spawn_blocking(move || start_game(&app_handle, game_id))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Syncs saves, runs the pre-launch hook and starts the game, blocking until it's started
+pub fn start_game(app_handle: &AppHandle, game_id: String) -> Result<(), String> {
+    let state = app_handle.state::<Mutex<AppState>>();
+    let process_manager = state.lock().unwrap().process_manager.clone();
+    if process_manager.lock().unwrap().is_running(&game_id) {
+        return Err("Game or setup is already running.".to_owned());
+    }
+    // The game has to see the latest saves, so this finishes first
+    sync_saves(app_handle, &game_id);
+    // Whatever the script sets up, the game may well need
+    run_hook(&game_id, HookStage::PreLaunch)?;
+
     let state_lock = state.lock().unwrap();
     let mut process_manager_lock = state_lock.process_manager.lock().unwrap();
 
@@ -14,3 +45,254 @@ pub fn launch_game(game_id: String, state: tauri::State<'_, Mutex<AppState>>) ->
 
     Ok(())
 }
+
+#[tauri::command]
+pub fn set_compatibility_layer(layer: Option<CompatibilityLayer>) -> Result<(), String> {
+    if layer.as_ref().is_some_and(|layer| !layer.is_installed()) {
+        return Err("That Wine or Proton install couldn't be found".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.compatibility_layer = layer;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_game_compatibility(
+    game_id: String,
+    compatibility: GameCompatibility,
+) -> Result<(), String> {
+    if compatibility
+        .layer
+        .as_ref()
+        .is_some_and(|layer| !layer.is_installed())
+    {
+        return Err("That Wine or Proton install couldn't be found".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.games.compatibility.insert(game_id, compatibility);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_game_compatibility(game_id: String) -> GameCompatibility {
+    DB.borrow_data()
+        .unwrap()
+        .games
+        .compatibility
+        .get(&game_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn set_launch_args(game_id: String, args: String) -> Result<(), String> {
+    // Caught now, rather than when the game won't start
+    split_args(&args)?;
+
+    update_game_settings(&game_id, |settings| settings.launch.args = args);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_launch_args(game_id: String) -> String {
+    game_settings(&game_id).launch.args
+}
+
+#[tauri::command]
+pub fn set_launch_env(game_id: String, env: HashMap<String, String>) -> Result<(), String> {
+    for name in env.keys() {
+        check_env_name(name)?;
+    }
+
+    update_game_settings(&game_id, |settings| settings.launch.env = env);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_launch_env(game_id: String) -> HashMap<String, String> {
+    game_settings(&game_id).launch.env
+}
+
+#[tauri::command]
+pub fn set_launch_hooks(game_id: String, pre_launch: Option<String>, post_exit: Option<String>) {
+    update_game_settings(&game_id, |settings| {
+        settings.launch.pre_launch = pre_launch;
+        settings.launch.post_exit = post_exit;
+    });
+}
+
+#[tauri::command]
+pub fn set_hook_timeout(seconds: u64) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.hook_timeout = seconds;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+fn check_profile(profile: &CompatibilityProfile) -> Result<(), String> {
+    if profile.name.trim().is_empty() {
+        return Err("Profiles need a name".to_string());
+    }
+    if profile
+        .layer
+        .as_ref()
+        .is_some_and(|layer| !layer.is_installed())
+    {
+        return Err("That Wine or Proton install couldn't be found".to_string());
+    }
+    split_args(&profile.args)?;
+    split_args(&profile.wrapper)?;
+    for name in profile.env.keys() {
+        check_env_name(name)?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_compatibility_profiles() -> Vec<CompatibilityProfile> {
+    DB.borrow_data()
+        .unwrap()
+        .games
+        .compatibility_profiles
+        .clone()
+}
+
+/// Creates the profile if it has no id yet, otherwise replaces the one with its id
+#[tauri::command]
+pub fn save_compatibility_profile(
+    mut profile: CompatibilityProfile,
+) -> Result<CompatibilityProfile, String> {
+    check_profile(&profile)?;
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let profiles = &mut db_lock.games.compatibility_profiles;
+    if profile.id.is_empty() {
+        profile.id = Uuid::new_v4().to_string();
+        profiles.push(profile.clone());
+    } else {
+        let existing = profiles
+            .iter_mut()
+            .find(|existing| existing.id == profile.id)
+            .ok_or("Profile doesn't exist".to_string())?;
+        *existing = profile.clone();
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(profile)
+}
+
+#[tauri::command]
+pub fn delete_compatibility_profile(profile_id: String) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock
+        .games
+        .compatibility_profiles
+        .retain(|profile| profile.id != profile_id);
+    // Games using it go back to their own settings
+    for compatibility in db_lock.games.compatibility.values_mut() {
+        if compatibility.profile.as_ref() == Some(&profile_id) {
+            compatibility.profile = None;
+        }
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+/// Sets which profile the games use, or stops them using one with None
+#[tauri::command]
+pub fn apply_compatibility_profile(
+    profile_id: Option<String>,
+    game_ids: Vec<String>,
+) -> Result<(), String> {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let exists = |id: &String| {
+        db_lock
+            .games
+            .compatibility_profiles
+            .iter()
+            .any(|profile| profile.id == *id)
+    };
+    if profile_id.as_ref().is_some_and(|id| !exists(id)) {
+        return Err("Profile doesn't exist".to_string());
+    }
+    for game_id in game_ids {
+        db_lock
+            .games
+            .compatibility
+            .entry(game_id)
+            .or_default()
+            .profile = profile_id.clone();
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+
+    Ok(())
+}
+
+/// The profile as JSON, for sharing
+#[tauri::command]
+pub fn export_compatibility_profile(profile_id: String) -> Result<String, String> {
+    let db_lock = DB.borrow_data().unwrap();
+    let profile = db_lock
+        .games
+        .compatibility_profiles
+        .iter()
+        .find(|profile| profile.id == profile_id)
+        .ok_or("Profile doesn't exist".to_string())?;
+    serde_json::to_string_pretty(profile).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn import_compatibility_profile(data: String) -> Result<CompatibilityProfile, String> {
+    let mut profile: CompatibilityProfile =
+        serde_json::from_str(&data).map_err(|e| format!("Not a valid profile: {}", e))?;
+    profile.id = String::new();
+    // Wine and Proton live somewhere different on every machine,
+    // so that's left for the user to pick
+    if profile
+        .layer
+        .as_ref()
+        .is_some_and(|layer| !layer.is_installed())
+    {
+        profile.layer = None;
+    }
+    save_compatibility_profile(profile)
+}
+
+#[tauri::command]
+pub fn fetch_game_playtime(game_id: String) -> Playtime {
+    DB.borrow_data()
+        .unwrap()
+        .games
+        .playtime
+        .get(&game_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn set_cloud_saves(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.cloud_saves = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn sync_game_saves(app_handle: AppHandle, game_id: String) {
+    spawn(move || sync_saves(&app_handle, &game_id));
+}
+
+#[tauri::command]
+pub fn resolve_save_conflict(app_handle: AppHandle, game_id: String, keep: SaveSide) {
+    spawn(move || resolve_conflict(&app_handle, &game_id, keep));
+}
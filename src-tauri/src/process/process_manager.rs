@@ -1,31 +1,45 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{File, OpenOptions},
     io::{Stdout, Write},
     path::{Path, PathBuf},
-    process::{Child, Command},
-    sync::LazyLock,
+    process::Command,
+    sync::{Arc, LazyLock, Mutex},
+    thread::spawn,
 };
 
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
 
 use crate::{
-    db::{GameStatus, DATA_ROOT_DIR},
+    achievements::refresh_achievements,
+    db::GameStatus,
+    game_settings::settings_for,
+    profiles::profile_dir,
     DB,
 };
 
+use super::compatibility::{
+    compatibility_command, layer_for, prefix_for, profile_for, wrap_command,
+};
+use super::hooks::{run_hook, HookStage};
+use super::launch_options::{split_args, LaunchContext, LaunchOptions};
+use super::playtime::monitor_game;
+use super::save_sync::sync_saves;
+
 pub struct ProcessManager {
     current_platform: Platform,
     log_output_dir: PathBuf,
-    processes: HashMap<String, Child>,
+    // Games that are running, removed by their monitors as they exit
+    processes: Arc<Mutex<HashSet<String>>>,
+    app_handle: AppHandle,
 }
 
 impl ProcessManager {
-    pub fn new() -> Self {
-        let root_dir_lock = DATA_ROOT_DIR.lock().unwrap();
-        let log_output_dir = root_dir_lock.join("logs");
-        drop(root_dir_lock);
+    pub fn new(app_handle: AppHandle) -> Self {
+        // Each profile's games log to their own directory
+        let log_output_dir = profile_dir().join("logs");
 
         ProcessManager {
             current_platform: if cfg!(windows) {
@@ -34,8 +48,9 @@ impl ProcessManager {
                 Platform::Linux
             },
 
-            processes: HashMap::new(),
+            processes: Arc::new(Mutex::new(HashSet::new())),
             log_output_dir,
+            app_handle,
         }
     }
 
@@ -53,33 +68,57 @@ impl ProcessManager {
         (absolute_exe.to_str().unwrap().to_owned(), args)
     }
 
-    pub fn valid_platform(&self, platform: &Platform) -> Result<bool, String> {
+    pub fn valid_platform(&self, game_id: &str, platform: &Platform) -> Result<bool, String> {
         let current = &self.current_platform;
         let valid_platforms = PROCESS_COMPATABILITY_MATRIX
             .get(current)
             .ok_or("Incomplete platform compatability matrix.")?;
 
-        Ok(valid_platforms.contains(platform))
+        if valid_platforms.contains(platform) {
+            return Ok(true);
+        }
+
+        // Windows builds can run through Wine or Proton, once one is set up
+        let db_lock = DB.borrow_data().unwrap();
+        Ok(self.needs_compatibility_layer(platform) && layer_for(&db_lock, game_id).is_some())
+    }
+
+    fn needs_compatibility_layer(&self, platform: &Platform) -> bool {
+        self.current_platform == Platform::Linux && *platform == Platform::Windows
+    }
+
+    pub fn is_running(&self, game_id: &str) -> bool {
+        self.processes.lock().unwrap().contains(game_id)
     }
 
     pub fn launch_game(&mut self, game_id: String) -> Result<(), String> {
-        if self.processes.contains_key(&game_id) {
+        if self.processes.lock().unwrap().contains(&game_id) {
             return Err("Game or setup is already running.".to_owned());
         }
 
         let db_lock = DB.borrow_data().unwrap();
+        // Being moved, updated or the like, so its files aren't settled
+        if db_lock.games.transient_statuses.contains_key(&game_id) {
+            return Err("Game is busy, try again once it's finished".to_owned());
+        }
         let game_status = db_lock
             .games
             .statuses
             .get(&game_id)
             .ok_or("Game not installed")?;
 
-        let GameStatus::Installed {
-            version_name,
-            install_dir,
-        } = game_status
-        else {
-            return Err("Game not installed.".to_owned());
+        // An update being available doesn't stop the installed version running
+        let (version_name, install_dir) = match game_status {
+            GameStatus::Installed {
+                version_name,
+                install_dir,
+            }
+            | GameStatus::UpdateAvailable {
+                version_name,
+                install_dir,
+                ..
+            } => (version_name, install_dir),
+            _ => return Err("Game not installed.".to_owned()),
         };
 
         let game_version = db_lock
@@ -90,8 +129,24 @@ impl ProcessManager {
             .get(version_name)
             .ok_or("Invalid version name".to_owned())?;
 
-        let (command, args) =
+        let (command, mut args) =
             self.process_command(install_dir, game_version.launch_command.clone());
+        let context = LaunchContext {
+            game_id: &game_id,
+            version_name,
+            install_dir: Path::new(install_dir),
+        };
+        let settings = settings_for(&db_lock, &game_id);
+        let options = settings.launch;
+        let profile = profile_for(&db_lock, &game_id).cloned().unwrap_or_default();
+        // Templated the same way as the game's own
+        let profile_options = LaunchOptions {
+            args: profile.args,
+            env: profile.env,
+            ..Default::default()
+        };
+        args.extend(profile_options.launch_args(&context)?);
+        args.extend(options.launch_args(&context)?);
 
         info!("launching process {} in {}", command, install_dir);
 
@@ -120,15 +175,55 @@ impl ProcessManager {
 
         info!("opened log file for {}", command);
 
-        let launch_process = Command::new(command)
+        let uses_layer = self.needs_compatibility_layer(&game_version.platform);
+        let mut launch_command = if uses_layer {
+            let layer = layer_for(&db_lock, &game_id)
+                .ok_or("No Wine or Proton runtime is set up for this game.")?;
+            info!("running {} through {:?}", game_id, layer);
+            compatibility_command(&layer, &prefix_for(&db_lock, &game_id), &command, &args)
+                .map_err(|v| v.to_string())?
+        } else {
+            let mut launch_command = Command::new(command);
+            launch_command.args(args);
+            launch_command
+        };
+        // The game's own are set last, so they win over the profile's
+        // and the compatibility layer's
+        launch_command.envs(profile_options.launch_env(&context));
+        let compatibility = db_lock.games.compatibility.get(&game_id);
+        if let Some(compatibility) = compatibility.filter(|_| uses_layer) {
+            launch_command.envs(&compatibility.env);
+        }
+        launch_command.envs(options.launch_env(&context));
+        let launch_command = wrap_command(launch_command, &split_args(&settings.wrapper)?);
+        let mut launch_command = wrap_command(launch_command, &split_args(&profile.wrapper)?);
+
+        // Its own process group, so the playtime monitor can tell
+        // when everything the game started has exited too
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            launch_command.process_group(0);
+        }
+
+        let launch_process = launch_command
             .current_dir(install_dir)
             .stdout(log_file)
             .stderr(error_file)
-            .args(args)
             .spawn()
             .map_err(|v| v.to_string())?;
 
-        self.processes.insert(game_id, launch_process);
+        self.processes.lock().unwrap().insert(game_id.clone());
+        let app_handle = self.app_handle.clone();
+        let processes = self.processes.clone();
+        spawn(move || {
+            monitor_game(app_handle.clone(), game_id.clone(), launch_process, processes);
+            if let Err(e) = run_hook(&game_id, HookStage::PostExit) {
+                warn!("{}", e);
+            }
+            sync_saves(&app_handle, &game_id);
+            refresh_achievements(&app_handle, &game_id);
+        });
 
         Ok(())
     }
@@ -146,7 +241,8 @@ pub static PROCESS_COMPATABILITY_MATRIX: LazyLock<ProcessCompatabilityMatrix> =
         let mut matrix: ProcessCompatabilityMatrix = HashMap::new();
 
         matrix.insert(Platform::Windows, vec![Platform::Windows]);
-        matrix.insert(Platform::Linux, vec![Platform::Linux]); // TODO: add Proton support
+        // Windows builds are allowed too, when there's a compatibility layer for them
+        matrix.insert(Platform::Linux, vec![Platform::Linux]);
 
         return matrix;
     });
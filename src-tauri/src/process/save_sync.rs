@@ -0,0 +1,632 @@
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    fs::{self, create_dir_all, remove_file, rename, File},
+    io,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use directories::{BaseDirs, UserDirs};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use urlencoding::encode;
+
+use crate::{
+    auth::generate_authorization_header,
+    db::{DatabaseImpls, GameStatus},
+    remote::{blocking_http_client, server_supports, RemoteAccessError},
+    signing::sign_request,
+    DB,
+};
+
+/// A directory a game keeps its saves in, as declared by the server.
+/// `path` may start with {install}, {home}, {data}, {config} or {documents};
+/// without one it's relative to the game's install directory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveLocation {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveFile {
+    pub location: String,
+    // Relative to the location, separated by /
+    pub path: String,
+    pub size: u64,
+    // Seconds since the unix epoch
+    pub modified: u64,
+    // Blake3, in hex
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveManifest {
+    pub files: Vec<SaveFile>,
+}
+
+impl SaveManifest {
+    /// Identifies what's in the saves. Timestamps are left out, since
+    /// copying files between machines doesn't keep them.
+    fn content_hash(&self) -> String {
+        let mut files: Vec<&SaveFile> = self.files.iter().collect();
+        files.sort_by(|a, b| (&a.location, &a.path).cmp(&(&b.location, &b.path)));
+
+        let mut hasher = blake3::Hasher::new();
+        for file in files {
+            hasher.update(file.location.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(file.path.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(file.hash.as_bytes());
+            hasher.update(b"\n");
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|file| file.modified)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// The saves as they were the last time this machine and the server agreed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncedSaves {
+    pub hash: String,
+    // Seconds since the unix epoch
+    pub synced_at: u64,
+    // Location and path of every file that was synced, the only ones
+    // a download is allowed to remove
+    #[serde(default)]
+    pub files: HashSet<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum SaveSide {
+    Local,
+    Remote,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub enum SyncOutcome {
+    UpToDate,
+    Uploaded,
+    Downloaded,
+    // Both sides changed since they last matched, so neither was touched
+    Conflict,
+}
+
+#[derive(Debug)]
+pub enum SaveSyncError {
+    Remote(RemoteAccessError),
+    Io(io::Error),
+}
+
+impl Display for SaveSyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveSyncError::Remote(error) => write!(f, "{}", error),
+            SaveSyncError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<RemoteAccessError> for SaveSyncError {
+    fn from(error: RemoteAccessError) -> Self {
+        SaveSyncError::Remote(error)
+    }
+}
+impl From<reqwest::Error> for SaveSyncError {
+    fn from(error: reqwest::Error) -> Self {
+        SaveSyncError::Remote(error.into())
+    }
+}
+impl From<url::ParseError> for SaveSyncError {
+    fn from(error: url::ParseError) -> Self {
+        SaveSyncError::Remote(error.into())
+    }
+}
+impl From<io::Error> for SaveSyncError {
+    fn from(error: io::Error) -> Self {
+        SaveSyncError::Io(error)
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveSyncProgressEvent {
+    pub game_id: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveSyncCompleteEvent {
+    pub game_id: String,
+    pub outcome: Option<SyncOutcome>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveConflictEvent {
+    pub game_id: String,
+    pub local_modified: u64,
+    pub remote_modified: u64,
+}
+
+/// Brings the game's saves and the server's copy in line, whichever way
+/// they need to go. Run before a game launches and after it exits; a failure
+/// is reported but never stops the game from running.
+pub fn sync_saves(app_handle: &AppHandle, game_id: &str) {
+    if !DB.borrow_data().unwrap().settings.cloud_saves || !server_supports(app_handle, "saves") {
+        return;
+    }
+    let result = sync_game(app_handle, game_id, None);
+    report_sync(app_handle, game_id, result);
+}
+
+/// Settles a conflict by overwriting the other side with `keep`
+pub fn resolve_conflict(app_handle: &AppHandle, game_id: &str, keep: SaveSide) {
+    let result = sync_game(app_handle, game_id, Some(keep));
+    report_sync(app_handle, game_id, result);
+}
+
+fn report_sync(app_handle: &AppHandle, game_id: &str, result: Result<SyncOutcome, SaveSyncError>) {
+    let (outcome, error) = match result {
+        Ok(outcome) => {
+            info!("synced saves for {}: {:?}", game_id, outcome);
+            (Some(outcome), None)
+        }
+        Err(e) => {
+            warn!("failed to sync saves for {}: {}", game_id, e);
+            (None, Some(e.to_string()))
+        }
+    };
+    app_handle
+        .emit(
+            "save_sync_complete",
+            SaveSyncCompleteEvent {
+                game_id: game_id.to_string(),
+                outcome,
+                error,
+            },
+        )
+        .unwrap();
+}
+
+fn sync_game(
+    app_handle: &AppHandle,
+    game_id: &str,
+    force: Option<SaveSide>,
+) -> Result<SyncOutcome, SaveSyncError> {
+    let locations = save_locations(game_id);
+    if locations.is_empty() {
+        return Ok(SyncOutcome::UpToDate);
+    }
+
+    let local = scan_locations(&locations)?;
+    let remote = fetch_remote_manifest(game_id)?;
+    let local_hash = local.content_hash();
+    let remote_hash = remote.as_ref().map(SaveManifest::content_hash);
+
+    let side = match force {
+        Some(side) => side,
+        None => {
+            if remote_hash.as_ref() == Some(&local_hash) {
+                record_synced(game_id, local_hash, &local);
+                return Ok(SyncOutcome::UpToDate);
+            }
+
+            let last_synced = DB
+                .borrow_data()
+                .unwrap()
+                .games
+                .synced_saves
+                .get(game_id)
+                .map(|synced| synced.hash.clone());
+            let local_changed = last_synced.as_ref() != Some(&local_hash);
+            let remote_changed = remote_hash != last_synced;
+            match (local_changed, remote_changed) {
+                (_, false) => SaveSide::Local,
+                (false, true) => SaveSide::Remote,
+                // Nothing here yet, like on a new machine
+                (true, true) if local.files.is_empty() => SaveSide::Remote,
+                (true, true) if remote.is_none() => SaveSide::Local,
+                (true, true) => {
+                    app_handle
+                        .emit(
+                            "save_conflict",
+                            SaveConflictEvent {
+                                game_id: game_id.to_string(),
+                                local_modified: local.last_modified(),
+                                remote_modified: remote
+                                    .as_ref()
+                                    .map(SaveManifest::last_modified)
+                                    .unwrap_or(0),
+                            },
+                        )
+                        .unwrap();
+                    return Ok(SyncOutcome::Conflict);
+                }
+            }
+        }
+    };
+
+    match side {
+        SaveSide::Local => {
+            upload(app_handle, game_id, &locations, &local, remote.as_ref())?;
+            record_synced(game_id, local_hash, &local);
+            Ok(SyncOutcome::Uploaded)
+        }
+        SaveSide::Remote => {
+            let remote = remote.unwrap_or_default();
+            download(app_handle, game_id, &locations, &local, &remote)?;
+            record_synced(game_id, remote.content_hash(), &remote);
+            Ok(SyncOutcome::Downloaded)
+        }
+    }
+}
+
+fn record_synced(game_id: &str, hash: String, manifest: &SaveManifest) {
+    let synced_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let files = manifest
+        .files
+        .iter()
+        .map(|file| (file.location.clone(), file.path.clone()))
+        .collect();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.games.synced_saves.insert(
+        game_id.to_string(),
+        SyncedSaves {
+            hash,
+            synced_at,
+            files,
+        },
+    );
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+// The installed version's save locations, resolved to directories on this machine
+fn save_locations(game_id: &str) -> Vec<(String, PathBuf)> {
+    let db_lock = DB.borrow_data().unwrap();
+    let (version_name, install_dir) = match db_lock
+        .games
+        .statuses
+        .get(game_id)
+        .and_then(GameStatus::installed)
+    {
+        Some(installed) => installed,
+        None => return Vec::new(),
+    };
+    let version = match db_lock
+        .games
+        .versions
+        .get(game_id)
+        .and_then(|versions| versions.get(version_name))
+    {
+        Some(version) => version,
+        None => return Vec::new(),
+    };
+
+    version
+        .save_locations
+        .iter()
+        .filter_map(|location| {
+            let path = resolve_save_path(&location.path, Path::new(install_dir));
+            if path.is_none() {
+                warn!("can't find save location {} for {}", location.path, game_id);
+            }
+            Some((location.name.clone(), path?))
+        })
+        .collect()
+}
+
+// Every location has to be a directory of its own, never a whole user
+// folder or the install directory, since syncing sends everything in it
+// and can remove files from it
+fn resolve_save_path(path: &str, install_dir: &Path) -> Option<PathBuf> {
+    let (base, rest) = match path.strip_prefix('{').and_then(|path| path.split_once('}')) {
+        Some((placeholder, rest)) => {
+            let base_dirs = BaseDirs::new()?;
+            let base = match placeholder {
+                "install" => install_dir.to_path_buf(),
+                "home" => base_dirs.home_dir().to_path_buf(),
+                "data" => base_dirs.data_dir().to_path_buf(),
+                "config" => base_dirs.config_dir().to_path_buf(),
+                "documents" => UserDirs::new()?.document_dir()?.to_path_buf(),
+                _ => return None,
+            };
+            (base, rest)
+        }
+        None => (install_dir.to_path_buf(), path),
+    };
+    Some(base.join(relative_path(rest)?))
+}
+
+// Paths from the server can't be allowed to climb out of where they
+// belong, or to be where they belong itself
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path.trim_start_matches(['/', '\\']));
+    let mut components = path.components();
+    let escapes = components
+        .clone()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    let named = components.any(|component| matches!(component, Component::Normal(_)));
+    (named && !escapes).then(|| path.to_path_buf())
+}
+
+fn scan_locations(locations: &[(String, PathBuf)]) -> io::Result<SaveManifest> {
+    let mut files = Vec::new();
+    for (name, root) in locations {
+        // Games usually only make their save directory once there's something to save
+        if root.is_dir() {
+            scan_dir(name, root, root, &mut files)?;
+        }
+    }
+    Ok(SaveManifest { files })
+}
+
+fn scan_dir(location: &str, root: &Path, dir: &Path, files: &mut Vec<SaveFile>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            scan_dir(location, root, &path, files)?;
+            continue;
+        }
+        // Links could point anywhere, so they're left alone
+        if !file_type.is_file() {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut File::open(&path)?, &mut hasher)?;
+
+        let relative = path.strip_prefix(root).unwrap();
+        files.push(SaveFile {
+            location: location.to_string(),
+            path: relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            size: metadata.len(),
+            modified,
+            hash: hasher.finalize().to_hex().to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn saves_url(game_id: &str) -> Result<String, SaveSyncError> {
+    let url = DB
+        .fetch_base_url()
+        .join(&format!("/api/v1/client/saves?id={}", game_id))?;
+    Ok(url.to_string())
+}
+
+fn save_file_url(game_id: &str, file: &SaveFile) -> Result<String, SaveSyncError> {
+    let url = DB.fetch_base_url().join(&format!(
+        "/api/v1/client/saves/file?id={}&location={}&path={}",
+        game_id,
+        encode(&file.location),
+        encode(&file.path)
+    ))?;
+    Ok(url.to_string())
+}
+
+fn check_status(
+    response: reqwest::blocking::Response,
+) -> Result<reqwest::blocking::Response, SaveSyncError> {
+    if !response.status().is_success() {
+        return Err(RemoteAccessError::from_response(response).into());
+    }
+    Ok(response)
+}
+
+// None if nothing's been uploaded for the game yet
+fn fetch_remote_manifest(game_id: &str) -> Result<Option<SaveManifest>, SaveSyncError> {
+    let response = blocking_http_client()
+        .get(saves_url(game_id)?)
+        .header("Authorization", generate_authorization_header())
+        .send()?;
+    if response.status() == 404 {
+        return Ok(None);
+    }
+    Ok(Some(check_status(response)?.json::<SaveManifest>()?))
+}
+
+fn location_root<'a>(locations: &'a [(String, PathBuf)], name: &str) -> Option<&'a PathBuf> {
+    locations
+        .iter()
+        .find(|(location, _)| location == name)
+        .map(|(_, root)| root)
+}
+
+fn emit_progress(app_handle: &AppHandle, game_id: &str, done: usize, total: usize) {
+    app_handle
+        .emit(
+            "save_sync_progress",
+            SaveSyncProgressEvent {
+                game_id: game_id.to_string(),
+                done,
+                total,
+            },
+        )
+        .unwrap();
+}
+
+// Sends the files the server doesn't have, then the manifest, which
+// the server drops anything that isn't listed in
+fn upload(
+    app_handle: &AppHandle,
+    game_id: &str,
+    locations: &[(String, PathBuf)],
+    local: &SaveManifest,
+    remote: Option<&SaveManifest>,
+) -> Result<(), SaveSyncError> {
+    let existing: HashSet<(&str, &str, &str)> = remote
+        .map(|remote| {
+            remote
+                .files
+                .iter()
+                .map(|file| {
+                    (
+                        file.location.as_str(),
+                        file.path.as_str(),
+                        file.hash.as_str(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let changed: Vec<&SaveFile> = local
+        .files
+        .iter()
+        .filter(|file| !existing.contains(&(&file.location, &file.path, &file.hash)))
+        .collect();
+
+    let client = blocking_http_client();
+    for (done, file) in changed.iter().enumerate() {
+        emit_progress(app_handle, game_id, done, changed.len());
+        // Scanned from these locations, so the root is always there
+        let root = location_root(locations, &file.location).unwrap();
+        let data = fs::read(root.join(&file.path))?;
+        let url = save_file_url(game_id, file)?;
+        let request = client
+            .put(&url)
+            .header("Authorization", generate_authorization_header());
+        let response = sign_request(request, "PUT", &url, &data)
+            .body(data)
+            .send()?;
+        check_status(response)?;
+    }
+
+    let url = saves_url(game_id)?;
+    let body = serde_json::to_vec(local).unwrap();
+    let request = client
+        .put(&url)
+        .header("Authorization", generate_authorization_header())
+        .header("Content-Type", "application/json");
+    let response = sign_request(request, "PUT", &url, &body)
+        .body(body)
+        .send()?;
+    check_status(response)?;
+    emit_progress(app_handle, game_id, changed.len(), changed.len());
+
+    Ok(())
+}
+
+// Fetches whatever differs from the server's copy, and removes local
+// files the server no longer has. Only files that were synced before
+// are removed; anything else in the location was never ours.
+fn download(
+    app_handle: &AppHandle,
+    game_id: &str,
+    locations: &[(String, PathBuf)],
+    local: &SaveManifest,
+    remote: &SaveManifest,
+) -> Result<(), SaveSyncError> {
+    let existing: HashSet<(&str, &str, &str)> = local
+        .files
+        .iter()
+        .map(|file| {
+            (
+                file.location.as_str(),
+                file.path.as_str(),
+                file.hash.as_str(),
+            )
+        })
+        .collect();
+    let changed: Vec<&SaveFile> = remote
+        .files
+        .iter()
+        .filter(|file| !existing.contains(&(&file.location, &file.path, &file.hash)))
+        .collect();
+
+    let client = blocking_http_client();
+    for (done, file) in changed.iter().enumerate() {
+        emit_progress(app_handle, game_id, done, changed.len());
+        let (root, relative) = match (
+            location_root(locations, &file.location),
+            relative_path(&file.path),
+        ) {
+            (Some(root), Some(relative)) => (root, relative),
+            _ => {
+                warn!(
+                    "skipping save file {} in {} for {}",
+                    file.path, file.location, game_id
+                );
+                continue;
+            }
+        };
+
+        let response = client
+            .get(save_file_url(game_id, file)?)
+            .header("Authorization", generate_authorization_header())
+            .send()?;
+        let data = check_status(response)?.bytes()?;
+
+        // Written alongside first, so a failed download doesn't
+        // leave the game with half a save
+        let path = root.join(relative);
+        create_dir_all(path.parent().unwrap())?;
+        let partial_path = path.with_file_name(format!(
+            "{}.drop-partial",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        fs::write(&partial_path, &data)?;
+        rename(&partial_path, &path)?;
+    }
+
+    let kept: HashSet<(&str, &str)> = remote
+        .files
+        .iter()
+        .map(|file| (file.location.as_str(), file.path.as_str()))
+        .collect();
+    let synced = DB
+        .borrow_data()
+        .unwrap()
+        .games
+        .synced_saves
+        .get(game_id)
+        .map(|synced| synced.files.clone())
+        .unwrap_or_default();
+    for file in local.files.iter() {
+        if kept.contains(&(file.location.as_str(), file.path.as_str())) {
+            continue;
+        }
+        if !synced.contains(&(file.location.clone(), file.path.clone())) {
+            continue;
+        }
+        let root = location_root(locations, &file.location).unwrap();
+        remove_file(root.join(&file.path))?;
+    }
+    emit_progress(app_handle, game_id, changed.len(), changed.len());
+
+    Ok(())
+}
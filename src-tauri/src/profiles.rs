@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use log::{info, warn};
+use rustbreak::backend::Backend;
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use uuid::Uuid;
+
+use crate::{
+    cleanup::shutdown_download_manager,
+    db::{write_durably, DatabaseAuth, DATA_ROOT_DIR},
+    keychain::delete_secrets,
+    sqlite_backend::SqliteBackend,
+};
+
+/// The profile everything was in before there were profiles. It keeps
+/// its data where it always was, at the top of the data directory.
+pub const DEFAULT_PROFILE: &str = "default";
+const PROFILES_FILE: &str = "profiles.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+}
+
+/// Kept outside any profile's database, since it decides which one is opened
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Profiles {
+    pub active: String,
+    pub profiles: Vec<Profile>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            active: DEFAULT_PROFILE.to_string(),
+            profiles: vec![Profile {
+                id: DEFAULT_PROFILE.to_string(),
+                name: "Default".to_string(),
+            }],
+        }
+    }
+}
+
+fn data_root_dir() -> PathBuf {
+    DATA_ROOT_DIR.lock().unwrap().clone()
+}
+
+fn read_profiles() -> Profiles {
+    let path = data_root_dir().join(PROFILES_FILE);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(_) => return Profiles::default(),
+    };
+    match serde_json::from_slice(&data) {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            warn!(
+                "couldn't read {}, using the default profile: {}",
+                path.display(),
+                e
+            );
+            Profiles::default()
+        }
+    }
+}
+
+fn write_profiles(profiles: &Profiles) -> Result<(), String> {
+    let path = data_root_dir().join(PROFILES_FILE);
+    write_durably(&path, &serde_json::to_vec_pretty(profiles).unwrap()).map_err(|e| e.to_string())
+}
+
+/// Which profile this run of the app uses. Switching restarts the app, so
+/// it never changes while it's running.
+static ACTIVE_PROFILE: LazyLock<String> = LazyLock::new(|| {
+    let profiles = read_profiles();
+    if profiles
+        .profiles
+        .iter()
+        .any(|profile| profile.id == profiles.active)
+    {
+        profiles.active
+    } else {
+        warn!(
+            "profile {} no longer exists, using the default",
+            profiles.active
+        );
+        DEFAULT_PROFILE.to_string()
+    }
+});
+
+fn profile_dir_for(data_root_dir: &Path, profile_id: &str) -> PathBuf {
+    if profile_id == DEFAULT_PROFILE {
+        data_root_dir.to_path_buf()
+    } else {
+        data_root_dir.join("profiles").join(profile_id)
+    }
+}
+
+/// Where the active profile's database, cache, games and Wine prefixes go
+pub fn profile_dir() -> PathBuf {
+    profile_dir_for(&data_root_dir(), &ACTIVE_PROFILE)
+}
+
+fn find_profile<'a>(profiles: &'a mut Profiles, id: &str) -> Result<&'a mut Profile, String> {
+    profiles
+        .profiles
+        .iter_mut()
+        .find(|profile| profile.id == id)
+        .ok_or("That profile doesn't exist".to_string())
+}
+
+fn check_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Profiles need a name".to_string());
+    }
+    Ok(name.to_string())
+}
+
+#[tauri::command]
+pub fn fetch_profiles() -> Profiles {
+    Profiles {
+        active: ACTIVE_PROFILE.clone(),
+        ..read_profiles()
+    }
+}
+
+/// Adds an empty profile, signed out and with nothing installed. It's used
+/// once it's switched to.
+#[tauri::command]
+pub fn create_profile(name: String) -> Result<Profile, String> {
+    let profile = Profile {
+        id: Uuid::new_v4().to_string(),
+        name: check_name(&name)?,
+    };
+    let mut profiles = read_profiles();
+    profiles.profiles.push(profile.clone());
+    write_profiles(&profiles)?;
+    info!("created profile {} ({})", profile.name, profile.id);
+    Ok(profile)
+}
+
+#[tauri::command]
+pub fn rename_profile(id: String, name: String) -> Result<(), String> {
+    let mut profiles = read_profiles();
+    find_profile(&mut profiles, &id)?.name = check_name(&name)?;
+    write_profiles(&profiles)
+}
+
+// Its sign-in secrets are in the keychain, outside its directory
+fn delete_profile_secrets(profile_dir: &Path) {
+    let data = SqliteBackend::open(&profile_dir.join("drop.sqlite"))
+        .and_then(|mut backend| backend.get_data().map_err(|e| e.to_string()));
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("couldn't open the profile's database to sign it out: {}", e);
+            return;
+        }
+    };
+    let auth = serde_json::from_slice::<serde_json::Value>(&data)
+        .ok()
+        .and_then(|mut database| database.get_mut("auth").map(serde_json::Value::take))
+        .and_then(|auth| serde_json::from_value::<DatabaseAuth>(auth).ok());
+    if let Some(auth) = auth {
+        delete_secrets(&auth);
+    }
+}
+
+/// Removes a profile along with everything in its directory, including
+/// games installed to its default location. The profile in use and the
+/// default one can't be deleted.
+#[tauri::command]
+pub fn delete_profile(id: String) -> Result<(), String> {
+    if id == *ACTIVE_PROFILE {
+        return Err("Switch to another profile before deleting this one".to_string());
+    }
+    if id == DEFAULT_PROFILE {
+        return Err("The default profile can't be deleted".to_string());
+    }
+    let mut profiles = read_profiles();
+    find_profile(&mut profiles, &id)?;
+
+    let profile_dir = profile_dir_for(&data_root_dir(), &id);
+    if profile_dir.exists() {
+        delete_profile_secrets(&profile_dir);
+        fs::remove_dir_all(&profile_dir).map_err(|e| e.to_string())?;
+    }
+    profiles.profiles.retain(|profile| profile.id != id);
+    write_profiles(&profiles)?;
+    info!("deleted profile {}", id);
+    Ok(())
+}
+
+/// Restarts the app into another profile, so nothing carries on with the
+/// current profile's sign-in or library
+#[tauri::command]
+pub fn switch_profile(app: AppHandle, id: String) -> Result<(), String> {
+    if id == *ACTIVE_PROFILE {
+        return Ok(());
+    }
+    let mut profiles = read_profiles();
+    find_profile(&mut profiles, &id)?;
+    profiles.active = id;
+    write_profiles(&profiles)?;
+
+    info!("switching to profile {}", profiles.active);
+    // Its queue is saved for when the profile is next used
+    shutdown_download_manager(&app);
+    app.restart();
+}
@@ -0,0 +1,91 @@
+use reqwest::Proxy;
+
+use crate::{remote::rebuild_http_clients, DB};
+
+/// Proxies the HTTP clients go through, or None for a direct connection.
+/// reqwest finds HTTP_PROXY, HTTPS_PROXY and NO_PROXY, and the Windows and
+/// macOS proxy settings, by itself. Linux desktops keep theirs elsewhere.
+pub fn configured_proxies() -> Option<Vec<Proxy>> {
+    if !DB.borrow_data().unwrap().settings.use_system_proxy {
+        return None;
+    }
+
+    #[cfg(target_os = "linux")]
+    return Some(desktop_proxies());
+    #[cfg(not(target_os = "linux"))]
+    Some(Vec::new())
+}
+
+// The environment wins, like it does for most other programs
+#[cfg(target_os = "linux")]
+fn desktop_proxies() -> Vec<Proxy> {
+    let from_env = ["HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY"]
+        .iter()
+        .any(|name| {
+            std::env::var_os(name).is_some() || std::env::var_os(name.to_lowercase()).is_some()
+        });
+    if from_env || gsettings("org.gnome.system.proxy", "mode").as_deref() != Some("manual") {
+        return Vec::new();
+    }
+
+    let no_proxy = gsettings("org.gnome.system.proxy", "ignore-hosts")
+        .and_then(|hosts| reqwest::NoProxy::from_string(&parse_list(&hosts).join(",")));
+
+    let mut proxies = Vec::new();
+    for scheme in ["http", "https"] {
+        let schema = format!("org.gnome.system.proxy.{}", scheme);
+        let host = match gsettings(&schema, "host").filter(|host| !host.is_empty()) {
+            Some(host) => host,
+            None => continue,
+        };
+        let port = gsettings(&schema, "port").unwrap_or_else(|| "8080".to_string());
+        // Either way, it's spoken to over plain HTTP
+        let url = format!("http://{}:{}", host, port);
+        let proxy = match scheme {
+            "http" => Proxy::http(&url),
+            _ => Proxy::https(&url),
+        };
+        match proxy {
+            Ok(proxy) => proxies.push(proxy.no_proxy(no_proxy.clone())),
+            Err(e) => log::warn!("ignoring desktop proxy {}: {}", url, e),
+        }
+    }
+    proxies
+}
+
+// GNOME's, which other desktops tend to set too
+#[cfg(target_os = "linux")]
+fn gsettings(schema: &str, key: &str) -> Option<String> {
+    let output = std::process::Command::new("gsettings")
+        .args(["get", schema, key])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?;
+    Some(value.trim().trim_matches('\'').to_string())
+}
+
+// e.g. ['localhost', '127.0.0.0/8']
+#[cfg(target_os = "linux")]
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .trim_start_matches('@')
+        .trim_start_matches("as ")
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(|item| item.trim().trim_matches('\'').to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[tauri::command]
+pub fn set_use_system_proxy(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.use_system_proxy = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+}
@@ -0,0 +1,210 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::runtime::Runtime;
+
+use crate::{
+    auth::generate_authorization_header,
+    db::DatabaseImpls,
+    library::refresh_metadata_logic,
+    remote::{http_client, server_supports, RemoteAccessError},
+    updates::check_for_update,
+    AppState, AppStatus, DB,
+};
+
+/// First wait before reconnecting, doubled each time it fails after that
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+/// The server sends a comment at least this often, so a quiet stream is a dead one
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(90);
+
+static PUSH_RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+});
+
+/// One event off the stream, before it's been made sense of
+#[derive(Default, Debug, PartialEq)]
+pub struct ServerSentEvent {
+    pub event: String,
+    pub data: String,
+}
+
+/// Splits a text/event-stream into events, however it's broken into chunks
+#[derive(Default)]
+pub struct EventParser {
+    buffer: Vec<u8>,
+    event: ServerSentEvent,
+}
+
+impl EventParser {
+    /// Returns the events this chunk finishes
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<ServerSentEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                if !event.event.is_empty() || !event.data.is_empty() {
+                    events.push(event);
+                }
+                continue;
+            }
+            // Comments, which keep the connection alive
+            if line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event.event = value.to_string(),
+                "data" => {
+                    if !self.event.data.is_empty() {
+                        self.event.data.push('\n');
+                    }
+                    self.event.data.push_str(value);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GamePayload {
+    game_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerMessageEvent {
+    pub title: String,
+    pub message: String,
+}
+
+/// Keeps a connection open to the server's event stream while signed in,
+/// so new versions and library changes show up without waiting on a poll
+pub fn spawn_push_listener(app_handle: AppHandle) {
+    spawn(move || {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            if !signed_in(&app_handle) || !server_supports(&app_handle, "push") {
+                sleep(RECONNECT_DELAY);
+                continue;
+            }
+            if let Err(e) = listen(&app_handle, &mut delay) {
+                warn!("push channel dropped: {}", e);
+            }
+            sleep(delay);
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+fn signed_in(app_handle: &AppHandle) -> bool {
+    matches!(
+        app_handle.state::<Mutex<AppState>>().lock().unwrap().status,
+        AppStatus::SignedIn
+    )
+}
+
+// Returns once the stream ends or goes quiet, or the user signs out
+fn listen(app_handle: &AppHandle, delay: &mut Duration) -> Result<(), RemoteAccessError> {
+    let mut response = PUSH_RUNTIME.block_on(connect())?;
+    info!("connected to push channel");
+    *delay = RECONNECT_DELAY;
+
+    let mut parser = EventParser::default();
+    while signed_in(app_handle) {
+        let chunk =
+            PUSH_RUNTIME.block_on(tokio::time::timeout(KEEPALIVE_TIMEOUT, response.chunk()));
+        let chunk = match chunk {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                warn!("push channel went quiet, reconnecting");
+                return Ok(());
+            }
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => {
+                info!("push channel closed by server");
+                return Ok(());
+            }
+        };
+        // Handled off the runtime, since they make blocking requests
+        for event in parser.push(&chunk) {
+            handle_event(app_handle, event);
+        }
+    }
+    Ok(())
+}
+
+async fn connect() -> Result<reqwest::Response, RemoteAccessError> {
+    let endpoint = DB.fetch_base_url().join("/api/v1/client/events")?;
+    let response = http_client()
+        .get(endpoint.to_string())
+        .header("Authorization", generate_authorization_header())
+        .header("Accept", "text/event-stream")
+        .send()
+        .await?;
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_async_response(response).await);
+    }
+    Ok(response)
+}
+
+fn handle_event(app_handle: &AppHandle, event: ServerSentEvent) {
+    match event.event.as_str() {
+        // A new version of a game was uploaded
+        "version" => {
+            if let Some(payload) = parse_payload::<GamePayload>(&event) {
+                check_for_update(app_handle, &payload.game_id);
+            }
+        }
+        // A game's details were edited
+        "game" => {
+            if let Some(payload) = parse_payload::<GamePayload>(&event) {
+                if let Err(e) = refresh_metadata_logic(app_handle.clone(), Some(payload.game_id)) {
+                    warn!("couldn't refresh pushed game: {}", e);
+                }
+            }
+        }
+        // Games were added to or removed from the user's library
+        "library" => match refresh_metadata_logic(app_handle.clone(), None) {
+            Ok(()) => app_handle.emit("update_library", ()).unwrap(),
+            Err(e) => warn!("couldn't refresh library: {}", e),
+        },
+        // Something from the server's admins
+        "message" => {
+            if let Some(message) = parse_payload::<ServerMessageEvent>(&event) {
+                app_handle.emit("server_message", message).unwrap();
+            }
+        }
+        other => info!("ignoring unknown push event {:?}", other),
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(event: &ServerSentEvent) -> Option<T> {
+    match serde_json::from_str(&event.data) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            warn!("couldn't parse {} push event: {}", event.event, e);
+            None
+        }
+    }
+}
@@ -1,26 +1,57 @@
 use std::{
     fmt::{Display, Formatter},
-    sync::{Arc, Mutex},
+    io,
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::Duration,
 };
 
 use http::StatusCode;
 use log::{info, warn};
-use serde::Deserialize;
+use reqwest::{Certificate, Identity, Proxy};
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
 use url::{ParseError, Url};
 
-use crate::{AppState, AppStatus, DB};
+use crate::{
+    auth::clear_sign_in,
+    cache,
+    db::DatabaseImpls,
+    proxy::configured_proxies,
+    tls::{
+        client_certificate, fetch_server_certificate, is_certificate_error, pinned_fingerprint,
+        pinned_tls_config, root_certificates, ServerCertificate,
+    },
+    AppState, AppStatus, DB,
+};
 
+/// Everything that can go wrong talking to the server. Responses with the
+/// wrong status all end up as Status, built with from_response, so retry
+/// policies and messages can be decided in one place.
 #[derive(Debug, Clone)]
 pub enum RemoteAccessError {
+    // Couldn't get an answer at all, or couldn't read it
     FetchError(Arc<reqwest::Error>),
     ParsingError(ParseError),
-    InvalidCodeError(u16),
+    // The start of the body is kept, since servers usually say what went wrong
+    Status {
+        endpoint: String,
+        status: StatusCode,
+        body: String,
+    },
     InvalidEndpoint,
     HandshakeFailed,
     GameNotFound,
     InvalidResponse,
-    InvalidRedirect,
-    ManifestDownloadFailed(StatusCode, String),
+    InvalidTwoFactorCode,
+    // There's no sign-in waiting on a code any more
+    TwoFactorExpired,
+    // A sign-in callback for a sign-in this client didn't start
+    UnexpectedCallback,
+    // The server's certificate couldn't be verified, and this is what it is
+    UntrustedCertificate(ServerCertificate),
+    // The saved sign-in's secrets couldn't be read from the keychain
+    CredentialsUnavailable(String),
 }
 
 impl Display for RemoteAccessError {
@@ -30,17 +61,36 @@ impl Display for RemoteAccessError {
             RemoteAccessError::ParsingError(parse_error) => {
                 write!(f, "{}", parse_error)
             }
-            RemoteAccessError::InvalidCodeError(error) => write!(f, "Invalid HTTP code {}", error),
+            RemoteAccessError::Status {
+                endpoint,
+                status,
+                body,
+            } => {
+                write!(f, "{} responded with {}", endpoint, status)?;
+                if !body.is_empty() {
+                    write!(f, ": {}", body)?;
+                }
+                Ok(())
+            }
             RemoteAccessError::InvalidEndpoint => write!(f, "Invalid drop endpoint"),
             RemoteAccessError::HandshakeFailed => write!(f, "Failed to complete handshake"),
             RemoteAccessError::GameNotFound => write!(f, "Could not find game on server"),
             RemoteAccessError::InvalidResponse => write!(f, "Server returned an invalid response"),
-            RemoteAccessError::InvalidRedirect => write!(f, "Server redirect was invalid"),
-            RemoteAccessError::ManifestDownloadFailed(status, response) => write!(
+            RemoteAccessError::InvalidTwoFactorCode => write!(f, "That code isn't right"),
+            RemoteAccessError::TwoFactorExpired => {
+                write!(f, "The sign-in expired, please start again")
+            }
+            RemoteAccessError::UnexpectedCallback => {
+                write!(f, "That sign-in wasn't started here, please start again")
+            }
+            RemoteAccessError::UntrustedCertificate(certificate) => write!(
                 f,
-                "Failed to download game manifest: {} {}",
-                status, response
+                "The server's certificate isn't trusted (SHA-256 {})",
+                certificate.fingerprint
             ),
+            RemoteAccessError::CredentialsUnavailable(error) => {
+                write!(f, "Couldn't load the saved sign-in: {}", error)
+            }
         }
     }
 }
@@ -55,22 +105,473 @@ impl From<ParseError> for RemoteAccessError {
         RemoteAccessError::ParsingError(err)
     }
 }
-impl From<u16> for RemoteAccessError {
-    fn from(err: u16) -> Self {
-        RemoteAccessError::InvalidCodeError(err)
+impl std::error::Error for RemoteAccessError {}
+
+impl RemoteAccessError {
+    /// For a response that didn't have the status the caller wanted
+    pub fn from_response(response: reqwest::blocking::Response) -> Self {
+        let endpoint = endpoint_path(response.url());
+        let status = response.status();
+        let body = body_excerpt(&response.text().unwrap_or_default());
+        RemoteAccessError::Status {
+            endpoint,
+            status,
+            body,
+        }
+    }
+
+    pub async fn from_async_response(response: reqwest::Response) -> Self {
+        let endpoint = endpoint_path(response.url());
+        let status = response.status();
+        let body = body_excerpt(&response.text().await.unwrap_or_default());
+        RemoteAccessError::Status {
+            endpoint,
+            status,
+            body,
+        }
+    }
+
+    /// The HTTP status the server responded with, if it got that far
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            RemoteAccessError::FetchError(error) => error.status().map(|status| status.as_u16()),
+            RemoteAccessError::Status { status, .. } => Some(status.as_u16()),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            RemoteAccessError::FetchError(error) => ErrorClass::from_reqwest(error),
+            RemoteAccessError::Status { status, .. } => ErrorClass::from_status(status.as_u16()),
+            _ => ErrorClass::Client,
+        }
+    }
+
+    /// Whether the same request could work if it's made again later, because
+    /// the network or the server let it down rather than it being refused
+    pub fn is_retryable(&self) -> bool {
+        self.class().is_transient()
+    }
+
+    /// Whether the server couldn't be reached at all, or couldn't answer,
+    /// rather than having turned the request down
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            RemoteAccessError::FetchError(error) => error.is_connect() || error.is_timeout(),
+            _ => self.status_code().is_some_and(|status| status >= 500),
+        }
     }
 }
 
-impl std::error::Error for RemoteAccessError {}
+/// Broadly what went wrong with a request or a download, which
+/// decides whether it's worth trying again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Timeout,
+    // Couldn't connect (the name didn't resolve, or it was refused),
+    // reset or cut off part way through
+    Connection,
+    // 5xx, or asked to slow down
+    Server,
+    // 4xx, the server won't give us this no matter how often we ask
+    Client,
+    // The data arrived, but was wrong
+    Corrupted,
+    // Our own disk or state
+    Local,
+}
+
+impl ErrorClass {
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorClass::Timeout
+                | ErrorClass::Connection
+                | ErrorClass::Server
+                | ErrorClass::Corrupted
+        )
+    }
+
+    fn from_status(code: u16) -> Self {
+        match code {
+            408 => ErrorClass::Timeout,
+            429 | 500.. => ErrorClass::Server,
+            _ => ErrorClass::Client,
+        }
+    }
+
+    /// For an I/O error from a connection to the server. Anything that
+    /// isn't clearly the connection's doing is left to the caller.
+    pub(crate) fn from_connection_io(error: &io::Error) -> Option<Self> {
+        match error.kind() {
+            io::ErrorKind::TimedOut => Some(ErrorClass::Timeout),
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::Interrupted => Some(ErrorClass::Connection),
+            _ => None,
+        }
+    }
+
+    fn from_reqwest(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return ErrorClass::Timeout;
+        }
+        if let Some(status) = error.status() {
+            return ErrorClass::from_status(status.as_u16());
+        }
+
+        // reqwest doesn't say much about why a connection failed,
+        // but the I/O errors it wraps can
+        let mut source = std::error::Error::source(error);
+        while let Some(cause) = source {
+            if let Some(class) = cause
+                .downcast_ref::<io::Error>()
+                .and_then(ErrorClass::from_connection_io)
+            {
+                return class;
+            }
+            source = cause.source();
+        }
+
+        if error.is_connect() || error.is_request() || error.is_body() || error.is_decode() {
+            ErrorClass::Connection
+        } else {
+            ErrorClass::Client
+        }
+    }
+}
+
+// Without the query, which can carry IDs and tokens that don't belong in logs
+fn endpoint_path(url: &Url) -> String {
+    url.path().to_string()
+}
+
+/// Longest piece of an error response we hold on to
+const BODY_EXCERPT_LENGTH: usize = 200;
+
+pub fn body_excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_EXCERPT_LENGTH) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+// One of each, so that every request can reuse pooled connections and TLS
+// sessions. Both pick up the system proxy settings (unless they're turned
+// off) and TLS roots, plus any added CA certificates and the remote's client
+// certificate, or only accept the remote's pinned certificate. They're
+// rebuilt when any of those or the timeouts change, which drops the old
+// connection pools.
+static HTTP_CLIENT: LazyLock<RwLock<reqwest::Client>> =
+    LazyLock::new(|| RwLock::new(build_http_client(&ClientSettings::current())));
+static BLOCKING_HTTP_CLIENT: LazyLock<RwLock<reqwest::blocking::Client>> =
+    LazyLock::new(|| RwLock::new(build_blocking_http_client(&ClientSettings::current())));
+// For anywhere other than the remote, like mirrors and the content endpoint,
+// which mustn't be shown its client certificate or held to its pinned one
+static OTHER_HTTP_CLIENT: LazyLock<RwLock<reqwest::Client>> =
+    LazyLock::new(|| RwLock::new(build_http_client(&ClientSettings::for_others())));
+
+pub fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.read().unwrap().clone()
+}
+
+/// The client for a request to `url`, which is the remote's own only if
+/// that's where it's going
+pub fn http_client_for(url: &Url) -> reqwest::Client {
+    if url.origin() == DB.fetch_base_url().origin() {
+        return http_client();
+    }
+    OTHER_HTTP_CLIENT.read().unwrap().clone()
+}
+
+pub fn blocking_http_client() -> reqwest::blocking::Client {
+    BLOCKING_HTTP_CLIENT.read().unwrap().clone()
+}
+
+pub struct NetworkTimeouts {
+    pub connect: Duration,
+    pub request: Duration,
+    pub chunk_read: Duration,
+}
+
+impl NetworkTimeouts {
+    pub fn current() -> Self {
+        let settings = &DB.borrow_data().unwrap().settings;
+        Self {
+            connect: Duration::from_secs(settings.connect_timeout),
+            request: Duration::from_secs(settings.request_timeout),
+            chunk_read: Duration::from_secs(settings.chunk_read_timeout),
+        }
+    }
+}
+
+/// How the server's certificate is checked, and what's presented to it
+enum ClientTls {
+    // The platform's TLS, trusting the system's roots and any added CAs
+    Platform(Option<Identity>),
+    // Only the certificate pinned for the server is accepted
+    Pinned(ClientConfig),
+}
+
+/// Everything the HTTP clients are built with
+struct ClientSettings {
+    timeouts: NetworkTimeouts,
+    root_certificates: Vec<Certificate>,
+    tls: ClientTls,
+    proxies: Option<Vec<Proxy>>,
+}
+
+impl ClientSettings {
+    // For the remote in use
+    fn current() -> Self {
+        let base_url = DB.borrow_data().unwrap().base_url.clone();
+        let origin = Url::parse(&base_url)
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_default();
+        Self::for_origin(&origin)
+    }
+
+    fn for_origin(origin: &str) -> Self {
+        let client_certificate = client_certificate(origin);
+        let tls = match pinned_fingerprint(origin) {
+            Some(fingerprint) => {
+                match pinned_tls_config(fingerprint, client_certificate.as_ref()) {
+                    Ok(config) => ClientTls::Pinned(config),
+                    Err(e) => {
+                        warn!(
+                            "couldn't set up the pinned certificate for {}: {}",
+                            origin, e
+                        );
+                        ClientTls::Platform(None)
+                    }
+                }
+            }
+            None => ClientTls::Platform(client_certificate.and_then(|certificate| {
+                certificate
+                    .identity()
+                    .inspect_err(|e| warn!("skipping client certificate for {}: {}", origin, e))
+                    .ok()
+            })),
+        };
+        Self {
+            timeouts: NetworkTimeouts::current(),
+            root_certificates: root_certificates(),
+            tls,
+            proxies: configured_proxies(),
+        }
+    }
+
+    fn for_others() -> Self {
+        Self {
+            timeouts: NetworkTimeouts::current(),
+            root_certificates: root_certificates(),
+            tls: ClientTls::Platform(None),
+            proxies: configured_proxies(),
+        }
+    }
+}
+
+// Chunks can take far longer than any API request, so the async client has
+// no overall timeout. Chunk downloads time out when they stop receiving
+// data, and the other async requests set their own.
+fn build_http_client(settings: &ClientSettings) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(settings.timeouts.connect);
+    for certificate in settings.root_certificates.iter() {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    match &settings.tls {
+        ClientTls::Platform(Some(identity)) => builder = builder.identity(identity.clone()),
+        ClientTls::Platform(None) => {}
+        ClientTls::Pinned(config) => builder = builder.use_preconfigured_tls(config.clone()),
+    }
+    match &settings.proxies {
+        Some(proxies) => {
+            for proxy in proxies {
+                builder = builder.proxy(proxy.clone());
+            }
+        }
+        None => builder = builder.no_proxy(),
+    }
+    builder.build().expect("failed to build HTTP client")
+}
+
+fn blocking_http_client_builder(settings: &ClientSettings) -> reqwest::blocking::ClientBuilder {
+    let mut builder = reqwest::blocking::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(settings.timeouts.connect)
+        .timeout(settings.timeouts.request);
+    for certificate in settings.root_certificates.iter() {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    match &settings.tls {
+        ClientTls::Platform(Some(identity)) => builder = builder.identity(identity.clone()),
+        ClientTls::Platform(None) => {}
+        ClientTls::Pinned(config) => builder = builder.use_preconfigured_tls(config.clone()),
+    }
+    match &settings.proxies {
+        Some(proxies) => {
+            for proxy in proxies {
+                builder = builder.proxy(proxy.clone());
+            }
+        }
+        None => builder = builder.no_proxy(),
+    }
+    builder
+}
+
+fn build_blocking_http_client(settings: &ClientSettings) -> reqwest::blocking::Client {
+    blocking_http_client_builder(settings)
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+/// Accepts any certificate and records what it was, only for showing the
+/// user what a server presents. Never send anything through it.
+pub fn inspecting_http_client() -> reqwest::blocking::Client {
+    blocking_http_client_builder(&ClientSettings::for_others())
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+/// Requests already in flight keep the settings they started with
+pub fn rebuild_http_clients() {
+    let settings = ClientSettings::current();
+    *HTTP_CLIENT.write().unwrap() = build_http_client(&settings);
+    *BLOCKING_HTTP_CLIENT.write().unwrap() = build_blocking_http_client(&settings);
+    *OTHER_HTTP_CLIENT.write().unwrap() = build_http_client(&ClientSettings::for_others());
+}
+
+const USER_AGENT: &str = concat!("Drop Desktop Client/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct DropHealthcheck {
     app_name: String,
+    #[serde(flatten)]
+    capabilities: ServerCapabilities,
+}
+
+/// What the server says it can do, from its healthcheck. Older servers only
+/// send their name, so features they don't list are assumed to be there.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCapabilities {
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub hash_algorithms: Vec<String>,
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    #[serde(default)]
+    pub features: Option<Vec<String>>,
+    // Where chunks are downloaded from, if not from the API
+    #[serde(default)]
+    pub content: Option<ContentEndpoint>,
+}
+
+/// Object storage or a CDN serving chunk content. Chunk requests are made
+/// relative to its URL, without the client's Authorization header; if it's
+/// signed, the API hands out a query string to add to them instead.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentEndpoint {
+    pub url: String,
+    #[serde(default)]
+    pub signed: bool,
+}
+
+impl ServerCapabilities {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features
+            .as_ref()
+            .is_none_or(|features| features.iter().any(|supported| supported == feature))
+    }
+
+    /// Only true for features the server lists, for ones older servers can't have
+    pub fn advertises(&self, feature: &str) -> bool {
+        self.features
+            .as_ref()
+            .is_some_and(|features| features.iter().any(|supported| supported == feature))
+    }
+}
+
+/// Fetches the remote's healthcheck, checking it's actually Drop
+pub fn fetch_capabilities() -> Result<ServerCapabilities, RemoteAccessError> {
+    let endpoint = DB.fetch_base_url().join("/api/v1")?;
+    let response = blocking_http_client().get(endpoint.to_string()).send()?;
+    if response.status() != 200 {
+        return Err(RemoteAccessError::from_response(response));
+    }
+    let healthcheck = response.json::<DropHealthcheck>()?;
+    if healthcheck.app_name != "Drop" {
+        return Err(RemoteAccessError::InvalidEndpoint);
+    }
+    Ok(healthcheck.capabilities)
+}
+
+pub fn set_capabilities(app_handle: &AppHandle, capabilities: ServerCapabilities) {
+    let state = app_handle.state::<Mutex<AppState>>();
+    state.lock().unwrap().capabilities = Some(capabilities);
+}
+
+/// Whether the remote has a feature, going by its last healthcheck
+pub fn server_supports(app_handle: &AppHandle, feature: &str) -> bool {
+    let state = app_handle.state::<Mutex<AppState>>();
+    let state_lock = state.lock().unwrap();
+    state_lock
+        .capabilities
+        .as_ref()
+        .is_none_or(|capabilities| capabilities.supports(feature))
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteChangedEvent {
+    // Installed games that need checking against the new server
+    pub unverified: Vec<String>,
+}
+
+/// Forgets everything that came from the old server when the client is
+/// pointed at a new one: the sign in, queued downloads, cached metadata and
+/// versions, and where saves were synced to. Installed games stay where they
+/// are, but aren't updated until verify_installed_games has found them on
+/// the new server. Returns those games.
+fn migrate_remote(app: &AppHandle) -> Vec<String> {
+    clear_sign_in(app);
+    cache::clear_all();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let games = &mut db_lock.games;
+    games.versions.clear();
+    games.dlc_parents.clear();
+    games.synced_saves.clear();
+    let installed: Vec<String> = games
+        .statuses
+        .iter()
+        .filter(|(_, status)| status.installed().is_some())
+        .map(|(game_id, _)| game_id.clone())
+        .collect();
+    games.unverified.extend(installed);
+    let unverified = games.unverified.iter().cloned().collect();
+    drop(db_lock);
+    DB.save().unwrap();
+
+    unverified
 }
 
 async fn use_remote_logic<'a>(
     url: String,
+    app: &AppHandle,
     state: tauri::State<'_, Mutex<AppState>>,
 ) -> Result<(), RemoteAccessError> {
     info!("connecting to url {}", url);
@@ -78,7 +579,26 @@ async fn use_remote_logic<'a>(
 
     // Test Drop url
     let test_endpoint = base_url.join("/api/v1")?;
-    let response = reqwest::get(test_endpoint.to_string()).await?;
+    // Built for this remote, which may need a different client certificate
+    let client = build_http_client(&ClientSettings::for_origin(
+        &base_url.origin().ascii_serialization(),
+    ));
+    let response = match client
+        .get(test_endpoint.to_string())
+        .timeout(NetworkTimeouts::current().request)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        // Likely self-signed, so the user gets the chance to trust it
+        Err(e) if is_certificate_error(&e) => {
+            return Err(match fetch_server_certificate(base_url.as_str()) {
+                Ok(certificate) => RemoteAccessError::UntrustedCertificate(certificate),
+                Err(_) => e.into(),
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
 
     let result = response.json::<DropHealthcheck>().await?;
 
@@ -87,8 +607,22 @@ async fn use_remote_logic<'a>(
         return Err(RemoteAccessError::InvalidEndpoint);
     }
 
+    let previous_url = DB.borrow_data().unwrap().base_url.clone();
+    let changed =
+        !previous_url.is_empty() && Url::parse(&previous_url).ok() != Some(base_url.clone());
+    if changed {
+        info!(
+            "remote changed from {}, clearing what came from it",
+            previous_url
+        );
+        let unverified = migrate_remote(app);
+        app.emit("remote/changed", RemoteChangedEvent { unverified })
+            .unwrap();
+    }
+
     let mut app_state = state.lock().unwrap();
     app_state.status = AppStatus::SignedOut;
+    app_state.capabilities = Some(result.capabilities);
     drop(app_state);
 
     let mut db_state = DB.borrow_data_mut().unwrap();
@@ -96,6 +630,7 @@ async fn use_remote_logic<'a>(
     drop(db_state);
 
     DB.save().unwrap();
+    rebuild_http_clients();
 
     Ok(())
 }
@@ -103,9 +638,10 @@ async fn use_remote_logic<'a>(
 #[tauri::command]
 pub async fn use_remote<'a>(
     url: String,
+    app: AppHandle,
     state: tauri::State<'_, Mutex<AppState>>,
 ) -> Result<(), String> {
-    let result = use_remote_logic(url, state).await;
+    let result = use_remote_logic(url, &app, state).await;
 
     if result.is_err() {
         return Err(result.err().unwrap().to_string());
@@ -114,6 +650,28 @@ pub async fn use_remote<'a>(
     Ok(())
 }
 
+#[tauri::command]
+pub fn set_network_timeouts(
+    connect_timeout: u64,
+    request_timeout: u64,
+    chunk_read_timeout: u64,
+) -> Result<(), String> {
+    if connect_timeout == 0 || request_timeout == 0 || chunk_read_timeout == 0 {
+        return Err("Timeouts must be at least one second".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.connect_timeout = connect_timeout;
+    db_lock.settings.request_timeout = request_timeout;
+    db_lock.settings.chunk_read_timeout = chunk_read_timeout;
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+
+    Ok(())
+}
+
 #[tauri::command]
 pub fn gen_drop_url(path: String) -> Result<String, String> {
     let base_url = {
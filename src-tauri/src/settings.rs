@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    downloads::{download_schedule::DownloadWindow, write_backend::DiskWriteMode},
+    process::compatibility::CompatibilityLayer,
+    tls::ClientCertificate,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    // How many GameDownloadAgents may run at the same time
+    pub max_concurrent_downloads: usize,
+    // Bytes per second shared between all downloads, None is unlimited
+    pub bandwidth_limit: Option<usize>,
+    // Times of day downloads may run in, empty is any time
+    pub download_windows: Vec<DownloadWindow>,
+    // How many times a chunk is tried before the download fails
+    pub max_download_attempts: u32,
+    // How many chunks of a single game are downloaded at once
+    pub download_threads: usize,
+    // Whether cancelling a download removes the files it already wrote
+    pub delete_partial_on_cancel: bool,
+    // Whether queueing a game starts downloading it straight away
+    pub auto_start_downloads: bool,
+    // Bytes collected from the network before they're written out
+    pub read_buffer_size: usize,
+    // Bytes held in memory before they're flushed to disk
+    pub write_buffer_size: usize,
+    // How chunks are written into the game's files
+    pub disk_write_mode: DiskWriteMode,
+    // Whether game files only take up the space downloaded so far,
+    // instead of all being reserved up front
+    pub sparse_files: bool,
+    // Seconds to wait for a connection to the server
+    pub connect_timeout: u64,
+    // Seconds an API request (library, manifests, auth) may take in total
+    pub request_timeout: u64,
+    // Seconds a chunk download may go without receiving anything
+    pub chunk_read_timeout: u64,
+    // Seconds a chunk may go without receiving anything before it's restarted
+    // and reported as stalled. Only used below chunk_read_timeout.
+    pub stall_timeout: u64,
+    // Most consecutive small chunks asked for in one request, from servers
+    // that advertise batching. 1 turns batching off.
+    pub chunks_per_request: usize,
+    // What runs Windows builds on Linux, unless a game has its own
+    pub compatibility_layer: Option<CompatibilityLayer>,
+    // Whether updates found for installed games are queued straight away
+    pub auto_update: bool,
+    // Whether saves are synced with the server around each launch
+    pub cloud_saves: bool,
+    // Whether installing a game adds shortcuts for it to the desktop and menu
+    pub create_shortcuts: bool,
+    // Seconds a game's pre-launch or post-exit script may run for
+    pub hook_timeout: u64,
+    // Seconds to keep retrying the server for when it's down at startup
+    pub startup_retry_period: u64,
+    // PEM bundles trusted on top of the system's root certificates
+    pub ca_certificates: Vec<String>,
+    // Self-signed certificates the user chose to trust, keyed by the
+    // origin of the server that presented them
+    pub pinned_certificates: HashMap<String, String>,
+    // Keyed by the origin of the server they're presented to, secrets sealed
+    pub client_certificates: HashMap<String, ClientCertificate>,
+    // Whether the proxy set in the environment or the OS is used
+    pub use_system_proxy: bool,
+    // Whether the database is encrypted on disk, see db_encryption.rs
+    pub encrypt_database: bool,
+    // Bytes the cache of server metadata, images and manifests may take up
+    pub cache_size_limit: u64,
+    // Carried by the links our shortcuts launch games through, see shortcuts.rs
+    pub shortcut_key: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_concurrent_downloads: 1,
+            bandwidth_limit: None,
+            download_windows: Vec::new(),
+            max_download_attempts: 5,
+            download_threads: 4,
+            delete_partial_on_cancel: true,
+            auto_start_downloads: true,
+            read_buffer_size: 256 * 1024,
+            write_buffer_size: 256 * 1024,
+            disk_write_mode: DiskWriteMode::default(),
+            sparse_files: false,
+            connect_timeout: 10,
+            request_timeout: 30,
+            chunk_read_timeout: 30,
+            stall_timeout: 20,
+            chunks_per_request: 8,
+            compatibility_layer: None,
+            auto_update: false,
+            cloud_saves: true,
+            create_shortcuts: false,
+            hook_timeout: 60,
+            startup_retry_period: 60,
+            ca_certificates: Vec::new(),
+            pinned_certificates: HashMap::new(),
+            client_certificates: HashMap::new(),
+            use_system_proxy: true,
+            encrypt_database: false,
+            cache_size_limit: 512 * 1024 * 1024,
+            shortcut_key: String::new(),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    fs::{self, create_dir_all, remove_file},
+    io,
+    path::PathBuf,
+    thread::spawn,
+};
+
+use directories::{BaseDirs, UserDirs};
+use log::{info, warn};
+use tauri::AppHandle;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    cache::fetch_object, db::GameStatus, library::fetch_cached_game,
+    process::process_commands::start_game, profiles::profile_dir, DB,
+};
+
+// Made the first time a shortcut is, and kept from then on
+fn shortcut_key() -> String {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    if !db_lock.settings.shortcut_key.is_empty() {
+        return db_lock.settings.shortcut_key.clone();
+    }
+    let key = Uuid::new_v4().simple().to_string();
+    db_lock.settings.shortcut_key = key.clone();
+    drop(db_lock);
+    DB.save().unwrap();
+    key
+}
+
+/// Shortcuts don't point at the game itself, but ask Drop to launch it,
+/// so they keep working across updates, moves and compatibility changes
+fn launch_url(game_id: &str) -> String {
+    format!("drop://launch/{}?key={}", game_id, shortcut_key())
+}
+
+/// Launches the game a drop://launch link is for. Any web page can open
+/// these links, so only ones with the key our shortcuts carry are followed.
+pub fn handle_launch_link(app_handle: AppHandle, url: &Url) {
+    let game_id = url.path().trim_start_matches('/').to_string();
+    let key = url
+        .query_pairs()
+        .find(|(name, _)| name == "key")
+        .map(|(_, key)| key.into_owned());
+    let expected = DB.borrow_data().unwrap().settings.shortcut_key.clone();
+    if expected.is_empty() || key.as_deref() != Some(expected.as_str()) {
+        warn!(
+            "ignoring launch link for {} that didn't come from a shortcut",
+            game_id
+        );
+        return;
+    }
+
+    spawn(move || {
+        if let Err(e) = start_game(&app_handle, game_id.clone()) {
+            warn!("couldn't launch {} from link: {}", game_id, e);
+        }
+    });
+}
+
+// Game names can have anything in them, so they're cut down
+// to what any filesystem will take
+fn shortcut_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .filter(|c| !c.is_control())
+        .collect();
+    name.trim().trim_end_matches('.').to_string()
+}
+
+// The desktop and the application menu
+fn shortcut_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(desktop) = UserDirs::new().and_then(|dirs| dirs.desktop_dir().map(PathBuf::from)) {
+        dirs.push(desktop);
+    }
+    if let Some(base_dirs) = BaseDirs::new() {
+        #[cfg(target_os = "linux")]
+        dirs.push(base_dirs.data_dir().join("applications"));
+        #[cfg(target_os = "windows")]
+        dirs.push(
+            base_dirs
+                .data_dir()
+                .join("Microsoft/Windows/Start Menu/Programs"),
+        );
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let _ = base_dirs;
+    }
+    dirs
+}
+
+// Windows names shortcuts after their file, where Linux goes by what's inside
+fn shortcut_file_name(game_id: &str, name: &str) -> String {
+    if cfg!(target_os = "windows") {
+        match shortcut_name(name) {
+            name if name.is_empty() => format!("{}.url", game_id),
+            name => format!("{}.url", name),
+        }
+    } else {
+        format!("drop-{}.desktop", game_id)
+    }
+}
+
+fn shortcut_paths(game_id: &str, name: &str) -> Vec<PathBuf> {
+    shortcut_dirs()
+        .into_iter()
+        .map(|dir| dir.join(shortcut_file_name(game_id, name)))
+        .collect()
+}
+
+// Saved next to the profile's database, since shortcuts need a file they can point at
+fn save_icon(game_id: &str, icon_id: &str) -> Option<PathBuf> {
+    let (content_type, data) = match fetch_object(icon_id) {
+        Ok(object) => object,
+        Err(e) => {
+            warn!("couldn't fetch icon for {}: {}", game_id, e);
+            return None;
+        }
+    };
+    let extension = match content_type.as_str() {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        _ => "png",
+    };
+    let path = profile_dir()
+        .join("icons")
+        .join(format!("{}.{}", game_id, extension));
+    create_dir_all(path.parent().unwrap()).ok()?;
+    fs::write(&path, data).ok()?;
+    Some(path)
+}
+
+#[cfg(target_os = "linux")]
+fn write_shortcuts(game_id: &str, name: &str, icon: Option<PathBuf>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut contents = format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name={}\n\
+         Exec=xdg-open \"{}\"\n\
+         Terminal=false\n\
+         Categories=Game;\n",
+        shortcut_name(name),
+        launch_url(game_id)
+    );
+    if let Some(icon) = icon {
+        contents.push_str(&format!("Icon={}\n", icon.display()));
+    }
+
+    for path in shortcut_paths(game_id, name) {
+        create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &contents)?;
+        // Desktops won't run entries that aren't executable
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+// Internet shortcuts, rather than .lnk files, since those
+// can only point at a program and not a drop:// link
+#[cfg(target_os = "windows")]
+fn write_shortcuts(game_id: &str, name: &str, icon: Option<PathBuf>) -> io::Result<()> {
+    let mut contents = format!("[InternetShortcut]\r\nURL={}\r\n", launch_url(game_id));
+    if let Some(icon) = icon {
+        contents.push_str(&format!("IconFile={}\r\nIconIndex=0\r\n", icon.display()));
+    }
+
+    for path in shortcut_paths(game_id, name) {
+        create_dir_all(path.parent().unwrap())?;
+        fs::write(path, &contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn write_shortcuts(_game_id: &str, _name: &str, _icon: Option<PathBuf>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Shortcuts aren't supported on this platform",
+    ))
+}
+
+pub fn create_shortcuts(app_handle: &AppHandle, game_id: &str) -> Result<(), String> {
+    let installed = DB
+        .borrow_data()
+        .unwrap()
+        .games
+        .statuses
+        .get(game_id)
+        .and_then(GameStatus::installed)
+        .is_some();
+    if !installed {
+        return Err("Game isn't installed".to_string());
+    }
+    let game = fetch_cached_game(app_handle, game_id)
+        .ok_or("Game details haven't been loaded yet".to_string())?;
+
+    let icon = save_icon(game_id, &game.m_icon_id);
+    write_shortcuts(game_id, &game.m_name, icon)
+        .map_err(|e| format!("Couldn't create shortcut: {}", e))?;
+    info!("created shortcuts for {}", game_id);
+    Ok(())
+}
+
+/// Called once a game has been installed for the first time
+pub fn on_game_installed(app_handle: &AppHandle, game_id: &str) {
+    if !DB.borrow_data().unwrap().settings.create_shortcuts {
+        return;
+    }
+    // The icon might have to be downloaded
+    let app_handle = app_handle.clone();
+    let game_id = game_id.to_string();
+    spawn(move || {
+        if let Err(e) = create_shortcuts(&app_handle, &game_id) {
+            warn!("couldn't create shortcuts for {}: {}", game_id, e);
+        }
+    });
+}
+
+#[tauri::command]
+pub fn create_game_shortcut(app_handle: AppHandle, game_id: String) -> Result<(), String> {
+    create_shortcuts(&app_handle, &game_id)
+}
+
+#[tauri::command]
+pub fn remove_game_shortcut(app_handle: AppHandle, game_id: String) -> Result<(), String> {
+    let name = fetch_cached_game(&app_handle, &game_id)
+        .map(|game| game.m_name)
+        .unwrap_or_default();
+    for path in shortcut_paths(&game_id, &name) {
+        match remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Couldn't remove {}: {}", path.display(), e)),
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_create_shortcuts(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.create_shortcuts = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
@@ -0,0 +1,234 @@
+use std::{collections::HashMap, path::Path};
+
+use rusqlite::{params, Connection};
+use rustbreak::{backend::Backend, error::BackendError};
+use serde_json::{Map, Value};
+
+use crate::db_encryption::{database_key, is_sealed, seal, unseal, DatabaseKey};
+
+// Each top level field is a row, as is each entry of the maps in games,
+// which are the ones that grow with the library. The map itself gets an
+// empty row, so it comes back even when there's nothing in it. Nothing
+// reads rows on their own, the database is always loaded whole, so there
+// are no indexes besides the key.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    section TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (section, key)
+) WITHOUT ROWID;
+";
+
+const GAMES_FIELD: &str = "games";
+
+type EntryKey = (String, String);
+
+/// Keeps the database in SQLite instead of one JSON file. Each save still
+/// serializes the whole database, since that's what rustbreak hands over,
+/// and it's split back into rows here. Only the rows that changed since the
+/// last save are written to disk, in a single transaction. SQLite's
+/// write-ahead log means a crash partway through a save leaves the database
+/// as it was before it.
+///
+/// With the encrypt_database setting on, each value is encrypted on its own
+/// (see db_encryption.rs). Keys stay readable: they're game ids and field
+/// names, which the server knows anyway.
+pub struct SqliteBackend {
+    connection: Connection,
+    // What's in the database right now, decrypted
+    written: HashMap<EntryKey, String>,
+    // Whether what's on disk is encrypted
+    encrypted: bool,
+    // Only read from the keychain once something needs it
+    key: Option<DatabaseKey>,
+}
+
+fn read_rows(connection: &Connection) -> rusqlite::Result<Vec<(EntryKey, String)>> {
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    // A save isn't done until it's on disk. Commits are atomic either way,
+    // but with NORMAL the last few could be lost if the power goes.
+    connection.pragma_update(None, "synchronous", "FULL")?;
+    connection.execute_batch(SCHEMA)?;
+    connection
+        .prepare("SELECT section, key, value FROM entries")?
+        .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+        .collect()
+}
+
+// What an encrypted value is tied to
+fn location((section, key): &EntryKey) -> String {
+    format!("{}/{}", section, key)
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+        let rows = read_rows(&connection).map_err(|e| e.to_string())?;
+
+        let mut backend = Self {
+            connection,
+            written: HashMap::new(),
+            encrypted: false,
+            key: None,
+        };
+        for (entry_key, value) in rows {
+            let value = if is_sealed(&value) {
+                backend.encrypted = true;
+                unseal(&backend.key()?, &location(&entry_key), &value)?
+            } else {
+                value
+            };
+            backend.written.insert(entry_key, value);
+        }
+        Ok(backend)
+    }
+
+    /// Whether nothing has been saved yet
+    pub fn is_empty(&self) -> bool {
+        self.written.is_empty()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    fn key(&mut self) -> Result<DatabaseKey, String> {
+        if let Some(key) = self.key {
+            return Ok(key);
+        }
+        let key = database_key()?;
+        self.key = Some(key);
+        Ok(key)
+    }
+}
+
+fn internal(error: impl ToString) -> BackendError {
+    BackendError::Internal(error.to_string())
+}
+
+fn entries(database: &Value) -> Result<HashMap<EntryKey, String>, BackendError> {
+    let fields = database
+        .as_object()
+        .ok_or_else(|| internal("the database isn't a JSON object"))?;
+
+    let mut entries = HashMap::new();
+    for (field, value) in fields {
+        let games = match value.as_object() {
+            Some(games) if field == GAMES_FIELD => games,
+            _ => {
+                entries.insert((String::new(), field.clone()), value.to_string());
+                continue;
+            }
+        };
+        for (games_field, value) in games {
+            let map = match value.as_object() {
+                Some(map) => map,
+                None => {
+                    entries.insert((field.clone(), games_field.clone()), value.to_string());
+                    continue;
+                }
+            };
+            entries.insert((field.clone(), games_field.clone()), "{}".to_string());
+            let section = format!("{}.{}", field, games_field);
+            for (key, value) in map {
+                entries.insert((section.clone(), key.clone()), value.to_string());
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn assemble(entries: &HashMap<EntryKey, String>) -> Result<Value, BackendError> {
+    let mut database = Map::new();
+    let mut games = Map::new();
+    let mut maps: HashMap<&str, Map<String, Value>> = HashMap::new();
+    for ((section, key), value) in entries {
+        let value: Value = serde_json::from_str(value).map_err(internal)?;
+        match section.as_str() {
+            "" => {
+                database.insert(key.clone(), value);
+            }
+            GAMES_FIELD => {
+                games.insert(key.clone(), value);
+            }
+            section => {
+                let games_field = section
+                    .strip_prefix("games.")
+                    .ok_or_else(|| internal(format!("unknown section {}", section)))?;
+                maps.entry(games_field)
+                    .or_default()
+                    .insert(key.clone(), value);
+            }
+        }
+    }
+    for (games_field, map) in maps {
+        games.insert(games_field.to_string(), Value::Object(map));
+    }
+    if !games.is_empty() {
+        database.insert(GAMES_FIELD.to_string(), Value::Object(games));
+    }
+    Ok(Value::Object(database))
+}
+
+impl Backend for SqliteBackend {
+    fn get_data(&mut self) -> Result<Vec<u8>, BackendError> {
+        serde_json::to_vec(&assemble(&self.written)?).map_err(internal)
+    }
+
+    fn put_data(&mut self, data: &[u8]) -> Result<(), BackendError> {
+        let database: Value = serde_json::from_slice(data).map_err(internal)?;
+        let entries = entries(&database)?;
+        let encrypt = database.pointer("/settings/encryptDatabase") == Some(&Value::Bool(true));
+        // Turning encryption on or off changes how every row is stored
+        let rewrite = encrypt != self.encrypted;
+        let cipher_key = if encrypt {
+            Some(self.key().map_err(internal)?)
+        } else {
+            None
+        };
+
+        let transaction = self.connection.transaction().map_err(internal)?;
+        {
+            let mut upsert = transaction
+                .prepare_cached(
+                    "INSERT INTO entries (section, key, value) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (section, key) DO UPDATE SET value = excluded.value",
+                )
+                .map_err(internal)?;
+            for (entry_key, value) in &entries {
+                if rewrite || self.written.get(entry_key) != Some(value) {
+                    let stored = match &cipher_key {
+                        Some(cipher_key) => seal(cipher_key, &location(entry_key), value),
+                        None => value.clone(),
+                    };
+                    let (section, key) = entry_key;
+                    upsert
+                        .execute(params![section, key, stored])
+                        .map_err(internal)?;
+                }
+            }
+
+            let mut delete = transaction
+                .prepare_cached("DELETE FROM entries WHERE section = ?1 AND key = ?2")
+                .map_err(internal)?;
+            for entry_key in self.written.keys() {
+                if !entries.contains_key(entry_key) {
+                    let (section, key) = entry_key;
+                    delete.execute(params![section, key]).map_err(internal)?;
+                }
+            }
+        }
+        transaction.commit().map_err(internal)?;
+
+        self.written = entries;
+        self.encrypted = encrypt;
+        if rewrite {
+            // Otherwise the old rows can hang around in free pages and the WAL
+            self.connection
+                .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+                .map_err(internal)?;
+        }
+        Ok(())
+    }
+}
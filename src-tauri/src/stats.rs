@@ -0,0 +1,379 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    download_dirs::{fetch_download_dir_stats, DownloadDirStats},
+    process::playtime::Playtime,
+    DB,
+};
+
+/// How long received bytes are held in memory before they're written out.
+/// Chunks finish far too often to save the database for each one.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// How many of the most recent days `get_usage_stats` returns
+const RECENT_DAYS: usize = 90;
+/// Oldest events other than completions are dropped past this, so a
+/// game's history can't grow forever
+const MAX_EVENTS_PER_GAME: usize = 500;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum DownloadEventKind {
+    Queued,
+    Started,
+    // Only completions were recorded at first, so that's what older records are
+    #[default]
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// Something the download manager did with a download of the game,
+/// updates included
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadRecord {
+    pub version_name: String,
+    #[serde(default)]
+    pub kind: DownloadEventKind,
+    // Seconds since the unix epoch
+    #[serde(alias = "completedAt")]
+    pub at: u64,
+    // Left to download when it was queued, downloaded so far when it was
+    // cancelled or failed, and what the whole download was made of (not
+    // what the game takes up after) once it completed
+    pub bytes: Option<u64>,
+    // Why it failed
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl DownloadRecord {
+    fn completed_bytes(&self) -> Option<u64> {
+        match self.kind {
+            DownloadEventKind::Completed => self.bytes,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadHistoryEntry {
+    pub game_id: String,
+    #[serde(flatten)]
+    pub record: DownloadRecord,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadHistoryPage {
+    // Newest first
+    pub entries: Vec<DownloadHistoryEntry>,
+    // Across every page
+    pub total: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameStats {
+    pub playtime: Playtime,
+    // Seconds since the unix epoch, for the installed version
+    pub installed_at: Option<u64>,
+    pub install_size: Option<u64>,
+    // Only the ones that completed
+    pub downloads: Vec<DownloadRecord>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryStats {
+    pub installed_games: usize,
+    pub install_size: u64,
+    pub total_playtime: u64,
+    pub total_downloaded: u64,
+    // Game ids, most played first
+    pub most_played: Vec<String>,
+}
+
+/// What was downloaded on one day, local time
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyUsage {
+    pub total: u64,
+    // Keyed by game id
+    pub per_game: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeriodUsage {
+    // YYYY-MM-DD for days, YYYY-MM for months
+    pub period: String,
+    pub total: u64,
+    pub per_game: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageStats {
+    // Newest first
+    pub days: Vec<PeriodUsage>,
+    pub months: Vec<PeriodUsage>,
+    // Keyed by game id, over all time
+    pub per_game: HashMap<String, u64>,
+    pub total: u64,
+    pub library_folders: Vec<DownloadDirStats>,
+}
+
+/// How many games `LibraryStats::most_played` lists
+const MOST_PLAYED_COUNT: usize = 5;
+
+pub fn record_download_event(
+    game_id: &str,
+    version_name: &str,
+    kind: DownloadEventKind,
+    bytes: Option<u64>,
+    error: Option<String>,
+) {
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let history = db_lock
+        .games
+        .download_history
+        .entry(game_id.to_string())
+        .or_default();
+    history.push(DownloadRecord {
+        version_name: version_name.to_string(),
+        kind,
+        at,
+        bytes,
+        error,
+    });
+    // Completions are kept, they're what the totals are made of
+    if history.len() > MAX_EVENTS_PER_GAME {
+        if let Some(oldest) = history
+            .iter()
+            .position(|record| record.kind != DownloadEventKind::Completed)
+        {
+            history.remove(oldest);
+        }
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn get_game_stats(game_id: String) -> GameStats {
+    let db_lock = DB.borrow_data().unwrap();
+    let manifest = db_lock.games.install_manifests.get(&game_id);
+
+    GameStats {
+        playtime: db_lock
+            .games
+            .playtime
+            .get(&game_id)
+            .cloned()
+            .unwrap_or_default(),
+        installed_at: manifest.map(|manifest| manifest.installed_at),
+        install_size: manifest.map(|manifest| manifest.size),
+        downloads: db_lock
+            .games
+            .download_history
+            .get(&game_id)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.kind == DownloadEventKind::Completed)
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Every game's download events, newest first, optionally just for one
+/// game. Pages start at 0.
+#[tauri::command]
+pub fn get_download_history(
+    page: usize,
+    page_size: usize,
+    game_id: Option<String>,
+) -> DownloadHistoryPage {
+    let page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+    let db_lock = DB.borrow_data().unwrap();
+    let mut entries: Vec<(&String, usize, &DownloadRecord)> = db_lock
+        .games
+        .download_history
+        .iter()
+        .filter(|(id, _)| game_id.as_ref().is_none_or(|game_id| *id == game_id))
+        .flat_map(|(id, history)| {
+            history
+                .iter()
+                .enumerate()
+                .map(move |(index, record)| (id, index, record))
+        })
+        .collect();
+    // Each game's are already in order, which settles events in the same second
+    entries.sort_unstable_by_key(|(_, index, record)| Reverse((record.at, *index)));
+
+    DownloadHistoryPage {
+        total: entries.len(),
+        entries: entries
+            .into_iter()
+            .skip(page.saturating_mul(page_size))
+            .take(page_size)
+            .map(|(game_id, _, record)| DownloadHistoryEntry {
+                game_id: game_id.clone(),
+                record: record.clone(),
+            })
+            .collect(),
+    }
+}
+
+#[tauri::command]
+pub fn get_library_stats() -> LibraryStats {
+    let db_lock = DB.borrow_data().unwrap();
+    let games = &db_lock.games;
+
+    let installed: Vec<&String> = games
+        .statuses
+        .iter()
+        .filter(|(_, status)| status.installed().is_some())
+        .map(|(game_id, _)| game_id)
+        .collect();
+    let install_size = installed
+        .iter()
+        .filter_map(|game_id| games.install_manifests.get(*game_id))
+        .map(|manifest| manifest.size)
+        .sum();
+
+    let mut played: Vec<(&String, u64)> = games
+        .playtime
+        .iter()
+        .filter(|(_, playtime)| playtime.total_seconds > 0)
+        .map(|(game_id, playtime)| (game_id, playtime.total_seconds))
+        .collect();
+    played.sort_by_key(|(_, seconds)| Reverse(*seconds));
+
+    LibraryStats {
+        installed_games: installed.len(),
+        install_size,
+        total_playtime: played.iter().map(|(_, seconds)| seconds).sum(),
+        total_downloaded: games
+            .download_history
+            .values()
+            .flatten()
+            .filter_map(DownloadRecord::completed_bytes)
+            .sum(),
+        most_played: played
+            .into_iter()
+            .take(MOST_PLAYED_COUNT)
+            .map(|(game_id, _)| game_id.clone())
+            .collect(),
+    }
+}
+
+#[derive(Default)]
+struct PendingUsage {
+    per_game: HashMap<String, u64>,
+    last_flush: Option<Instant>,
+}
+
+static PENDING_USAGE: LazyLock<Mutex<PendingUsage>> = LazyLock::new(Mutex::default);
+
+/// Counts bytes received from the server for a game, retries and all, since
+/// that's what a metered connection charges for
+pub fn record_downloaded(game_id: &str, bytes: u64) {
+    if bytes == 0 {
+        return;
+    }
+    let mut pending = PENDING_USAGE.lock().unwrap();
+    *pending.per_game.entry(game_id.to_string()).or_default() += bytes;
+    let due = pending
+        .last_flush
+        .is_none_or(|last_flush| last_flush.elapsed() >= FLUSH_INTERVAL);
+    if due {
+        flush(&mut pending);
+    }
+}
+
+/// Writes out whatever's been counted since the last time
+pub fn flush_usage() {
+    flush(&mut PENDING_USAGE.lock().unwrap());
+}
+
+fn flush(pending: &mut PendingUsage) {
+    pending.last_flush = Some(Instant::now());
+    if pending.per_game.is_empty() {
+        return;
+    }
+    let today = Local::now().format("%Y-%m-%d").to_string();
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let day = db_lock.games.bandwidth_usage.entry(today).or_default();
+    for (game_id, bytes) in pending.per_game.drain() {
+        day.total += bytes;
+        *day.per_game.entry(game_id).or_default() += bytes;
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+fn add_usage(per_game: &mut HashMap<String, u64>, day: &DailyUsage) {
+    for (game_id, bytes) in &day.per_game {
+        *per_game.entry(game_id.clone()).or_default() += bytes;
+    }
+}
+
+/// Bytes downloaded by day, month and game, and how full each library
+/// folder is
+#[tauri::command]
+pub fn get_usage_stats() -> UsageStats {
+    flush_usage();
+    let library_folders = fetch_download_dir_stats().unwrap_or_default();
+
+    let db_lock = DB.borrow_data().unwrap();
+    let games = &db_lock.games;
+
+    let days: BTreeMap<&String, &DailyUsage> = games.bandwidth_usage.iter().collect();
+    let mut months: BTreeMap<&str, PeriodUsage> = BTreeMap::new();
+    let mut per_game = HashMap::new();
+    for (date, day) in &days {
+        // Dates are YYYY-MM-DD, so the month is the start of it
+        let month = &date[..date.len().min(7)];
+        let month = months.entry(month).or_insert_with(|| PeriodUsage {
+            period: month.to_string(),
+            total: 0,
+            per_game: HashMap::new(),
+        });
+        month.total += day.total;
+        add_usage(&mut month.per_game, day);
+        add_usage(&mut per_game, day);
+    }
+
+    UsageStats {
+        days: days
+            .into_iter()
+            .rev()
+            .take(RECENT_DAYS)
+            .map(|(date, day)| PeriodUsage {
+                period: date.clone(),
+                total: day.total,
+                per_game: day.per_game.clone(),
+            })
+            .collect(),
+        months: months.into_values().rev().collect(),
+        total: per_game.values().sum(),
+        per_game,
+        library_folders,
+    }
+}
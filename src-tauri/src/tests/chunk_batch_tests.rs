@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{mpsc::channel, Arc},
+    time::Duration,
+};
+
+use reqwest::Response;
+
+use crate::downloads::{
+    download_agent::GameDownloadError,
+    download_logic::{
+        receive_batch, BatchReceiver, BatchedChunk, ChunkActivity, ChunkSource, WriterSettings,
+    },
+    download_thread_control_flag::{DownloadThreadControl, DownloadThreadControlFlag},
+    manifest::{ChecksumAlgorithm, DropDownloadContext, FileAttributes},
+    progress_object::{ProgressHandle, ProgressObject},
+    rate_limiter::RateLimiter,
+    write_backend::DiskWriteMode,
+};
+
+const CHUNK_LENGTH: usize = 1024;
+
+fn context(path: &PathBuf, index: usize, data: &[u8]) -> DropDownloadContext {
+    DropDownloadContext {
+        file_name: "game.bin".to_string(),
+        version: "1.0".to_string(),
+        index,
+        offset: (index * CHUNK_LENGTH) as u64,
+        game_id: "game".to_string(),
+        path: path.clone(),
+        checksum: blake3::hash(data).to_hex().to_string(),
+        checksum_algorithm: ChecksumAlgorithm::Blake3,
+        length: data.len(),
+        permissions: 0o644,
+        attributes: FileAttributes::default(),
+    }
+}
+
+// Receives `body` as the response to a batch of `chunks`, returning the
+// result, how many chunks were completed and what each chunk's progress is
+async fn receive(
+    chunks: &[Vec<u8>],
+    body: Vec<u8>,
+) -> (Result<(), GameDownloadError>, usize, Vec<usize>) {
+    let path = std::env::temp_dir().join(format!("drop-test-{}.bin", uuid::Uuid::new_v4()));
+    fs::write(&path, vec![0; chunks.len() * CHUNK_LENGTH]).unwrap();
+
+    // Kept so progress updates have somewhere to go
+    let (sender, _receiver) = channel();
+    let progress = Arc::new(ProgressObject::new(body.len(), chunks.len(), sender));
+    let batch: Vec<BatchedChunk> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, data)| BatchedChunk {
+            index,
+            context: context(&path, index, data),
+            progress: ProgressHandle::new(progress.get(index), progress.clone()),
+        })
+        .collect();
+
+    let global_rate_limiter = RateLimiter::new(None);
+    let receiver = BatchReceiver {
+        control_flag: DownloadThreadControl::new(DownloadThreadControlFlag::Go),
+        rate_limiter: Arc::new(RateLimiter::new(None)),
+        global_rate_limiter: &global_rate_limiter,
+        activity: Arc::new(ChunkActivity::default()),
+        writer_settings: WriterSettings {
+            read_buffer_size: CHUNK_LENGTH,
+            write_buffer_size: CHUNK_LENGTH,
+            write_mode: DiskWriteMode::Buffered,
+        },
+        read_timeout: Duration::from_secs(5),
+    };
+    let mut source = ChunkSource::new(Response::from(http::Response::new(body)));
+    let mut completed = 0;
+    let result = receive_batch(&mut source, &batch, &receiver, &mut completed).await;
+
+    let progress = batch.iter().map(|chunk| chunk.progress.get()).collect();
+    fs::remove_file(&path).unwrap();
+    (result, completed, progress)
+}
+
+fn chunks() -> Vec<Vec<u8>> {
+    vec![vec![1; CHUNK_LENGTH], vec![2; CHUNK_LENGTH]]
+}
+
+#[tokio::test]
+async fn test_batch_received_in_full() {
+    let chunks = chunks();
+    let (result, completed, progress) = receive(&chunks, chunks.concat()).await;
+    assert!(result.is_ok());
+    assert_eq!(completed, 2);
+    assert_eq!(progress, vec![CHUNK_LENGTH, CHUNK_LENGTH]);
+}
+
+#[tokio::test]
+async fn test_batch_with_trailing_bytes_is_rejected() {
+    let chunks = chunks();
+    let mut body = chunks.concat();
+    body.extend_from_slice(b"trailing");
+
+    let (result, completed, progress) = receive(&chunks, body).await;
+    assert!(matches!(result, Err(GameDownloadError::Communication(_))));
+    // Not even the chunks before the last one count
+    assert_eq!(completed, 0);
+    assert_eq!(progress, vec![0, 0]);
+}
@@ -0,0 +1,22 @@
+use crate::db_encryption::{is_sealed, seal, unseal};
+
+const KEY: [u8; 32] = [7; 32];
+
+#[test]
+fn test_sealed_values_round_trip() {
+    let value = r#"{"type":"Installed"}"#;
+    let sealed = seal(&KEY, "games.statuses/a", value);
+    assert!(is_sealed(&sealed));
+    assert!(!sealed.contains("Installed"));
+    assert_eq!(unseal(&KEY, "games.statuses/a", &sealed).unwrap(), value);
+    // A fresh nonce each time
+    assert_ne!(seal(&KEY, "games.statuses/a", value), sealed);
+}
+
+#[test]
+fn test_sealed_values_are_tied_to_their_location() {
+    let sealed = seal(&KEY, "games.statuses/a", "{}");
+    assert!(unseal(&KEY, "games.statuses/b", &sealed).is_err());
+    assert!(unseal(&[8; 32], "games.statuses/a", &sealed).is_err());
+    assert!(unseal(&KEY, "games.statuses/a", "{}").is_err());
+}
@@ -0,0 +1,32 @@
+use crate::downloads::download_schedule::{is_within_windows, DownloadWindow};
+
+#[test]
+fn test_no_windows_always_allowed() {
+    assert!(is_within_windows(&[], 0));
+    assert!(is_within_windows(&[], 12 * 60));
+}
+
+#[test]
+fn test_window_within_day() {
+    let windows = [DownloadWindow {
+        start: 60,
+        end: 7 * 60,
+    }];
+    assert!(!is_within_windows(&windows, 59));
+    assert!(is_within_windows(&windows, 60));
+    assert!(is_within_windows(&windows, 7 * 60 - 1));
+    assert!(!is_within_windows(&windows, 7 * 60));
+}
+
+#[test]
+fn test_window_wraps_midnight() {
+    let windows = [DownloadWindow {
+        start: 23 * 60,
+        end: 2 * 60,
+    }];
+    assert!(is_within_windows(&windows, 23 * 60 + 30));
+    assert!(is_within_windows(&windows, 0));
+    assert!(is_within_windows(&windows, 60));
+    assert!(!is_within_windows(&windows, 2 * 60));
+    assert!(!is_within_windows(&windows, 12 * 60));
+}
@@ -0,0 +1,77 @@
+use std::io;
+
+use http::StatusCode;
+
+use crate::downloads::download_agent::GameDownloadError;
+use crate::remote::{ErrorClass, RemoteAccessError};
+
+fn status_error(code: u16) -> RemoteAccessError {
+    RemoteAccessError::Status {
+        endpoint: "/api/v1/client/chunk".to_string(),
+        status: StatusCode::from_u16(code).unwrap(),
+        body: String::new(),
+    }
+}
+
+fn class_of_status(code: u16) -> ErrorClass {
+    GameDownloadError::Communication(status_error(code)).class()
+}
+
+#[test]
+fn test_status_codes() {
+    assert_eq!(class_of_status(500), ErrorClass::Server);
+    assert_eq!(class_of_status(503), ErrorClass::Server);
+    assert_eq!(class_of_status(429), ErrorClass::Server);
+    assert_eq!(class_of_status(408), ErrorClass::Timeout);
+    assert_eq!(class_of_status(404), ErrorClass::Client);
+    assert_eq!(class_of_status(403), ErrorClass::Client);
+}
+
+#[test]
+fn test_io_errors() {
+    let reset = GameDownloadError::Interrupted(io::Error::from(io::ErrorKind::ConnectionReset));
+    assert_eq!(reset.class(), ErrorClass::Connection);
+    assert!(reset.is_retryable());
+
+    let cut_short = GameDownloadError::Interrupted(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert!(cut_short.is_retryable());
+
+    // A file on disk that's shorter than it should be won't fix itself
+    let short_file = GameDownloadError::IoError(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert_eq!(short_file.class(), ErrorClass::Local);
+    assert!(!short_file.is_retryable());
+
+    let full = GameDownloadError::IoError(io::Error::from(io::ErrorKind::StorageFull));
+    assert_eq!(full.class(), ErrorClass::Local);
+    assert!(!full.is_retryable());
+}
+
+#[test]
+fn test_checksum_is_transient() {
+    assert!(GameDownloadError::Checksum.is_retryable());
+}
+
+#[test]
+fn test_unexpected_status_keeps_code() {
+    let error = GameDownloadError::Communication(RemoteAccessError::Status {
+        endpoint: "/api/v1/client/chunk".to_string(),
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        body: "down for maintenance".to_string(),
+    });
+    assert_eq!(error.status_code(), Some(503));
+    assert_eq!(error.class(), ErrorClass::Server);
+    assert_eq!(
+        error.to_string(),
+        "/api/v1/client/chunk responded with 503 Service Unavailable: down for maintenance"
+    );
+}
+
+#[test]
+fn test_remote_errors_retryable() {
+    assert!(status_error(503).is_retryable());
+    assert!(status_error(429).is_retryable());
+    assert!(status_error(408).is_retryable());
+    assert!(!status_error(404).is_retryable());
+    assert!(!status_error(401).is_retryable());
+    assert!(!RemoteAccessError::GameNotFound.is_retryable());
+}
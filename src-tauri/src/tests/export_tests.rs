@@ -0,0 +1,27 @@
+use crate::export::{to_csv, ExportedGame};
+
+#[test]
+fn test_csv_quotes_fields() {
+    let game = ExportedGame {
+        id: "abc".to_string(),
+        name: "Hello, \"World\"".to_string(),
+        status: "Installed".to_string(),
+        installed_version: Some("1.0".to_string()),
+        latest_version: None,
+        install_dir: Some("/games/hello".to_string()),
+        install_size: Some(1024),
+        playtime_seconds: 60,
+        last_played: None,
+        favourite: true,
+        hidden: false,
+        tags: vec!["rpg".to_string(), "co-op".to_string()],
+    };
+    let csv = to_csv(&[game]);
+    let mut lines = csv.lines();
+    assert!(lines.next().unwrap().starts_with("id,name,status,"));
+    assert_eq!(
+        lines.next().unwrap(),
+        "abc,\"Hello, \"\"World\"\"\",Installed,1.0,,/games/hello,1024,60,,true,false,rpg;co-op"
+    );
+    assert_eq!(lines.next(), None);
+}
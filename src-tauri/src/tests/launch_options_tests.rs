@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use crate::process::launch_options::{split_args, LaunchContext, LaunchOptions};
+
+#[test]
+fn test_split_plain_args() {
+    assert_eq!(
+        split_args("  -windowed   -fps 60 ").unwrap(),
+        vec!["-windowed", "-fps", "60"]
+    );
+    assert!(split_args("").unwrap().is_empty());
+}
+
+#[test]
+fn test_split_quoted_args() {
+    assert_eq!(
+        split_args(r#"--name "Some Player" 'it''s' "" a\ b"#).unwrap(),
+        vec!["--name", "Some Player", "its", "", "a b"]
+    );
+    assert_eq!(split_args(r#""say \"hi\"""#).unwrap(), vec![r#"say "hi""#]);
+    assert_eq!(split_args(r"'C:\Games'").unwrap(), vec![r"C:\Games"]);
+}
+
+#[test]
+fn test_split_rejects_unfinished_args() {
+    assert!(split_args("\"open").is_err());
+    assert!(split_args("trailing\\").is_err());
+}
+
+#[test]
+fn test_templates_filled_in() {
+    let options = LaunchOptions {
+        args: "--data={install_dir}/data --id {game_id} {version}".to_string(),
+        ..Default::default()
+    };
+    let context = LaunchContext {
+        game_id: "abc",
+        version_name: "1.0",
+        install_dir: Path::new("/games/abc"),
+    };
+    assert_eq!(
+        options.launch_args(&context).unwrap(),
+        vec!["--data=/games/abc/data", "--id", "abc", "1.0"]
+    );
+}
@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use crate::downloads::manifest::{
+    is_valid_windows_path, part_path, validate_manifest, ChecksumAlgorithm, DropChunk,
+    DropManifest, FileAttributes, FileLink, ManifestError,
+};
+
+fn manifest_with(path: &str, lengths: Vec<usize>, checksums: Vec<String>) -> DropManifest {
+    let chunk = DropChunk {
+        permissions: 0o644,
+        ids: lengths.iter().map(|_| "id".to_string()).collect(),
+        checksums,
+        lengths,
+        version_name: "1.0".to_string(),
+        checksum_algorithm: ChecksumAlgorithm::Md5,
+        attributes: FileAttributes::default(),
+        link: None,
+    };
+    DropManifest::from([(path.to_string(), chunk)])
+}
+
+#[test]
+fn test_valid_manifest_size() {
+    let manifest = manifest_with(
+        "bin/game",
+        vec![100, 50],
+        vec!["a".repeat(32), "b".repeat(32)],
+    );
+    assert_eq!(validate_manifest(&manifest).unwrap(), 150);
+}
+
+#[test]
+fn test_rejects_paths_outside_install() {
+    for path in [
+        "../escape",
+        "/etc/passwd",
+        "bin/../../escape",
+        "",
+        ".",
+        "./.",
+    ] {
+        let manifest = manifest_with(path, vec![1], vec!["a".repeat(32)]);
+        assert!(matches!(
+            validate_manifest(&manifest),
+            Err(ManifestError::UnsafePath { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_rejects_bad_chunks() {
+    let missing_checksum = manifest_with("game", vec![1, 1], vec!["a".repeat(32)]);
+    assert!(matches!(
+        validate_manifest(&missing_checksum),
+        Err(ManifestError::InconsistentChunks { .. })
+    ));
+
+    let empty_chunk = manifest_with("game", vec![1, 0], vec!["a".repeat(32), "b".repeat(32)]);
+    assert!(matches!(
+        validate_manifest(&empty_chunk),
+        Err(ManifestError::EmptyChunk { .. })
+    ));
+
+    let short_checksum = manifest_with("game", vec![1], vec!["abc".to_string()]);
+    assert!(matches!(
+        validate_manifest(&short_checksum),
+        Err(ManifestError::InvalidChecksum { .. })
+    ));
+}
+
+#[test]
+fn test_part_path_keeps_extension() {
+    assert_eq!(
+        part_path(Path::new("games/bin/game.exe")),
+        Path::new("games/bin/game.exe.part")
+    );
+}
+
+#[test]
+fn test_links_stay_inside_install() {
+    let mut manifest = manifest_with("lib/libgame.so.1", vec![1], vec!["a".repeat(32)]);
+    let mut add_link = |path: &str, link: FileLink| {
+        let mut chunk = manifest["lib/libgame.so.1"].clone();
+        chunk.ids.clear();
+        chunk.checksums.clear();
+        chunk.lengths.clear();
+        chunk.link = Some(link);
+        manifest.insert(path.to_string(), chunk);
+        let result = validate_manifest(&manifest);
+        manifest.remove(path);
+        result
+    };
+
+    let symlink = |target: &str| FileLink::Symlink {
+        target: target.to_string(),
+    };
+    assert!(add_link("lib/libgame.so", symlink("libgame.so.1")).is_ok());
+    assert!(add_link("bin/libgame.so", symlink("../lib/libgame.so.1")).is_ok());
+    for target in ["../../escape", "/usr/lib/libgame.so", ""] {
+        assert!(matches!(
+            add_link("lib/libgame.so", symlink(target)),
+            Err(ManifestError::UnsafeLink { .. })
+        ));
+    }
+
+    let hardlink = |target: &str| FileLink::Hardlink {
+        target: target.to_string(),
+    };
+    assert!(add_link("bin/game", hardlink("lib/libgame.so.1")).is_ok());
+    assert!(matches!(
+        add_link("bin/game", hardlink("lib/missing")),
+        Err(ManifestError::UnsafeLink { .. })
+    ));
+}
+
+#[test]
+fn test_links_resolve_through_other_links() {
+    let symlink = |path: &str, target: &str| {
+        let mut manifest = manifest_with(path, vec![], vec![]);
+        let chunk = manifest.get_mut(path).unwrap();
+        chunk.link = Some(FileLink::Symlink {
+            target: target.to_string(),
+        });
+        manifest
+    };
+    let combined = |links: &[(&str, &str)]| {
+        let mut manifest = DropManifest::new();
+        for (path, target) in links {
+            manifest.extend(symlink(path, target));
+        }
+        validate_manifest(&manifest)
+    };
+
+    assert!(combined(&[("a", "."), ("a/b/l", "../x")]).is_ok());
+    // Each looks fine on its own, but together they lead out
+    for links in [
+        &[("a", "."), ("a/b/l", "../../x")][..],
+        &[("a", "b/c"), ("b/c", ".."), ("a/l", "../x")],
+        &[("a", "b"), ("b", "a")],
+    ] {
+        assert!(matches!(
+            combined(links),
+            Err(ManifestError::UnsafeLink { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_accepts_empty_files() {
+    let no_chunks = manifest_with("placeholder", vec![], vec![]);
+    assert_eq!(validate_manifest(&no_chunks).unwrap(), 0);
+
+    let empty_chunk = manifest_with("placeholder", vec![0], vec!["a".repeat(32)]);
+    assert_eq!(validate_manifest(&empty_chunk).unwrap(), 0);
+    assert!(empty_chunk["placeholder"].is_empty_file());
+}
+
+#[test]
+fn test_windows_reserved_names() {
+    for path in ["bin/game.exe", "./data/console.pak", "saves/nul_backup"] {
+        assert!(is_valid_windows_path(path), "{}", path);
+    }
+    for path in [
+        "CON",
+        "data/nul.txt",
+        "Com1.log",
+        "bin/trailing.",
+        "bin/space ",
+        "a:b",
+    ] {
+        assert!(!is_valid_windows_path(path), "{}", path);
+    }
+}
@@ -0,0 +1,100 @@
+use serde_json::json;
+
+use crate::db::InstallManifest;
+use crate::migrations::{migrate, SCHEMA_VERSION};
+
+#[test]
+fn test_migrates_unversioned_database() {
+    let mut database = json!({ "baseUrl": "https://drop.example.com", "games": {} });
+    assert_eq!(migrate(&mut database), Ok(0));
+    assert_eq!(database["schemaVersion"], SCHEMA_VERSION);
+    assert_eq!(database["baseUrl"], "https://drop.example.com");
+
+    // Already current, so nothing runs
+    assert_eq!(migrate(&mut database), Ok(SCHEMA_VERSION));
+}
+
+#[test]
+fn test_refuses_newer_database() {
+    let mut database = json!({ "schemaVersion": SCHEMA_VERSION + 1 });
+    assert!(migrate(&mut database).is_err());
+}
+
+#[test]
+fn test_gathers_game_settings() {
+    let mut database = json!({
+        "schemaVersion": 1,
+        "games": {
+            "launchOptions": { "a": { "args": "-windowed" } },
+            "autoUpdate": { "a": false, "b": true },
+            "downloadQueue": [
+                { "gameId": "b", "version": "1.0", "bandwidthLimit": 1024 },
+                { "gameId": "c", "version": "1.0", "bandwidthLimit": null },
+            ],
+        },
+    });
+    migrate(&mut database).unwrap();
+
+    let games = &database["games"];
+    assert_eq!(
+        games["gameSettings"],
+        json!({
+            "a": { "launch": { "args": "-windowed" }, "autoUpdate": false },
+            "b": { "autoUpdate": true, "bandwidthLimit": 1024 },
+        })
+    );
+    assert!(games.get("launchOptions").is_none());
+    assert!(games.get("autoUpdate").is_none());
+    assert_eq!(
+        games["downloadQueue"][0],
+        json!({ "gameId": "b", "version": "1.0" })
+    );
+}
+
+#[test]
+fn test_moves_installed_files_out_of_database() {
+    let install_dir = std::env::temp_dir().join(format!("drop-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&install_dir).unwrap();
+    let files = json!({
+        "game.exe": {
+            "permissions": 493,
+            "ids": ["a"],
+            "checksums": ["b"],
+            "lengths": [1024, 512],
+            "versionName": "1.0",
+        },
+    });
+    let mut database = json!({
+        "schemaVersion": 2,
+        "games": {
+            "installManifests": {
+                "a": {
+                    "versionName": "1.0",
+                    "installDir": install_dir.to_string_lossy(),
+                    "files": files,
+                    "installedAt": 0,
+                },
+                "b": {
+                    "versionName": "1.0",
+                    "installDir": install_dir.join("missing").to_string_lossy(),
+                    "files": {},
+                    "installedAt": 0,
+                },
+            },
+        },
+    });
+    migrate(&mut database).unwrap();
+
+    let manifest: InstallManifest =
+        serde_json::from_value(database["games"]["installManifests"]["a"].clone()).unwrap();
+    assert_eq!(manifest.size, 1536);
+    let written = manifest.files("a").unwrap();
+    assert_eq!(written["game.exe"].lengths, vec![1024, 512]);
+
+    // Nowhere to put the list, so there's nothing to check against
+    let manifest = &database["games"]["installManifests"]["b"];
+    assert!(manifest.get("files").is_none());
+    assert!(manifest["filesHash"].is_null());
+
+    std::fs::remove_dir_all(install_dir).unwrap();
+}
@@ -1 +1,13 @@
+mod chunk_batch_tests;
+mod db_encryption_tests;
+mod download_schedule_tests;
+mod error_class_tests;
+mod export_tests;
+mod launch_options_tests;
+mod manifest_tests;
+mod migration_tests;
 mod progress_tests;
+mod push_tests;
+mod signing_tests;
+mod sqlite_backend_tests;
+mod throttle_tests;
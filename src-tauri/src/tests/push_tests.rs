@@ -0,0 +1,29 @@
+use crate::push::{EventParser, ServerSentEvent};
+
+#[test]
+fn test_events_split_across_chunks() {
+    let mut parser = EventParser::default();
+    assert!(parser.push(b"event: ver").is_empty());
+    assert!(parser.push(b"sion\r\ndata: {\"gameId\":").is_empty());
+    assert_eq!(
+        parser.push(b"\"abc\"}\r\n\r\nevent: library\n"),
+        vec![ServerSentEvent {
+            event: "version".to_string(),
+            data: r#"{"gameId":"abc"}"#.to_string(),
+        }]
+    );
+    assert_eq!(parser.push(b"data:\n\n").len(), 1);
+}
+
+#[test]
+fn test_comments_ignored_and_data_joined() {
+    let mut parser = EventParser::default();
+    assert!(parser.push(b": keepalive\n\n").is_empty());
+    assert_eq!(
+        parser.push(b"data: one\ndata: two\nid: 7\n\n"),
+        vec![ServerSentEvent {
+            event: String::new(),
+            data: "one\ntwo".to_string(),
+        }]
+    );
+}
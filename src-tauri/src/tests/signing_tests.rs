@@ -0,0 +1,29 @@
+use crate::signing::request_signature;
+
+#[test]
+fn test_request_signature() {
+    let signature = request_signature(
+        b"secret",
+        "put",
+        "/api/v1/client/saves?id=abc",
+        1700000000,
+        "n0",
+        b"{}",
+    );
+    assert_eq!(
+        signature,
+        "bf00284bc47d3615ee6b85657d4e172ec4e036e24de3a37b4bb4a2a5925ed092"
+    );
+    // Anything else about the request changes it
+    assert_ne!(
+        request_signature(
+            b"secret",
+            "PUT",
+            "/api/v1/client/saves?id=abd",
+            1700000000,
+            "n0",
+            b"{}"
+        ),
+        signature
+    );
+}
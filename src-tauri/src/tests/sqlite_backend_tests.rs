@@ -0,0 +1,39 @@
+use rustbreak::backend::Backend;
+use serde_json::{json, Value};
+
+use crate::sqlite_backend::SqliteBackend;
+
+#[test]
+fn test_sqlite_backend_round_trip() {
+    let path = std::env::temp_dir().join(format!("drop-test-{}.sqlite", uuid::Uuid::new_v4()));
+    let mut backend = SqliteBackend::open(&path).unwrap();
+    assert!(backend.is_empty());
+
+    let database = json!({
+        "baseUrl": "https://drop.example.com",
+        "games": {
+            "installDirs": ["/games"],
+            "statuses": { "a": { "type": "Remote" }, "b": { "type": "Remote" } },
+            "versions": {}
+        }
+    });
+    backend.put_data(database.to_string().as_bytes()).unwrap();
+
+    // Dropping one game and changing the other
+    let database = json!({
+        "baseUrl": "https://drop.example.com",
+        "games": {
+            "installDirs": ["/games"],
+            "statuses": { "a": { "type": "Installed" } },
+            "versions": {}
+        }
+    });
+    backend.put_data(database.to_string().as_bytes()).unwrap();
+    drop(backend);
+
+    let mut backend = SqliteBackend::open(&path).unwrap();
+    let loaded: Value = serde_json::from_slice(&backend.get_data().unwrap()).unwrap();
+    assert_eq!(loaded, database);
+
+    let _ = std::fs::remove_file(&path);
+}
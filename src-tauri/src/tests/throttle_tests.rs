@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+
+use crate::throttle::parse_retry_after;
+
+#[test]
+fn test_retry_after_seconds() {
+    let now = Utc::now();
+    assert_eq!(
+        parse_retry_after(" 120 ", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+}
+
+#[test]
+fn test_retry_after_date() {
+    let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 27, 0).unwrap();
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+        Some(Duration::from_secs(60))
+    );
+    // Already passed
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+}
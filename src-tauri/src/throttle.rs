@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use http::{header::RETRY_AFTER, HeaderMap, StatusCode};
+use log::warn;
+
+use crate::remote::RemoteAccessError;
+
+/// When a 429 doesn't say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+/// However long the server asks for, requests don't wait longer than this
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+// So a server saying 0 doesn't get hammered
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Times a blocking request is made again after being rate limited
+const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+
+/// Requests are held off by class, so metadata being rate limited doesn't
+/// stop downloads and the other way round
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RequestClass {
+    Chunks,
+    Metadata,
+    Objects,
+}
+
+// When each class can go again
+static HELD_UNTIL: LazyLock<Mutex<HashMap<RequestClass, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Holds the class off for as long as the 429's headers say
+pub fn note_rate_limited(class: RequestClass, headers: &HeaderMap) {
+    let delay = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, Utc::now()))
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+        .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER);
+    warn!(
+        "server is rate limiting {:?} requests, holding off for {}s",
+        class,
+        delay.as_secs()
+    );
+
+    let until = Instant::now() + delay;
+    let mut held_until = HELD_UNTIL.lock().unwrap();
+    let entry = held_until.entry(class).or_insert(until);
+    *entry = (*entry).max(until);
+}
+
+/// How long until requests of this class can go out, if they're held off
+pub fn remaining(class: RequestClass) -> Option<Duration> {
+    let held_until = HELD_UNTIL.lock().unwrap();
+    held_until
+        .get(&class)
+        .and_then(|until| until.checked_duration_since(Instant::now()))
+        .filter(|remaining| !remaining.is_zero())
+}
+
+/// Sends the request once the class is free to, waiting out a rate limit
+/// and trying again a few times before handing back the 429. `request` is
+/// called for each attempt, since the authorization header can't be reused.
+pub fn send_throttled(
+    class: RequestClass,
+    request: impl Fn() -> reqwest::blocking::RequestBuilder,
+) -> Result<reqwest::blocking::Response, RemoteAccessError> {
+    let mut retries = 0;
+    loop {
+        if let Some(wait) = remaining(class) {
+            sleep(wait);
+        }
+        let response = request().send()?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(response);
+        }
+        note_rate_limited(class, response.headers());
+        if retries == MAX_RATE_LIMITED_RETRIES {
+            return Ok(response);
+        }
+        retries += 1;
+    }
+}
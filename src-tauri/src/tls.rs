@@ -0,0 +1,662 @@
+use std::{error::Error, fs, io, sync::Arc};
+
+use foreign_types::ForeignType;
+use log::{info, warn};
+use openssl::pkcs12::Pkcs12;
+use openssl::{
+    error::ErrorStack, hash::MessageDigest, nid::Nid, pkey::PKey, sha::sha256, x509::X509,
+};
+use reqwest::{tls::TlsInfo, Certificate, Identity};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    db_encryption::{database_key, is_sealed, seal, unseal, DatabaseKey},
+    remote::{inspecting_http_client, rebuild_http_clients},
+    DB,
+};
+
+/// A root certificate added on top of the system's, for servers
+/// behind a CA of their own
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaCertificate {
+    pub subject: String,
+    // SHA-256 of the DER encoding, as hex pairs separated by colons
+    pub fingerprint: String,
+}
+
+/// A certificate the client identifies itself with, for servers behind
+/// mutual TLS. Kept as read when it was set up, so the files can go. The
+/// private key, or the bundle and its password, are sealed with the key in
+/// the OS keychain (they're too big for some keychains to hold themselves).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientCertificate {
+    Pem { certificate: String, key: String },
+    // The bundle's bytes in hex
+    Pkcs12 { bundle: String, password: String },
+}
+
+// What each secret is sealed for, so one can't be swapped for another
+fn secret_location(origin: &str, field: &str) -> String {
+    format!("settings.clientCertificates.{}.{}", origin, field)
+}
+
+fn open_secret(origin: &str, field: &str, value: &str) -> Result<String, String> {
+    if !is_sealed(value) {
+        return Ok(value.to_string());
+    }
+    unseal(&database_key()?, &secret_location(origin, field), value)
+}
+
+impl ClientCertificate {
+    fn is_sealed(&self) -> bool {
+        match self {
+            ClientCertificate::Pem { key, .. } => is_sealed(key),
+            ClientCertificate::Pkcs12 { bundle, .. } => is_sealed(bundle),
+        }
+    }
+
+    fn sealed(self, database_key: &DatabaseKey, origin: &str) -> Self {
+        let seal_field =
+            |field: &str, value: &str| seal(database_key, &secret_location(origin, field), value);
+        match self {
+            ClientCertificate::Pem { certificate, key } => ClientCertificate::Pem {
+                certificate,
+                key: seal_field("key", &key),
+            },
+            ClientCertificate::Pkcs12 { bundle, password } => ClientCertificate::Pkcs12 {
+                bundle: seal_field("bundle", &bundle),
+                password: seal_field("password", &password),
+            },
+        }
+    }
+
+    fn unsealed(&self, origin: &str) -> Result<Self, String> {
+        Ok(match self {
+            ClientCertificate::Pem { certificate, key } => ClientCertificate::Pem {
+                certificate: certificate.clone(),
+                key: open_secret(origin, "key", key)?,
+            },
+            ClientCertificate::Pkcs12 { bundle, password } => ClientCertificate::Pkcs12 {
+                bundle: open_secret(origin, "bundle", bundle)?,
+                password: open_secret(origin, "password", password)?,
+            },
+        })
+    }
+
+    pub fn identity(&self) -> Result<Identity, String> {
+        match self {
+            ClientCertificate::Pem { certificate, key } => {
+                Identity::from_pkcs8_pem(certificate.as_bytes(), key.as_bytes())
+            }
+            ClientCertificate::Pkcs12 { bundle, password } => {
+                let bundle = hex::decode(bundle).map_err(|e| e.to_string())?;
+                Identity::from_pkcs12_der(&bundle, password)
+            }
+        }
+        .map_err(|e| format!("Couldn't use client certificate: {}", e))
+    }
+
+    // The chain and PKCS#8 key, for TLS that isn't the platform's
+    fn der(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+        let (chain, key) = match self {
+            ClientCertificate::Pem { certificate, key } => (
+                X509::stack_from_pem(certificate.as_bytes()).map_err(|e| e.to_string())?,
+                PKey::private_key_from_pem(key.as_bytes()).map_err(|e| e.to_string())?,
+            ),
+            ClientCertificate::Pkcs12 { bundle, password } => {
+                let bundle = hex::decode(bundle).map_err(|e| e.to_string())?;
+                let parsed = Pkcs12::from_der(&bundle)
+                    .and_then(|bundle| bundle.parse2(password))
+                    .map_err(|e| e.to_string())?;
+                let mut chain: Vec<X509> = parsed.cert.into_iter().collect();
+                chain.extend(parsed.ca.into_iter().flatten());
+                (chain, parsed.pkey.ok_or("The bundle has no private key")?)
+            }
+        };
+        let chain = chain
+            .iter()
+            .map(|certificate| certificate.to_der().map(CertificateDer::from))
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        let key = key.private_key_to_pkcs8().map_err(|e| e.to_string())?;
+        Ok((chain, PrivatePkcs8KeyDer::from(key).into()))
+    }
+
+    fn certificate(&self) -> Option<X509> {
+        match self {
+            ClientCertificate::Pem { certificate, .. } => {
+                X509::from_pem(certificate.as_bytes()).ok()
+            }
+            ClientCertificate::Pkcs12 { bundle, password } => {
+                Pkcs12::from_der(&hex::decode(bundle).ok()?)
+                    .ok()?
+                    .parse2(password)
+                    .ok()?
+                    .cert
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientCertificateInfo {
+    pub origin: String,
+    pub subject: String,
+    pub fingerprint: String,
+}
+
+/// What a server presented, for the user to decide whether to trust it
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCertificate {
+    pub origin: String,
+    pub subject: String,
+    pub fingerprint: String,
+    // CA certificates can't be pinned
+    pub is_ca: bool,
+    #[serde(skip)]
+    pub pem: String,
+}
+
+pub fn fingerprint(certificate: &X509) -> String {
+    certificate
+        .digest(MessageDigest::sha256())
+        .map(|digest| {
+            digest
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+        .unwrap_or_default()
+}
+
+pub fn subject(certificate: &X509) -> String {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "Unnamed certificate".to_string())
+}
+
+/// Every extra root certificate, for the HTTP clients. A bundle that
+/// can't be read any more is skipped, rather than breaking every request.
+pub fn root_certificates() -> Vec<Certificate> {
+    let bundles = DB.borrow_data().unwrap().settings.ca_certificates.clone();
+    bundles
+        .iter()
+        .flat_map(|pem| match Certificate::from_pem_bundle(pem.as_bytes()) {
+            Ok(certificates) => certificates,
+            Err(e) => {
+                warn!("skipping unreadable CA certificate: {}", e);
+                Vec::new()
+            }
+        })
+        .collect()
+}
+
+// Whether the certificate can sign others, which a pinned one mustn't
+fn is_ca(certificate: &X509) -> bool {
+    // SAFETY: only reads the certificate, which outlives the call
+    unsafe { openssl_sys::X509_check_ca(certificate.as_ptr()) != 0 }
+}
+
+/// SHA-256 of the certificate pinned for `origin`, if there is one
+pub fn pinned_fingerprint(origin: &str) -> Option<[u8; 32]> {
+    let db_lock = DB.borrow_data().unwrap();
+    let pem = db_lock.settings.pinned_certificates.get(origin)?;
+    let certificate = match X509::from_pem(pem.as_bytes()) {
+        Ok(certificate) => certificate,
+        Err(e) => {
+            warn!(
+                "skipping unreadable pinned certificate for {}: {}",
+                origin, e
+            );
+            return None;
+        }
+    };
+    if is_ca(&certificate) {
+        warn!("skipping pinned certificate for {}, it's a CA", origin);
+        return None;
+    }
+    Some(sha256(&certificate.to_der().ok()?))
+}
+
+/// Accepts the one certificate that was pinned and nothing else, so a
+/// pin never vouches for any other server
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if sha256(end_entity.as_ref()) != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// TLS for a server with a pinned certificate, presenting `client_certificate` if it needs one
+pub fn pinned_tls_config(
+    fingerprint: [u8; 32],
+    client_certificate: Option<&ClientCertificate>,
+) -> Result<ClientConfig, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerifier {
+            fingerprint,
+            provider,
+        }));
+    match client_certificate {
+        Some(client_certificate) => {
+            let (chain, key) = client_certificate.der()?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("Couldn't use client certificate: {}", e))
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn add_pem(pem: String) -> Result<(), String> {
+    let certificates = X509::stack_from_pem(pem.as_bytes())
+        .map_err(|e| format!("Not a PEM certificate: {}", e))?;
+    if certificates.is_empty() {
+        return Err("No certificates were found".to_string());
+    }
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.ca_certificates.push(pem);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn add_ca_certificate(pem: String) -> Result<(), String> {
+    add_pem(pem)
+}
+
+/// The file is read now, so it can be moved or deleted afterwards
+#[tauri::command]
+pub fn add_ca_certificate_file(path: String) -> Result<(), String> {
+    let pem = fs::read_to_string(&path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    add_pem(pem)
+}
+
+#[tauri::command]
+pub fn remove_ca_certificate(index: usize) -> Result<(), String> {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    if index >= db_lock.settings.ca_certificates.len() {
+        return Err("Invalid certificate".to_string());
+    }
+    db_lock.settings.ca_certificates.remove(index);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+    Ok(())
+}
+
+/// One entry per added bundle, described by its first certificate
+#[tauri::command]
+pub fn fetch_ca_certificates() -> Vec<CaCertificate> {
+    let bundles = DB.borrow_data().unwrap().settings.ca_certificates.clone();
+    bundles
+        .iter()
+        .map(|pem| {
+            let certificates = X509::stack_from_pem(pem.as_bytes()).unwrap_or_default();
+            match certificates.first() {
+                Some(certificate) => CaCertificate {
+                    subject: subject(certificate),
+                    fingerprint: fingerprint(certificate),
+                },
+                None => CaCertificate {
+                    subject: "Unreadable certificate".to_string(),
+                    fingerprint: String::new(),
+                },
+            }
+        })
+        .collect()
+}
+
+// OpenSSL's codes for a certificate that didn't verify
+const ERR_LIB_SSL: i32 = 20;
+const SSL_R_CERTIFICATE_VERIFY_FAILED: i32 = 134;
+
+fn rejected_certificate(error: &(dyn Error + 'static)) -> bool {
+    // From a pinned certificate's verifier
+    if let Some(error) = error.downcast_ref::<rustls::Error>() {
+        return matches!(
+            error,
+            rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented
+        );
+    }
+    if let Some(stack) = error.downcast_ref::<ErrorStack>() {
+        return stack.errors().iter().any(|error| {
+            error.library_code() == ERR_LIB_SSL
+                && error.reason_code() == SSL_R_CERTIFICATE_VERIFY_FAILED
+        });
+    }
+    // Schannel and Secure Transport errors don't say why the handshake failed
+    #[cfg(any(windows, target_os = "macos"))]
+    if error.is::<native_tls::Error>() {
+        return true;
+    }
+    false
+}
+
+/// Whether a request failed because the server's certificate wasn't trusted
+pub fn is_certificate_error(error: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if rejected_certificate(error) {
+            return true;
+        }
+        // io::Error's source skips over the error it wraps
+        source = match error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+        {
+            Some(inner) => Some(inner),
+            None => error.source(),
+        };
+    }
+    false
+}
+
+/// Connects without checking the certificate, only to see what it is.
+/// Goes through the proxy like any other request.
+pub fn fetch_server_certificate(url: &str) -> Result<ServerCertificate, String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    let response = inspecting_http_client()
+        .get(url.clone())
+        .send()
+        .map_err(|e| e.to_string())?;
+    let der = response
+        .extensions()
+        .get::<TlsInfo>()
+        .and_then(TlsInfo::peer_certificate)
+        .ok_or("Server didn't present a certificate")?;
+    let certificate = X509::from_der(der).map_err(|e| e.to_string())?;
+
+    Ok(ServerCertificate {
+        origin: url.origin().ascii_serialization(),
+        subject: subject(&certificate),
+        fingerprint: fingerprint(&certificate),
+        is_ca: is_ca(&certificate),
+        pem: String::from_utf8(certificate.to_pem().map_err(|e| e.to_string())?).unwrap(),
+    })
+}
+
+#[tauri::command]
+pub fn fetch_untrusted_certificate(url: String) -> Result<ServerCertificate, String> {
+    fetch_server_certificate(&url)
+}
+
+/// Pins the server's certificate, as long as it's still the one the user
+/// was shown. It's fetched again, so a different one can't be slipped in.
+/// From then on that server has to present exactly this certificate.
+#[tauri::command]
+pub fn trust_server_certificate(url: String, fingerprint: String) -> Result<(), String> {
+    let certificate = fetch_server_certificate(&url)?;
+    if certificate.fingerprint != fingerprint {
+        return Err("The server's certificate has changed, check it again".to_string());
+    }
+    // One that can sign for other servers would be trusted for those too
+    if certificate.is_ca {
+        return Err("That's a CA certificate, add it as a CA instead".to_string());
+    }
+
+    info!(
+        "trusting certificate {} for {}",
+        certificate.fingerprint, certificate.origin
+    );
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock
+        .settings
+        .pinned_certificates
+        .insert(certificate.origin, certificate.pem);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn untrust_server_certificate(origin: String) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.pinned_certificates.remove(&origin);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+}
+
+/// The certificates pinned for each server
+#[tauri::command]
+pub fn fetch_trusted_certificates() -> Vec<ServerCertificate> {
+    let pinned = DB
+        .borrow_data()
+        .unwrap()
+        .settings
+        .pinned_certificates
+        .clone();
+    pinned
+        .into_iter()
+        .filter_map(|(origin, pem)| {
+            let certificate = X509::from_pem(pem.as_bytes()).ok()?;
+            Some(ServerCertificate {
+                origin,
+                subject: subject(&certificate),
+                fingerprint: fingerprint(&certificate),
+                is_ca: is_ca(&certificate),
+                pem,
+            })
+        })
+        .collect()
+}
+
+fn origin_of(url: &str) -> Result<String, String> {
+    Ok(Url::parse(url)
+        .map_err(|e| e.to_string())?
+        .origin()
+        .ascii_serialization())
+}
+
+/// The certificate to present to the server at `origin`, if it needs one,
+/// with its secrets unsealed
+pub fn client_certificate(origin: &str) -> Option<ClientCertificate> {
+    let certificate = DB
+        .borrow_data()
+        .unwrap()
+        .settings
+        .client_certificates
+        .get(origin)
+        .cloned()?;
+    certificate
+        .unsealed(origin)
+        .inspect_err(|e| warn!("skipping client certificate for {}: {}", origin, e))
+        .ok()
+}
+
+// Falls back to keeping them as they are without a keychain, like the sign-in does
+fn seal_if_possible(certificate: ClientCertificate, origin: &str) -> ClientCertificate {
+    match database_key() {
+        Ok(database_key) => certificate.sealed(&database_key, origin),
+        Err(e) => {
+            warn!("keeping client certificate for {} unsealed: {}", origin, e);
+            certificate
+        }
+    }
+}
+
+/// Seals the secrets of client certificates set up before they were
+pub fn seal_client_certificates() {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    let unsealed: Vec<String> = db_lock
+        .settings
+        .client_certificates
+        .iter()
+        .filter(|(_, certificate)| !certificate.is_sealed())
+        .map(|(origin, _)| origin.clone())
+        .collect();
+    if unsealed.is_empty() {
+        return;
+    }
+    let database_key = match database_key() {
+        Ok(database_key) => database_key,
+        Err(e) => {
+            warn!("leaving client certificates unsealed: {}", e);
+            return;
+        }
+    };
+    for origin in unsealed {
+        let certificates = &mut db_lock.settings.client_certificates;
+        let certificate = certificates.remove(&origin).unwrap();
+        certificates.insert(origin.clone(), certificate.sealed(&database_key, &origin));
+    }
+    drop(db_lock);
+    DB.save().unwrap();
+    info!("sealed client certificates with the keychain's key");
+}
+
+/// Sets the certificate presented to the server at `url`. With a key it's a
+/// PEM certificate and PKCS#8 key, without one a PKCS#12 bundle.
+#[tauri::command]
+pub fn set_client_certificate(
+    url: String,
+    certificate_path: String,
+    key_path: Option<String>,
+    password: Option<String>,
+) -> Result<(), String> {
+    let origin = origin_of(&url)?;
+    let read = |path: &String| fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e));
+    let certificate = match key_path {
+        Some(key_path) => ClientCertificate::Pem {
+            certificate: String::from_utf8_lossy(&read(&certificate_path)?).to_string(),
+            key: String::from_utf8_lossy(&read(&key_path)?).to_string(),
+        },
+        None => ClientCertificate::Pkcs12 {
+            bundle: hex::encode(read(&certificate_path)?),
+            password: password.unwrap_or_default(),
+        },
+    };
+    // Checked now, rather than on the next request
+    certificate.identity()?;
+    let certificate = seal_if_possible(certificate, &origin);
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock
+        .settings
+        .client_certificates
+        .insert(origin, certificate);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn remove_client_certificate(url: String) -> Result<(), String> {
+    let origin = origin_of(&url)?;
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.client_certificates.remove(&origin);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    rebuild_http_clients();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_client_certificates() -> Vec<ClientCertificateInfo> {
+    let certificates = DB
+        .borrow_data()
+        .unwrap()
+        .settings
+        .client_certificates
+        .clone();
+    certificates
+        .into_iter()
+        .map(|(origin, client_certificate)| {
+            let certificate = client_certificate
+                .unsealed(&origin)
+                .ok()
+                .and_then(|client_certificate| client_certificate.certificate());
+            ClientCertificateInfo {
+                origin,
+                subject: certificate
+                    .as_ref()
+                    .map(subject)
+                    .unwrap_or_else(|| "Unreadable certificate".to_string()),
+                fingerprint: certificate.as_ref().map(fingerprint).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
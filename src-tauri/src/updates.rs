@@ -0,0 +1,375 @@
+use std::{
+    sync::Mutex,
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use http::StatusCode;
+use log::{info, warn};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{
+    db::GameStatus,
+    download_dirs::default_download_dir,
+    game_settings::{settings_for, update_game_settings},
+    library::{fetch_game_verion_options_logic, GameUpdateEvent, UpdateAvailableEvent},
+    remote::RemoteAccessError,
+    state::GameStatusManager,
+    AppState, AppStatus, DB,
+};
+
+/// How often installed games are checked against the server
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Checks for updates straight away, then every UPDATE_CHECK_INTERVAL
+pub fn spawn_update_checker(app_handle: AppHandle) {
+    spawn(move || loop {
+        let signed_in = matches!(
+            app_handle.state::<Mutex<AppState>>().lock().unwrap().status,
+            AppStatus::SignedIn
+        );
+        if signed_in {
+            check_all_games(&app_handle);
+        }
+        sleep(UPDATE_CHECK_INTERVAL);
+    });
+}
+
+/// Returns the games which have an update available
+fn check_all_games(app_handle: &AppHandle) -> Vec<String> {
+    let installed: Vec<String> = DB
+        .borrow_data()
+        .unwrap()
+        .games
+        .statuses
+        .iter()
+        .filter(|(_, status)| {
+            matches!(
+                status,
+                GameStatus::Installed { .. } | GameStatus::UpdateAvailable { .. }
+            )
+        })
+        .map(|(game_id, _)| game_id.clone())
+        .collect();
+
+    let mut updates = Vec::new();
+    for game_id in installed {
+        if check_for_update(app_handle, &game_id) {
+            updates.push(game_id);
+        }
+    }
+    info!("found updates for {} installed games", updates.len());
+
+    updates
+}
+
+/// Checks a single game, queueing the update if it's set to update
+/// automatically. Returns whether it has one.
+pub fn check_for_update(app_handle: &AppHandle, game_id: &str) -> bool {
+    match check_game(app_handle, game_id) {
+        Ok(Some(latest_version)) => {
+            if auto_update_enabled(game_id) {
+                queue_update(app_handle, game_id, latest_version);
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!("couldn't check {} for updates: {}", game_id, e);
+            false
+        }
+    }
+}
+
+// Marks the game as having an update, or not, depending on the newest version
+// the server has for this platform. Returns the version to update to, if any.
+fn check_game(app_handle: &AppHandle, game_id: &str) -> Result<Option<String>, RemoteAccessError> {
+    // Installed from a previous server, so its versions mean nothing here yet
+    if DB.borrow_data().unwrap().games.unverified.contains(game_id) {
+        return Ok(None);
+    }
+    let state = app_handle.state::<Mutex<AppState>>();
+    let versions = fetch_game_verion_options_logic(game_id.to_string(), &state)?;
+    let latest = match versions.iter().max_by_key(|version| version.version_index) {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    // Whatever's working on the game sets its status once it's done
+    if db_lock.games.transient_statuses.contains_key(game_id) {
+        return Ok(None);
+    }
+    let (version_name, install_dir, known_latest) = match db_lock.games.statuses.get(game_id) {
+        Some(GameStatus::Installed {
+            version_name,
+            install_dir,
+        }) => (version_name.clone(), install_dir.clone(), None),
+        Some(GameStatus::UpdateAvailable {
+            version_name,
+            install_dir,
+            latest_version,
+        }) => (
+            version_name.clone(),
+            install_dir.clone(),
+            Some(latest_version.clone()),
+        ),
+        _ => return Ok(None),
+    };
+
+    // A version the server doesn't list any more can't be compared,
+    // and a pinned version stays put until it's unpinned
+    let pinned = db_lock.games.pinned_versions.contains_key(game_id);
+    let update = !pinned
+        && versions
+            .iter()
+            .find(|version| version.version_name == version_name)
+            .is_some_and(|installed| latest.version_index > installed.version_index);
+    let latest_version = update.then(|| latest.version_name.clone());
+    if known_latest == latest_version {
+        return Ok(latest_version);
+    }
+
+    let status = match latest_version.clone() {
+        Some(latest_version) => GameStatus::UpdateAvailable {
+            version_name: version_name.clone(),
+            install_dir,
+            latest_version,
+        },
+        None => GameStatus::Installed {
+            version_name: version_name.clone(),
+            install_dir,
+        },
+    };
+    db_lock.games.statuses.insert(game_id.to_string(), status);
+    drop(db_lock);
+    DB.save().unwrap();
+
+    let game_id = game_id.to_string();
+    app_handle
+        .emit(
+            &format!("update_game/{}", game_id),
+            GameUpdateEvent {
+                game_id: game_id.clone(),
+                status: GameStatusManager::fetch_state(&game_id),
+            },
+        )
+        .unwrap();
+    if let Some(latest_version) = latest_version.clone() {
+        info!(
+            "{} has an update from {} to {}",
+            game_id, version_name, latest_version
+        );
+        app_handle
+            .emit(
+                "update_available",
+                UpdateAvailableEvent {
+                    game_id,
+                    installed_version: version_name,
+                    latest_version,
+                },
+            )
+            .unwrap();
+    }
+
+    Ok(latest_version)
+}
+
+// A game's own setting wins over the global one
+fn auto_update_enabled(game_id: &str) -> bool {
+    let db_lock = DB.borrow_data().unwrap();
+    settings_for(&db_lock, game_id)
+        .auto_update
+        .unwrap_or(db_lock.settings.auto_update)
+}
+
+fn queue_update(app_handle: &AppHandle, game_id: &str, version: String) {
+    let state = app_handle.state::<Mutex<AppState>>();
+    let (download_manager, process_manager) = {
+        let state_lock = state.lock().unwrap();
+        (
+            state_lock.download_manager.clone(),
+            state_lock.process_manager.clone(),
+        )
+    };
+    // Left for the next check, once it's closed
+    if process_manager.lock().unwrap().is_running(game_id) {
+        info!("not queueing update of {}: it's running", game_id);
+        return;
+    }
+    if download_manager
+        .read_queue()
+        .iter()
+        .any(|queued| queued.id == game_id)
+    {
+        return;
+    }
+
+    info!("queueing update of {} to {}", game_id, version);
+    // Updates go wherever the game is already installed, whichever directory is passed
+    if let Err(e) =
+        download_manager.queue_game(game_id.to_string(), version, default_download_dir())
+    {
+        warn!("couldn't queue update of {}: {}", game_id, e);
+    }
+}
+
+#[tauri::command]
+pub fn check_for_updates(app_handle: AppHandle) -> Vec<String> {
+    check_all_games(&app_handle)
+}
+
+/// Checks games installed from a previous server against this one. Games
+/// the server has the installed version of go back to being updated as
+/// usual; the ones it doesn't are returned, for the user to reinstall or
+/// uninstall.
+#[tauri::command]
+pub fn verify_installed_games(app_handle: AppHandle) -> Result<Vec<String>, String> {
+    let unverified: Vec<String> = DB
+        .borrow_data()
+        .unwrap()
+        .games
+        .unverified
+        .iter()
+        .cloned()
+        .collect();
+    let state = app_handle.state::<Mutex<AppState>>();
+
+    let mut missing = Vec::new();
+    for game_id in unverified {
+        let installed_version = DB
+            .borrow_data()
+            .unwrap()
+            .games
+            .statuses
+            .get(&game_id)
+            .and_then(GameStatus::installed)
+            .map(|(version_name, _)| version_name.clone());
+        // Uninstalled since, so there's nothing left to check
+        let found = match installed_version {
+            None => true,
+            Some(installed_version) => {
+                match fetch_game_verion_options_logic(game_id.clone(), &state) {
+                    Ok(versions) => versions
+                        .iter()
+                        .any(|version| version.version_name == installed_version),
+                    Err(RemoteAccessError::Status { status, .. })
+                        if status == StatusCode::NOT_FOUND =>
+                    {
+                        false
+                    }
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+        };
+        if !found {
+            missing.push(game_id);
+            continue;
+        }
+
+        let mut db_lock = DB.borrow_data_mut().unwrap();
+        db_lock.games.unverified.remove(&game_id);
+        drop(db_lock);
+        DB.save().unwrap();
+        check_for_update(&app_handle, &game_id);
+    }
+    info!("{} installed games aren't on this server", missing.len());
+
+    Ok(missing)
+}
+
+/// Installs a specific version over the current one, usually an older one,
+/// and pins the game to it so update checks leave it alone
+#[tauri::command]
+pub fn rollback_game(
+    state: tauri::State<'_, Mutex<AppState>>,
+    game_id: String,
+    version: String,
+) -> Result<(), String> {
+    let db_lock = DB.borrow_data().unwrap();
+    let installed_version = match db_lock
+        .games
+        .statuses
+        .get(&game_id)
+        .and_then(GameStatus::installed)
+    {
+        Some((version_name, _)) => version_name.clone(),
+        None => return Err("Game isn't installed".to_string()),
+    };
+    let busy = db_lock.games.transient_statuses.contains_key(&game_id);
+    drop(db_lock);
+    if busy {
+        return Err("Game is busy, try again once it's finished".to_string());
+    }
+    if installed_version == version {
+        return Err("That version is already installed".to_string());
+    }
+
+    let versions =
+        fetch_game_verion_options_logic(game_id.clone(), &state).map_err(|e| e.to_string())?;
+    if !versions.iter().any(|option| option.version_name == version) {
+        return Err("Version isn't available for this platform".to_string());
+    }
+
+    // Only pinned once the rollback has actually finished
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock
+        .games
+        .pending_pins
+        .insert(game_id.clone(), version.clone());
+    drop(db_lock);
+    DB.save().unwrap();
+
+    info!(
+        "rolling {} back from {} to {}",
+        game_id, installed_version, version
+    );
+    // Goes over the installed version in place, reusing whatever chunks match
+    let queued = state.lock().unwrap().download_manager.queue_game(
+        game_id.clone(),
+        version,
+        default_download_dir(),
+    );
+    if queued.is_err() {
+        DB.borrow_data_mut()
+            .unwrap()
+            .games
+            .pending_pins
+            .remove(&game_id);
+        DB.save().unwrap();
+        return Err("An error occurred while communicating with the download manager.".to_string());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn unpin_game_version(game_id: String) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.games.pinned_versions.remove(&game_id);
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+#[tauri::command]
+pub fn fetch_pinned_version(game_id: String) -> Option<String> {
+    DB.borrow_data()
+        .unwrap()
+        .games
+        .pinned_versions
+        .get(&game_id)
+        .cloned()
+}
+
+#[tauri::command]
+pub fn set_auto_update(enabled: bool) {
+    let mut db_lock = DB.borrow_data_mut().unwrap();
+    db_lock.settings.auto_update = enabled;
+    drop(db_lock);
+    DB.save().unwrap();
+}
+
+// None goes back to following the global setting
+#[tauri::command]
+pub fn set_game_auto_update(game_id: String, enabled: Option<bool>) {
+    update_game_settings(&game_id, |settings| settings.auto_update = enabled);
+}
@@ -23,6 +23,15 @@ export type User = {
 export type AppState = {
   status: AppStatus;
   user?: User;
+  remoteStatus: RemoteStatus;
+  capabilities?: ServerCapabilities;
+};
+
+export type ServerCapabilities = {
+  version?: string;
+  hashAlgorithms: string[];
+  chunkSize?: number;
+  features?: string[];
 };
 
 export type Game = {
@@ -34,6 +43,32 @@ export type Game = {
   mBannerId: string;
   mCoverId: string;
   mImageLibrary: string[];
+  hidden: boolean;
+  favourite: boolean;
+};
+
+export type Collection = {
+  id: string;
+  name: string;
+  gameIds: string[];
+};
+
+export type Device = {
+  id: string;
+  name: string;
+  platform: string;
+  lastSeen?: string;
+  current: boolean;
+};
+
+export type HealthcheckRetry = {
+  attempt: number;
+  retryIn: number;
+  error: string;
+};
+
+export type RemoteChanged = {
+  unverified: Array<string>;
 };
 
 export enum AppStatus {
@@ -42,19 +77,118 @@ export enum AppStatus {
   SignedIn = "SignedIn",
   SignedInNeedsReauth = "SignedInNeedsReauth",
   ServerUnavailable = "ServerUnavailable",
+  AwaitingTwoFactor = "AwaitingTwoFactor",
+}
+
+export type TwoFactorChallenge = {
+  method: "totp" | "email";
+};
+
+export enum RemoteStatus {
+  Online = "Online",
+  Degraded = "Degraded",
+  Offline = "Offline",
 }
 
+export type RemoteStatusEvent = {
+  status: RemoteStatus;
+  previous: RemoteStatus;
+  latencyMs?: number;
+};
+
 export enum GameStatusEnum {
   Remote = "Remote",
   Queued = "Queued",
   Downloading = "Downloading",
   Installed = "Installed",
+  UpdateAvailable = "UpdateAvailable",
   Updating = "Updating",
   Uninstalling = "Uninstalling",
+  Moving = "Moving",
+  Importing = "Importing",
   SetupRequired = "SetupRequired",
 }
 
 export type GameStatus = {
   type: GameStatusEnum;
   version_name?: string;
+  latest_version?: string;
+};
+
+export type DownloadDir = {
+  path: string;
+  freeSpace?: number;
+  totalSpace?: number;
+  isDefault: boolean;
+  used: number;
+};
+
+export type GameSettings = {
+  launch: {
+    args: string;
+    env: { [name: string]: string };
+    preLaunch?: string;
+    postExit?: string;
+  };
+  wrapper: string;
+  autoUpdate?: boolean;
+  bandwidthLimit?: number;
+};
+
+export type Profile = {
+  id: string;
+  name: string;
+};
+
+export type Profiles = {
+  active: string;
+  profiles: Array<Profile>;
+};
+
+export type OrphanedDownload = {
+  gameId: string;
+  version: string;
+  size: number;
+  installDir?: number;
+};
+
+export type OrphanedDownloadsEvent = {
+  reclaimed: number;
+  resumable: Array<OrphanedDownload>;
+};
+
+export enum DownloadEventKind {
+  Queued = "Queued",
+  Started = "Started",
+  Completed = "Completed",
+  Cancelled = "Cancelled",
+  Failed = "Failed",
+}
+
+export type DownloadHistoryEntry = {
+  gameId: string;
+  versionName: string;
+  kind: DownloadEventKind;
+  at: number;
+  bytes?: number;
+  error?: string;
+};
+
+export type DownloadHistoryPage = {
+  entries: Array<DownloadHistoryEntry>;
+  total: number;
+};
+
+export type PeriodUsage = {
+  period: string;
+  total: number;
+  perGame: { [gameId: string]: number };
+};
+
+export type UsageStats = {
+  days: Array<PeriodUsage>;
+  months: Array<PeriodUsage>;
+  perGame: { [gameId: string]: number };
+  total: number;
+  libraryFolders: Array<DownloadDir>;
 };